@@ -1,87 +1,197 @@
 use proc_macro::{self, TokenStream};
+use proc_macro_error::{abort, proc_macro_error};
 use quote::quote;
-use syn::{parse_macro_input, Data, DataStruct, DeriveInput, Fields, Generics, Ident};
+use syn::{
+    parse_macro_input, punctuated::Punctuated, Data, DataStruct, DeriveInput, Fields, Ident, Token,
+    Type, TypePath,
+};
+
+/// Implements `PacketProperties` for a packet struct with a `PropertyBundle`
+/// field. The properties permitted in the packet are declared with the
+/// `properties` attribute, for example
+/// `#[properties(ReasonString, UserProperty)]`, and are also available to the
+/// crate as the `SUPPORTED_PROPERTIES` constant of the packet.
+#[proc_macro_derive(PacketProperties, attributes(properties))]
+#[proc_macro_error]
+pub fn packet_properties(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(input);
+    derive_packet_properties(&input)
+}
 
 #[proc_macro_derive(PropertyEncode)]
+#[proc_macro_error]
 pub fn property_encode(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input);
     derive_property_encoder(&input)
 }
 
+#[proc_macro_derive(PropertyDecode)]
+#[proc_macro_error]
+pub fn property_decode(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(input);
+    derive_property_decoder(&input)
+}
+
 #[proc_macro_derive(PropertySize)]
+#[proc_macro_error]
 pub fn property_size(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input);
     derive_property_size(&input)
 }
 
-fn derive_property_encoder(input: &DeriveInput) -> TokenStream {
-    let ident = &input.ident;
-    let generics = &input.generics;
-    let _pkg_name = std::env::var("CARGO_PKG_NAME").ok().unwrap_or_default();
+fn derive_packet_properties(input: &DeriveInput) -> TokenStream {
+    let name = &input.ident;
+    let field = property_field(input);
+    let supported = supported_properties(input);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
 
-    match input.data {
-        Data::Struct(DataStruct {
-            fields: Fields::Named(ref _fields),
-            ..
-        }) => encoder_impl(ident, generics),
-        Data::Struct(DataStruct {
-            fields: Fields::Unnamed(_),
-            ..
-        }) => todo!(),
-        Data::Struct(DataStruct {
-            fields: Fields::Unit,
-            ..
-        }) => todo!(),
-        Data::Enum(_) => todo!(),
-        Data::Union(_) => todo!(),
-    }
+    let gen = quote! {
+        impl #impl_generics #name #type_generics #where_clause {
+            /// Properties permitted in the packet, MQTT v5 2.2.2.2.
+            pub(crate) const SUPPORTED_PROPERTIES: &'static [crate::PropertyType] =
+                &[#(crate::PropertyType::#supported),*];
+        }
+
+        impl #impl_generics crate::property::PacketProperties for #name #type_generics #where_clause {
+            fn supported_properties() -> alloc::collections::BTreeSet<crate::PropertyType> {
+                Self::SUPPORTED_PROPERTIES.iter().copied().collect()
+            }
+
+            fn properties(&self) -> &crate::property::PropertyBundle {
+                &self.#field
+            }
+
+            fn properties_mut(&mut self) -> &mut crate::property::PropertyBundle {
+                &mut self.#field
+            }
+
+            fn set_properties(&mut self, properties: crate::property::PropertyBundle) {
+                self.#field = properties;
+            }
+        }
+    };
+    gen.into()
 }
 
-fn derive_property_size(input: &DeriveInput) -> TokenStream {
-    let ident = &input.ident;
-    let generics = &input.generics;
-    let _pkg_name = std::env::var("CARGO_PKG_NAME").ok().unwrap_or_default();
+fn derive_property_encoder(input: &DeriveInput) -> TokenStream {
+    let name = &input.ident;
+    let field = property_field(input);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
 
-    match input.data {
-        Data::Struct(DataStruct {
-            fields: Fields::Named(ref _fields),
-            ..
-        }) => size_impl(ident, generics),
-        Data::Struct(DataStruct {
-            fields: Fields::Unnamed(_),
-            ..
-        }) => todo!(),
-        Data::Struct(DataStruct {
-            fields: Fields::Unit,
-            ..
-        }) => todo!(),
-        Data::Enum(_) => todo!(),
-        Data::Union(_) => todo!(),
-    }
+    let gen = quote! {
+        impl #impl_generics crate::property::PropertyEncode for #name #type_generics #where_clause {
+            fn property_encode(
+                &self,
+                dest: &mut impl bytes::BufMut,
+            ) -> Result<(), crate::codec::MqttCodecError> {
+                crate::Encode::encode(&self.#field, dest)
+            }
+        }
+    };
+    gen.into()
 }
 
-fn encoder_impl(name: &Ident, generics: &Generics) -> TokenStream {
-    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+fn derive_property_decoder(input: &DeriveInput) -> TokenStream {
+    let name = &input.ident;
+    let field = property_field(input);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
 
     let gen = quote! {
-        impl #impl_generics PropertyEncode #type_generics for #name #where_clause{
-            fn property_encode() -> Result<(), crate::codec::MQTTCodecError>{
-                unimplemented!()
+        impl #impl_generics crate::property::PropertyDecode for #name #type_generics #where_clause {
+            fn property_decode(
+                &mut self,
+                src: &mut bytes::BytesMut,
+            ) -> Result<(), crate::codec::MqttCodecError> {
+                crate::Decode::decode(&mut self.#field, src)
             }
         }
     };
     gen.into()
 }
 
-fn size_impl(name: &Ident, generics: &Generics) -> TokenStream {
-    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+fn derive_property_size(input: &DeriveInput) -> TokenStream {
+    let name = &input.ident;
+    let field = property_field(input);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
 
     let gen = quote! {
-        impl #impl_generics PropertySize #type_generics for #name #where_clause {
-            fn property_size_internal() -> u32 {
-                5
+        impl #impl_generics crate::property::PropertySize for #name #type_generics #where_clause {
+            fn property_size_internal(&self) -> u32 {
+                crate::Size::size(&self.#field)
             }
         }
     };
     gen.into()
 }
+
+/// Finds the `PropertyBundle` field of the packet struct.
+fn property_field(input: &DeriveInput) -> &Ident {
+    let fields = match input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(ref fields),
+            ..
+        }) => fields,
+        _ => abort!(
+            input.ident,
+            "property derives are only supported on structs with named fields"
+        ),
+    };
+    let mut bundles = fields
+        .named
+        .iter()
+        .filter(|field| is_property_bundle(&field.ty));
+    match (bundles.next(), bundles.next()) {
+        (Some(field), None) => field.ident.as_ref().unwrap(),
+        (None, _) => abort!(
+            input.ident,
+            "{} does not have a PropertyBundle field",
+            input.ident
+        ),
+        (Some(_), Some(field)) => abort!(
+            field.ident,
+            "{} has more than one PropertyBundle field",
+            input.ident
+        ),
+    }
+}
+
+fn is_property_bundle(ty: &Type) -> bool {
+    match ty {
+        Type::Path(TypePath { qself: None, path }) => path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "PropertyBundle")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Gets the property types listed in the `properties` attribute.
+fn supported_properties(input: &DeriveInput) -> Vec<Ident> {
+    let mut attrs = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("properties"));
+    let attr = match (attrs.next(), attrs.next()) {
+        (Some(attr), None) => attr,
+        (None, _) => abort!(
+            input.ident,
+            "{} does not declare its permitted properties",
+            input.ident;
+            help = "add #[properties(...)] listing the permitted property types"
+        ),
+        (Some(_), Some(attr)) => abort!(attr, "properties may only be declared once"),
+    };
+    let props = match attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated) {
+        Ok(props) => props,
+        Err(e) => abort!(e.span(), "expected a list of property types: {}", e),
+    };
+    let mut supported: Vec<Ident> = Vec::new();
+    for prop in props {
+        if supported.contains(&prop) {
+            abort!(prop, "property {} is declared more than once", prop);
+        }
+        supported.push(prop);
+    }
+    supported
+}
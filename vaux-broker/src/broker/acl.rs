@@ -0,0 +1,247 @@
+const CLIENT_ID_PLACEHOLDER: &str = "{client_id}";
+/// Topics reserved for the broker, used for messages between cluster nodes
+const RESERVED_ROOT: &str = "$vaux";
+const LEVEL_SEPARATOR: char = '/';
+const WILDCARDS: [char; 2] = ['+', '#'];
+const SINGLE_LEVEL: &str = "+";
+const MULTI_LEVEL: &str = "#";
+
+use std::fmt::{Display, Formatter};
+
+use vaux_mqtt::TopicFilter;
+
+/// Error for a response topic template that cannot be enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateError {
+    /// the template does not contain the "{client_id}" placeholder
+    MissingPlaceholder,
+    /// the template starts with the placeholder, leaving no root to protect
+    EmptyRoot,
+    /// the template contains a wildcard character
+    Wildcard,
+    /// the placeholder does not fill a whole topic level
+    PartialLevel,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::MissingPlaceholder => {
+                write!(f, "must contain {}", CLIENT_ID_PLACEHOLDER)
+            }
+            TemplateError::EmptyRoot => {
+                write!(f, "must have a topic root before {}", CLIENT_ID_PLACEHOLDER)
+            }
+            TemplateError::Wildcard => write!(f, "must not contain wildcards"),
+            TemplateError::PartialLevel => {
+                write!(f, "{} must be a whole topic level", CLIENT_ID_PLACEHOLDER)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Access control for client subscriptions. When a response topic template
+/// is configured each client is given a response topic prefix and only the
+/// client may subscribe to topics under its prefix. Any client may publish to
+/// a response topic so that responders can reply to a request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    response_template: Option<String>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the template used to create the response topic prefix for a
+    /// client. The placeholder "{client_id}" is replaced with the client
+    /// identifier, e.g. "$response/{client_id}/".
+    ///
+    /// The part of the template before the placeholder is the response topic
+    /// root that subscriptions are restricted under, so a template without a
+    /// root, such as "{client_id}/response", is rejected along with templates
+    /// without the placeholder or with wildcards. The placeholder must be a
+    /// whole topic level, so "$response/x{client_id}" is rejected as well.
+    pub fn with_response_template(mut self, template: &str) -> Result<Self, TemplateError> {
+        match template.split_once(CLIENT_ID_PLACEHOLDER) {
+            None => return Err(TemplateError::MissingPlaceholder),
+            Some(("", _)) => return Err(TemplateError::EmptyRoot),
+            Some(_) if template.contains(WILDCARDS) => return Err(TemplateError::Wildcard),
+            Some((root, rest))
+                if !root.ends_with(LEVEL_SEPARATOR)
+                    || !(rest.is_empty() || rest.starts_with(LEVEL_SEPARATOR)) =>
+            {
+                return Err(TemplateError::PartialLevel)
+            }
+            Some(_) => {}
+        }
+        self.response_template = Some(template.to_string());
+        Ok(self)
+    }
+
+    /// Gets the response topic prefix for the client, or None if no response
+    /// template is configured. A client identifier containing a level
+    /// separator or wildcard cannot be used in a topic name so no prefix is
+    /// available for the client.
+    pub fn response_prefix(&self, client_id: &str) -> Option<String> {
+        let template = self.response_template.as_ref()?;
+        if client_id.contains(LEVEL_SEPARATOR) || client_id.contains(WILDCARDS) {
+            return None;
+        }
+        Some(template.replace(CLIENT_ID_PLACEHOLDER, client_id))
+    }
+
+    /// Determines if the topic name or topic filter is under the "$vaux" root
+    /// reserved for the broker. Clients may not publish or subscribe to
+    /// reserved topics.
+    pub fn is_reserved(topic: &str) -> bool {
+        match topic.strip_prefix(RESERVED_ROOT) {
+            Some(rest) => rest.is_empty() || rest.starts_with(LEVEL_SEPARATOR),
+            None => false,
+        }
+    }
+
+    /// Determines if the client may subscribe to the topic filter. Reserved
+    /// topics may not be subscribed to. The filter is compared with the
+    /// response topic root level by level and is denied if it could match a
+    /// topic under the prefix of another client, so a wildcard at or above
+    /// the client identifier level is only permitted when the filter cannot
+    /// reach the response topic root. A shared subscription is checked
+    /// against the filter without the "$share/{ShareName}/" prefix.
+    pub fn can_subscribe(&self, client_id: &str, filter: &TopicFilter) -> bool {
+        let filter = filter.filter();
+        if Acl::is_reserved(filter) {
+            return false;
+        }
+        let root = match self
+            .response_template
+            .as_ref()
+            .and_then(|template| template.split_once(CLIENT_ID_PLACEHOLDER))
+        {
+            Some((root, _)) => root.trim_end_matches(LEVEL_SEPARATOR),
+            None => return true,
+        };
+        let mut levels = filter.split(LEVEL_SEPARATOR);
+        for root_level in root.split(LEVEL_SEPARATOR) {
+            match levels.next() {
+                // the filter ends above the client identifier level
+                None => return true,
+                Some(MULTI_LEVEL) => return false,
+                Some(SINGLE_LEVEL) => {}
+                Some(level) if level != root_level => return true,
+                Some(_) => {}
+            }
+        }
+        match levels.next() {
+            None => true,
+            Some(level) => self.response_prefix(client_id).is_some() && level == client_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response_prefix() {
+        assert_eq!(None, Acl::new().response_prefix("client-1"));
+        let acl = Acl::new()
+            .with_response_template("$response/{client_id}/")
+            .unwrap();
+        assert_eq!(
+            Some("$response/client-1/".to_string()),
+            acl.response_prefix("client-1")
+        );
+        assert_eq!(None, acl.response_prefix("client/1"));
+        assert_eq!(None, acl.response_prefix("client+1"));
+    }
+
+    #[test]
+    fn test_is_reserved() {
+        assert!(Acl::is_reserved("$vaux"));
+        assert!(Acl::is_reserved("$vaux/cluster/takeover"));
+        assert!(Acl::is_reserved("$vaux/#"));
+        assert!(!Acl::is_reserved("$vauxiliary/a"));
+        assert!(!Acl::is_reserved("sensor/$vaux"));
+        assert!(!Acl::is_reserved("#"));
+    }
+
+    fn filter(filter: &str) -> TopicFilter {
+        TopicFilter::new(filter).unwrap()
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        assert_eq!(
+            Err(TemplateError::EmptyRoot),
+            Acl::new().with_response_template("{client_id}/resp")
+        );
+        assert_eq!(
+            Err(TemplateError::MissingPlaceholder),
+            Acl::new().with_response_template("$response/")
+        );
+        assert_eq!(
+            Err(TemplateError::Wildcard),
+            Acl::new().with_response_template("$response/+/{client_id}/")
+        );
+        assert_eq!(
+            Err(TemplateError::PartialLevel),
+            Acl::new().with_response_template("$response/x{client_id}")
+        );
+        assert_eq!(
+            Err(TemplateError::PartialLevel),
+            Acl::new().with_response_template("$response/{client_id}x/")
+        );
+        assert!(Acl::new()
+            .with_response_template("$response/{client_id}")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_can_subscribe() {
+        assert!(Acl::new().can_subscribe("client-1", &filter("$response/client-2/#")));
+        let acl = Acl::new()
+            .with_response_template("$response/{client_id}/")
+            .unwrap();
+        assert!(acl.can_subscribe("client-1", &filter("sensor/#")));
+        assert!(acl.can_subscribe("client-1", &filter("$response/client-1/#")));
+        assert!(acl.can_subscribe("client-1", &filter("$response/client-1")));
+        assert!(!acl.can_subscribe("client-1", &filter("$response/client-10/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$response/client-2/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$response/+/reply")));
+        assert!(!acl.can_subscribe("client-1", &filter("$response/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$vaux/#")));
+        assert!(acl.can_subscribe("client-1", &filter("$response")));
+    }
+
+    #[test]
+    fn test_can_subscribe_wildcards() {
+        let acl = Acl::new()
+            .with_response_template("responses/{client_id}/")
+            .unwrap();
+        assert!(!acl.can_subscribe("client-1", &filter("#")));
+        assert!(!acl.can_subscribe("client-1", &filter("+/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("+/x")));
+        assert!(!acl.can_subscribe("client-1", &filter("+/victim/reply")));
+        assert!(!acl.can_subscribe("client-1", &filter("+/+")));
+        assert!(acl.can_subscribe("client-1", &filter("+/client-1/#")));
+        assert!(acl.can_subscribe("client-1", &filter("+")));
+        assert!(acl.can_subscribe("client-1", &filter("sensor/+/temp")));
+        assert!(acl.can_subscribe("client-1", &filter("responsesx/#")));
+    }
+
+    #[test]
+    fn test_can_subscribe_shared() {
+        let acl = Acl::new()
+            .with_response_template("$response/{client_id}/")
+            .unwrap();
+        assert!(acl.can_subscribe("client-1", &filter("$share/g/$response/client-1/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$share/g/$response/victim/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$share/g/$response/#")));
+        assert!(!acl.can_subscribe("client-1", &filter("$share/g/$vaux/cluster/+")));
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::RwLock;
+use tokio_util::codec::Framed;
+use vaux_mqtt::codec::frame_len;
+use vaux_mqtt::property::{PacketProperties, Property, PropertyBundle};
+use vaux_mqtt::publish::Publish;
+use vaux_mqtt::{Connect, MqttCodecError, Packet, Reason, Subscribe, TopicFilter};
+
+use crate::broker::inbound::Received;
+use crate::broker::retained::RetainedStore;
+use crate::broker::router::Router;
+use crate::broker::session::SessionState;
+use vaux_mqtt::decoder::MAX_PACKET_SIZE;
+use vaux_mqtt::MqttCodec;
+
+const CLUSTER_TOPIC_SUBSCRIPTIONS: &str = "$vaux/cluster/subscriptions";
+const CLUSTER_TOPIC_TAKEOVER: &str = "$vaux/cluster/takeover";
+const CLUSTER_TOPIC_FORWARD: &str = "$vaux/cluster/forward";
+const CLUSTER_TOPIC_RETAIN: &str = "$vaux/cluster/retain";
+const CLUSTER_TOPIC_SESSION: &str = "$vaux/cluster/session";
+const PROPERTY_CLIENT_ID: &str = "client_id";
+const PROPERTY_TRANSFER: &str = "transfer";
+const PROPERTY_SESSION_EXPIRY: &str = "session_expiry";
+const PROPERTY_PENDING_RELEASE: &str = "pending_release";
+const PROPERTY_RELEASED: &str = "released";
+const FILTER_SEPARATOR: char = '\n';
+const PEER_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time to wait for peers to reply to a session take over
+const TAKEOVER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Cluster configuration for a broker node. Each node listens for peer links
+/// on the cluster address and opens a link to every peer in the static peer
+/// list. The cluster address also identifies the node to its peers so it
+/// must match the address listed for the node in the peer configuration.
+///
+/// Every node in the cluster is configured with the same secret. A node
+/// presents the secret when it opens a link and links from nodes that do not
+/// present it are refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    pub cluster_addr: SocketAddr,
+    pub peers: Vec<SocketAddr>,
+    pub secret: String,
+}
+
+impl ClusterConfig {
+    pub fn new(cluster_addr: SocketAddr, peers: Vec<SocketAddr>, secret: &str) -> Self {
+        Self {
+            cluster_addr,
+            peers,
+            secret: secret.to_string(),
+        }
+    }
+}
+
+/// Messages exchanged between cluster nodes. Cluster messages are carried as
+/// MQTT PUBLISH packets on the peer links, each with a reserved topic under
+/// "$vaux/cluster". A publish forwarded for delivery to the local subscribers
+/// of the receiving node is encoded in the payload of a forward message, so
+/// the topic chosen by a client can never be read as a control message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ClusterMessage {
+    /// Summary of the topic filters subscribed to on the sending node
+    Subscriptions(Vec<String>),
+    /// A client with the client identifier has connected to the sending node.
+    /// The receiving node discards any session held for the client, replying
+    /// with the session state if transfer is true.
+    TakeOver { client_id: String, transfer: bool },
+    /// Reply to a take over with the state of the session held for the
+    /// client, or None if the node held no session for the client
+    Session(String, Option<SessionState>),
+    /// A publish received from a client of the sending node
+    Forward(Box<Publish>),
+    /// A retained message to store without delivery, sent to peers without
+    /// matching subscribers and to every peer when a link is established
+    Retain(Box<Publish>),
+}
+
+impl From<ClusterMessage> for Packet {
+    fn from(message: ClusterMessage) -> Self {
+        let mut publish = Publish::default();
+        let (topic, payload) = match message {
+            ClusterMessage::Subscriptions(filters) => (
+                CLUSTER_TOPIC_SUBSCRIPTIONS,
+                filters.join(&FILTER_SEPARATOR.to_string()).into_bytes(),
+            ),
+            ClusterMessage::TakeOver {
+                client_id,
+                transfer,
+            } => {
+                if transfer {
+                    publish
+                        .properties_mut()
+                        .add_user_property(PROPERTY_TRANSFER.to_string(), true.to_string());
+                }
+                (CLUSTER_TOPIC_TAKEOVER, client_id.into_bytes())
+            }
+            ClusterMessage::Session(client_id, state) => {
+                let props = publish.properties_mut();
+                props.add_user_property(PROPERTY_CLIENT_ID.to_string(), client_id);
+                (CLUSTER_TOPIC_SESSION, encode_session(state, props))
+            }
+            ClusterMessage::Forward(publish) => (CLUSTER_TOPIC_FORWARD, encode_publish(*publish)),
+            ClusterMessage::Retain(publish) => (CLUSTER_TOPIC_RETAIN, encode_publish(*publish)),
+        };
+        publish.topic_name = Some(topic.to_string());
+        publish.set_payload(payload);
+        Packet::Publish(publish)
+    }
+}
+
+impl TryFrom<Publish> for ClusterMessage {
+    type Error = MqttCodecError;
+
+    fn try_from(publish: Publish) -> Result<Self, Self::Error> {
+        let payload = || {
+            String::from_utf8(publish.payload().unwrap_or_default().to_vec())
+                .map_err(|e| MqttCodecError::new(&format!("invalid cluster payload: {}", e)))
+        };
+        match publish.topic_name.as_deref() {
+            Some(CLUSTER_TOPIC_SUBSCRIPTIONS) => Ok(ClusterMessage::Subscriptions(
+                payload()?
+                    .split(FILTER_SEPARATOR)
+                    .filter(|f| !f.is_empty())
+                    .map(|f| f.to_string())
+                    .collect(),
+            )),
+            Some(CLUSTER_TOPIC_TAKEOVER) => Ok(ClusterMessage::TakeOver {
+                client_id: payload()?,
+                transfer: publish
+                    .properties()
+                    .user_property(PROPERTY_TRANSFER)
+                    .any(|transfer| transfer == "true"),
+            }),
+            Some(CLUSTER_TOPIC_SESSION) => {
+                let client_id = publish
+                    .properties()
+                    .user_property(PROPERTY_CLIENT_ID)
+                    .next()
+                    .ok_or_else(|| MqttCodecError::new("session message without client_id"))?;
+                Ok(ClusterMessage::Session(
+                    client_id.to_string(),
+                    decode_session(&publish)?,
+                ))
+            }
+            Some(CLUSTER_TOPIC_FORWARD) => Ok(ClusterMessage::Forward(decode_publish(&publish)?)),
+            Some(CLUSTER_TOPIC_RETAIN) => Ok(ClusterMessage::Retain(decode_publish(&publish)?)),
+            topic => Err(MqttCodecError::new(&format!(
+                "unknown cluster message topic: {:?}",
+                topic
+            ))),
+        }
+    }
+}
+
+/// Encodes session state for a session message. The session expiry and the
+/// inbound and outbound QoS 2 packet identifiers awaiting release are set as
+/// user properties of the message, which has no session expiry property when
+/// the node held no session. The payload holds a SUBSCRIBE packet for each
+/// subscription, carrying the subscription identifier, followed by a PUBLISH
+/// packet for each message awaiting delivery. Unacknowledged messages keep
+/// their packet identifier and have the DUP flag set, which queued messages
+/// never have.
+fn encode_session(state: Option<SessionState>, props: &mut PropertyBundle) -> Vec<u8> {
+    let state = match state {
+        Some(state) => state,
+        None => return Vec::new(),
+    };
+    props.add_user_property(
+        PROPERTY_SESSION_EXPIRY.to_string(),
+        state.session_expiry.to_string(),
+    );
+    for packet_id in state.pending_release {
+        props.add_user_property(PROPERTY_PENDING_RELEASE.to_string(), packet_id.to_string());
+    }
+    for packet_id in state.released {
+        props.add_user_property(PROPERTY_RELEASED.to_string(), packet_id.to_string());
+    }
+    let mut payload = BytesMut::new();
+    for (subscription, subscription_id) in state.subscriptions {
+        let mut subscribe = Subscribe::new(1, vec![subscription]);
+        if let Some(id) = subscription_id {
+            subscribe
+                .properties_mut()
+                .set_property(Property::SubscriptionIdentifier(id));
+        }
+        let _ = vaux_mqtt::encode(Packet::Subscribe(subscribe), &mut payload);
+    }
+    for mut publish in state.inflight {
+        publish.header.set_dup(true);
+        let _ = vaux_mqtt::encode(Packet::Publish(publish), &mut payload);
+    }
+    for mut publish in state.messages {
+        // the receiving node assigns a new packet identifier on delivery
+        publish.packet_id = Some(1);
+        publish.header.set_dup(false);
+        let _ = vaux_mqtt::encode(Packet::Publish(publish), &mut payload);
+    }
+    payload.to_vec()
+}
+
+/// Decodes the session state from a session message.
+fn decode_session(message: &Publish) -> Result<Option<SessionState>, MqttCodecError> {
+    let props = message.properties();
+    let invalid = |e| MqttCodecError::new(&format!("invalid session message: {}", e));
+    let session_expiry = match props.user_property(PROPERTY_SESSION_EXPIRY).next() {
+        Some(expiry) => expiry.parse().map_err(invalid)?,
+        None => return Ok(None),
+    };
+    let mut state = SessionState {
+        session_expiry,
+        pending_release: props
+            .user_property(PROPERTY_PENDING_RELEASE)
+            .map(|id| id.parse().map_err(invalid))
+            .collect::<Result<_, _>>()?,
+        released: props
+            .user_property(PROPERTY_RELEASED)
+            .map(|id| id.parse().map_err(invalid))
+            .collect::<Result<_, _>>()?,
+        ..Default::default()
+    };
+    let mut src = BytesMut::from(message.payload().unwrap_or_default());
+    while !src.is_empty() {
+        // decode requires a buffer holding a single packet
+        let mut frame = match frame_len(&src)? {
+            Some(len) if len <= src.len() => src.split_to(len),
+            _ => return Err(MqttCodecError::new("truncated packet in session message")),
+        };
+        match vaux_mqtt::decode(&mut frame)? {
+            Some((Packet::Subscribe(subscribe), _)) => {
+                let subscription_id = subscribe.properties().subscription_ids().first().copied();
+                for subscription in subscribe.subscriptions() {
+                    state
+                        .subscriptions
+                        .push((subscription.clone(), subscription_id));
+                }
+            }
+            Some((Packet::Publish(publish), _)) if publish.header.dup() => {
+                state.inflight.push(publish)
+            }
+            Some((Packet::Publish(publish), _)) => state.messages.push(publish),
+            _ => return Err(MqttCodecError::new("invalid packet in session message")),
+        }
+    }
+    Ok(Some(state))
+}
+
+/// Encodes a publish carried in the payload of a cluster message.
+fn encode_publish(publish: Publish) -> Vec<u8> {
+    let mut payload = BytesMut::new();
+    // a publish decoded from a client always encodes
+    let _ = vaux_mqtt::encode(Packet::Publish(publish), &mut payload);
+    payload.to_vec()
+}
+
+/// Decodes the publish carried in the payload of a cluster message.
+fn decode_publish(message: &Publish) -> Result<Box<Publish>, MqttCodecError> {
+    let mut src = BytesMut::from(message.payload().unwrap_or_default());
+    match vaux_mqtt::decode(&mut src)? {
+        Some((Packet::Publish(publish), _)) if src.is_empty() => Ok(Box::new(publish)),
+        _ => Err(MqttCodecError::new("invalid publish in cluster message")),
+    }
+}
+
+/// Runtime state for a clustered broker node.
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    config: ClusterConfig,
+    links: HashMap<SocketAddr, UnboundedSender<ClusterMessage>>,
+    remote_filters: RwLock<HashMap<SocketAddr, Vec<TopicFilter>>>,
+    /// take overs waiting for replies from peers, keyed by client identifier
+    takeovers: Mutex<HashMap<String, TakeOverReplies>>,
+}
+
+type TakeOverReplies = UnboundedSender<(SocketAddr, Option<SessionState>)>;
+
+impl Cluster {
+    /// Creates the cluster state and starts a link task for each configured
+    /// peer. Link tasks connect to the peer, retrying until the peer is
+    /// available, and reconnect if the link is lost.
+    pub(crate) fn start(
+        config: ClusterConfig,
+        router: Arc<RwLock<Router>>,
+        retained: Arc<RwLock<RetainedStore>>,
+    ) -> Arc<Self> {
+        let mut links = HashMap::new();
+        for peer in &config.peers {
+            if *peer == config.cluster_addr {
+                continue;
+            }
+            let (sender, receiver) = mpsc::unbounded_channel();
+            links.insert(*peer, sender);
+            tokio::spawn(Cluster::run_link(
+                config.cluster_addr,
+                config.secret.clone(),
+                *peer,
+                receiver,
+                router.clone(),
+                retained.clone(),
+            ));
+        }
+        Arc::new(Self {
+            config,
+            links,
+            remote_filters: RwLock::new(HashMap::new()),
+            takeovers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks the CONNECT opening a peer link. The client identifier is the
+    /// cluster address of the peer and the password is the cluster secret.
+    /// Returns the peer address or the reason the link is refused.
+    pub(crate) fn authenticate(&self, connect: &Connect) -> Result<SocketAddr, Reason> {
+        let secret = connect.password.as_deref().unwrap_or_default();
+        if !secret_eq(secret, self.config.secret.as_bytes()) {
+            return Err(Reason::AuthenticationErr);
+        }
+        match connect.client_id.parse() {
+            Ok(addr) if addr != self.config.cluster_addr => Ok(addr),
+            _ => Err(Reason::InvalidClientId),
+        }
+    }
+
+    /// Sends the local subscription summary to every peer.
+    pub(crate) fn announce_subscriptions(&self, filters: Vec<String>) {
+        self.broadcast(ClusterMessage::Subscriptions(filters));
+    }
+
+    /// Notifies every peer that the client has connected to this node so that
+    /// any session held by the client on a peer is taken over. When transfer
+    /// is true the peers reply with the session state and the take over waits
+    /// for a reply from each peer with a link to this node, for at most
+    /// TAKEOVER_TIMEOUT. Returns the session state held by a peer, or None if
+    /// no peer held a session for the client.
+    pub(crate) async fn take_over(&self, client_id: &str, transfer: bool) -> Option<SessionState> {
+        let mut peers: Vec<SocketAddr> = self.remote_filters.read().await.keys().copied().collect();
+        let wait = transfer && !peers.is_empty();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        if wait {
+            self.takeovers
+                .lock()
+                .unwrap()
+                .insert(client_id.to_string(), sender);
+        }
+        self.broadcast(ClusterMessage::TakeOver {
+            client_id: client_id.to_string(),
+            transfer,
+        });
+        if !wait {
+            return None;
+        }
+        let mut state = None;
+        let _ = tokio::time::timeout(TAKEOVER_TIMEOUT, async {
+            while let Some((peer, reply)) = receiver.recv().await {
+                peers.retain(|p| *p != peer);
+                if reply.is_some() {
+                    state = reply;
+                }
+                if peers.is_empty() {
+                    break;
+                }
+            }
+        })
+        .await;
+        self.takeovers.lock().unwrap().remove(client_id);
+        state
+    }
+
+    /// Passes the session state received from a peer to the take over waiting
+    /// for the client. State received after the take over has finished is
+    /// discarded.
+    pub(crate) fn session_received(
+        &self,
+        peer: SocketAddr,
+        client_id: &str,
+        state: Option<SessionState>,
+    ) {
+        match self.takeovers.lock().unwrap().get(client_id) {
+            Some(takeover) => {
+                let _ = takeover.send((peer, state));
+            }
+            None if state.is_some() => {
+                eprintln!("session for {} from {} discarded", client_id, peer);
+            }
+            None => {}
+        }
+    }
+
+    /// Sends a message to a single peer.
+    pub(crate) fn send(&self, peer: &SocketAddr, message: ClusterMessage) {
+        if let Some(link) = self.links.get(peer) {
+            let _ = link.send(message);
+        }
+    }
+
+    /// Forwards the publish to each peer with a subscription matching the
+    /// topic. A publish with the RETAIN flag set is also sent to every other
+    /// peer so that each node holds the same retained messages. Returns the
+    /// number of peers the publish was forwarded to for delivery.
+    pub(crate) async fn forward(&self, publish: &mut Received<'_>) -> usize {
+        let remote_filters = self.remote_filters.read().await;
+        let mut forwarded = 0;
+        for (peer, link) in &self.links {
+            let matched = remote_filters.get(peer).is_some_and(|filters| {
+                filters
+                    .iter()
+                    .any(|filter| filter.matches_name(publish.topic()))
+            });
+            let message = if matched {
+                ClusterMessage::Forward(Box::new(publish.publish().clone()))
+            } else if publish.retain() {
+                ClusterMessage::Retain(Box::new(publish.publish().clone()))
+            } else {
+                continue;
+            };
+            if link.send(message).is_ok() && matched {
+                forwarded += 1;
+            }
+        }
+        forwarded
+    }
+
+    /// Records the subscription summary received from a peer. Filters that
+    /// are not valid topic filters are ignored.
+    pub(crate) async fn set_remote_filters(&self, peer: SocketAddr, filters: Vec<String>) {
+        let filters = filters
+            .into_iter()
+            .filter_map(|filter| TopicFilter::new(filter).ok())
+            .collect();
+        self.remote_filters.write().await.insert(peer, filters);
+    }
+
+    /// Removes the subscription summary for a peer whose link has closed.
+    pub(crate) async fn remove_peer(&self, peer: &SocketAddr) {
+        self.remote_filters.write().await.remove(peer);
+    }
+
+    fn broadcast(&self, message: ClusterMessage) {
+        for link in self.links.values() {
+            let _ = link.send(message.clone());
+        }
+    }
+
+    async fn run_link(
+        local: SocketAddr,
+        secret: String,
+        peer: SocketAddr,
+        mut receiver: UnboundedReceiver<ClusterMessage>,
+        router: Arc<RwLock<Router>>,
+        retained: Arc<RwLock<RetainedStore>>,
+    ) {
+        loop {
+            let stream = match TcpStream::connect(peer).await {
+                Ok(stream) => stream,
+                Err(_) => {
+                    tokio::time::sleep(PEER_RECONNECT_INTERVAL).await;
+                    continue;
+                }
+            };
+            let mut framed = Framed::new(stream, peer_codec());
+            let mut connect = Connect::default();
+            connect.client_id = local.to_string();
+            connect.password = Some(secret.as_bytes().to_vec());
+            if framed
+                .send(Packet::Connect(Box::new(connect)))
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(PEER_RECONNECT_INTERVAL).await;
+                continue;
+            }
+            match framed.next().await {
+                Some(Ok(Packet::ConnAck(ack))) if ack.reason() == Reason::Success => {}
+                Some(Ok(Packet::ConnAck(ack))) => {
+                    eprintln!("cluster link to {} refused: {}", peer, ack.reason());
+                    tokio::time::sleep(PEER_RECONNECT_INTERVAL).await;
+                    continue;
+                }
+                _ => {
+                    tokio::time::sleep(PEER_RECONNECT_INTERVAL).await;
+                    continue;
+                }
+            }
+            // messages queued while the link was down are sent before the
+            // retained messages so that an older retained message queued for
+            // the peer never replaces a newer one
+            let mut handshake = Vec::new();
+            while let Ok(message) = receiver.try_recv() {
+                handshake.push(message);
+            }
+            handshake.push(ClusterMessage::Subscriptions(router.read().await.filters()));
+            for publish in retained.write().await.messages() {
+                handshake.push(ClusterMessage::Retain(Box::new(publish)));
+            }
+            let mut connected = true;
+            for message in handshake {
+                if framed.send(message.into()).await.is_err() {
+                    connected = false;
+                    break;
+                }
+            }
+            while connected {
+                match receiver.recv().await {
+                    Some(message) => {
+                        if let Err(e) = framed.send(message.into()).await {
+                            eprintln!("cluster link to {} lost: {}", peer, e);
+                            connected = false;
+                        }
+                    }
+                    // cluster state dropped, broker is shutting down
+                    None => return,
+                }
+            }
+            tokio::time::sleep(PEER_RECONNECT_INTERVAL).await;
+        }
+    }
+}
+
+/// Creates the codec for a peer link. Peer links carry publishes of up to the
+/// client maximum packet size inside cluster messages, and session transfers
+/// holding many messages, so the link accepts packets up to the MQTT maximum.
+/// Only authenticated peers can send on a link.
+pub(crate) fn peer_codec() -> MqttCodec {
+    MqttCodec::new().with_max_packet_size(MAX_PACKET_SIZE)
+}
+
+/// Compares the secrets in time independent of where they differ.
+fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use vaux_mqtt::{QoSLevel, Subscription};
+
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = vec![
+            ClusterMessage::Subscriptions(vec!["a/+".to_string(), "b/#".to_string()]),
+            ClusterMessage::Subscriptions(Vec::new()),
+            ClusterMessage::TakeOver {
+                client_id: "client-1".to_string(),
+                transfer: false,
+            },
+            ClusterMessage::TakeOver {
+                client_id: "client-1".to_string(),
+                transfer: true,
+            },
+            ClusterMessage::Session("client-1".to_string(), None),
+            ClusterMessage::Session(
+                "client-1".to_string(),
+                Some(SessionState {
+                    session_expiry: 60,
+                    ..Default::default()
+                }),
+            ),
+        ];
+        for message in messages {
+            let packet: Packet = message.clone().into();
+            if let Packet::Publish(publish) = packet {
+                assert_eq!(message, ClusterMessage::try_from(publish).unwrap());
+            } else {
+                panic!("expected cluster message to be carried as PUBLISH");
+            }
+        }
+    }
+
+    /// Carries the publish in a forward message and returns the publish
+    /// read from the message.
+    fn forward_round_trip(publish: &Publish) -> Publish {
+        let packet: Packet = ClusterMessage::Forward(Box::new(publish.clone())).into();
+        let envelope = match packet {
+            Packet::Publish(envelope) => envelope,
+            p => panic!("expected PUBLISH, found {:?}", p),
+        };
+        assert_eq!(Some(CLUSTER_TOPIC_FORWARD), envelope.topic_name.as_deref());
+        match ClusterMessage::try_from(envelope) {
+            Ok(ClusterMessage::Forward(forwarded)) => *forwarded,
+            other => panic!("expected forwarded publish, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_forward_message() {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.packet_id = Some(7);
+        publish.set_payload(b"21.5".to_vec());
+        let forwarded = forward_round_trip(&publish);
+        assert_eq!(publish.topic_name, forwarded.topic_name);
+        assert_eq!(publish.qos(), forwarded.qos());
+        assert_eq!(publish.payload(), forwarded.payload());
+    }
+
+    #[test]
+    fn test_session_message() {
+        let mut message = Publish::default();
+        message.topic_name = Some("sensor/temp".to_string());
+        message.set_qos(QoSLevel::AtLeastOnce);
+        message.set_payload(b"21.5".to_vec());
+        let mut inflight = message.clone();
+        inflight.packet_id = Some(7);
+        let mut subscription = Subscription::new("sensor/#".to_string(), QoSLevel::ExactlyOnce);
+        subscription.no_local = true;
+        let state = SessionState {
+            session_expiry: 3600,
+            subscriptions: vec![
+                (subscription, Some(5)),
+                (
+                    Subscription::new("cmd/+".to_string(), QoSLevel::AtMostOnce),
+                    None,
+                ),
+            ],
+            inflight: vec![inflight.clone()],
+            released: vec![4],
+            messages: vec![message.clone()],
+            pending_release: vec![3, 9],
+        };
+        let packet: Packet =
+            ClusterMessage::Session("client-1".to_string(), Some(state.clone())).into();
+        let envelope = match packet {
+            Packet::Publish(envelope) => envelope,
+            p => panic!("expected PUBLISH, found {:?}", p),
+        };
+        let (client_id, decoded) = match ClusterMessage::try_from(envelope) {
+            Ok(ClusterMessage::Session(client_id, Some(decoded))) => (client_id, decoded),
+            other => panic!("expected session message, found {:?}", other),
+        };
+        assert_eq!("client-1", client_id);
+        assert_eq!(state.session_expiry, decoded.session_expiry);
+        assert_eq!(state.subscriptions, decoded.subscriptions);
+        assert_eq!(state.pending_release, decoded.pending_release);
+        assert_eq!(state.released, decoded.released);
+        assert_eq!(1, decoded.inflight.len());
+        assert_eq!(Some(7), decoded.inflight[0].packet_id);
+        assert!(decoded.inflight[0].header.dup());
+        assert_eq!(1, decoded.messages.len());
+        assert!(!decoded.messages[0].header.dup());
+        assert_eq!(message.topic_name, decoded.messages[0].topic_name);
+        assert_eq!(message.payload(), decoded.messages[0].payload());
+    }
+
+    #[test]
+    fn test_retain_message() {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.header.set_retain(true);
+        publish.set_payload(b"21.5".to_vec());
+        let packet: Packet = ClusterMessage::Retain(Box::new(publish.clone())).into();
+        let envelope = match packet {
+            Packet::Publish(envelope) => envelope,
+            p => panic!("expected PUBLISH, found {:?}", p),
+        };
+        assert_eq!(Some(CLUSTER_TOPIC_RETAIN), envelope.topic_name.as_deref());
+        match ClusterMessage::try_from(envelope) {
+            Ok(ClusterMessage::Retain(retained)) => {
+                assert_eq!(publish.topic_name, retained.topic_name);
+                assert!(retained.header.retain());
+                assert_eq!(publish.payload(), retained.payload());
+            }
+            other => panic!("expected retained message, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_client_topic_not_control() {
+        // only publishes with a cluster message topic are read from a peer link
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        assert!(ClusterMessage::try_from(publish).is_err());
+        // a client publish to a cluster message topic is forwarded as data
+        let mut publish = Publish::default();
+        publish.topic_name = Some(CLUSTER_TOPIC_TAKEOVER.to_string());
+        publish.set_payload(b"client-1".to_vec());
+        let forwarded = forward_round_trip(&publish);
+        assert_eq!(publish.topic_name, forwarded.topic_name);
+        assert_eq!(publish.payload(), forwarded.payload());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let local: SocketAddr = "127.0.0.1:7883".parse().unwrap();
+        let cluster = Cluster {
+            config: ClusterConfig::new(local, Vec::new(), "secret"),
+            links: HashMap::new(),
+            remote_filters: RwLock::new(HashMap::new()),
+            takeovers: Mutex::new(HashMap::new()),
+        };
+        let mut connect = Connect::default();
+        connect.client_id = "127.0.0.1:7884".to_string();
+        assert_eq!(
+            Err(Reason::AuthenticationErr),
+            cluster.authenticate(&connect)
+        );
+        connect.password = Some(b"secreT".to_vec());
+        assert_eq!(
+            Err(Reason::AuthenticationErr),
+            cluster.authenticate(&connect)
+        );
+        connect.password = Some(b"secret".to_vec());
+        assert_eq!(
+            Ok("127.0.0.1:7884".parse().unwrap()),
+            cluster.authenticate(&connect)
+        );
+        connect.client_id = local.to_string();
+        assert_eq!(Err(Reason::InvalidClientId), cluster.authenticate(&connect));
+    }
+}
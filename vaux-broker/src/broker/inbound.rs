@@ -0,0 +1,232 @@
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use vaux_mqtt::codec::frame_len;
+use vaux_mqtt::packet_ref::PublishRef;
+use vaux_mqtt::publish::Publish;
+use vaux_mqtt::{MqttCodec, MqttCodecError, Packet, PropertyType, ProtocolVersion, QoSLevel};
+
+/// Packet type of PUBLISH in the high nibble of the first byte
+const PUBLISH_TYPE: u8 = 0x30;
+
+/// A packet read from a client connection.
+#[derive(Debug)]
+pub(crate) enum Inbound {
+    /// complete MQTT v5 PUBLISH frame, decoded by the caller with
+    /// [`vaux_mqtt::packet_ref::PacketRef::decode`]
+    Publish(Bytes),
+    Packet(Packet),
+}
+
+/// Codec for client connections. An MQTT v5 PUBLISH is returned as the
+/// undecoded frame so that it can be routed from a borrowed [`PublishRef`],
+/// every other packet is decoded by [`MqttCodec`]. A PUBLISH larger than the
+/// maximum packet size is left to [`MqttCodec`] to reject.
+#[derive(Debug, Default)]
+pub(crate) struct ClientCodec {
+    codec: MqttCodec,
+}
+
+impl ClientCodec {
+    pub(crate) fn new(codec: MqttCodec) -> Self {
+        Self { codec }
+    }
+
+    pub(crate) fn codec(&self) -> &MqttCodec {
+        &self.codec
+    }
+
+    pub(crate) fn codec_mut(&mut self) -> &mut MqttCodec {
+        &mut self.codec
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Inbound;
+    type Error = MqttCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.codec.version() == ProtocolVersion::V5
+            && src.first().is_some_and(|byte| byte & 0xf0 == PUBLISH_TYPE)
+        {
+            if let Some(len) = frame_len(src)? {
+                if len <= src.len() && len <= self.codec.max_packet_size() {
+                    return Ok(Some(Inbound::Publish(src.split_to(len).freeze())));
+                }
+            }
+        }
+        Ok(self.codec.decode(src)?.map(Inbound::Packet))
+    }
+}
+
+impl Encoder<Packet> for ClientCodec {
+    type Error = MqttCodecError;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.codec.encode(packet, dst)
+    }
+}
+
+/// The PUBLISH being routed, borrowed from the packet it was received in.
+#[derive(Debug)]
+enum Source<'a> {
+    Borrowed(PublishRef<'a>),
+    Owned(&'a Publish),
+}
+
+/// A PUBLISH received from a client or a peer node. The topic name is the
+/// name resolved from any topic alias. A PUBLISH read as a [`PublishRef`] is
+/// only copied into an owned [`Publish`] when it is delivered or retained.
+#[derive(Debug)]
+pub(crate) struct Received<'a> {
+    topic: &'a str,
+    source: Source<'a>,
+    owned: Option<Publish>,
+}
+
+impl<'a> Received<'a> {
+    /// Creates the received PUBLISH from the borrowed packet with the topic
+    /// name resolved from its topic alias.
+    pub(crate) fn borrowed(topic: &'a str, publish: PublishRef<'a>) -> Self {
+        Self {
+            topic,
+            source: Source::Borrowed(publish),
+            owned: None,
+        }
+    }
+
+    /// Creates the received PUBLISH from an owned packet, or None if the
+    /// packet has no topic name.
+    pub(crate) fn owned(publish: &'a Publish) -> Option<Self> {
+        Some(Self {
+            topic: publish.topic_name.as_deref()?,
+            source: Source::Owned(publish),
+            owned: None,
+        })
+    }
+
+    pub(crate) fn topic(&self) -> &'a str {
+        self.topic
+    }
+
+    pub(crate) fn qos(&self) -> QoSLevel {
+        match &self.source {
+            Source::Borrowed(publish) => publish.qos,
+            Source::Owned(publish) => publish.qos(),
+        }
+    }
+
+    pub(crate) fn packet_id(&self) -> Option<u16> {
+        match &self.source {
+            Source::Borrowed(publish) => publish.packet_id,
+            Source::Owned(publish) => publish.packet_id,
+        }
+    }
+
+    pub(crate) fn retain(&self) -> bool {
+        match &self.source {
+            Source::Borrowed(publish) => publish.retain,
+            Source::Owned(publish) => publish.header.retain(),
+        }
+    }
+
+    /// Gets the PUBLISH as an owned packet, copying a borrowed packet on
+    /// first use. The copy has the resolved topic name and no topic alias.
+    pub(crate) fn publish(&mut self) -> &Publish {
+        match &self.source {
+            Source::Owned(publish) => publish,
+            Source::Borrowed(publish) => self.owned.get_or_insert_with(|| {
+                let mut owned = Publish::from(publish);
+                owned.topic_name = Some(self.topic.to_string());
+                owned
+                    .properties_mut()
+                    .clear_property(&PropertyType::TopicAlias);
+                owned
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use vaux_mqtt::encode;
+    use vaux_mqtt::packet_ref::PacketRef;
+    use vaux_mqtt::property::Property;
+
+    fn test_publish() -> Publish {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.packet_id = Some(7);
+        publish.set_payload(b"21.5".to_vec());
+        publish
+    }
+
+    fn encoded(packet: Packet) -> BytesMut {
+        let mut dest = BytesMut::new();
+        encode(packet, &mut dest).unwrap();
+        dest
+    }
+
+    #[test]
+    fn test_decode_publish_frame() {
+        let mut codec = ClientCodec::default();
+        let frame = encoded(Packet::Publish(test_publish()));
+        let mut src = frame.clone();
+        src.extend_from_slice(&encoded(Packet::PingRequest(vaux_mqtt::FixedHeader::new(
+            vaux_mqtt::PacketType::PingReq,
+        ))));
+        // an incomplete frame is not returned
+        let mut partial = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        match codec.decode(&mut src).unwrap() {
+            Some(Inbound::Publish(decoded)) => assert_eq!(&frame[..], &decoded[..]),
+            other => panic!("expected publish frame, decoded {:?}", other),
+        }
+        match codec.decode(&mut src).unwrap() {
+            Some(Inbound::Packet(Packet::PingRequest(_))) => {}
+            other => panic!("expected ping request, decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_publish_v3() {
+        let mut codec = ClientCodec::new(MqttCodec::new().with_version(ProtocolVersion::V3_1_1));
+        let mut src = BytesMut::new();
+        codec
+            .encode(Packet::Publish(test_publish()), &mut src)
+            .unwrap();
+        match codec.decode(&mut src).unwrap() {
+            Some(Inbound::Packet(Packet::Publish(publish))) => {
+                assert_eq!(Some("sensor/temp"), publish.topic_name.as_deref())
+            }
+            other => panic!("expected publish packet, decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_publish_too_large() {
+        let mut codec = ClientCodec::new(MqttCodec::new().with_max_packet_size(8));
+        let mut src = encoded(Packet::Publish(test_publish()));
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn test_received_publish() {
+        let mut publish = test_publish();
+        publish.topic_name = None;
+        publish
+            .properties_mut()
+            .set_property(Property::TopicAlias(1));
+        let frame = encoded(Packet::Publish(publish));
+        let borrowed = match PacketRef::decode(&frame).unwrap().unwrap().0 {
+            PacketRef::Publish(publish) => publish,
+            other => panic!("expected publish, decoded {:?}", other),
+        };
+        let mut received = Received::borrowed("sensor/temp", borrowed);
+        assert_eq!(QoSLevel::AtLeastOnce, received.qos());
+        assert_eq!(Some(7), received.packet_id());
+        // the owned copy has the resolved topic name in place of the alias
+        assert_eq!(test_publish(), *received.publish());
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use vaux_mqtt::property::Property;
+use vaux_mqtt::publish::Publish;
+use vaux_mqtt::PropertyType;
+
+/// Application message held by the broker for later delivery, either as a
+/// retained message or queued for a disconnected session. The message expiry
+/// interval is measured from the time the broker received the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StoredMessage {
+    publish: Publish,
+    received: Instant,
+    expiry: Option<Duration>,
+}
+
+impl StoredMessage {
+    /// Stores the publish with a received time of Instant::now().
+    pub(crate) fn new(publish: Publish) -> Self {
+        Self::received_at(publish, Instant::now())
+    }
+
+    pub(crate) fn received_at(publish: Publish, received: Instant) -> Self {
+        let expiry = match publish
+            .properties()
+            .get_property(&PropertyType::MessageExpiry)
+        {
+            Some(Property::MessageExpiry(secs)) => Some(Duration::from_secs(*secs as u64)),
+            _ => None,
+        };
+        Self {
+            publish,
+            received,
+            expiry,
+        }
+    }
+
+    /// Determines if the message expiry interval has passed. Messages without
+    /// a message expiry interval do not expire.
+    pub(crate) fn expired(&self) -> bool {
+        match self.expiry {
+            Some(expiry) => self.received.elapsed() >= expiry,
+            None => false,
+        }
+    }
+
+    /// Gets the publish for delivery, or None if the message has expired. The
+    /// message expiry interval of the publish is set to the lifetime remaining
+    /// as required by MQTT v5 3.3.2.3.3.
+    pub(crate) fn publish(&self) -> Option<Publish> {
+        let mut publish = self.publish.clone();
+        if let Some(expiry) = self.expiry {
+            let remaining = expiry.checked_sub(self.received.elapsed())?;
+            if remaining.is_zero() {
+                return None;
+            }
+            // round up so that a message with less than a second remaining
+            // is not delivered with an interval of 0
+            let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            publish
+                .properties_mut()
+                .set_property(Property::MessageExpiry(secs as u32));
+        }
+        Some(publish)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn publish(expiry: Option<u32>) -> Publish {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        if let Some(expiry) = expiry {
+            publish
+                .properties_mut()
+                .set_property(Property::MessageExpiry(expiry));
+        }
+        publish
+    }
+
+    #[test]
+    fn test_remaining_expiry() {
+        let received = Instant::now() - Duration::from_secs(4);
+        let message = StoredMessage::received_at(publish(Some(10)), received);
+        assert!(!message.expired());
+        let delivered = message.publish().expect("expected unexpired message");
+        match delivered
+            .properties()
+            .get_property(&PropertyType::MessageExpiry)
+        {
+            Some(Property::MessageExpiry(remaining)) => {
+                assert!(
+                    *remaining <= 6 && *remaining >= 5,
+                    "remaining {}",
+                    remaining
+                )
+            }
+            p => panic!("expected message expiry, found {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_expired() {
+        let received = Instant::now() - Duration::from_secs(10);
+        let message = StoredMessage::received_at(publish(Some(10)), received);
+        assert!(message.expired());
+        assert_eq!(None, message.publish());
+    }
+
+    #[test]
+    fn test_no_expiry() {
+        let received = Instant::now() - Duration::from_secs(3600);
+        let message = StoredMessage::received_at(publish(None), received);
+        assert!(!message.expired());
+        assert_eq!(Some(publish(None)), message.publish());
+    }
+}
@@ -1,7 +1,18 @@
-pub(crate) mod codec;
+pub(crate) mod acl;
+pub(crate) mod cluster;
+pub(crate) mod inbound;
+pub(crate) mod message;
+pub(crate) mod retained;
+pub(crate) mod router;
 pub(crate) mod session;
 
-use crate::broker::session::Session;
+use crate::broker::acl::{Acl, TemplateError};
+
+use crate::broker::cluster::{Cluster, ClusterConfig, ClusterMessage};
+use crate::broker::inbound::{ClientCodec, Inbound, Received};
+use crate::broker::retained::RetainedStore;
+use crate::broker::router::Router;
+use crate::broker::session::{Session, SessionState};
 use futures::{SinkExt, StreamExt};
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
@@ -9,25 +20,55 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::RwLock;
-use tokio::time::timeout;
+use tokio::time::{error::Elapsed, timeout};
 use tokio_util::codec::Framed;
 use uuid::Uuid;
-use vaux_mqtt::property::Property;
+use vaux_mqtt::codec::ErrorKind;
+use vaux_mqtt::packet_ref::PacketRef;
+use vaux_mqtt::property::{PacketProperties, Property, PropertyRef};
+use vaux_mqtt::subscribe::RetainHandling;
+use vaux_mqtt::validate::validate_topic_name;
 use vaux_mqtt::Packet::PingResponse;
 use vaux_mqtt::{
-    ConnAck, Disconnect, FixedHeader, MqttCodecError, Packet, PacketType, PropertyType, Reason,
+    encoded_len, ConnAck, Connect, Disconnect, FixedHeader, MqttCodecError, Packet, PacketType,
+    PropertyType, ProtocolVersion, PubResp, QoSLevel, Reason, SubAck, Subscribe, TopicAliases,
+    TopicFilter, UnsubAck, Unsubscribe,
 };
 
-use self::codec::MqttCodec;
+use vaux_mqtt::MqttCodec;
 
 pub const DEFAULT_PORT: u16 = 1883;
 pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1";
 const DEFAULT_KEEP_ALIVE: u64 = 30; // 60 seconds
+/// Maximum topic alias accepted from clients, advertised in CONNACK
+const DEFAULT_TOPIC_ALIAS_MAX: u16 = 64;
+/// Maximum packet size accepted from clients, advertised in CONNACK
+pub const DEFAULT_MAX_PACKET_SIZE: u32 = 1024 * 1024;
+
+pub type SessionPool = Arc<RwLock<HashMap<String, Arc<RwLock<Session>>>>>;
+type MqttFramed<'a> = Framed<&'a mut TcpStream, ClientCodec>;
 
 #[derive(Debug, Clone)]
 pub struct Broker {
     listen_addr: SocketAddr,
+    cluster: Option<ClusterConfig>,
+    subscription_ids: bool,
+    max_packet_size: u32,
+    acl: Acl,
+}
+
+/// Broker state shared by every client and peer connection
+#[derive(Debug, Clone)]
+struct BrokerContext {
+    session_pool: SessionPool,
+    router: Arc<RwLock<Router>>,
+    retained: Arc<RwLock<RetainedStore>>,
+    cluster: Option<Arc<Cluster>>,
+    subscription_ids: bool,
+    max_packet_size: u32,
+    acl: Acl,
 }
 
 impl Default for Broker {
@@ -35,11 +76,14 @@ impl Default for Broker {
     /// port (1883) for unsecure traffic
     fn default() -> Self {
         Broker {
-            listen_addr: SocketAddr::try_from((
+            listen_addr: SocketAddr::from((
                 Ipv4Addr::from_str(DEFAULT_LISTEN_ADDR).unwrap(),
                 DEFAULT_PORT,
-            ))
-            .unwrap(),
+            )),
+            cluster: None,
+            subscription_ids: true,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            acl: Acl::new(),
         }
     }
 }
@@ -49,21 +93,104 @@ impl Broker {
     /// not be used until the command line interface is developed. Remove the
     /// dead_code override when complete
     pub fn new(listen_addr: SocketAddr) -> Self {
-        Broker { listen_addr }
+        Broker {
+            listen_addr,
+            cluster: None,
+            subscription_ids: true,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            acl: Acl::new(),
+        }
+    }
+
+    /// Runs the broker as a node in a cluster. Messages published to the
+    /// broker are forwarded to peer nodes with matching subscribers and
+    /// sessions are taken over across nodes by client identifier.
+    pub fn with_cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Enables or disables subscription identifiers. Subscription identifiers
+    /// are enabled by default. When disabled the broker advertises that
+    /// subscription identifiers are not available in CONNACK and disconnects
+    /// clients that subscribe with a subscription identifier.
+    pub fn with_subscription_ids(mut self, enabled: bool) -> Self {
+        self.subscription_ids = enabled;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a packet accepted from a client,
+    /// including the fixed header. The maximum is advertised to clients in
+    /// CONNACK and a client sending a larger packet is disconnected with the
+    /// packet too large reason. The default is 1 MiB.
+    pub fn with_max_packet_size(mut self, max_packet_size: u32) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets the template for the response topic prefix returned to clients
+    /// that request response information in CONNECT. The placeholder
+    /// "{client_id}" in the template is replaced with the client identifier.
+    /// Subscriptions under the response topic root are restricted to the
+    /// client's own prefix. An error is returned for a template that cannot
+    /// be enforced, see [`Acl::with_response_template`].
+    pub fn with_response_template(mut self, template: &str) -> Result<Self, TemplateError> {
+        self.acl = self.acl.with_response_template(template)?;
+        Ok(self)
     }
 
     pub async fn run(
         &mut self,
-        session_pool: Arc<RwLock<HashMap<String, Arc<RwLock<Session>>>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        session_pool: SessionPool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let router = Arc::new(RwLock::new(Router::new()));
+        let retained = Arc::new(RwLock::new(RetainedStore::new()));
+        let cluster = match &self.cluster {
+            Some(config) => {
+                let listener = TcpListener::bind(config.cluster_addr).await?;
+                let cluster = Cluster::start(config.clone(), router.clone(), retained.clone());
+                println!("cluster accepting peers on {:?}", config.cluster_addr);
+                Some((listener, cluster))
+            }
+            None => None,
+        };
+        let ctx = BrokerContext {
+            session_pool,
+            router,
+            retained,
+            cluster: cluster.as_ref().map(|(_, cluster)| cluster.clone()),
+            subscription_ids: self.subscription_ids,
+            max_packet_size: self.max_packet_size,
+            acl: self.acl.clone(),
+        };
+        if let Some((listener, _)) = cluster {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((mut socket, _)) => {
+                            let ctx = ctx.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = Broker::handle_peer(&mut socket, ctx).await {
+                                    eprintln!("error in peer link: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            eprintln!("unable to accept peer connection: {}", e);
+                        }
+                    }
+                }
+            });
+        }
         match TcpListener::bind(self.listen_addr).await {
             Ok(listener) => {
                 println!("broker accepting request on {:?}", self.listen_addr);
                 loop {
-                    let session_pool = session_pool.clone();
+                    let ctx = ctx.clone();
                     let (mut socket, _) = listener.accept().await?;
                     tokio::spawn(async move {
-                        match Broker::handle_client(&mut socket, session_pool).await {
+                        match Broker::handle_client(&mut socket, ctx).await {
                             Ok(_) => {}
                             Err(e) => {
                                 // TODO unhandled error in client handler should result in disconnect
@@ -82,68 +209,49 @@ impl Broker {
 
     async fn handle_client(
         stream: &mut TcpStream,
-        session_pool: Arc<RwLock<HashMap<String, Arc<RwLock<Session>>>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let session_id: String;
-        let mut framed = Framed::new(stream, MqttCodec {});
+        ctx: BrokerContext,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let codec = MqttCodec::new().with_max_packet_size(ctx.max_packet_size as usize);
+        let mut framed = Framed::new(stream, ClientCodec::new(codec));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
         let session = match framed.next().await {
-            Some(Ok(Packet::Connect(packet))) => {
-                let mut active_session: Option<Arc<RwLock<Session>>> = None;
-                let mut ack = ConnAck::default();
-                // handle the client id
-                if packet.client_id.is_empty() {
-                    session_id = Uuid::new_v4().to_string();
-                    ack.properties_mut().set_property(
-                        vaux_mqtt::property::Property::AssignedClientId(session_id.clone()),
-                    );
-                } else {
-                    session_id = packet.client_id.clone();
-                }
-                if let Some(session) = session_pool.read().await.get(&session_id) {
-                    let mut session_lock = session.blocking_write();
-                    if session_lock.connected() {
-                        // handle take over
-                        session_lock.set_orphaned();
-                    } else if !session_lock.orphaned() {
-                        session_lock.set_connected(true);
-                        active_session = Some(session.clone());
-                        ack.session_present = true;
-                    }
-                }
-                if packet.clean_start || active_session.is_none() {
-                    let session =
-                        Session::new(session_id.clone(), Duration::from_secs(DEFAULT_KEEP_ALIVE));
-                    let session = Arc::new(RwLock::new(session));
-                    session_pool
-                        .write()
-                        .await
-                        .insert(session_id.clone(), session.clone());
-                    active_session = Some(session);
-                }
-                if packet.keep_alive > DEFAULT_KEEP_ALIVE as u16 {
-                    ack.properties_mut()
-                        .set_property(vaux_mqtt::property::Property::KeepAlive(
-                            DEFAULT_KEEP_ALIVE as u16,
-                        ));
-                } else {
-                    let mut session = active_session.as_ref().unwrap().write().await;
-                    if let Some(Property::SessionExpiryInterval(expiry)) = packet
-                        .properties()
-                        .get_property(&PropertyType::SessionExpiryInterval)
-                    {
-                        session.session_expiry = Duration::from_secs(*expiry as u64);
-                    }
-                    session.set_keep_alive(packet.keep_alive as u64);
+            Some(Ok(Inbound::Packet(Packet::Connect(packet)))) => {
+                framed
+                    .codec_mut()
+                    .codec_mut()
+                    .set_version(packet.protocol_version);
+                if let Some(max_packet_size) = packet.properties().max_packet_size() {
+                    // packets sent to the client are limited by the size it
+                    // accepts, not by the limit for packets it sends
+                    framed
+                        .codec_mut()
+                        .codec_mut()
+                        .set_max_send_size(max_packet_size as usize);
                 }
+                let (session, ack) = Broker::connect(&ctx, &packet, sender.clone()).await;
                 framed.send(Packet::ConnAck(ack)).await?;
-                active_session
+                let outbound_max = packet.properties().topic_alias_max().unwrap_or(0);
+                Some((
+                    session,
+                    TopicAliases::new(DEFAULT_TOPIC_ALIAS_MAX, outbound_max),
+                ))
             }
-            Some(Ok(Packet::PingRequest(_packet))) => {
+            Some(Ok(Inbound::Packet(Packet::PingRequest(_packet)))) => {
                 // allow clients without connected session to ping
                 let resp = PingResponse(FixedHeader::new(PacketType::PingResp));
                 framed.send(resp).await?;
                 None
             }
+            Some(Err(e)) => {
+                // reject a malformed CONNECT with the reason for the error,
+                // a DISCONNECT must not be sent before CONNACK
+                if !matches!(e.kind(), ErrorKind::Io(_)) {
+                    let mut ack = ConnAck::default();
+                    ack.set_reason(e.reason());
+                    framed.send(Packet::ConnAck(ack)).await?;
+                }
+                return Err(Box::new(e));
+            }
             _ => {
                 let header = Disconnect::new(Reason::ProtocolErr);
                 let disconnect = Packet::Disconnect(header);
@@ -151,61 +259,643 @@ impl Broker {
                 return Err(Box::new(MqttCodecError::new("connect packet not received")));
             }
         };
-        if let Some(session) = session {
-            loop {
-                let duration = Duration::from_secs(session.read().await.keep_alive());
-                match timeout(duration, framed.next()).await {
-                    Ok(request) => {
-                        if session.read().await.orphaned() {
-                            // respond with session taken over error
-                            // TODO
-                        }
-                        if let Some(request) = request {
-                            session.write().await.set_last_active();
-                            match request {
-                                Ok(request) => match request {
-                                    Packet::PingRequest(_) => {
-                                        let header = FixedHeader::new(PacketType::PingResp);
-                                        framed.send(Packet::PingResponse(header)).await?;
-                                    }
-                                    Packet::Disconnect(_) => {
-                                        // exit loop closing connection
-                                        session.write().await.set_connected(false);
-                                        break;
-                                    }
-                                    req => {
-                                        return Err(Box::new(MqttCodecError::new(
-                                            format!("unexpected packet type: {:?}", req).as_str(),
-                                        )));
-                                    }
-                                },
-                                Err(e) => {
-                                    session.write().await.set_connected(false);
-                                    // disconnect with protocol error
-                                    let disconnect = Disconnect::new(Reason::ProtocolErr);
-                                    framed.send(Packet::Disconnect(disconnect)).await?;
+        if let Some((session, mut aliases)) = session {
+            let result =
+                Broker::session_loop(&ctx, &session, &mut framed, &mut receiver, &mut aliases)
+                    .await;
+            session.write().await.detach(&sender);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Establishes the session for a CONNECT request, resuming an existing
+    /// session for the client identifier unless a clean start is requested.
+    /// An existing connection for the session, on this node or a peer node,
+    /// is disconnected.
+    async fn connect(
+        ctx: &BrokerContext,
+        packet: &Connect,
+        sender: UnboundedSender<Packet>,
+    ) -> (Arc<RwLock<Session>>, ConnAck) {
+        let mut ack = ConnAck::default();
+        ack.properties_mut()
+            .set_property(Property::TopicAliasMax(DEFAULT_TOPIC_ALIAS_MAX));
+        ack.properties_mut()
+            .set_property(Property::MaxPacketSize(ctx.max_packet_size));
+        if !ctx.subscription_ids {
+            // subscription identifiers are available unless advertised otherwise
+            ack.properties_mut()
+                .set_property(Property::SubIdAvail(false));
+        }
+        // handle the client id
+        let session_id = if packet.client_id.is_empty() {
+            let session_id = Uuid::new_v4().to_string();
+            ack.properties_mut()
+                .set_property(Property::AssignedClientId(session_id.clone()));
+            session_id
+        } else {
+            packet.client_id.clone()
+        };
+        let existing = ctx.session_pool.read().await.get(&session_id).cloned();
+        // a session held on a peer node is moved to this node to be resumed
+        let transferred = match &ctx.cluster {
+            Some(cluster) => {
+                let transfer = existing.is_none() && !packet.clean_start;
+                cluster.take_over(&session_id, transfer).await
+            }
+            None => None,
+        };
+        if let Some(existing) = &existing {
+            let existing = existing.read().await;
+            if existing.connected() {
+                // handle take over
+                existing.send(Packet::Disconnect(Disconnect::new(Reason::SessionTakeOver)));
+            }
+        }
+        let session = match existing {
+            Some(session) if !packet.clean_start => {
+                ack.session_present = true;
+                session
+            }
+            existing => {
+                if existing.is_some() {
+                    ctx.router.write().await.unsubscribe_all(&session_id);
+                }
+                let mut session =
+                    Session::new(session_id.clone(), Duration::from_secs(DEFAULT_KEEP_ALIVE));
+                if let Some(state) = transferred {
+                    ack.session_present = true;
+                    let subscriptions = session.restore(state);
+                    let mut router = ctx.router.write().await;
+                    for (subscription, subscription_id) in subscriptions {
+                        let _ = router.subscribe(&session_id, subscription, subscription_id);
+                    }
+                }
+                if existing.is_some() || ack.session_present {
+                    Broker::subscriptions_changed(ctx).await;
+                }
+                let session = Arc::new(RwLock::new(session));
+                ctx.session_pool
+                    .write()
+                    .await
+                    .insert(session_id.clone(), session.clone());
+                session
+            }
+        };
+        {
+            let mut session = session.write().await;
+            session.attach(sender);
+            if packet.keep_alive > DEFAULT_KEEP_ALIVE as u16 {
+                ack.properties_mut()
+                    .set_property(Property::KeepAlive(DEFAULT_KEEP_ALIVE as u16));
+                session.set_keep_alive(DEFAULT_KEEP_ALIVE);
+            } else {
+                session.set_keep_alive(packet.keep_alive as u64);
+            }
+            if let Some(expiry) = packet.properties().session_expiry_interval() {
+                session.session_expiry = Duration::from_secs(expiry as u64);
+            } else if packet.protocol_version == ProtocolVersion::V3_1_1 && !packet.clean_start {
+                // an MQTT 3.1.1 session without clean session does not expire
+                session.session_expiry = Duration::from_secs(u32::MAX as u64);
+            }
+        }
+        if packet.properties().req_resp_info() == Some(true) {
+            if let Some(prefix) = ctx.acl.response_prefix(&session_id) {
+                ack.properties_mut()
+                    .set_property(Property::RespInfo(prefix));
+            }
+        }
+        (session, ack)
+    }
+
+    async fn session_loop(
+        ctx: &BrokerContext,
+        session: &Arc<RwLock<Session>>,
+        framed: &mut MqttFramed<'_>,
+        receiver: &mut UnboundedReceiver<Packet>,
+        aliases: &mut TopicAliases,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let keep_alive = session.read().await.keep_alive();
+            tokio::select! {
+                request = Broker::next_request(framed, keep_alive) => match request {
+                    Ok(Some(Ok(request))) => {
+                        session.write().await.set_last_active();
+                        let request = match request {
+                            Inbound::Publish(frame) => {
+                                Broker::receive_publish(ctx, session, framed, aliases, &frame)
+                                    .await?;
+                                continue;
+                            }
+                            Inbound::Packet(request) => request,
+                        };
+                        match request {
+                            Packet::PingRequest(_) => {
+                                let header = FixedHeader::new(PacketType::PingResp);
+                                framed.send(Packet::PingResponse(header)).await?;
+                            }
+                            Packet::Disconnect(_) => {
+                                // exit loop closing connection
+                                break;
+                            }
+                            Packet::Publish(mut publish) => {
+                                // an MQTT 3.1.1 PUBLISH is decoded as an owned packet
+                                if let Err(reason) = aliases.resolve_inbound(&mut publish) {
+                                    Broker::disconnect(framed, reason).await?;
+                                    return Err(Box::new(MqttCodecError::new(
+                                        format!("topic alias error: {}", reason).as_str(),
+                                    )));
+                                }
+                                if let Some(Err(e)) =
+                                    publish.topic_name.as_deref().map(validate_topic_name)
+                                {
+                                    Broker::disconnect(framed, e.reason()).await?;
                                     return Err(Box::new(e));
                                 }
-                            } // match request
-                        } // if let Some(request ...
+                                if let Some(received) = Received::owned(&publish) {
+                                    Broker::handle_publish(ctx, session, framed, received).await?;
+                                }
+                            }
+                            Packet::PubAck(ack) | Packet::PubComp(ack) => {
+                                session.write().await.acknowledge(ack.packet_id);
+                            }
+                            Packet::PubRec(rec) if (rec.reason() as u8) >= 0x80 => {
+                                // the publish ends with a PUBREC reporting failure
+                                session.write().await.acknowledge(rec.packet_id);
+                            }
+                            Packet::PubRec(rec) => {
+                                let mut rel = PubResp::new_pubrel();
+                                rel.packet_id = rec.packet_id;
+                                if !session.write().await.release(rec.packet_id) {
+                                    rel.set_reason(Reason::PacketIdNotFound)?;
+                                }
+                                framed.send(Packet::PubRel(rel)).await?;
+                            }
+                            Packet::PubRel(rel) => {
+                                let mut comp = PubResp::new_pubcomp();
+                                comp.packet_id = rel.packet_id;
+                                if !session.write().await.release_qos2(rel.packet_id) {
+                                    comp.set_reason(Reason::PacketIdNotFound)?;
+                                }
+                                framed.send(Packet::PubComp(comp)).await?;
+                            }
+                            Packet::Subscribe(subscribe) => {
+                                Broker::handle_subscribe(ctx, session, framed, subscribe).await?;
+                            }
+                            Packet::Unsubscribe(unsubscribe) => {
+                                Broker::handle_unsubscribe(ctx, session, framed, unsubscribe)
+                                    .await?;
+                            }
+                            req => {
+                                Broker::disconnect(framed, Reason::ProtocolErr).await?;
+                                return Err(Box::new(MqttCodecError::new(
+                                    format!("unexpected packet type: {:?}", req).as_str(),
+                                )));
+                            }
+                        }
+                    }
+                    Ok(Some(Err(e))) => {
+                        // disconnect with the reason for the decode error
+                        if !matches!(e.kind(), ErrorKind::Io(_)) {
+                            Broker::disconnect(framed, e.reason()).await?;
+                        }
+                        return Err(Box::new(e));
+                    }
+                    Ok(None) => {
+                        // connection closed by the client
+                        break;
                     }
                     Err(_elapsed) => {
                         // connection keep alive expired
-                        session.write().await.set_connected(false);
-                        let disconnect = Disconnect::new(Reason::KeepAliveTimeout);
-                        framed.send(Packet::Disconnect(disconnect)).await?;
+                        Broker::disconnect(framed, Reason::KeepAliveTimeout).await?;
                         break;
                     }
+                },
+                Some(mut packet) = receiver.recv() => {
+                    if let Packet::Publish(publish) = &mut packet {
+                        aliases.apply_outbound(publish);
+                        let codec = framed.codec().codec();
+                        if encoded_len(&packet, codec.version()) > codec.max_send_size() {
+                            // a PUBLISH too large for the client is discarded as
+                            // if it had been delivered, MQTT v5 3.1.2.11.4
+                            if let Packet::Publish(publish) = &packet {
+                                aliases.discard_outbound(publish);
+                                if let Some(packet_id) = publish.packet_id {
+                                    session.write().await.acknowledge(packet_id);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    if let Packet::Disconnect(disconnect) = packet {
+                        // session taken over by another connection
+                        Broker::disconnect(framed, disconnect.reason).await?;
+                        break;
+                    }
+                    framed.send(packet).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a DISCONNECT with the reason to the client. An MQTT 3.1.1 server
+    /// cannot send DISCONNECT, so nothing is sent and the caller closes the
+    /// connection.
+    async fn disconnect(framed: &mut MqttFramed<'_>, reason: Reason) -> Result<(), MqttCodecError> {
+        if framed.codec().codec().version() == ProtocolVersion::V5 {
+            framed
+                .send(Packet::Disconnect(Disconnect::new(reason)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next packet from the client waiting at most the keep alive
+    /// interval. A keep alive of 0 disables the keep alive mechanism.
+    async fn next_request(
+        framed: &mut MqttFramed<'_>,
+        keep_alive: u64,
+    ) -> Result<Option<Result<Inbound, MqttCodecError>>, Elapsed> {
+        if keep_alive == 0 {
+            Ok(framed.next().await)
+        } else {
+            timeout(Duration::from_secs(keep_alive), framed.next()).await
+        }
+    }
+
+    /// Handles a PUBLISH frame received from an MQTT v5 client. The topic name
+    /// is resolved from the borrowed packet, the message is only copied when
+    /// it is delivered to a matching subscriber or retained.
+    async fn receive_publish(
+        ctx: &BrokerContext,
+        session: &Arc<RwLock<Session>>,
+        framed: &mut MqttFramed<'_>,
+        aliases: &mut TopicAliases,
+        frame: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let publish = match PacketRef::decode(frame) {
+            Ok(Some((PacketRef::Publish(publish), _))) => publish,
+            Ok(_) => return Err(Box::new(MqttCodecError::new("publish frame not decoded"))),
+            Err(e) => {
+                Broker::disconnect(framed, e.reason()).await?;
+                return Err(Box::new(e));
+            }
+        };
+        let topic = (!publish.topic_name.is_empty()).then_some(publish.topic_name);
+        let alias = publish
+            .properties
+            .iter()
+            .find_map(|property| match property {
+                PropertyRef::TopicAlias(alias) => Some(alias),
+                _ => None,
+            });
+        let topic = match aliases.resolve_inbound_topic(topic, alias) {
+            Ok(topic) => topic,
+            Err(reason) => {
+                Broker::disconnect(framed, reason).await?;
+                return Err(Box::new(MqttCodecError::new(
+                    format!("topic alias error: {}", reason).as_str(),
+                )));
+            }
+        };
+        if let Err(e) = validate_topic_name(topic) {
+            Broker::disconnect(framed, e.reason()).await?;
+            return Err(Box::new(e));
+        }
+        Broker::handle_publish(ctx, session, framed, Received::borrowed(topic, publish)).await
+    }
+
+    async fn handle_publish(
+        ctx: &BrokerContext,
+        session: &Arc<RwLock<Session>>,
+        framed: &mut MqttFramed<'_>,
+        mut publish: Received<'_>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if Acl::is_reserved(publish.topic()) {
+            // reserved topics are refused without delivery, there is no
+            // acknowledgement for QoS 0
+            let mut ack = match publish.qos() {
+                QoSLevel::AtMostOnce => return Ok(()),
+                QoSLevel::AtLeastOnce => PubResp::new_puback(),
+                QoSLevel::ExactlyOnce => PubResp::new_pubrec(),
+            };
+            ack.packet_id = publish.packet_id().unwrap_or_default();
+            ack.set_reason(Reason::NotAuthorized)?;
+            let ack = match publish.qos() {
+                QoSLevel::AtLeastOnce => Packet::PubAck(ack),
+                _ => Packet::PubRec(ack),
+            };
+            framed.send(ack).await?;
+            return Ok(());
+        }
+        if publish.retain() {
+            ctx.retained.write().await.retain(publish.publish());
+        }
+        let client_id = session.read().await.id().to_string();
+        if publish.qos() == QoSLevel::AtMostOnce {
+            Broker::route(ctx, &client_id, &mut publish).await;
+            return Ok(());
+        }
+        let packet_id = publish.packet_id().ok_or_else(|| {
+            MqttCodecError::new("MQTTv5 3.3.2.2 packet identifier must be included for QOS 1 or 2")
+        })?;
+        if publish.qos() == QoSLevel::AtLeastOnce {
+            let mut ack = PubResp::new_puback();
+            ack.packet_id = packet_id;
+            if Broker::route(ctx, &client_id, &mut publish).await == 0 {
+                ack.set_reason(Reason::NoSubscribers)?;
+            }
+            framed.send(Packet::PubAck(ack)).await?;
+        } else {
+            let mut rec = PubResp::new_pubrec();
+            rec.packet_id = packet_id;
+            // a duplicate QoS 2 publish is acknowledged without delivery
+            if session.write().await.receive_qos2(packet_id)
+                && Broker::route(ctx, &client_id, &mut publish).await == 0
+            {
+                rec.set_reason(Reason::NoSubscribers)?;
+            }
+            framed.send(Packet::PubRec(rec)).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_subscribe(
+        ctx: &BrokerContext,
+        session: &Arc<RwLock<Session>>,
+        framed: &mut MqttFramed<'_>,
+        subscribe: Subscribe,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let subscription_id = match subscribe
+            .properties()
+            .get_property(&PropertyType::SubscriptionIdentifier)
+        {
+            Some(Property::SubscriptionIdentifier(id)) => Some(*id),
+            _ => None,
+        };
+        let invalid_id = match subscription_id {
+            Some(_) if !ctx.subscription_ids => Some(Reason::SubIdUnsupported),
+            // MQTTv5 3.8.2.1.2 subscription identifier of 0 is a protocol error
+            Some(0) => Some(Reason::ProtocolErr),
+            _ => None,
+        };
+        if let Some(reason) = invalid_id {
+            Broker::disconnect(framed, reason).await?;
+            return Err(Box::new(MqttCodecError::new(
+                format!("invalid subscription identifier: {}", reason).as_str(),
+            )));
+        }
+        let client_id = session.read().await.id().to_string();
+        let mut ack = SubAck::new(subscribe.packet_id());
+        let mut retained = Vec::new();
+        {
+            let mut router = ctx.router.write().await;
+            for subscription in subscribe.subscriptions() {
+                let filter = match TopicFilter::new(subscription.filter.as_str()) {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        ack.add_reason(e.reason());
+                        continue;
+                    }
+                };
+                if !ctx.acl.can_subscribe(&client_id, &filter) {
+                    ack.add_reason(Reason::NotAuthorized);
+                    continue;
+                }
+                let new = match router.subscribe(&client_id, subscription.clone(), subscription_id)
+                {
+                    Ok(new) => new,
+                    Err(e) => {
+                        ack.add_reason(e.reason());
+                        continue;
+                    }
+                };
+                ack.add_reason(match subscription.qos {
+                    QoSLevel::AtMostOnce => Reason::GrantedQoS0,
+                    QoSLevel::AtLeastOnce => Reason::GrantedQoS1,
+                    QoSLevel::ExactlyOnce => Reason::GrantedQoS2,
+                });
+                let send_retained = match subscription.handling {
+                    RetainHandling::Send => true,
+                    RetainHandling::SendNew => new,
+                    RetainHandling::None => false,
+                };
+                if send_retained {
+                    retained.push(subscription.clone());
+                }
+            }
+        }
+        framed.send(Packet::SubAck(ack)).await?;
+        Broker::subscriptions_changed(ctx).await;
+        // retained messages are sent after the SUBACK, MQTT v5 3.3.1.3
+        for subscription in retained {
+            let filter = match TopicFilter::new(subscription.filter.as_str()) {
+                Ok(filter) => filter,
+                Err(_) => continue,
+            };
+            let messages = ctx.retained.write().await.matches(&filter);
+            let mut session = session.write().await;
+            for mut publish in messages {
+                if (subscription.qos as u8) < (publish.qos() as u8) {
+                    publish.set_qos(subscription.qos);
+                }
+                if let Some(id) = subscription_id {
+                    publish.properties_mut().add_subscription_id(id);
                 }
-            } // loop
+                session.deliver(publish);
+            }
         }
         Ok(())
     }
+
+    /// Removes the subscriptions with the topic filters of the UNSUBSCRIBE
+    /// and acknowledges each filter with an UNSUBACK reason code, MQTT v5
+    /// 3.10.4.
+    async fn handle_unsubscribe(
+        ctx: &BrokerContext,
+        session: &Arc<RwLock<Session>>,
+        framed: &mut MqttFramed<'_>,
+        unsubscribe: Unsubscribe,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let client_id = session.read().await.id().to_string();
+        let mut ack = UnsubAck::new(unsubscribe.packet_id());
+        {
+            let mut router = ctx.router.write().await;
+            for filter in unsubscribe.filters() {
+                ack.add_reason(match router.unsubscribe(&client_id, filter) {
+                    Ok(true) => Reason::Success,
+                    Ok(false) => Reason::NoSubscriptionExisted,
+                    Err(e) => e.reason(),
+                });
+            }
+        }
+        framed.send(Packet::UnsubAck(ack)).await?;
+        Broker::subscriptions_changed(ctx).await;
+        Ok(())
+    }
+
+    /// Routes a publish received from a client to local subscribers and to
+    /// cluster peers with matching subscribers. Returns the number of local
+    /// sessions and peers the publish was delivered to.
+    async fn route(ctx: &BrokerContext, client_id: &str, publish: &mut Received<'_>) -> usize {
+        let mut delivered = Broker::deliver_local(ctx, Some(client_id), publish).await;
+        if let Some(cluster) = &ctx.cluster {
+            delivered += cluster.forward(publish).await;
+        }
+        delivered
+    }
+
+    /// Delivers a publish to the sessions on this node with a matching
+    /// subscription. The publish is delivered at the lower of the published
+    /// QoS and the subscription QoS, with the subscription identifier of each
+    /// matching subscription. The RETAIN flag is cleared unless a matching
+    /// subscription has the retain as published option set, MQTT v5 3.3.1.3.
+    ///
+    /// The publishing client, if connected to this node, does not receive the
+    /// publish through subscriptions with the no local option set.
+    async fn deliver_local(
+        ctx: &BrokerContext,
+        publisher: Option<&str>,
+        publish: &mut Received<'_>,
+    ) -> usize {
+        let matches = ctx.router.read().await.matches(publish.topic(), publisher);
+        let session_pool = ctx.session_pool.read().await;
+        let mut delivered = 0;
+        for matched in matches {
+            if let Some(session) = session_pool.get(&matched.client_id) {
+                let mut outbound = publish.publish().clone();
+                if (matched.subscription.qos as u8) < (publish.qos() as u8) {
+                    outbound.set_qos(matched.subscription.qos);
+                }
+                if !matched.retain_as_published {
+                    outbound.header.set_retain(false);
+                }
+                let props = outbound.properties_mut();
+                props.clear_property(&PropertyType::SubscriptionIdentifier);
+                for id in matched.subscription_ids {
+                    props.add_subscription_id(id);
+                }
+                if session.write().await.deliver(outbound) {
+                    delivered += 1;
+                }
+            }
+        }
+        delivered
+    }
+
+    async fn subscriptions_changed(ctx: &BrokerContext) {
+        if let Some(cluster) = &ctx.cluster {
+            cluster.announce_subscriptions(ctx.router.read().await.filters());
+        }
+    }
+
+    /// Discards the session for the client identifier after the client has
+    /// connected to a peer node, disconnecting the client if connected. When
+    /// transfer is true the state of the session is returned so that it can
+    /// be resumed on the peer.
+    async fn take_over(
+        ctx: &BrokerContext,
+        client_id: &str,
+        transfer: bool,
+    ) -> Option<SessionState> {
+        let session = ctx.session_pool.write().await.remove(client_id)?;
+        let mut session = session.write().await;
+        if session.connected() {
+            session.send(Packet::Disconnect(Disconnect::new(Reason::SessionTakeOver)));
+        }
+        let mut router = ctx.router.write().await;
+        let state = transfer.then(|| session.take_state(router.subscriptions(client_id)));
+        router.unsubscribe_all(client_id);
+        drop(router);
+        Broker::subscriptions_changed(ctx).await;
+        state
+    }
+
+    async fn handle_peer(
+        stream: &mut TcpStream,
+        ctx: BrokerContext,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let cluster = match &ctx.cluster {
+            Some(cluster) => cluster.clone(),
+            None => return Err(Box::new(MqttCodecError::new("cluster not configured"))),
+        };
+        let mut framed = Framed::new(stream, cluster::peer_codec());
+        // the link is opened with a CONNECT carrying the cluster secret
+        let peer = match framed.next().await {
+            Some(Ok(Packet::Connect(connect))) => cluster.authenticate(&connect),
+            _ => Err(Reason::ProtocolErr),
+        };
+        let mut ack = ConnAck::default();
+        let peer = match peer {
+            Ok(peer) => peer,
+            Err(reason) => {
+                ack.set_reason(reason);
+                framed.send(Packet::ConnAck(ack)).await?;
+                return Err(Box::new(MqttCodecError::new(&format!(
+                    "peer link refused: {}",
+                    reason
+                ))));
+            }
+        };
+        framed.send(Packet::ConnAck(ack)).await?;
+        let mut result = Ok(());
+        while let Some(packet) = framed.next().await {
+            let message = match packet {
+                Ok(Packet::Publish(publish)) => ClusterMessage::try_from(publish),
+                Ok(packet) => Err(MqttCodecError::new(&format!(
+                    "unexpected packet type on peer link: {}",
+                    PacketType::from(&packet)
+                ))),
+                Err(e) => Err(e),
+            };
+            match message {
+                Ok(ClusterMessage::Subscriptions(filters)) => {
+                    cluster.set_remote_filters(peer, filters).await;
+                }
+                Ok(ClusterMessage::TakeOver {
+                    client_id,
+                    transfer,
+                }) => {
+                    let state = Broker::take_over(&ctx, &client_id, transfer).await;
+                    if transfer {
+                        cluster.send(&peer, ClusterMessage::Session(client_id, state));
+                    }
+                }
+                Ok(ClusterMessage::Session(client_id, state)) => {
+                    cluster.session_received(peer, &client_id, state);
+                }
+                Ok(ClusterMessage::Forward(publish)) => {
+                    if publish.header.retain() {
+                        ctx.retained.write().await.retain(&publish);
+                    }
+                    // forwarded messages are only delivered locally so that
+                    // messages are never forwarded more than once
+                    if let Some(mut received) = Received::owned(&publish) {
+                        Broker::deliver_local(&ctx, None, &mut received).await;
+                    }
+                }
+                Ok(ClusterMessage::Retain(publish)) => {
+                    ctx.retained.write().await.retain(&publish);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        cluster.remove_peer(&peer).await;
+        result.map_err(|e| e.into())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use tokio::io::AsyncWriteExt;
+    use vaux_mqtt::publish::Publish;
+    use vaux_mqtt::Subscription;
 
     #[test]
     /// Tests the default initialization behaviors for the broker. Changing the
@@ -235,11 +925,10 @@ mod test {
         const EXPECTED_IP_ADDR: &str = "127.0.0.1";
         const EXPECTED_PORT: u16 = 1883;
 
-        let listen_addr = SocketAddr::try_from((
+        let listen_addr = SocketAddr::from((
             Ipv4Addr::from_str(DEFAULT_LISTEN_ADDR).unwrap(),
             DEFAULT_PORT,
-        ))
-        .unwrap();
+        ));
 
         let broker = Broker::new(listen_addr);
         assert_eq!(
@@ -253,4 +942,861 @@ mod test {
             "expected default listen port to be 1883"
         );
     }
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+    const TEST_SECRET: &str = "cluster-secret";
+
+    fn local_addr(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn start_broker(broker: Broker) {
+        tokio::spawn(async move {
+            let mut broker = broker;
+            let _ = broker.run(SessionPool::default()).await;
+        });
+    }
+
+    async fn connect_client(port: u16, client_id: &str) -> Framed<TcpStream, MqttCodec> {
+        let mut connect = Connect::default();
+        connect.client_id = client_id.to_string();
+        connect_with(port, connect).await.0
+    }
+
+    async fn connect_with(port: u16, connect: Connect) -> (Framed<TcpStream, MqttCodec>, ConnAck) {
+        let start = std::time::Instant::now();
+        let stream = loop {
+            match TcpStream::connect(local_addr(port)).await {
+                Ok(stream) => break stream,
+                Err(e) if start.elapsed() > TEST_TIMEOUT => panic!("unable to connect: {}", e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        let mut framed = Framed::new(stream, MqttCodec::default());
+        framed.codec_mut().set_version(connect.protocol_version);
+        framed
+            .send(Packet::Connect(Box::new(connect)))
+            .await
+            .unwrap();
+        match next_packet(&mut framed).await {
+            Packet::ConnAck(ack) => {
+                assert_eq!(Reason::Success, ack.reason());
+                (framed, ack)
+            }
+            p => panic!("expected CONNACK, found {:?}", p),
+        }
+    }
+
+    async fn next_packet(framed: &mut Framed<TcpStream, MqttCodec>) -> Packet {
+        match timeout(TEST_TIMEOUT, framed.next()).await {
+            Ok(Some(Ok(packet))) => packet,
+            other => panic!("expected packet, found {:?}", other),
+        }
+    }
+
+    async fn subscribe(framed: &mut Framed<TcpStream, MqttCodec>, filter: &str) {
+        let subscribe = Subscribe::new(
+            1,
+            vec![Subscription::new(filter.to_string(), QoSLevel::AtMostOnce)],
+        );
+        framed.send(Packet::Subscribe(subscribe)).await.unwrap();
+        match next_packet(framed).await {
+            Packet::SubAck(ack) => assert_eq!(&[Reason::GrantedQoS0], ack.reasons()),
+            p => panic!("expected SUBACK, found {:?}", p),
+        }
+    }
+
+    fn test_publish(topic: &str, payload: &str) -> Packet {
+        let mut publish = Publish::default();
+        publish.topic_name = Some(topic.to_string());
+        publish.set_payload(payload.as_bytes().to_vec());
+        Packet::Publish(publish)
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        const PORT: u16 = 21883;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut subscriber = connect_client(PORT, "test-sub").await;
+        subscribe(&mut subscriber, "test/+").await;
+        let mut publisher = connect_client(PORT, "test-pub").await;
+        publisher
+            .send(test_publish("test/local", "hello"))
+            .await
+            .unwrap();
+        match next_packet(&mut subscriber).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("test/local"), publish.topic_name.as_deref());
+                assert_eq!(Some(&b"hello"[..]), publish.payload());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe() {
+        const PORT: u16 = 21902;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut subscriber = connect_client(PORT, "unsub-sub").await;
+        subscribe(&mut subscriber, "unsub/+").await;
+        let unsubscribe = Unsubscribe::builder(2)
+            .with_filter("unsub/+")
+            .with_filter("unsub/#")
+            .build()
+            .unwrap();
+        subscriber
+            .send(Packet::Unsubscribe(unsubscribe))
+            .await
+            .unwrap();
+        match next_packet(&mut subscriber).await {
+            Packet::UnsubAck(ack) => {
+                assert_eq!(2, ack.packet_id());
+                assert_eq!(
+                    &[Reason::Success, Reason::NoSubscriptionExisted],
+                    ack.reasons()
+                );
+            }
+            p => panic!("expected UNSUBACK, found {:?}", p),
+        }
+        let mut publisher = connect_client(PORT, "unsub-pub").await;
+        publisher
+            .send(test_publish("unsub/local", "hello"))
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(200), subscriber.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_topic_alias() {
+        const PORT: u16 = 21888;
+        const TOPIC: &str = "test/alias/with/a/long/topic/name";
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut connect = Connect::default();
+        connect.client_id = "alias-sub".to_string();
+        connect
+            .properties_mut()
+            .set_property(Property::TopicAliasMax(1));
+        let (mut subscriber, _) = connect_with(PORT, connect).await;
+        subscribe(&mut subscriber, "test/alias/#").await;
+        let mut connect = Connect::default();
+        connect.client_id = "alias-pub".to_string();
+        let (mut publisher, ack) = connect_with(PORT, connect).await;
+        assert_eq!(
+            Some(&Property::TopicAliasMax(DEFAULT_TOPIC_ALIAS_MAX)),
+            ack.properties().get_property(&PropertyType::TopicAliasMax)
+        );
+        let mut first = Publish::default();
+        first.topic_name = Some(TOPIC.to_string());
+        first.properties_mut().set_property(Property::TopicAlias(1));
+        first.set_payload(b"first".to_vec());
+        publisher.send(Packet::Publish(first)).await.unwrap();
+        let mut second = Publish::default();
+        second
+            .properties_mut()
+            .set_property(Property::TopicAlias(1));
+        second.set_payload(b"second".to_vec());
+        publisher.send(Packet::Publish(second)).await.unwrap();
+        for (topic, payload) in [(Some(TOPIC), &b"first"[..]), (None, &b"second"[..])] {
+            match next_packet(&mut subscriber).await {
+                Packet::Publish(publish) => {
+                    assert_eq!(topic, publish.topic_name.as_deref());
+                    assert_eq!(Some(payload), publish.payload());
+                    assert_eq!(
+                        Some(&Property::TopicAlias(1)),
+                        publish.properties().get_property(&PropertyType::TopicAlias)
+                    );
+                }
+                p => panic!("expected PUBLISH, found {:?}", p),
+            }
+        }
+        // an alias that has not been mapped disconnects the client
+        let mut unmapped = Publish::default();
+        unmapped
+            .properties_mut()
+            .set_property(Property::TopicAlias(2));
+        publisher.send(Packet::Publish(unmapped)).await.unwrap();
+        match next_packet(&mut publisher).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(Reason::InvalidTopicAlias, disconnect.reason)
+            }
+            p => panic!("expected DISCONNECT, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retained_expiry() {
+        const PORT: u16 = 21889;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut publisher = connect_client(PORT, "retain-pub").await;
+        for (topic, expiry) in [("retain/live", 60), ("retain/expired", 0)] {
+            let mut publish = Publish::default();
+            publish.topic_name = Some(topic.to_string());
+            publish.header.set_retain(true);
+            publish.set_qos(QoSLevel::AtLeastOnce);
+            publish.packet_id = Some(1);
+            publish
+                .properties_mut()
+                .set_property(Property::MessageExpiry(expiry));
+            publish.set_payload(b"retained".to_vec());
+            publisher.send(Packet::Publish(publish)).await.unwrap();
+            match next_packet(&mut publisher).await {
+                Packet::PubAck(ack) => assert_eq!(Reason::NoSubscribers, ack.reason()),
+                p => panic!("expected PUBACK, found {:?}", p),
+            }
+        }
+        let mut subscriber = connect_client(PORT, "retain-sub").await;
+        subscribe(&mut subscriber, "retain/+").await;
+        match next_packet(&mut subscriber).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("retain/live"), publish.topic_name.as_deref());
+                assert!(publish.header.retain());
+                match publish
+                    .properties()
+                    .get_property(&PropertyType::MessageExpiry)
+                {
+                    Some(Property::MessageExpiry(remaining)) => assert!(*remaining <= 60),
+                    p => panic!("expected message expiry, found {:?}", p),
+                }
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the expired retained message is not delivered
+        assert!(timeout(Duration::from_millis(200), subscriber.next())
+            .await
+            .is_err());
+    }
+
+    fn subscribe_with_id(filter: &str, id: u32) -> Packet {
+        let mut subscribe = Subscribe::new(
+            1,
+            vec![Subscription::new(filter.to_string(), QoSLevel::AtMostOnce)],
+        );
+        subscribe
+            .properties_mut()
+            .set_property(Property::SubscriptionIdentifier(id));
+        Packet::Subscribe(subscribe)
+    }
+
+    #[tokio::test]
+    async fn test_subscription_ids() {
+        const PORT: u16 = 21890;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut subscriber = connect_client(PORT, "subid-sub").await;
+        for (filter, id) in [("subid/+", 5), ("subid/#", 9), ("other/#", 11)] {
+            subscriber
+                .send(subscribe_with_id(filter, id))
+                .await
+                .unwrap();
+            match next_packet(&mut subscriber).await {
+                Packet::SubAck(_) => {}
+                p => panic!("expected SUBACK, found {:?}", p),
+            }
+        }
+        let mut publisher = connect_client(PORT, "subid-pub").await;
+        publisher
+            .send(test_publish("subid/test", "hello"))
+            .await
+            .unwrap();
+        match next_packet(&mut subscriber).await {
+            Packet::Publish(publish) => {
+                assert_eq!(vec![5, 9], publish.properties().subscription_ids());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_ids_disabled() {
+        const PORT: u16 = 21891;
+        start_broker(Broker::new(local_addr(PORT)).with_subscription_ids(false));
+        let mut connect = Connect::default();
+        connect.client_id = "subid-disabled".to_string();
+        let (mut client, ack) = connect_with(PORT, connect).await;
+        assert_eq!(
+            Some(&Property::SubIdAvail(false)),
+            ack.properties().get_property(&PropertyType::SubIdAvail)
+        );
+        client.send(subscribe_with_id("subid/+", 5)).await.unwrap();
+        match next_packet(&mut client).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(Reason::SubIdUnsupported, disconnect.reason)
+            }
+            p => panic!("expected DISCONNECT, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_information() {
+        const PORT: u16 = 21892;
+        start_broker(
+            Broker::new(local_addr(PORT))
+                .with_response_template("$response/{client_id}/")
+                .unwrap(),
+        );
+        let mut connect = Connect::default();
+        connect.client_id = "requester".to_string();
+        connect
+            .properties_mut()
+            .set_property(Property::ReqRespInfo(true));
+        let (mut requester, ack) = connect_with(PORT, connect).await;
+        let prefix = match ack.properties().get_property(&PropertyType::RespInfo) {
+            Some(Property::RespInfo(prefix)) => prefix.clone(),
+            p => panic!("expected response information, found {:?}", p),
+        };
+        assert_eq!("$response/requester/", prefix);
+        subscribe(&mut requester, &format!("{}#", prefix)).await;
+        let mut other = connect_client(PORT, "other").await;
+        let subscribe = Subscribe::new(
+            1,
+            vec![
+                Subscription::new("$response/requester/#".to_string(), QoSLevel::AtMostOnce),
+                Subscription::new(
+                    "$share/g/$response/requester/#".to_string(),
+                    QoSLevel::AtMostOnce,
+                ),
+            ],
+        );
+        other.send(Packet::Subscribe(subscribe)).await.unwrap();
+        match next_packet(&mut other).await {
+            Packet::SubAck(ack) => assert_eq!(
+                &[Reason::NotAuthorized, Reason::NotAuthorized],
+                ack.reasons()
+            ),
+            p => panic!("expected SUBACK, found {:?}", p),
+        }
+        // any client may publish a response to the prefix
+        other
+            .send(test_publish("$response/requester/reply", "response"))
+            .await
+            .unwrap();
+        match next_packet(&mut requester).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some(&b"response"[..]), publish.payload());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_error_reason() {
+        const PORT: u16 = 21894;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut client = connect_client(PORT, "malformed").await;
+        // PUBLISH with both QoS bits set
+        client
+            .get_mut()
+            .write_all(&[0x36, 0x03, 0x00, 0x01, b'a'])
+            .await
+            .unwrap();
+        match next_packet(&mut client).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(Reason::MalformedPacket, disconnect.reason)
+            }
+            p => panic!("expected DISCONNECT, found {:?}", p),
+        }
+        let stream = TcpStream::connect(local_addr(PORT)).await.unwrap();
+        let mut client = Framed::new(stream, MqttCodec::default());
+        // CONNECT with protocol level 3
+        client
+            .get_mut()
+            .write_all(&[
+                0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x03, 0x02, 0x00, 0x3c, 0x00, 0x01,
+                b'a',
+            ])
+            .await
+            .unwrap();
+        match next_packet(&mut client).await {
+            Packet::ConnAck(ack) => assert_eq!(Reason::UnsupportedProtocolVersion, ack.reason()),
+            p => panic!("expected CONNACK, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v3_client() {
+        const PORT: u16 = 21893;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut connect = Connect::default();
+        connect.protocol_version = ProtocolVersion::V3_1_1;
+        connect.client_id = "v3-client".to_string();
+        let (mut v3_client, ack) = connect_with(PORT, connect).await;
+        assert!(ack.properties().is_empty());
+        subscribe(&mut v3_client, "sensor/#").await;
+        let mut v5_client = connect_client(PORT, "v5-client").await;
+        subscribe(&mut v5_client, "sensor/#").await;
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_payload(b"21".to_vec());
+        publish
+            .properties_mut()
+            .set_property(Property::MessageExpiry(60));
+        v5_client.send(Packet::Publish(publish)).await.unwrap();
+        match next_packet(&mut v3_client).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("sensor/temp"), publish.topic_name.as_deref());
+                assert_eq!(Some(&b"21"[..]), publish.payload());
+                assert!(publish.properties().is_empty());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        v3_client
+            .send(test_publish("sensor/humidity", "40"))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            match next_packet(&mut v5_client).await {
+                Packet::Publish(publish) => {
+                    if publish.topic_name.as_deref() == Some("sensor/humidity") {
+                        assert_eq!(Some(&b"40"[..]), publish.payload());
+                        return;
+                    }
+                }
+                p => panic!("expected PUBLISH, found {:?}", p),
+            }
+        }
+        panic!("publish from MQTT 3.1.1 client not received");
+    }
+
+    #[tokio::test]
+    async fn test_max_packet_size() {
+        const PORT: u16 = 21901;
+        start_broker(Broker::new(local_addr(PORT)).with_max_packet_size(64));
+        let mut connect = Connect::default();
+        connect.client_id = "max-packet".to_string();
+        let (mut client, ack) = connect_with(PORT, connect).await;
+        assert_eq!(
+            Some(&Property::MaxPacketSize(64)),
+            ack.properties().get_property(&PropertyType::MaxPacketSize)
+        );
+        client
+            .send(test_publish("sensor/temp", &"x".repeat(64)))
+            .await
+            .unwrap();
+        match next_packet(&mut client).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(Reason::PacketTooLarge, disconnect.reason)
+            }
+            p => panic!("expected DISCONNECT, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_send_size() {
+        const PORT: u16 = 21903;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut connect = Connect::default();
+        connect.client_id = "max-send".to_string();
+        connect
+            .properties_mut()
+            .set_property(Property::MaxPacketSize(48));
+        let (mut subscriber, _) = connect_with(PORT, connect).await;
+        let subscribe = Subscribe::new(
+            1,
+            vec![Subscription::new(
+                "send/#".to_string(),
+                QoSLevel::AtLeastOnce,
+            )],
+        );
+        subscriber.send(Packet::Subscribe(subscribe)).await.unwrap();
+        assert!(matches!(
+            next_packet(&mut subscriber).await,
+            Packet::SubAck(_)
+        ));
+        let mut publisher = connect_client(PORT, "max-send-pub").await;
+        // the first publish is too large for the subscriber and is dropped
+        // without closing the connection of the subscriber
+        for payload in ["x".repeat(48), "small".to_string()] {
+            let mut publish = Publish::default();
+            publish.topic_name = Some("send/temp".to_string());
+            publish.set_qos(QoSLevel::AtLeastOnce);
+            publish.set_packet_id(1).unwrap();
+            publish.set_payload(payload.into_bytes());
+            publisher.send(Packet::Publish(publish)).await.unwrap();
+            assert!(matches!(
+                next_packet(&mut publisher).await,
+                Packet::PubAck(_)
+            ));
+        }
+        match next_packet(&mut subscriber).await {
+            Packet::Publish(publish) => assert_eq!(Some(&b"small"[..]), publish.payload()),
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_resume() {
+        const PORT: u16 = 21904;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut connect = Connect::default();
+        connect.client_id = "resume-client".to_string();
+        connect.clean_start = false;
+        connect
+            .properties_mut()
+            .set_property(Property::SessionExpiryInterval(3600));
+        let (mut client, _) = connect_with(PORT, connect.clone()).await;
+        let subscribe = Subscribe::new(
+            1,
+            vec![Subscription::new(
+                "resume/#".to_string(),
+                QoSLevel::ExactlyOnce,
+            )],
+        );
+        client.send(Packet::Subscribe(subscribe)).await.unwrap();
+        next_packet(&mut client).await;
+        let mut publisher = connect_client(PORT, "resume-pub").await;
+        for (packet_id, qos) in [(1, QoSLevel::AtLeastOnce), (2, QoSLevel::ExactlyOnce)] {
+            let mut publish = Publish::default();
+            publish.topic_name = Some("resume/temp".to_string());
+            publish.set_qos(qos);
+            publish.packet_id = Some(packet_id);
+            publisher.send(Packet::Publish(publish)).await.unwrap();
+            next_packet(&mut publisher).await;
+        }
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            match next_packet(&mut client).await {
+                Packet::Publish(publish) => sent.push((publish.packet_id, publish.qos())),
+                p => panic!("expected PUBLISH, found {:?}", p),
+            }
+        }
+        // drop the connection without acknowledging either publish
+        drop(client);
+
+        let (mut client, ack) = connect_with(PORT, connect).await;
+        assert!(ack.session_present);
+        for (packet_id, qos) in sent {
+            match next_packet(&mut client).await {
+                Packet::Publish(publish) => {
+                    assert_eq!(packet_id, publish.packet_id);
+                    assert_eq!(qos, publish.qos());
+                    assert!(publish.header.dup());
+                }
+                p => panic!("expected PUBLISH, found {:?}", p),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserved_topics() {
+        const PORT: u16 = 21895;
+        start_broker(Broker::new(local_addr(PORT)));
+        let mut client = connect_client(PORT, "reserved").await;
+        let mut publish = Publish::default();
+        publish.topic_name = Some("$vaux/cluster/takeover".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.packet_id = Some(1);
+        publish.set_payload(b"victim".to_vec());
+        client.send(Packet::Publish(publish)).await.unwrap();
+        match next_packet(&mut client).await {
+            Packet::PubAck(ack) => assert_eq!(Reason::NotAuthorized, ack.reason()),
+            p => panic!("expected PUBACK, found {:?}", p),
+        }
+        let subscribe = Subscribe::new(
+            1,
+            vec![
+                Subscription::new("$vaux/#".to_string(), QoSLevel::AtMostOnce),
+                Subscription::new("$share/g/$vaux/cluster/+".to_string(), QoSLevel::AtMostOnce),
+                Subscription::new("sensor/#".to_string(), QoSLevel::AtMostOnce),
+            ],
+        );
+        client.send(Packet::Subscribe(subscribe)).await.unwrap();
+        match next_packet(&mut client).await {
+            Packet::SubAck(ack) => assert_eq!(
+                &[
+                    Reason::NotAuthorized,
+                    Reason::NotAuthorized,
+                    Reason::GrantedQoS0
+                ],
+                ack.reasons()
+            ),
+            p => panic!("expected SUBACK, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_secret() {
+        const PORT: u16 = 21896;
+        let cluster_addr = local_addr(27896);
+        start_broker(
+            Broker::new(local_addr(PORT)).with_cluster(ClusterConfig::new(
+                cluster_addr,
+                Vec::new(),
+                TEST_SECRET,
+            )),
+        );
+        let start = std::time::Instant::now();
+        let stream = loop {
+            match TcpStream::connect(cluster_addr).await {
+                Ok(stream) => break stream,
+                Err(_) if start.elapsed() < TEST_TIMEOUT => {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Err(e) => panic!("unable to connect to cluster address: {}", e),
+            }
+        };
+        let mut link = Framed::new(stream, MqttCodec::default());
+        let mut connect = Connect::default();
+        connect.client_id = local_addr(27897).to_string();
+        connect.password = Some(b"wrong".to_vec());
+        link.send(Packet::Connect(Box::new(connect))).await.unwrap();
+        match next_packet(&mut link).await {
+            Packet::ConnAck(ack) => assert_eq!(Reason::AuthenticationErr, ack.reason()),
+            p => panic!("expected CONNACK, found {:?}", p),
+        }
+        // the link is closed without reading cluster messages
+        assert!(matches!(
+            timeout(TEST_TIMEOUT, link.next()).await,
+            Ok(None) | Ok(Some(Err(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cluster_forward() {
+        const PORT_A: u16 = 21884;
+        const PORT_B: u16 = 21885;
+        let cluster_a = local_addr(27884);
+        let cluster_b = local_addr(27885);
+        let peers = vec![cluster_a, cluster_b];
+        start_broker(
+            Broker::new(local_addr(PORT_A)).with_cluster(ClusterConfig::new(
+                cluster_a,
+                peers.clone(),
+                TEST_SECRET,
+            )),
+        );
+        start_broker(
+            Broker::new(local_addr(PORT_B)).with_cluster(ClusterConfig::new(
+                cluster_b,
+                peers,
+                TEST_SECRET,
+            )),
+        );
+        let mut subscriber = connect_client(PORT_B, "cluster-sub").await;
+        subscribe(&mut subscriber, "cluster/#").await;
+        let mut publisher = connect_client(PORT_A, "cluster-pub").await;
+        // the subscription summary reaches node A once the peer links are up
+        let received = timeout(TEST_TIMEOUT, async {
+            loop {
+                publisher
+                    .send(test_publish("cluster/forward", "hello"))
+                    .await
+                    .unwrap();
+                if let Ok(Some(Ok(packet))) =
+                    timeout(Duration::from_millis(200), subscriber.next()).await
+                {
+                    return packet;
+                }
+            }
+        })
+        .await
+        .expect("expected forwarded publish");
+        match received {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("cluster/forward"), publish.topic_name.as_deref());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    /// Subscribes to the filter on a new connection to the broker until a
+    /// retained message is received, returning the message.
+    async fn next_retained(port: u16, client_id: &str, filter: &str) -> Publish {
+        timeout(TEST_TIMEOUT, async {
+            loop {
+                let mut client = connect_client(port, client_id).await;
+                subscribe(&mut client, filter).await;
+                if let Ok(Some(Ok(Packet::Publish(publish)))) =
+                    timeout(Duration::from_millis(200), client.next()).await
+                {
+                    return publish;
+                }
+            }
+        })
+        .await
+        .expect("expected retained message")
+    }
+
+    #[tokio::test]
+    async fn test_cluster_retained() {
+        const PORT_A: u16 = 21897;
+        const PORT_B: u16 = 21898;
+        let cluster_a = local_addr(27898);
+        let cluster_b = local_addr(27899);
+        let peers = vec![cluster_a, cluster_b];
+        start_broker(
+            Broker::new(local_addr(PORT_A)).with_cluster(ClusterConfig::new(
+                cluster_a,
+                peers.clone(),
+                TEST_SECRET,
+            )),
+        );
+        // retained before node B starts, sent when the link is established
+        let mut publisher = connect_client(PORT_A, "retain-pub").await;
+        let mut publish = Publish::default();
+        publish.topic_name = Some("retain/sync".to_string());
+        publish.header.set_retain(true);
+        publish.set_payload(b"sync".to_vec());
+        publisher
+            .send(Packet::Publish(publish.clone()))
+            .await
+            .unwrap();
+        start_broker(
+            Broker::new(local_addr(PORT_B)).with_cluster(ClusterConfig::new(
+                cluster_b,
+                peers,
+                TEST_SECRET,
+            )),
+        );
+        let retained = next_retained(PORT_B, "retain-sub", "retain/sync").await;
+        assert_eq!(Some(&b"sync"[..]), retained.payload());
+        // retained while the link is up without subscribers on node B
+        publish.topic_name = Some("retain/live".to_string());
+        publish.set_payload(b"live".to_vec());
+        publisher.send(Packet::Publish(publish)).await.unwrap();
+        let retained = next_retained(PORT_B, "retain-sub", "retain/live").await;
+        assert_eq!(Some(&b"live"[..]), retained.payload());
+    }
+
+    /// Waits until publishes are forwarded in both directions between the
+    /// nodes, which shows that the peer links are established.
+    async fn await_links(port_a: u16, port_b: u16) {
+        for (from, to) in [(port_a, port_b), (port_b, port_a)] {
+            let mut subscriber = connect_client(to, &format!("probe-sub-{}", to)).await;
+            subscribe(&mut subscriber, "probe/#").await;
+            let mut publisher = connect_client(from, &format!("probe-pub-{}", from)).await;
+            timeout(TEST_TIMEOUT, async {
+                loop {
+                    publisher
+                        .send(test_publish("probe/link", "probe"))
+                        .await
+                        .unwrap();
+                    if let Ok(Some(Ok(_))) =
+                        timeout(Duration::from_millis(200), subscriber.next()).await
+                    {
+                        return;
+                    }
+                }
+            })
+            .await
+            .expect("expected peer links");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_session_transfer() {
+        const PORT_A: u16 = 21899;
+        const PORT_B: u16 = 21900;
+        let cluster_a = local_addr(27900);
+        let cluster_b = local_addr(27901);
+        let peers = vec![cluster_a, cluster_b];
+        start_broker(
+            Broker::new(local_addr(PORT_A)).with_cluster(ClusterConfig::new(
+                cluster_a,
+                peers.clone(),
+                TEST_SECRET,
+            )),
+        );
+        start_broker(
+            Broker::new(local_addr(PORT_B)).with_cluster(ClusterConfig::new(
+                cluster_b,
+                peers,
+                TEST_SECRET,
+            )),
+        );
+        let mut connect = Connect::default();
+        connect.client_id = "transfer-client".to_string();
+        connect
+            .properties_mut()
+            .set_property(Property::SessionExpiryInterval(3600));
+        let (mut client, _) = connect_with(PORT_A, connect.clone()).await;
+        let subscribe = Subscribe::new(
+            1,
+            vec![Subscription::new(
+                "transfer/#".to_string(),
+                QoSLevel::AtLeastOnce,
+            )],
+        );
+        client.send(Packet::Subscribe(subscribe)).await.unwrap();
+        next_packet(&mut client).await;
+        await_links(PORT_A, PORT_B).await;
+        client
+            .send(Packet::Disconnect(Disconnect::default()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            timeout(TEST_TIMEOUT, client.next()).await,
+            Ok(None)
+        ));
+        // queued for the disconnected session on node A
+        let mut publisher = connect_client(PORT_A, "transfer-pub").await;
+        let mut publish = Publish::default();
+        publish.topic_name = Some("transfer/queued".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.packet_id = Some(1);
+        publish.set_payload(b"queued".to_vec());
+        publisher.send(Packet::Publish(publish)).await.unwrap();
+        next_packet(&mut publisher).await;
+
+        let (mut client, ack) = connect_with(PORT_B, connect).await;
+        assert!(ack.session_present);
+        match next_packet(&mut client).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("transfer/queued"), publish.topic_name.as_deref());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the subscription is held on node B
+        let mut publisher = connect_client(PORT_B, "transfer-pub").await;
+        publisher
+            .send(test_publish("transfer/live", "live"))
+            .await
+            .unwrap();
+        match next_packet(&mut client).await {
+            Packet::Publish(publish) => {
+                assert_eq!(Some("transfer/live"), publish.topic_name.as_deref());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_takeover() {
+        const PORT_A: u16 = 21886;
+        const PORT_B: u16 = 21887;
+        let cluster_a = local_addr(27886);
+        let cluster_b = local_addr(27887);
+        let peers = vec![cluster_a, cluster_b];
+        start_broker(
+            Broker::new(local_addr(PORT_A)).with_cluster(ClusterConfig::new(
+                cluster_a,
+                peers.clone(),
+                TEST_SECRET,
+            )),
+        );
+        start_broker(
+            Broker::new(local_addr(PORT_B)).with_cluster(ClusterConfig::new(
+                cluster_b,
+                peers,
+                TEST_SECRET,
+            )),
+        );
+        let mut first = connect_client(PORT_A, "takeover-client").await;
+        // peer links are established with a retry interval, reconnect on node B
+        // until the take over reaches node A
+        let start = std::time::Instant::now();
+        loop {
+            let _second = connect_client(PORT_B, "takeover-client").await;
+            if let Ok(Some(Ok(packet))) = timeout(Duration::from_millis(500), first.next()).await {
+                match packet {
+                    Packet::Disconnect(disconnect) => {
+                        assert_eq!(Reason::SessionTakeOver, disconnect.reason);
+                        break;
+                    }
+                    p => panic!("expected DISCONNECT, found {:?}", p),
+                }
+            }
+            assert!(start.elapsed() < TEST_TIMEOUT, "expected session take over");
+        }
+    }
 }
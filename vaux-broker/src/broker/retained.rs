@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use vaux_mqtt::publish::Publish;
+use vaux_mqtt::{TopicFilter, TopicName};
+
+use crate::broker::message::StoredMessage;
+
+/// Retained messages keyed by topic name. Only the most recent retained
+/// message for a topic is held and expired messages are discarded.
+#[derive(Debug, Default)]
+pub struct RetainedStore {
+    messages: HashMap<TopicName, StoredMessage>,
+}
+
+impl RetainedStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a publish with the RETAIN flag set, replacing the retained
+    /// message for the topic. A publish with an empty payload removes the
+    /// retained message for the topic as described in MQTT v5 3.3.1.3.
+    pub fn retain(&mut self, publish: &Publish) {
+        let topic = match publish.topic_name.as_deref().map(TopicName::new) {
+            Some(Ok(topic)) => topic,
+            _ => return,
+        };
+        match publish.payload() {
+            Some(payload) if !payload.is_empty() => {
+                self.messages
+                    .insert(topic, StoredMessage::new(publish.clone()));
+            }
+            _ => {
+                self.messages.remove(&topic);
+            }
+        }
+    }
+
+    /// Gets the unexpired retained messages with a topic matching the topic
+    /// filter. Expired messages are removed from the store.
+    pub fn matches(&mut self, filter: &TopicFilter) -> Vec<Publish> {
+        self.messages.retain(|_, message| !message.expired());
+        self.messages
+            .iter()
+            .filter(|(topic, _)| filter.matches(topic))
+            .filter_map(|(_, message)| message.publish())
+            .collect()
+    }
+
+    /// Gets every unexpired retained message. Expired messages are removed
+    /// from the store.
+    pub fn messages(&mut self) -> Vec<Publish> {
+        self.messages.retain(|_, message| !message.expired());
+        self.messages
+            .values()
+            .filter_map(|message| message.publish())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use vaux_mqtt::property::Property;
+
+    use super::*;
+
+    fn filter(filter: &str) -> TopicFilter {
+        TopicFilter::new(filter).unwrap()
+    }
+
+    fn retained(topic: &str, payload: &[u8]) -> Publish {
+        let mut publish = Publish::default();
+        publish.header.set_retain(true);
+        publish.topic_name = Some(topic.to_string());
+        publish.set_payload(payload.to_vec());
+        publish
+    }
+
+    #[test]
+    fn test_retain_replace_remove() {
+        let mut store = RetainedStore::new();
+        store.retain(&retained("sensor/temp", b"20"));
+        store.retain(&retained("sensor/temp", b"21"));
+        store.retain(&retained("sensor/humidity", b"40"));
+        assert_eq!(2, store.matches(&filter("#")).len());
+        let matched = store.matches(&filter("sensor/temp"));
+        assert_eq!(1, matched.len());
+        assert_eq!(Some(&b"21"[..]), matched[0].payload());
+        store.retain(&retained("sensor/temp", b""));
+        assert_eq!(1, store.matches(&filter("sensor/#")).len());
+    }
+
+    #[test]
+    fn test_expired_removed() {
+        let mut store = RetainedStore::new();
+        let mut publish = retained("sensor/temp", b"20");
+        publish
+            .properties_mut()
+            .set_property(Property::MessageExpiry(0));
+        store.retain(&publish);
+        assert!(store.matches(&filter("sensor/+")).is_empty());
+        assert!(store.messages.is_empty());
+        store.retain(&publish);
+        assert!(store.messages().is_empty());
+    }
+}
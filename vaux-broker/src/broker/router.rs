@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use vaux_mqtt::{MqttCodecError, Subscription, TopicFilter};
+
+/// Subscription table for the broker. The router holds the subscriptions for
+/// every session, connected or not, keyed by topic filter and then by client
+/// identifier.
+#[derive(Debug, Default)]
+pub struct Router {
+    subscriptions: HashMap<TopicFilter, HashMap<String, (Subscription, Option<u32>)>>,
+}
+
+/// The subscriptions held by a client that match a topic name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Matched {
+    pub client_id: String,
+    /// matching subscription with the highest QoS
+    pub subscription: Subscription,
+    /// subscription identifiers of every matching subscription
+    pub subscription_ids: Vec<u32>,
+    /// true if any matching subscription has the retain as published option
+    pub retain_as_published: bool,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the subscription for the client. An existing subscription for the
+    /// client with an identical topic filter is replaced as required by
+    /// MQTT v5 3.8.4. Returns true if the client did not already hold a
+    /// subscription with the topic filter, or an error if the topic filter is
+    /// not valid.
+    pub fn subscribe(
+        &mut self,
+        client_id: &str,
+        subscription: Subscription,
+        subscription_id: Option<u32>,
+    ) -> Result<bool, MqttCodecError> {
+        let filter = TopicFilter::new(subscription.filter.as_str())?;
+        Ok(self
+            .subscriptions
+            .entry(filter)
+            .or_default()
+            .insert(client_id.to_string(), (subscription, subscription_id))
+            .is_none())
+    }
+
+    /// Removes the subscription held by the client with the topic filter,
+    /// MQTT v5 3.10.4. Returns true if the client held the subscription, or
+    /// an error if the topic filter is not valid.
+    pub fn unsubscribe(&mut self, client_id: &str, filter: &str) -> Result<bool, MqttCodecError> {
+        let filter = TopicFilter::new(filter)?;
+        let Some(clients) = self.subscriptions.get_mut(&filter) else {
+            return Ok(false);
+        };
+        let removed = clients.remove(client_id).is_some();
+        if clients.is_empty() {
+            self.subscriptions.remove(&filter);
+        }
+        Ok(removed)
+    }
+
+    /// Removes all subscriptions held by the client.
+    pub fn unsubscribe_all(&mut self, client_id: &str) {
+        self.subscriptions.retain(|_, clients| {
+            clients.remove(client_id);
+            !clients.is_empty()
+        });
+    }
+
+    /// Gets the subscriptions held by the client with the subscription
+    /// identifier of each.
+    pub fn subscriptions(&self, client_id: &str) -> Vec<(Subscription, Option<u32>)> {
+        self.subscriptions
+            .values()
+            .filter_map(|clients| clients.get(client_id).cloned())
+            .collect()
+    }
+
+    /// Gets the distinct topic filters with at least one subscriber.
+    pub fn filters(&self) -> Vec<String> {
+        self.subscriptions
+            .keys()
+            .map(|filter| filter.to_string())
+            .collect()
+    }
+
+    /// Gets the matching subscriptions for each client subscribed to the
+    /// topic. Where a client holds more than one matching subscription the
+    /// subscription with the highest QoS is returned along with the
+    /// subscription identifiers of all the matching subscriptions.
+    ///
+    /// Subscriptions held by the publishing client with the no local option
+    /// set are not matched, MQTT v5 3.8.3.1.
+    pub fn matches(&self, topic: &str, publisher: Option<&str>) -> Vec<Matched> {
+        let mut matched: HashMap<&String, Matched> = HashMap::new();
+        for (filter, clients) in &self.subscriptions {
+            if !filter.matches_name(topic) {
+                continue;
+            }
+            for (client_id, (subscription, subscription_id)) in clients {
+                if subscription.no_local && publisher == Some(client_id.as_str()) {
+                    continue;
+                }
+                let entry = matched.entry(client_id).or_insert_with(|| Matched {
+                    client_id: client_id.clone(),
+                    subscription: subscription.clone(),
+                    subscription_ids: Vec::new(),
+                    retain_as_published: false,
+                });
+                if (entry.subscription.qos as u8) < (subscription.qos as u8) {
+                    entry.subscription = subscription.clone();
+                }
+                entry.retain_as_published |= subscription.retain_as;
+                if let Some(id) = subscription_id {
+                    entry.subscription_ids.push(*id);
+                }
+            }
+        }
+        matched
+            .into_values()
+            .map(|mut m| {
+                m.subscription_ids.sort_unstable();
+                m
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use vaux_mqtt::QoSLevel;
+
+    use super::*;
+
+    #[test]
+    fn test_matches_highest_qos() {
+        let mut router = Router::new();
+        router
+            .subscribe(
+                "client-1",
+                Subscription::new("sensor/+".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .unwrap();
+        router
+            .subscribe(
+                "client-1",
+                Subscription::new("sensor/#".to_string(), QoSLevel::AtLeastOnce),
+                Some(2),
+            )
+            .unwrap();
+        router
+            .subscribe(
+                "client-2",
+                Subscription::new("sensor/temp".to_string(), QoSLevel::AtMostOnce),
+                Some(7),
+            )
+            .unwrap();
+        let mut matched = router.matches("sensor/temp", None);
+        matched.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        assert_eq!(2, matched.len());
+        assert_eq!("client-1", matched[0].client_id);
+        assert_eq!(QoSLevel::AtLeastOnce, matched[0].subscription.qos);
+        assert_eq!(vec![2], matched[0].subscription_ids);
+        assert_eq!("client-2", matched[1].client_id);
+        assert_eq!(vec![7], matched[1].subscription_ids);
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        let mut router = Router::new();
+        assert!(router
+            .subscribe(
+                "client-1",
+                Subscription::new("sensor/#/temp".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .is_err());
+        assert!(router.filters().is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe_all() {
+        let mut router = Router::new();
+        router
+            .subscribe(
+                "client-1",
+                Subscription::new("sensor/+".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .unwrap();
+        router
+            .subscribe(
+                "client-2",
+                Subscription::new("sensor/+".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            vec![(
+                Subscription::new("sensor/+".to_string(), QoSLevel::AtMostOnce),
+                None
+            )],
+            router.subscriptions("client-1")
+        );
+        router.unsubscribe_all("client-1");
+        assert!(router.subscriptions("client-1").is_empty());
+        assert_eq!(vec!["sensor/+".to_string()], router.filters());
+        router.unsubscribe_all("client-2");
+        assert!(router.filters().is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let mut router = Router::new();
+        for client_id in ["client-1", "client-2"] {
+            router
+                .subscribe(
+                    client_id,
+                    Subscription::new("sensor/+".to_string(), QoSLevel::AtMostOnce),
+                    None,
+                )
+                .unwrap();
+        }
+        assert!(router.unsubscribe("client-1", "sensor/+").unwrap());
+        assert!(!router.unsubscribe("client-1", "sensor/+").unwrap());
+        assert!(!router.unsubscribe("client-1", "sensor/#").unwrap());
+        assert!(router.unsubscribe("client-1", "sensor/#/temp").is_err());
+        assert_eq!(vec!["sensor/+".to_string()], router.filters());
+        assert!(router.unsubscribe("client-2", "sensor/+").unwrap());
+        assert!(router.filters().is_empty());
+    }
+
+    #[test]
+    fn test_no_local() {
+        let mut router = Router::new();
+        let mut no_local = Subscription::new("chat/#".to_string(), QoSLevel::AtMostOnce);
+        no_local.no_local = true;
+        router
+            .subscribe("client-1", no_local.clone(), None)
+            .unwrap();
+        router.subscribe("client-2", no_local, None).unwrap();
+        let matched = router.matches("chat/room", Some("client-1"));
+        assert_eq!(1, matched.len());
+        assert_eq!("client-2", matched[0].client_id);
+        // a second matching subscription without no local still delivers
+        router
+            .subscribe(
+                "client-1",
+                Subscription::new("chat/+".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .unwrap();
+        assert_eq!(2, router.matches("chat/room", Some("client-1")).len());
+    }
+
+    #[test]
+    fn test_retain_as_published() {
+        let mut router = Router::new();
+        let mut retain_as = Subscription::new("status/#".to_string(), QoSLevel::AtMostOnce);
+        retain_as.retain_as = true;
+        router.subscribe("client-1", retain_as, None).unwrap();
+        router
+            .subscribe(
+                "client-2",
+                Subscription::new("status/+".to_string(), QoSLevel::AtMostOnce),
+                None,
+            )
+            .unwrap();
+        let mut matched = router.matches("status/device", None);
+        matched.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        assert!(matched[0].retain_as_published);
+        assert!(!matched[1].retain_as_published);
+    }
+}
@@ -1,42 +1,77 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
+use tokio::sync::mpsc::UnboundedSender;
+use vaux_mqtt::publish::Publish;
+use vaux_mqtt::{Packet, PubResp, QoSLevel, Subscription};
+
+use crate::broker::message::StoredMessage;
+
+/// Maximum number of messages queued for a disconnected session. The oldest
+/// message is discarded when the queue is full.
+const MAX_QUEUED_MESSAGES: usize = 1000;
+
+/// State of a session moved between cluster nodes when a client resumes on
+/// one node a session held on another.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SessionState {
+    pub(crate) session_expiry: u32,
+    /// subscriptions with the subscription identifier of each
+    pub(crate) subscriptions: Vec<(Subscription, Option<u32>)>,
+    /// unacknowledged messages with the packet identifier they were sent
+    /// with, in packet identifier order
+    pub(crate) inflight: Vec<Publish>,
+    /// outbound QoS 2 packet identifiers awaiting PUBCOMP
+    pub(crate) released: Vec<u16>,
+    /// queued messages to deliver to the client
+    pub(crate) messages: Vec<Publish>,
+    /// inbound QoS 2 packet identifiers awaiting PUBREL
+    pub(crate) pending_release: Vec<u16>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Session {
-    _id: String,
+    id: String,
     last_active: Instant,
     connected: bool,
-    orphaned: bool,
     keep_alive: Duration,
     pub session_expiry: Duration,
+    sender: Option<UnboundedSender<Packet>>,
+    last_packet_id: u16,
+    /// outbound QoS 1 and QoS 2 publish packets awaiting acknowledgement
+    inflight: HashMap<u16, Publish>,
+    /// outbound QoS 2 packet identifiers awaiting PUBCOMP
+    released: HashSet<u16>,
+    /// inbound QoS 2 packet identifiers awaiting PUBREL
+    pending_release: HashSet<u16>,
+    /// QoS 1 and QoS 2 messages received while the session was disconnected
+    queued: VecDeque<StoredMessage>,
 }
 
 impl Session {
     /// Creates a new session with the last active time set to Instant::now()
     pub fn new(id: String, keep_alive: Duration) -> Self {
         Session {
-            _id: id,
+            id,
             last_active: Instant::now(),
             connected: true,
-            orphaned: false,
             keep_alive,
             session_expiry: Duration::new(0, 0),
+            sender: None,
+            last_packet_id: 0,
+            inflight: HashMap::new(),
+            released: HashSet::new(),
+            pending_release: HashSet::new(),
+            queued: VecDeque::new(),
         }
     }
 
-    pub fn connected(&self) -> bool {
-        self.connected
+    pub fn id(&self) -> &str {
+        &self.id
     }
 
-    pub fn set_connected(&mut self, connected: bool) {
-        self.connected = connected;
-    }
-
-    pub fn orphaned(&self) -> bool {
-        self.orphaned
-    }
-
-    pub(crate) fn set_orphaned(&mut self) {
-        self.orphaned = true;
+    pub fn connected(&self) -> bool {
+        self.connected
     }
 
     /// Sets the last session activity to the time that the method is invoked.
@@ -52,4 +87,327 @@ impl Session {
     pub fn set_keep_alive(&mut self, secs: u64) {
         self.keep_alive = Duration::from_secs(secs);
     }
+
+    /// Attaches the session to the connection that will receive packets sent
+    /// to the session. Any previously attached connection is detached.
+    ///
+    /// Unacknowledged packets are sent again in packet identifier order,
+    /// MQTT v5 4.4: PUBREL for QoS 2 packets awaiting PUBCOMP and the
+    /// publish packets with their original packet identifiers and the DUP
+    /// flag set. Messages queued while the session was disconnected are then
+    /// delivered to the connection unless they have expired.
+    pub(crate) fn attach(&mut self, sender: UnboundedSender<Packet>) {
+        self.sender = Some(sender);
+        self.connected = true;
+        let mut released: Vec<u16> = self.released.iter().copied().collect();
+        released.sort_unstable();
+        for packet_id in released {
+            let mut pubrel = PubResp::new_pubrel();
+            pubrel.packet_id = packet_id;
+            self.send(Packet::PubRel(pubrel));
+        }
+        let mut inflight: Vec<u16> = self.inflight.keys().copied().collect();
+        inflight.sort_unstable();
+        for packet_id in inflight {
+            if let Some(publish) = self.inflight.get_mut(&packet_id) {
+                publish.header.set_dup(true);
+                let publish = publish.clone();
+                self.send(Packet::Publish(publish));
+            }
+        }
+        while let Some(message) = self.queued.pop_front() {
+            if let Some(publish) = message.publish() {
+                self.deliver(publish);
+            }
+        }
+    }
+
+    /// Detaches the connection from the session if the connection is the one
+    /// currently attached. Returns true if the connection was detached.
+    pub(crate) fn detach(&mut self, sender: &UnboundedSender<Packet>) -> bool {
+        match &self.sender {
+            Some(current) if current.same_channel(sender) => {
+                self.sender = None;
+                self.connected = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends a packet to the connection attached to the session. Returns false
+    /// if no connection is attached.
+    pub(crate) fn send(&self, packet: Packet) -> bool {
+        match &self.sender {
+            Some(sender) => sender.send(packet).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Delivers a publish packet to the connection attached to the session.
+    /// A packet identifier is assigned for QoS 1 and QoS 2 delivery and the
+    /// packet is held until acknowledged by the client.
+    ///
+    /// QoS 1 and QoS 2 packets for a disconnected session with a non-zero
+    /// session expiry are queued for delivery when the client reconnects.
+    pub(crate) fn deliver(&mut self, mut publish: Publish) -> bool {
+        if self.sender.is_none() {
+            return self.enqueue(publish);
+        }
+        if publish.qos() != QoSLevel::AtMostOnce {
+            let packet_id = self.next_packet_id();
+            publish.packet_id = Some(packet_id);
+            self.inflight.insert(packet_id, publish.clone());
+        } else {
+            publish.packet_id = None;
+        }
+        self.send(Packet::Publish(publish))
+    }
+
+    /// Removes the acknowledged outbound publish packet, or the QoS 2 packet
+    /// identifier awaiting PUBCOMP.
+    pub(crate) fn acknowledge(&mut self, packet_id: u16) -> Option<Publish> {
+        self.released.remove(&packet_id);
+        self.inflight.remove(&packet_id)
+    }
+
+    /// Records the PUBREC for an outbound QoS 2 publish packet, after which
+    /// the packet identifier awaits PUBCOMP. Returns false if no QoS 2
+    /// publish was sent with the packet identifier.
+    pub(crate) fn release(&mut self, packet_id: u16) -> bool {
+        match self.inflight.get(&packet_id) {
+            Some(publish) if publish.qos() == QoSLevel::ExactlyOnce => {
+                self.inflight.remove(&packet_id);
+                self.released.insert(packet_id);
+                true
+            }
+            _ => self.released.contains(&packet_id),
+        }
+    }
+
+    /// Records an inbound QoS 2 packet identifier. Returns false if the packet
+    /// identifier is already awaiting release, indicating a duplicate delivery.
+    pub(crate) fn receive_qos2(&mut self, packet_id: u16) -> bool {
+        self.pending_release.insert(packet_id)
+    }
+
+    /// Releases an inbound QoS 2 packet identifier. Returns false if the packet
+    /// identifier was not awaiting release.
+    pub(crate) fn release_qos2(&mut self, packet_id: u16) -> bool {
+        self.pending_release.remove(&packet_id)
+    }
+
+    /// Removes the state of the session so that it can be moved to another
+    /// node. Unacknowledged messages keep their packet identifiers so that
+    /// the client can recognize them when they are sent again, queued
+    /// messages that have expired are dropped.
+    pub(crate) fn take_state(
+        &mut self,
+        subscriptions: Vec<(Subscription, Option<u32>)>,
+    ) -> SessionState {
+        let mut inflight: Vec<Publish> = self.inflight.drain().map(|(_, p)| p).collect();
+        inflight.sort_unstable_by_key(|publish| publish.packet_id);
+        let mut released: Vec<u16> = self.released.drain().collect();
+        released.sort_unstable();
+        let mut pending_release: Vec<u16> = self.pending_release.iter().copied().collect();
+        pending_release.sort_unstable();
+        SessionState {
+            session_expiry: self.session_expiry.as_secs().min(u32::MAX as u64) as u32,
+            subscriptions,
+            inflight,
+            released,
+            messages: self
+                .queued
+                .drain(..)
+                .filter_map(|message| message.publish())
+                .collect(),
+            pending_release,
+        }
+    }
+
+    /// Restores the state of a session moved from another node, returning
+    /// the subscriptions of the session. Unacknowledged messages are sent
+    /// again with their packet identifiers and queued messages are
+    /// delivered when a connection is attached to the session.
+    pub(crate) fn restore(&mut self, state: SessionState) -> Vec<(Subscription, Option<u32>)> {
+        self.session_expiry = Duration::from_secs(state.session_expiry as u64);
+        for publish in state.inflight {
+            if let Some(packet_id) = publish.packet_id {
+                self.inflight.insert(packet_id, publish);
+            }
+        }
+        self.released.extend(state.released);
+        self.queued
+            .extend(state.messages.into_iter().map(StoredMessage::new));
+        self.pending_release.extend(state.pending_release);
+        state.subscriptions
+    }
+
+    fn enqueue(&mut self, publish: Publish) -> bool {
+        if self.session_expiry.is_zero() || publish.qos() == QoSLevel::AtMostOnce {
+            return false;
+        }
+        self.queued.retain(|message| !message.expired());
+        if self.queued.len() >= MAX_QUEUED_MESSAGES {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(StoredMessage::new(publish));
+        true
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.last_packet_id = self.last_packet_id.wrapping_add(1);
+            // packet identifier 0 is not permitted, MQTT v5 2.2.1
+            if self.last_packet_id != 0
+                && !self.inflight.contains_key(&self.last_packet_id)
+                && !self.released.contains(&self.last_packet_id)
+            {
+                return self.last_packet_id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+    use vaux_mqtt::property::Property;
+    use vaux_mqtt::PropertyType;
+
+    use super::*;
+
+    fn publish(qos: QoSLevel, expiry: Option<u32>) -> Publish {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(qos);
+        if let Some(expiry) = expiry {
+            publish
+                .properties_mut()
+                .set_property(Property::MessageExpiry(expiry));
+        }
+        publish
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut session = Session::new("client-1".to_string(), Duration::from_secs(30));
+        session.session_expiry = Duration::from_secs(60);
+        assert!(!session.deliver(publish(QoSLevel::AtMostOnce, None)));
+        assert!(session.deliver(publish(QoSLevel::AtLeastOnce, Some(60))));
+        assert!(session.deliver(publish(QoSLevel::AtLeastOnce, Some(0))));
+        assert_eq!(2, session.queued.len());
+        session.attach(sender);
+        assert!(session.queued.is_empty());
+        match receiver.try_recv() {
+            Ok(Packet::Publish(publish)) => {
+                assert!(publish.packet_id.is_some());
+                assert!(publish
+                    .properties()
+                    .has_property(&PropertyType::MessageExpiry));
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the message with an expiry of 0 expired while queued
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_no_queue_without_expiry() {
+        let mut session = Session::new("client-1".to_string(), Duration::from_secs(30));
+        assert!(!session.deliver(publish(QoSLevel::AtLeastOnce, None)));
+        assert!(session.queued.is_empty());
+    }
+
+    #[test]
+    fn test_take_restore() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut session = Session::new("client-1".to_string(), Duration::from_secs(30));
+        session.session_expiry = Duration::from_secs(60);
+        session.attach(sender.clone());
+        assert!(session.deliver(publish(QoSLevel::AtLeastOnce, None)));
+        assert!(session.deliver(publish(QoSLevel::ExactlyOnce, None)));
+        assert!(session.release(2));
+        session.detach(&sender);
+        assert!(session.deliver(publish(QoSLevel::ExactlyOnce, Some(60))));
+        assert!(session.receive_qos2(4));
+        let state = session.take_state(Vec::new());
+        assert_eq!(60, state.session_expiry);
+        assert_eq!(
+            vec![Some(1)],
+            state
+                .inflight
+                .iter()
+                .map(|p| p.packet_id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec![2], state.released);
+        assert_eq!(1, state.messages.len());
+        assert_eq!(vec![4], state.pending_release);
+        assert!(session.take_state(Vec::new()).inflight.is_empty());
+
+        let mut restored = Session::new("client-1".to_string(), Duration::from_secs(30));
+        restored.restore(state);
+        assert!(!restored.receive_qos2(4));
+        let (sender, mut restored_receiver) = mpsc::unbounded_channel();
+        restored.attach(sender);
+        match restored_receiver.try_recv() {
+            Ok(Packet::PubRel(pubrel)) => assert_eq!(2, pubrel.packet_id),
+            p => panic!("expected PUBREL, found {:?}", p),
+        }
+        match restored_receiver.try_recv() {
+            Ok(Packet::Publish(publish)) => {
+                assert_eq!(Some(1), publish.packet_id);
+                assert!(publish.header.dup());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the queued message is given a packet identifier not in use
+        match restored_receiver.try_recv() {
+            Ok(Packet::Publish(publish)) => {
+                assert_eq!(Some(3), publish.packet_id);
+                assert!(!publish.header.dup());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the deliveries from the original session
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_attach_resend() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut session = Session::new("client-1".to_string(), Duration::from_secs(30));
+        session.session_expiry = Duration::from_secs(60);
+        session.attach(sender.clone());
+        for qos in [
+            QoSLevel::ExactlyOnce,
+            QoSLevel::AtLeastOnce,
+            QoSLevel::ExactlyOnce,
+        ] {
+            assert!(session.deliver(publish(qos, None)));
+        }
+        assert!(session.release(1));
+        assert!(session.acknowledge(2).is_some());
+        session.detach(&sender);
+        while receiver.try_recv().is_ok() {}
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        session.attach(sender);
+        match receiver.try_recv() {
+            Ok(Packet::PubRel(pubrel)) => assert_eq!(1, pubrel.packet_id),
+            p => panic!("expected PUBREL, found {:?}", p),
+        }
+        match receiver.try_recv() {
+            Ok(Packet::Publish(publish)) => {
+                assert_eq!(Some(3), publish.packet_id);
+                assert!(publish.header.dup());
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        assert!(receiver.try_recv().is_err());
+        assert!(session.acknowledge(1).is_none());
+        assert!(!session.release(1));
+    }
 }
@@ -1,19 +1,16 @@
 mod broker;
 
-use crate::broker::session::Session;
-use crate::broker::{DEFAULT_LISTEN_ADDR, DEFAULT_PORT};
+use crate::broker::cluster::ClusterConfig;
+use crate::broker::{SessionPool, DEFAULT_LISTEN_ADDR, DEFAULT_MAX_PACKET_SIZE, DEFAULT_PORT};
 use broker::Broker;
 use clap::Parser;
-use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about=None)]
 struct Args {
-    #[clap(short = 'h', long)]
+    #[clap(short = 'l', long)]
     /// Listen address (default is "127.0.0.1")s
     listen_addr: Option<String>,
     #[clap(short, long)]
@@ -23,6 +20,24 @@ struct Args {
     max_sessions: Option<u32>,
     #[clap(short = 'a', long)]
     max_active_sessions: Option<u32>,
+    #[clap(short = 'c', long, requires = "cluster_secret")]
+    /// Address for cluster peer links, enables cluster mode (e.g. "10.0.0.1:7883")
+    cluster_addr: Option<SocketAddr>,
+    #[clap(short = 'k', long)]
+    /// Secret shared by the cluster nodes to authenticate peer links
+    cluster_secret: Option<String>,
+    #[clap(short = 'n', long = "peer", requires = "cluster_addr")]
+    /// Cluster address of a peer node, may be repeated
+    peers: Vec<SocketAddr>,
+    #[clap(long)]
+    /// Disables subscription identifiers
+    disable_subscription_ids: bool,
+    #[clap(short = 'm', long)]
+    /// Maximum size in bytes of a packet accepted from a client (default is 1048576)
+    max_packet_size: Option<u32>,
+    #[clap(short = 'r', long)]
+    /// Response topic template for clients requesting response information (e.g. "$response/{client_id}/")
+    response_template: Option<String>,
 }
 
 #[tokio::main]
@@ -47,11 +62,21 @@ async fn main() {
         Ipv4Addr::from_str(DEFAULT_LISTEN_ADDR).unwrap()
     };
     let listen_port = args.port.unwrap_or(DEFAULT_PORT);
-    let listen_addr = SocketAddr::try_from((listen_addr, listen_port)).unwrap();
+    let listen_addr = SocketAddr::from((listen_addr, listen_port));
 
-    let mut broker = Broker::new(listen_addr);
+    let mut broker = Broker::new(listen_addr)
+        .with_subscription_ids(!args.disable_subscription_ids)
+        .with_max_packet_size(args.max_packet_size.unwrap_or(DEFAULT_MAX_PACKET_SIZE));
+    if let Some(template) = args.response_template {
+        broker = match broker.with_response_template(&template) {
+            Ok(broker) => broker,
+            Err(e) => panic!("Response template, \"{}\" {}", template, e),
+        };
+    }
+    if let (Some(cluster_addr), Some(secret)) = (args.cluster_addr, args.cluster_secret) {
+        broker = broker.with_cluster(ClusterConfig::new(cluster_addr, args.peers, &secret));
+    }
     // TODO initialize from storage for long lived sessions
-    let session_pool: Arc<RwLock<HashMap<String, Arc<RwLock<Session>>>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+    let session_pool = SessionPool::default();
     let _ = broker.run(session_pool).await;
 }
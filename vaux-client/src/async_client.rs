@@ -0,0 +1,1180 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_util::codec::Framed;
+use vaux_mqtt::{
+    encoded_len, property::Property, publish::Publish, ConnAck, Connect, Disconnect, FixedHeader,
+    MqttCodec, MqttCodecError, Packet, PacketType, ProtocolVersion, PubResp, QoSLevel, Reason,
+    SubAck, Subscribe, Subscription, TopicAliases, TopicFilter,
+};
+
+use crate::{
+    client::{
+        DEFAULT_MAX_PACKET_SIZE, DEFAULT_RECV_MAX, DEFAULT_SESSION_EXPIRY, DEFAULT_TOPIC_ALIAS_MAX,
+    },
+    connection::DEFAULT_CONNECTION_TIMEOUT,
+    ErrorKind, MqttConnection, MqttError,
+};
+
+const DEFAULT_KEEP_ALIVE: u16 = 60;
+
+/// Transport for a session, a TCP or TLS stream to the broker.
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+type Transport = Framed<Box<dyn AsyncStream>, MqttCodec>;
+
+/// Requests from the client to the session task. Each request carries the
+/// channel used to complete the request once the broker has responded.
+#[derive(Debug)]
+enum Command {
+    Publish(Publish, oneshot::Sender<crate::Result<()>>),
+    Subscribe(Subscribe, oneshot::Sender<crate::Result<SubAck>>),
+    Disconnect,
+}
+
+/// Asynchronous MQTT client for use with the tokio runtime. The session with
+/// the broker runs as a tokio task that waits on the connection and on
+/// requests from the client, so there is no polling. Requests complete when
+/// the broker responds: `publish` when the QoS 1 or QoS 2 handshake completes
+/// and `subscribe` when the SUBACK is received. Application messages from the
+/// broker are received on the [`Messages`] stream.
+///
+/// Example:
+/// ```no_run
+/// use vaux_client::{AsyncMqttClient, MqttConnection};
+/// use vaux_mqtt::{publish::Publish, QoSLevel};
+///
+/// # async fn run() -> vaux_client::Result<()> {
+/// let mut client = AsyncMqttClient::new("sensor-1");
+/// let mut messages = client.messages().unwrap();
+/// client
+///     .connect(MqttConnection::new().with_host("localhost"), true)
+///     .await?;
+/// client.subscribe(&["sensor/+/cmd"], QoSLevel::AtLeastOnce).await?;
+///
+/// let mut publish = Publish::default();
+/// publish.topic_name = Some("sensor/1/temp".to_string());
+/// publish.set_qos(QoSLevel::AtLeastOnce);
+/// publish.set_payload("21.5");
+/// client.publish(publish).await?;
+///
+/// while let Some(message) = messages.recv().await {
+///     println!("{:?}: {:?}", message.topic_name, message.payload());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AsyncMqttClient {
+    client_id: String,
+    keep_alive: u16,
+    receive_max: u16,
+    session_expiry: u32,
+    max_packet_size: usize,
+    topic_alias_max: u16,
+    protocol_version: ProtocolVersion,
+    commands: Option<mpsc::UnboundedSender<Command>>,
+    message_send: mpsc::UnboundedSender<Publish>,
+    messages: Option<Messages>,
+    task: Option<JoinHandle<crate::Result<()>>>,
+}
+
+impl Default for AsyncMqttClient {
+    fn default() -> Self {
+        Self::new(&uuid::Uuid::new_v4().to_string())
+    }
+}
+
+impl AsyncMqttClient {
+    /// Creates a new client with the client ID. An empty client ID asks the
+    /// broker to assign one, which is available from `client_id` once
+    /// connected.
+    pub fn new(client_id: &str) -> Self {
+        let (message_send, message_recv) = mpsc::unbounded_channel();
+        Self {
+            client_id: client_id.to_string(),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            receive_max: DEFAULT_RECV_MAX,
+            session_expiry: DEFAULT_SESSION_EXPIRY,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            topic_alias_max: DEFAULT_TOPIC_ALIAS_MAX,
+            protocol_version: ProtocolVersion::default(),
+            commands: None,
+            message_send,
+            messages: Some(Messages { recv: message_recv }),
+            task: None,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    /// Sets the keep alive in seconds. The client sends a PINGREQ when no
+    /// other packet has been sent for the keep alive interval and closes the
+    /// connection if the broker does not respond within the next interval. A
+    /// keep alive of 0 disables the mechanism. A server keep alive in CONNACK
+    /// overrides this value, MQTT v5 3.1.2.10.
+    pub fn set_keep_alive(&mut self, keep_alive: u16) {
+        self.keep_alive = keep_alive;
+    }
+
+    pub fn receive_max(&self) -> u16 {
+        self.receive_max
+    }
+
+    /// Sets the number of QoS 1 and QoS 2 publish packets the client is
+    /// willing to process concurrently, sent to the broker in CONNECT.
+    pub fn set_receive_max(&mut self, receive_max: u16) {
+        self.receive_max = receive_max;
+    }
+
+    pub fn session_expiry(&self) -> u32 {
+        self.session_expiry
+    }
+
+    /// Sets the number of seconds the broker keeps the session after the
+    /// client disconnects. See [`crate::MqttClient::set_session_expiry`].
+    pub fn set_session_expiry(&mut self, session_expiry: u32) {
+        self.session_expiry = session_expiry;
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    pub fn topic_alias_max(&self) -> u16 {
+        self.topic_alias_max
+    }
+
+    /// Sets the maximum topic alias the client accepts from the broker. See
+    /// [`crate::MqttClient::set_topic_alias_max`].
+    pub fn set_topic_alias_max(&mut self, topic_alias_max: u16) {
+        self.topic_alias_max = topic_alias_max;
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    pub fn set_protocol_version(&mut self, protocol_version: ProtocolVersion) {
+        self.protocol_version = protocol_version;
+    }
+
+    /// Takes the stream of application messages received from the broker.
+    /// Messages are delivered once, QoS 1 and QoS 2 messages are acknowledged
+    /// by the client. The stream remains available across connections and
+    /// ends when the client is dropped. Returns None if the stream has
+    /// already been taken.
+    pub fn messages(&mut self) -> Option<Messages> {
+        self.messages.take()
+    }
+
+    /// Returns true while the session with the broker is active.
+    pub fn connected(&self) -> bool {
+        self.commands
+            .as_ref()
+            .is_some_and(|commands| !commands.is_closed())
+    }
+
+    /// Connects to the broker and starts a session, returning the CONNACK
+    /// once the broker has accepted the connection. An error is returned if
+    /// the broker cannot be reached or refuses the connection.
+    pub async fn connect(
+        &mut self,
+        mut connection: MqttConnection,
+        clean_start: bool,
+    ) -> crate::Result<ConnAck> {
+        let addr = connection.address();
+        let tls_config = connection.tls_config()?;
+        let timeout = Duration::from_millis(DEFAULT_CONNECTION_TIMEOUT);
+        let tcp = match time::timeout(timeout, TcpStream::connect(&addr)).await {
+            Ok(Ok(tcp)) => tcp,
+            Ok(Err(e)) => {
+                return Err(MqttError::new(
+                    &format!("unable to connect: {}", e),
+                    ErrorKind::Connection,
+                ))
+            }
+            Err(_) => return Err(MqttError::new("timeout", ErrorKind::Timeout)),
+        };
+        let stream: Box<dyn AsyncStream> = match tls_config {
+            Some((config, server_name)) => {
+                let connector = tokio_rustls::TlsConnector::from(config);
+                match connector.connect(server_name, tcp).await {
+                    Ok(tls) => Box::new(tls),
+                    Err(e) => {
+                        return Err(MqttError::new(
+                            &format!("unable to create TLS connection: {}", e),
+                            ErrorKind::Connection,
+                        ))
+                    }
+                }
+            }
+            None => Box::new(tcp),
+        };
+        self.start(stream, connection.credentials(), clean_start)
+            .await
+    }
+
+    /// Sends CONNECT on the stream and starts the session task once the
+    /// broker accepts the connection.
+    async fn start(
+        &mut self,
+        stream: Box<dyn AsyncStream>,
+        credentials: Option<(String, String)>,
+        clean_start: bool,
+    ) -> crate::Result<ConnAck> {
+        if self.connected() {
+            return Err(MqttError::new(
+                "client is already connected",
+                ErrorKind::Connection,
+            ));
+        }
+        let codec = MqttCodec::new()
+            .with_max_packet_size(self.max_packet_size)
+            .with_version(self.protocol_version);
+        let mut transport = Framed::new(stream, codec);
+        let connect = self.connect_packet(credentials, clean_start);
+        transport
+            .send(Packet::Connect(Box::new(connect)))
+            .await
+            .map_err(codec_error)?;
+
+        let timeout = Duration::from_millis(DEFAULT_CONNECTION_TIMEOUT);
+        let connack = match time::timeout(timeout, transport.next()).await {
+            Ok(Some(Ok(Packet::ConnAck(connack)))) => connack,
+            Ok(Some(Ok(Packet::Disconnect(disconnect)))) => {
+                return Err(MqttError::new(
+                    &format!("disconnect received: {:?}", disconnect),
+                    ErrorKind::Protocol(disconnect.reason),
+                ))
+            }
+            Ok(Some(Ok(_))) => {
+                return Err(MqttError::new(
+                    "unexpected packet type",
+                    ErrorKind::Protocol(Reason::ProtocolErr),
+                ))
+            }
+            Ok(Some(Err(e))) => return Err(codec_error(e)),
+            Ok(None) => {
+                return Err(MqttError::new(
+                    "connection closed by broker",
+                    ErrorKind::Connection,
+                ))
+            }
+            Err(_) => {
+                return Err(MqttError::new(
+                    "timeout waiting for CONNACK",
+                    ErrorKind::Timeout,
+                ))
+            }
+        };
+        if connack.reason() != Reason::Success {
+            return Err(MqttError::new(
+                "connection refused",
+                ErrorKind::Protocol(connack.reason()),
+            ));
+        }
+        if self.client_id.is_empty() {
+            match connack.properties().assigned_client_id() {
+                Some(id) => self.client_id = id.to_string(),
+                None => {
+                    return Err(MqttError::new(
+                        "no assigned client id",
+                        ErrorKind::Protocol(Reason::InvalidClientId),
+                    ))
+                }
+            }
+        }
+
+        let props = connack.properties();
+        let keep_alive = props.keep_alive().unwrap_or(self.keep_alive);
+        let keep_alive =
+            (keep_alive > 0).then(|| KeepAlive::new(Duration::from_secs(keep_alive as u64)));
+        let max_send_size = props.max_packet_size().map_or(self.max_packet_size, |max| {
+            self.max_packet_size.min(max as usize)
+        });
+        let session = Session {
+            transport,
+            protocol_version: self.protocol_version,
+            aliases: TopicAliases::new(self.topic_alias_max, props.topic_alias_max().unwrap_or(0)),
+            messages: self.message_send.clone(),
+            max_send_size,
+            send_quota: props.recv_max().unwrap_or(u16::MAX),
+            last_packet_id: 0,
+            pending_publish: HashMap::new(),
+            pending_subscribe: HashMap::new(),
+            queued_publish: VecDeque::new(),
+            inbound_qos2: HashSet::new(),
+            keep_alive,
+        };
+        let (commands, command_recv) = mpsc::unbounded_channel();
+        self.commands = Some(commands);
+        self.task = Some(tokio::spawn(session.run(command_recv)));
+        Ok(connack)
+    }
+
+    fn connect_packet(&self, credentials: Option<(String, String)>, clean_start: bool) -> Connect {
+        let mut connect = Connect::default();
+        connect.protocol_version = self.protocol_version;
+        connect.clean_start = clean_start;
+        connect.keep_alive = self.keep_alive;
+        connect.client_id = self.client_id.clone();
+        let props = connect.properties_mut();
+        props.set_property(Property::SessionExpiryInterval(self.session_expiry));
+        props.set_property(Property::RecvMax(self.receive_max));
+        props.set_property(Property::MaxPacketSize(self.max_packet_size as u32));
+        if self.topic_alias_max > 0 {
+            props.set_property(Property::TopicAliasMax(self.topic_alias_max));
+        }
+        if let Some((username, password)) = credentials {
+            connect.username = Some(username);
+            connect.password = Some(password.into_bytes());
+        }
+        connect
+    }
+
+    /// Publishes the message, completing when the broker has acknowledged
+    /// it: immediately once sent for QoS 0, on PUBACK for QoS 1 and on
+    /// PUBCOMP for QoS 2. The packet identifier is assigned by the client,
+    /// any identifier set on the publish is replaced. When the broker's
+    /// receive maximum is reached the publish waits until an earlier publish
+    /// is acknowledged. A reason code indicating failure from the broker is
+    /// returned as a protocol error.
+    pub async fn publish(&self, publish: Publish) -> crate::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.command(Command::Publish(publish, reply))?;
+        response.await.unwrap_or_else(|_| Err(not_connected()))
+    }
+
+    /// Subscribes to the topics in the topic filter with the given QoS level
+    /// and returns the SUBACK holding the reason code for each filter. An
+    /// error is returned, without subscribing to any of the topics, if a
+    /// topic filter is not valid.
+    pub async fn subscribe(&self, topic_filter: &[&str], qos: QoSLevel) -> crate::Result<SubAck> {
+        let mut subscribe = Subscribe::default();
+        for topic in topic_filter {
+            if let Err(e) = TopicFilter::new(*topic) {
+                return Err(MqttError::new(e.message(), ErrorKind::Protocol(e.reason())));
+            }
+            subscribe.add_subscription(Subscription {
+                filter: (*topic).to_string(),
+                qos,
+                ..Default::default()
+            });
+        }
+        self.send_subscribe(subscribe).await
+    }
+
+    /// Sends the SUBSCRIBE and returns the SUBACK. The packet identifier is
+    /// assigned by the client.
+    pub async fn send_subscribe(&self, subscribe: Subscribe) -> crate::Result<SubAck> {
+        let (reply, response) = oneshot::channel();
+        self.command(Command::Subscribe(subscribe, reply))?;
+        response.await.unwrap_or_else(|_| Err(not_connected()))
+    }
+
+    /// Sends DISCONNECT to the broker and waits for the session to end.
+    /// Returns the error that ended the session if it had already ended
+    /// because of an error.
+    pub async fn disconnect(&mut self) -> crate::Result<()> {
+        if let Some(commands) = self.commands.take() {
+            // the session may have ended, the result is taken from the task
+            let _ = commands.send(Command::Disconnect);
+        }
+        match self.task.take() {
+            Some(task) => task.await.map_err(|e| {
+                MqttError::new(
+                    &format!("unable to join session task: {}", e),
+                    ErrorKind::Transport,
+                )
+            })?,
+            None => Err(not_connected()),
+        }
+    }
+
+    fn command(&self, command: Command) -> crate::Result<()> {
+        match &self.commands {
+            Some(commands) => commands.send(command).map_err(|_| not_connected()),
+            None => Err(not_connected()),
+        }
+    }
+}
+
+/// Stream of the application messages received from the broker, taken from
+/// [`AsyncMqttClient::messages`]. Topic aliases are resolved so each message
+/// holds the full topic name.
+#[derive(Debug)]
+pub struct Messages {
+    recv: mpsc::UnboundedReceiver<Publish>,
+}
+
+impl Messages {
+    /// Receives the next message, or None once the client has been dropped.
+    pub async fn recv(&mut self) -> Option<Publish> {
+        self.recv.recv().await
+    }
+}
+
+impl Stream for Messages {
+    type Item = Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+/// State of a session with the broker, owned by the session task.
+struct Session {
+    transport: Transport,
+    protocol_version: ProtocolVersion,
+    aliases: TopicAliases,
+    messages: mpsc::UnboundedSender<Publish>,
+    /// largest packet the broker accepts, MQTT v5 3.2.2.3.6
+    max_send_size: usize,
+    /// QoS 1 and QoS 2 publish packets that may be sent before one is
+    /// acknowledged, from the broker's receive maximum, MQTT v5 4.9
+    send_quota: u16,
+    last_packet_id: u16,
+    pending_publish: HashMap<u16, (QoSLevel, oneshot::Sender<crate::Result<()>>)>,
+    pending_subscribe: HashMap<u16, oneshot::Sender<crate::Result<SubAck>>>,
+    queued_publish: VecDeque<(Publish, oneshot::Sender<crate::Result<()>>)>,
+    /// QoS 2 packets received from the broker awaiting PUBREL
+    inbound_qos2: HashSet<u16>,
+    keep_alive: Option<KeepAlive>,
+}
+
+/// Keep alive timer of a session, MQTT v5 3.1.2.10. A PINGREQ is due when no
+/// packet has been sent for the keep alive interval, and the PINGRESP is due
+/// within the interval after the PINGREQ whatever is sent in the meantime.
+struct KeepAlive {
+    period: Duration,
+    last_sent: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl KeepAlive {
+    fn new(period: Duration) -> Self {
+        Self {
+            period,
+            last_sent: Instant::now(),
+            ping_sent: None,
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        self.ping_sent.unwrap_or(self.last_sent) + self.period
+    }
+}
+
+impl Session {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) -> crate::Result<()> {
+        let result = self.process(&mut commands).await;
+        if let Err(e) = &result {
+            commands.close();
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Publish(_, reply) => {
+                        let _ = reply.send(Err(e.clone()));
+                    }
+                    Command::Subscribe(_, reply) => {
+                        let _ = reply.send(Err(e.clone()));
+                    }
+                    Command::Disconnect => {}
+                }
+            }
+            for (_, (_, reply)) in self.pending_publish.drain() {
+                let _ = reply.send(Err(e.clone()));
+            }
+            for (_, reply) in self.queued_publish.drain(..) {
+                let _ = reply.send(Err(e.clone()));
+            }
+            for (_, reply) in self.pending_subscribe.drain() {
+                let _ = reply.send(Err(e.clone()));
+            }
+        }
+        result
+    }
+
+    async fn process(
+        &mut self,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> crate::Result<()> {
+        loop {
+            let keep_alive = self.keep_alive.as_ref().map(KeepAlive::deadline);
+            tokio::select! {
+                packet = self.transport.next() => match packet {
+                    Some(Ok(packet)) => self.handle_packet(packet).await?,
+                    Some(Err(e)) => {
+                        // nothing after a packet that cannot be decoded can be read, so
+                        // disconnect with the reason for the decode error
+                        let error = codec_error(e);
+                        if let ErrorKind::Protocol(reason) = error.kind() {
+                            self.disconnect(reason).await;
+                        }
+                        return Err(error);
+                    }
+                    None => {
+                        return Err(MqttError::new(
+                            "connection closed by broker",
+                            ErrorKind::Connection,
+                        ))
+                    }
+                },
+                command = commands.recv() => match command {
+                    Some(Command::Publish(publish, reply)) => self.publish(publish, reply).await?,
+                    Some(Command::Subscribe(subscribe, reply)) => {
+                        self.subscribe(subscribe, reply).await?
+                    }
+                    // the client disconnected or was dropped
+                    Some(Command::Disconnect) | None => {
+                        self.disconnect(Reason::NormalDisconnect).await;
+                        return Ok(());
+                    }
+                },
+                _ = tick(keep_alive) => {
+                    if self.keep_alive.as_ref().is_some_and(|k| k.ping_sent.is_some()) {
+                        return Err(MqttError::new(
+                            "no PINGRESP received within keep alive",
+                            ErrorKind::Timeout,
+                        ));
+                    }
+                    self.send(Packet::PingRequest(FixedHeader::new(PacketType::PingReq)))
+                        .await?;
+                    if let Some(keep_alive) = self.keep_alive.as_mut() {
+                        keep_alive.ping_sent = Some(Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) -> crate::Result<()> {
+        match packet {
+            Packet::Publish(mut publish) => {
+                if let Err(reason) = self.aliases.resolve_inbound(&mut publish) {
+                    self.disconnect(reason).await;
+                    return Err(MqttError::new(
+                        "invalid topic alias received",
+                        ErrorKind::Protocol(reason),
+                    ));
+                }
+                self.receive(publish).await?;
+            }
+            Packet::PubAck(puback) => {
+                if let Some((QoSLevel::AtLeastOnce, _)) =
+                    self.pending_publish.get(&puback.packet_id)
+                {
+                    let (_, reply) = self.pending_publish.remove(&puback.packet_id).unwrap();
+                    let _ = reply.send(check_reason(puback.reason()));
+                    self.release_quota().await?;
+                }
+            }
+            Packet::PubRec(pubrec) => {
+                if let Some((QoSLevel::ExactlyOnce, _)) =
+                    self.pending_publish.get(&pubrec.packet_id)
+                {
+                    if let Err(e) = check_reason(pubrec.reason()) {
+                        // the publish ends with a PUBREC reporting failure, MQTT v5 4.3.3
+                        let (_, reply) = self.pending_publish.remove(&pubrec.packet_id).unwrap();
+                        let _ = reply.send(Err(e));
+                        self.release_quota().await?;
+                    } else {
+                        self.send_pub_resp(PacketType::PubRel, pubrec.packet_id, Reason::Success)
+                            .await?;
+                    }
+                } else {
+                    self.send_pub_resp(
+                        PacketType::PubRel,
+                        pubrec.packet_id,
+                        Reason::PacketIdNotFound,
+                    )
+                    .await?;
+                }
+            }
+            Packet::PubComp(pubcomp) => {
+                if let Some((QoSLevel::ExactlyOnce, _)) =
+                    self.pending_publish.get(&pubcomp.packet_id)
+                {
+                    let (_, reply) = self.pending_publish.remove(&pubcomp.packet_id).unwrap();
+                    let _ = reply.send(check_reason(pubcomp.reason()));
+                    self.release_quota().await?;
+                }
+            }
+            Packet::PubRel(pubrel) => {
+                let reason = if self.inbound_qos2.remove(&pubrel.packet_id) {
+                    Reason::Success
+                } else {
+                    Reason::PacketIdNotFound
+                };
+                self.send_pub_resp(PacketType::PubComp, pubrel.packet_id, reason)
+                    .await?;
+            }
+            Packet::SubAck(suback) => {
+                if let Some(reply) = self.pending_subscribe.remove(&suback.packet_id()) {
+                    let _ = reply.send(Ok(suback));
+                }
+            }
+            Packet::PingResponse(_) => {
+                if let Some(keep_alive) = self.keep_alive.as_mut() {
+                    keep_alive.ping_sent = None;
+                }
+            }
+            Packet::Disconnect(disconnect) => {
+                return Err(MqttError::new(
+                    &format!("disconnect received: {:?}", disconnect),
+                    ErrorKind::Protocol(disconnect.reason),
+                ));
+            }
+            _ => {
+                self.disconnect(Reason::ProtocolErr).await;
+                return Err(MqttError::new(
+                    "unexpected packet type",
+                    ErrorKind::Protocol(Reason::ProtocolErr),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a publish from the broker and delivers it to the
+    /// messages stream. A QoS 2 publish is delivered once, a retransmission
+    /// received before PUBREL is acknowledged again without delivery.
+    async fn receive(&mut self, publish: Publish) -> crate::Result<()> {
+        let qos = publish.qos();
+        let packet_id = match (qos, publish.packet_id) {
+            (QoSLevel::AtMostOnce, _) => 0,
+            (_, Some(packet_id)) => packet_id,
+            (_, None) => {
+                self.disconnect(Reason::MalformedPacket).await;
+                return Err(MqttError::new(
+                    "protocol error, no packet ID with QoS > 0",
+                    ErrorKind::Protocol(Reason::MalformedPacket),
+                ));
+            }
+        };
+        let deliver = qos != QoSLevel::ExactlyOnce || self.inbound_qos2.insert(packet_id);
+        if deliver {
+            // the consumer may have dropped the messages stream
+            let _ = self.messages.send(publish);
+        }
+        match qos {
+            QoSLevel::AtMostOnce => Ok(()),
+            QoSLevel::AtLeastOnce => {
+                self.send_pub_resp(PacketType::PubAck, packet_id, Reason::Success)
+                    .await
+            }
+            QoSLevel::ExactlyOnce => {
+                self.send_pub_resp(PacketType::PubRec, packet_id, Reason::Success)
+                    .await
+            }
+        }
+    }
+
+    async fn publish(
+        &mut self,
+        mut publish: Publish,
+        reply: oneshot::Sender<crate::Result<()>>,
+    ) -> crate::Result<()> {
+        let qos = publish.qos();
+        if qos == QoSLevel::AtMostOnce {
+            publish.packet_id = None;
+        } else {
+            // a placeholder identifier so the encoded size is known
+            publish.packet_id = Some(u16::MAX);
+        }
+        let packet = Packet::Publish(publish);
+        let len = encoded_len(&packet, self.protocol_version);
+        if len > self.max_send_size {
+            let _ = reply.send(Err(MqttError::new(
+                &format!(
+                    "packet size {} exceeds maximum packet size {}",
+                    len, self.max_send_size
+                ),
+                ErrorKind::Protocol(Reason::PacketTooLarge),
+            )));
+            return Ok(());
+        }
+        let Packet::Publish(publish) = packet else {
+            unreachable!()
+        };
+        if qos == QoSLevel::AtMostOnce {
+            self.send_publish(publish).await?;
+            let _ = reply.send(Ok(()));
+        } else if self.send_quota == 0 {
+            self.queued_publish.push_back((publish, reply));
+        } else {
+            self.send_acknowledged(publish, reply).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a QoS 1 or QoS 2 publish that completes when acknowledged.
+    async fn send_acknowledged(
+        &mut self,
+        mut publish: Publish,
+        reply: oneshot::Sender<crate::Result<()>>,
+    ) -> crate::Result<()> {
+        let packet_id = self.next_packet_id();
+        publish.packet_id = Some(packet_id);
+        self.pending_publish
+            .insert(packet_id, (publish.qos(), reply));
+        self.send_quota -= 1;
+        self.send_publish(publish).await
+    }
+
+    async fn send_publish(&mut self, mut publish: Publish) -> crate::Result<()> {
+        self.aliases.apply_outbound(&mut publish);
+        self.send(Packet::Publish(publish)).await
+    }
+
+    /// Returns the send quota of an acknowledged publish and sends publish
+    /// packets waiting on the quota.
+    async fn release_quota(&mut self) -> crate::Result<()> {
+        self.send_quota += 1;
+        while self.send_quota > 0 {
+            match self.queued_publish.pop_front() {
+                Some((publish, reply)) => self.send_acknowledged(publish, reply).await?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(
+        &mut self,
+        mut subscribe: Subscribe,
+        reply: oneshot::Sender<crate::Result<SubAck>>,
+    ) -> crate::Result<()> {
+        let packet_id = self.next_packet_id();
+        subscribe.set_packet_id(packet_id);
+        self.pending_subscribe.insert(packet_id, reply);
+        self.send(Packet::Subscribe(subscribe)).await
+    }
+
+    /// Gets the next packet identifier that is not in use, MQTT v5 2.2.1.
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.last_packet_id = self.last_packet_id.wrapping_add(1);
+            let packet_id = self.last_packet_id;
+            if packet_id != 0
+                && !self.pending_publish.contains_key(&packet_id)
+                && !self.pending_subscribe.contains_key(&packet_id)
+            {
+                return packet_id;
+            }
+        }
+    }
+
+    async fn send_pub_resp(
+        &mut self,
+        packet_type: PacketType,
+        packet_id: u16,
+        reason: Reason,
+    ) -> crate::Result<()> {
+        let (mut resp, packet): (PubResp, fn(PubResp) -> Packet) = match packet_type {
+            PacketType::PubAck => (PubResp::new_puback(), Packet::PubAck),
+            PacketType::PubRec => (PubResp::new_pubrec(), Packet::PubRec),
+            PacketType::PubRel => (PubResp::new_pubrel(), Packet::PubRel),
+            _ => (PubResp::new_pubcomp(), Packet::PubComp),
+        };
+        resp.packet_id = packet_id;
+        resp.set_reason(reason)
+            .map_err(|e| MqttError::new(e.message(), ErrorKind::Codec))?;
+        self.send(packet(resp)).await
+    }
+
+    async fn send(&mut self, packet: Packet) -> crate::Result<()> {
+        self.transport.send(packet).await.map_err(codec_error)?;
+        // any control packet sent restarts the keep alive, MQTT v5 3.1.2.10
+        if let Some(keep_alive) = self.keep_alive.as_mut() {
+            keep_alive.last_sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Sends DISCONNECT with the reason, ignoring errors as the connection is
+    /// closing.
+    async fn disconnect(&mut self, reason: Reason) {
+        let _ = self
+            .transport
+            .send(Packet::Disconnect(Disconnect::new(reason)))
+            .await;
+        let _ = self.transport.close().await;
+    }
+}
+
+/// Completes at the keep alive deadline, or never if keep alive is disabled.
+async fn tick(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Converts a reason code of 0x80 or greater, indicating failure, to an
+/// error, MQTT v5 2.4.
+fn check_reason(reason: Reason) -> crate::Result<()> {
+    if reason as u8 >= 0x80 {
+        return Err(MqttError::new(
+            &format!("publish failed: {:?}", reason),
+            ErrorKind::Protocol(reason),
+        ));
+    }
+    Ok(())
+}
+
+fn codec_error(e: MqttCodecError) -> MqttError {
+    match e.kind() {
+        vaux_mqtt::codec::ErrorKind::Io(_) => MqttError::new(e.message(), ErrorKind::IO),
+        _ => MqttError::new(e.message(), ErrorKind::Protocol(e.reason())),
+    }
+}
+
+fn not_connected() -> MqttError {
+    MqttError::new("client is not connected", ErrorKind::Connection)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    type Broker = Framed<DuplexStream, MqttCodec>;
+
+    fn connack(props: &[Property]) -> ConnAck {
+        let mut connack = ConnAck::default();
+        for prop in props {
+            connack.properties_mut().set_property(prop.clone());
+        }
+        connack
+    }
+
+    /// Starts a session with a broker on the other end of an in-memory
+    /// stream, the broker answering CONNECT with the CONNACK.
+    async fn start(
+        client: &mut AsyncMqttClient,
+        connack: ConnAck,
+    ) -> (crate::Result<ConnAck>, Box<Connect>, Broker) {
+        let (client_io, broker_io) = tokio::io::duplex(4096);
+        let mut broker = Framed::new(broker_io, MqttCodec::new());
+        let (result, connect) =
+            tokio::join!(client.start(Box::new(client_io), None, true), async {
+                let connect = match broker.next().await {
+                    Some(Ok(Packet::Connect(connect))) => connect,
+                    p => panic!("expected CONNECT, found {:?}", p),
+                };
+                broker.send(Packet::ConnAck(connack)).await.unwrap();
+                connect
+            });
+        (result, connect, broker)
+    }
+
+    async fn next(broker: &mut Broker) -> Packet {
+        broker.next().await.unwrap().unwrap()
+    }
+
+    fn publish(qos: QoSLevel) -> Publish {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(qos);
+        publish.set_payload("21.5");
+        publish
+    }
+
+    fn pub_resp(mut resp: PubResp, packet_id: u16, reason: Reason) -> PubResp {
+        resp.packet_id = packet_id;
+        resp.set_reason(reason).unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let mut client = AsyncMqttClient::new("");
+        client.set_keep_alive(0);
+        let connack = connack(&[Property::AssignedClientId("assigned".to_string())]);
+        let (result, connect, _broker) = start(&mut client, connack).await;
+        assert!(result.is_ok());
+        assert!(connect.clean_start);
+        assert_eq!(Some(DEFAULT_RECV_MAX), connect.properties().recv_max());
+        assert_eq!("assigned", client.client_id());
+        assert!(client.connected());
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let mut client = AsyncMqttClient::new("client");
+        let mut refused = ConnAck::default();
+        refused.set_reason(Reason::NotAuthorized);
+        let (result, _, _broker) = start(&mut client, refused).await;
+        match result {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::NotAuthorized), e.kind()),
+            Ok(_) => panic!("expected connection to be refused"),
+        }
+        assert!(!client.connected());
+    }
+
+    #[tokio::test]
+    async fn test_publish_handshake() {
+        let mut client = AsyncMqttClient::new("client");
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+
+        let (result, _) = tokio::join!(client.publish(publish(QoSLevel::AtLeastOnce)), async {
+            let packet_id = match next(&mut broker).await {
+                Packet::Publish(publish) => publish.packet_id.unwrap(),
+                p => panic!("expected PUBLISH, found {:?}", p),
+            };
+            let puback = pub_resp(PubResp::new_puback(), packet_id, Reason::Success);
+            broker.send(Packet::PubAck(puback)).await.unwrap();
+        });
+        assert!(result.is_ok());
+
+        let (result, _) = tokio::join!(client.publish(publish(QoSLevel::ExactlyOnce)), async {
+            let packet_id = match next(&mut broker).await {
+                Packet::Publish(publish) => publish.packet_id.unwrap(),
+                p => panic!("expected PUBLISH, found {:?}", p),
+            };
+            let pubrec = pub_resp(PubResp::new_pubrec(), packet_id, Reason::Success);
+            broker.send(Packet::PubRec(pubrec)).await.unwrap();
+            match next(&mut broker).await {
+                Packet::PubRel(pubrel) => assert_eq!(packet_id, pubrel.packet_id),
+                p => panic!("expected PUBREL, found {:?}", p),
+            }
+            let pubcomp = pub_resp(PubResp::new_pubcomp(), packet_id, Reason::Success);
+            broker.send(Packet::PubComp(pubcomp)).await.unwrap();
+        });
+        assert!(result.is_ok());
+
+        let (result, _) = tokio::join!(client.publish(publish(QoSLevel::AtLeastOnce)), async {
+            let packet_id = match next(&mut broker).await {
+                Packet::Publish(publish) => publish.packet_id.unwrap(),
+                p => panic!("expected PUBLISH, found {:?}", p),
+            };
+            let puback = pub_resp(PubResp::new_puback(), packet_id, Reason::QuotaExceeded);
+            broker.send(Packet::PubAck(puback)).await.unwrap();
+        });
+        match result {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::QuotaExceeded), e.kind()),
+            Ok(_) => panic!("expected publish to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_receive_max() {
+        let mut client = AsyncMqttClient::new("client");
+        let (_, _, mut broker) = start(&mut client, connack(&[Property::RecvMax(1)])).await;
+
+        let (first, second, _) = tokio::join!(
+            client.publish(publish(QoSLevel::AtLeastOnce)),
+            client.publish(publish(QoSLevel::AtLeastOnce)),
+            async {
+                let first = match next(&mut broker).await {
+                    Packet::Publish(publish) => publish.packet_id.unwrap(),
+                    p => panic!("expected PUBLISH, found {:?}", p),
+                };
+                // the second publish waits until the first is acknowledged
+                assert!(time::timeout(Duration::from_millis(50), broker.next())
+                    .await
+                    .is_err());
+                let puback = pub_resp(PubResp::new_puback(), first, Reason::Success);
+                broker.send(Packet::PubAck(puback)).await.unwrap();
+                let second = match next(&mut broker).await {
+                    Packet::Publish(publish) => publish.packet_id.unwrap(),
+                    p => panic!("expected PUBLISH, found {:?}", p),
+                };
+                let puback = pub_resp(PubResp::new_puback(), second, Reason::Success);
+                broker.send(Packet::PubAck(puback)).await.unwrap();
+            }
+        );
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let mut client = AsyncMqttClient::new("client");
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+
+        let (result, _) = tokio::join!(
+            client.subscribe(&["sensor/+", "sensor/#"], QoSLevel::AtLeastOnce),
+            async {
+                let subscribe = match next(&mut broker).await {
+                    Packet::Subscribe(subscribe) => subscribe,
+                    p => panic!("expected SUBSCRIBE, found {:?}", p),
+                };
+                assert_eq!(2, subscribe.subscriptions().len());
+                let mut suback = SubAck::new(subscribe.packet_id());
+                suback.add_reason(Reason::GrantedQoS1);
+                suback.add_reason(Reason::NotAuthorized);
+                broker.send(Packet::SubAck(suback)).await.unwrap();
+            }
+        );
+        let suback = result.unwrap();
+        assert_eq!(
+            &[Reason::GrantedQoS1, Reason::NotAuthorized],
+            suback.reasons()
+        );
+
+        match client
+            .subscribe(&["sensor/#/temp"], QoSLevel::AtMostOnce)
+            .await
+        {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::InvalidTopicFilter), e.kind()),
+            Ok(_) => panic!("expected invalid topic filter to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let mut client = AsyncMqttClient::new("client");
+        let mut messages = client.messages().unwrap();
+        assert!(client.messages().is_none());
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+
+        let mut qos1 = publish(QoSLevel::AtLeastOnce);
+        qos1.packet_id = Some(1);
+        broker.send(Packet::Publish(qos1)).await.unwrap();
+        assert_eq!(Some(&b"21.5"[..]), messages.next().await.unwrap().payload());
+        match next(&mut broker).await {
+            Packet::PubAck(puback) => assert_eq!(1, puback.packet_id),
+            p => panic!("expected PUBACK, found {:?}", p),
+        }
+
+        // a retransmitted QoS 2 publish is acknowledged but delivered once
+        let mut qos2 = publish(QoSLevel::ExactlyOnce);
+        qos2.packet_id = Some(2);
+        broker.send(Packet::Publish(qos2.clone())).await.unwrap();
+        broker.send(Packet::Publish(qos2)).await.unwrap();
+        for _ in 0..2 {
+            match next(&mut broker).await {
+                Packet::PubRec(pubrec) => assert_eq!(2, pubrec.packet_id),
+                p => panic!("expected PUBREC, found {:?}", p),
+            }
+        }
+        let pubrel = pub_resp(PubResp::new_pubrel(), 2, Reason::Success);
+        broker.send(Packet::PubRel(pubrel)).await.unwrap();
+        match next(&mut broker).await {
+            Packet::PubComp(pubcomp) => assert_eq!(Reason::Success, pubcomp.reason()),
+            p => panic!("expected PUBCOMP, found {:?}", p),
+        }
+        assert!(messages.next().await.is_some());
+        assert!(time::timeout(Duration::from_millis(50), messages.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broker_disconnect() {
+        let mut client = AsyncMqttClient::new("client");
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+
+        let (result, _) = tokio::join!(client.publish(publish(QoSLevel::AtLeastOnce)), async {
+            next(&mut broker).await;
+            let disconnect = Disconnect::new(Reason::ServerShutdown);
+            broker.send(Packet::Disconnect(disconnect)).await.unwrap();
+        });
+        match result {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::ServerShutdown), e.kind()),
+            Ok(_) => panic!("expected publish to fail"),
+        }
+        assert!(client.disconnect().await.is_err());
+        assert!(!client.connected());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_idle() {
+        let mut client = AsyncMqttClient::new("client");
+        client.set_keep_alive(10);
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+        let connected = Instant::now();
+        time::sleep(Duration::from_secs(5)).await;
+        client.publish(publish(QoSLevel::AtMostOnce)).await.unwrap();
+        match next(&mut broker).await {
+            Packet::Publish(_) => {}
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // the PINGREQ is sent after the keep alive with nothing sent
+        match next(&mut broker).await {
+            Packet::PingRequest(_) => {}
+            p => panic!("expected PINGREQ, found {:?}", p),
+        }
+        assert_eq!(Duration::from_secs(15), connected.elapsed());
+        // sending while the PINGREQ is outstanding does not delay the timeout
+        time::sleep(Duration::from_secs(5)).await;
+        client.publish(publish(QoSLevel::AtMostOnce)).await.unwrap();
+        match next(&mut broker).await {
+            Packet::Publish(_) => {}
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        assert!(broker.next().await.is_none());
+        assert_eq!(Duration::from_secs(25), connected.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive() {
+        let mut client = AsyncMqttClient::new("client");
+        client.set_keep_alive(10);
+        let (_, connect, mut broker) = start(&mut client, connack(&[])).await;
+        assert_eq!(10, connect.keep_alive);
+
+        match next(&mut broker).await {
+            Packet::PingRequest(_) => {}
+            p => panic!("expected PINGREQ, found {:?}", p),
+        }
+        let pingresp = Packet::PingResponse(FixedHeader::new(PacketType::PingResp));
+        broker.send(pingresp).await.unwrap();
+        match next(&mut broker).await {
+            Packet::PingRequest(_) => {}
+            p => panic!("expected PINGREQ, found {:?}", p),
+        }
+        // the session ends when the broker does not respond to PINGREQ
+        assert!(broker.next().await.is_none());
+        assert!(!client.connected());
+        match client.disconnect().await {
+            Err(e) => assert_eq!(ErrorKind::Timeout, e.kind()),
+            Ok(_) => panic!("expected keep alive timeout"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let mut client = AsyncMqttClient::new("client");
+        let (_, _, mut broker) = start(&mut client, connack(&[])).await;
+        assert!(client.disconnect().await.is_ok());
+        match next(&mut broker).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(Reason::NormalDisconnect, disconnect.reason)
+            }
+            p => panic!("expected DISCONNECT, found {:?}", p),
+        }
+        assert!(!client.connected());
+        match client.publish(publish(QoSLevel::AtMostOnce)).await {
+            Err(e) => assert_eq!(ErrorKind::Connection, e.kind()),
+            Ok(_) => panic!("expected publish to fail when not connected"),
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     io::{Read, Write},
     net::TcpStream,
     sync::{Arc, Mutex},
@@ -10,17 +10,19 @@ use std::{
 
 use bytes::BytesMut;
 use vaux_mqtt::{
-    decode, encode, property::Property, ConnAck, Connect, Packet, PropertyType, PubResp, QoSLevel,
-    Reason, Subscribe, Subscription,
+    encode_with_version, property::Property, publish::Publish, ConnAck, Connect, Disconnect,
+    Packet, PropertyType, ProtocolVersion, PubResp, QoSLevel, Reason, StreamDecoder, Subscribe,
+    Subscription, TopicAliases, TopicFilter, Unsubscribe,
 };
 
-use crate::{ErrorKind, MqttConnection, MqttError};
+use crate::{ConnectionState, ErrorKind, MqttConnection, MqttError, ReconnectPolicy};
 
-const DEFAULT_RECV_MAX: u16 = 100;
-const DEFAULT_SESSION_EXPIRY: u32 = 1000;
+pub(crate) const DEFAULT_RECV_MAX: u16 = 100;
+pub(crate) const DEFAULT_SESSION_EXPIRY: u32 = 1000;
 // 64K is the default max packet size
-const DEFAULT_MAX_PACKET_SIZE: usize = 64 * 1024;
+pub(crate) const DEFAULT_MAX_PACKET_SIZE: usize = 64 * 1024;
 const MAX_QUEUE_LEN: usize = 100;
+pub(crate) const DEFAULT_TOPIC_ALIAS_MAX: u16 = 32;
 
 #[derive(Debug)]
 struct MqttStream<'a> {
@@ -50,10 +52,7 @@ impl<'a> MqttStream<'a> {
         if let Some(ref mut tls) = self.tls {
             return tls.sock.set_read_timeout(timeout);
         }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no stream available",
-        ))
+        Err(std::io::Error::other("no stream available"))
     }
 
     fn shutdown(&mut self) -> std::io::Result<()> {
@@ -63,10 +62,7 @@ impl<'a> MqttStream<'a> {
         if let Some(ref mut tls) = self.tls {
             return tls.sock.shutdown(std::net::Shutdown::Both);
         }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no stream available",
-        ))
+        Err(std::io::Error::other("no stream available"))
     }
 }
 
@@ -78,10 +74,7 @@ impl<'a> Read for MqttStream<'a> {
         if let Some(ref mut tls) = self.tls {
             return tls.read(buf);
         }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no stream available",
-        ))
+        Err(std::io::Error::other("no stream available"))
     }
 }
 
@@ -93,10 +86,7 @@ impl<'a> Write for MqttStream<'a> {
         if let Some(ref mut tls) = self.tls {
             return tls.write(buf);
         }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no stream available",
-        ))
+        Err(std::io::Error::other("no stream available"))
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -106,10 +96,7 @@ impl<'a> Write for MqttStream<'a> {
         if let Some(ref mut tls) = self.tls {
             return tls.flush();
         }
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no stream available",
-        ))
+        Err(std::io::Error::other("no stream available"))
     }
 }
 
@@ -130,6 +117,11 @@ pub struct MqttClient {
     subscriptions: Vec<Subscription>,
     pending_qos1: Arc<Mutex<Vec<Packet>>>,
     max_packet_size: usize,
+    topic_alias_max: u16,
+    protocol_version: ProtocolVersion,
+    reconnect: Option<ReconnectPolicy>,
+    state_send: crossbeam_channel::Sender<ConnectionState>,
+    state_recv: crossbeam_channel::Receiver<ConnectionState>,
 }
 
 impl Default for MqttClient {
@@ -157,6 +149,7 @@ impl MqttClient {
             crossbeam_channel::Sender<vaux_mqtt::Packet>,
             crossbeam_channel::Receiver<vaux_mqtt::Packet>,
         ) = crossbeam_channel::unbounded();
+        let (state_send, state_recv) = crossbeam_channel::unbounded();
         Self {
             auto_ack,
             auto_packet_id,
@@ -173,6 +166,11 @@ impl MqttClient {
             subscriptions: Vec::new(),
             pending_qos1: Arc::new(Mutex::new(Vec::new())),
             max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            topic_alias_max: DEFAULT_TOPIC_ALIAS_MAX,
+            protocol_version: ProtocolVersion::default(),
+            reconnect: None,
+            state_send,
+            state_recv,
         }
     }
 
@@ -200,10 +198,74 @@ impl MqttClient {
         self.max_packet_size = max_packet_size;
     }
 
+    pub fn topic_alias_max(&self) -> u16 {
+        self.topic_alias_max
+    }
+
+    /// Sets the maximum topic alias the client accepts from the broker. The
+    /// broker may replace the topic name of publish packets sent to the client
+    /// with an alias up to this value, the client restores the topic name
+    /// before the packet is made available to consumers. A value of 0 disables
+    /// inbound topic aliases. The topic alias maximum must be set prior to
+    /// calling connect for the value to be used.
+    ///
+    /// Topic aliases for publish packets sent by the client are assigned
+    /// automatically up to the maximum advertised by the broker in CONNACK.
+    pub fn set_topic_alias_max(&mut self, topic_alias_max: u16) {
+        self.topic_alias_max = topic_alias_max;
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Sets the MQTT protocol version used to connect to the broker, MQTT v5
+    /// by default. When connected using MQTT 3.1.1 no properties are sent or
+    /// received, so features such as session expiry and topic aliases are not
+    /// available. The protocol version must be set prior to calling connect
+    /// for the value to be used.
+    pub fn set_protocol_version(&mut self, protocol_version: ProtocolVersion) {
+        self.protocol_version = protocol_version;
+    }
+
     pub fn connected(&self) -> bool {
         *self.connected.lock().unwrap()
     }
 
+    pub fn reconnect_policy(&self) -> Option<&ReconnectPolicy> {
+        self.reconnect.as_ref()
+    }
+
+    /// Sets the policy used to reconnect when the connection to the broker
+    /// drops. Without a policy the client thread ends with the error. With a
+    /// policy the client reconnects with clean start false, subscribes again
+    /// if the broker did not keep the session and retransmits unacknowledged
+    /// QoS 1 and QoS 2 publish packets with the DUP flag set. The client does
+    /// not reconnect if the first connection fails or the broker disconnects
+    /// the client because another client took over the session. The policy
+    /// must be set prior to calling start for the value to be used.
+    ///
+    /// A session expiry should be set so that the broker keeps the session
+    /// while the client reconnects.
+    /// Example:
+    /// ```
+    /// use vaux_client::{MqttClient, ReconnectPolicy};
+    ///
+    /// let mut client = MqttClient::default();
+    /// client.set_session_expiry(60 * 60);
+    /// client.set_reconnect_policy(Some(ReconnectPolicy::default().with_max_attempts(10)));
+    /// ```
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+    }
+
+    /// Gets a channel reporting changes to the state of the connection with
+    /// the broker. As with the consumer channel, each state change is received
+    /// by only one receiver.
+    pub fn connection_state(&self) -> crossbeam_channel::Receiver<ConnectionState> {
+        self.state_recv.clone()
+    }
+
     pub fn session_expiry(&self) -> u32 {
         self.session_expiry
     }
@@ -230,29 +292,53 @@ impl MqttClient {
         self.session_expiry = session_expiry;
     }
 
-    /// Helper method to subscribe to the topics in the topic filter. This helper
-    /// subscribes with a QoS level of "At Most Once", or 0. A SUBACK will
-    /// typically be returned on the consumer on a successful subscribe.
+    /// Helper method to subscribe to the topics in the topic filter with the
+    /// given QoS level. A SUBACK will typically be returned on the consumer on
+    /// a successful subscribe. An error is returned, without subscribing to
+    /// any of the topics, if a topic filter is not valid.
     pub fn subscribe(
         &mut self,
         packet_id: u16,
         topic_filter: &[&str],
         qos: QoSLevel,
-    ) -> std::result::Result<(), Box<crossbeam_channel::SendError<Packet>>> {
+    ) -> crate::Result<()> {
         let mut subscribe = Subscribe::default();
         subscribe.set_packet_id(packet_id);
         for topic in topic_filter {
-            let subscription = Subscription {
+            if let Err(e) = TopicFilter::new(*topic) {
+                return Err(MqttError::new(e.message(), ErrorKind::Protocol(e.reason())));
+            }
+            subscribe.add_subscription(Subscription {
                 filter: (*topic).to_string(),
                 qos,
                 ..Default::default()
-            };
-            self.subscriptions.push(subscription.clone());
-            subscribe.add_subscription(subscription);
+            });
         }
+        self.subscriptions
+            .extend_from_slice(subscribe.subscriptions());
         self.producer
             .send(vaux_mqtt::Packet::Subscribe(subscribe))
-            .map_err(|e| e.into())
+            .map_err(|e| MqttError::new(&e.to_string(), ErrorKind::Transport))
+    }
+
+    /// Helper method to unsubscribe from the topic filters. An UNSUBACK will
+    /// typically be returned on the consumer. The subscriptions are no longer
+    /// sent again when the client reconnects to a broker that does not have
+    /// the session. An error is returned, without unsubscribing from any of
+    /// the topics, if a topic filter is not valid.
+    pub fn unsubscribe(&mut self, packet_id: u16, topic_filter: &[&str]) -> crate::Result<()> {
+        let unsubscribe = topic_filter
+            .iter()
+            .fold(Unsubscribe::builder(packet_id), |builder, topic| {
+                builder.with_filter(*topic)
+            })
+            .build()
+            .map_err(|e| MqttError::new(e.message(), ErrorKind::Protocol(e.reason())))?;
+        self.subscriptions
+            .retain(|sub| !topic_filter.contains(&sub.filter.as_str()));
+        self.producer
+            .send(vaux_mqtt::Packet::Unsubscribe(unsubscribe))
+            .map_err(|e| MqttError::new(&e.to_string(), ErrorKind::Transport))
     }
 
     /// Attempts to start an MQTT session with the remote broker. The client will
@@ -345,198 +431,54 @@ impl MqttClient {
     /// Queued messages will be sent in the order they were received. Any messages
     /// that are queued when the client is stopped will remain queued until the client
     /// is started again or the client is dropped.
+    ///
+    /// When a reconnect policy is set the thread reconnects when the connection
+    /// drops, see [`MqttClient::set_reconnect_policy`].
     pub fn start(
         &mut self,
-        mut connection: MqttConnection,
+        connection: MqttConnection,
         clean_start: bool,
     ) -> JoinHandle<crate::Result<()>> {
-        let packet_recv = self.packet_recv.as_ref().unwrap().clone();
-        let packet_send = self.packet_send.as_ref().unwrap().clone();
-        let auto_ack = self.auto_ack;
-        let receive_max = self.receive_max;
-        let pending_qos1 = self.pending_qos1.clone();
-        let mut last_packet_id = self.last_packet_id;
-        let auto_packet_id = self.auto_packet_id;
-        let max_packet_size = self.max_packet_size;
-        let client_id = self.client_id.clone();
-        let session_expiry = self.session_expiry;
-        let connected = self.connected.clone();
-        let credentials = connection.credentials();
-        let last_error = self.last_error.clone();
+        let mut session = Session {
+            auto_ack: self.auto_ack,
+            auto_packet_id: self.auto_packet_id,
+            receive_max: self.receive_max,
+            max_packet_size: self.max_packet_size,
+            session_expiry: self.session_expiry,
+            topic_alias_max: self.topic_alias_max,
+            protocol_version: self.protocol_version,
+            client_id: self.client_id.clone(),
+            connected: self.connected.clone(),
+            last_error: self.last_error.clone(),
+            credentials: connection.credentials(),
+            packet_recv: self.packet_recv.as_ref().unwrap().clone(),
+            packet_send: self.packet_send.as_ref().unwrap().clone(),
+            state_send: self.state_send.clone(),
+            pending_qos1: self.pending_qos1.clone(),
+            last_packet_id: self.last_packet_id,
+            unacked: Vec::new(),
+            released: Vec::new(),
+            inbound_qos2: HashSet::new(),
+            pending_publish: Vec::new(),
+            subscriptions: Vec::new(),
+            qos_1_remaining: self.receive_max,
+            connections: 0,
+        };
+        let reconnect = self.reconnect.clone();
 
         thread::spawn(move || {
-            let mut buffer = vec![0; max_packet_size];
-            let mut offset = 0;
-
-            let mut stream = if connection.tls {
-                MqttStream::new_tls(
-                    connection.tls_conn.as_mut().unwrap(),
-                    connection.tcp_socket.as_mut().unwrap(),
-                )
-            } else {
-                MqttStream::new_tcp(connection.tcp_socket.take().unwrap())
-            };
-
-            if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(100))) {
-                return Err(MqttError::new(
-                    &format!("unable to set read timeout: {}", e),
-                    ErrorKind::Transport,
-                ));
-            }
-
-            match Self::send_connect(
-                &mut stream,
-                credentials,
-                client_id,
-                session_expiry,
-                clean_start,
-                connected,
-                &mut buffer,
-                &mut offset,
-            ) {
-                Ok(_) => {}
-                Err(e) => {
-                    let last_error = last_error.lock();
-                    if let Ok(mut last_error) = last_error {
-                        *last_error = Some(e.clone());
-                    }
-                    stream.shutdown().unwrap();
-                    return Err(e);
-                }
-            }
-            let mut pending_recv_ack: HashMap<u16, Packet> = HashMap::new();
-            let mut pending_publish: Vec<Packet> = Vec::new();
-            // TODO add size tracking to pending publish
-            // let mut pending_publish_size = 0;
-            let mut qos_1_remaining = receive_max;
-            pending_publish.append(&mut pending_qos1.lock().unwrap());
-            loop {
-                match MqttClient::read_next(&mut stream, max_packet_size, &mut buffer, &mut offset)
-                {
-                    Ok(result) => {
-                        if let Some(p) = result {
-                            match &p {
-                                Packet::Disconnect(d) => {
-                                    // TODO handle disconnect - verify shutdown behavior
-                                    stream.shutdown().unwrap();
-                                    pending_qos1.lock().unwrap().append(&mut pending_publish);
-                                    return Err(MqttError::new(
-                                        &format!("disconnect received: {:?}", d),
-                                        ErrorKind::Protocol(d.reason),
-                                    ));
-                                }
-                                Packet::Publish(publish) => {
-                                    match publish.qos() {
-                                        vaux_mqtt::QoSLevel::AtMostOnce => {}
-                                        vaux_mqtt::QoSLevel::AtLeastOnce => {
-                                            if auto_ack {
-                                                let mut puback = PubResp::new_puback();
-                                                if let Some(packet_id) = publish.packet_id {
-                                                    puback.packet_id = packet_id;
-                                                } else {
-                                                    stream.shutdown().unwrap();
-                                                    return Err(MqttError::new(
-                                                        "protocol error, no packet ID with QAS > 0",
-                                                        ErrorKind::Protocol(
-                                                            Reason::MalformedPacket,
-                                                        ),
-                                                    ));
-                                                }
-                                                if MqttClient::send(
-                                                    &mut stream,
-                                                    Packet::PubAck(puback),
-                                                )
-                                                .is_err()
-                                                {
-                                                    // TODO handle the pub ack next time through
-                                                    // push a message to the last error channel
-                                                    eprintln!("unable to send puback");
-                                                }
-                                            }
-                                        }
-                                        vaux_mqtt::QoSLevel::ExactlyOnce => todo!(),
-                                    }
-                                }
-                                Packet::PubAck(puback) => {
-                                    if let Some(_p) = pending_recv_ack.remove(&puback.packet_id) {
-                                        if qos_1_remaining < receive_max {
-                                            qos_1_remaining += 1;
-                                        }
-                                    } else {
-                                        // TODO PUBACK that was not expected
-                                    }
-                                }
-                                _ => {}
-                            }
-                            if let Err(e) = packet_recv.send(p.clone()) {
-                                stream.shutdown().unwrap();
-                                pending_qos1.lock().unwrap().append(&mut pending_publish);
-                                return Err(MqttError::new(
-                                    &format!("unable to send packet to consumer: {}", e),
-                                    ErrorKind::Transport,
-                                ));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        if e.kind() != ErrorKind::Timeout {
-                            // there may be nothing to read so this is not necessarily an error
-                            // TODO configure for disconnect/reconnect, PING or stop on timeouts
-                        }
-                    }
-                };
-                if let Ok(mut packet) = packet_send.recv_timeout(Duration::from_millis(10)) {
-                    if let Packet::Publish(mut p) = packet.clone() {
-                        if p.qos() == QoSLevel::AtLeastOnce {
-                            if auto_packet_id {
-                                last_packet_id += 1;
-                                p.packet_id = Some(last_packet_id);
-                                pending_recv_ack.insert(last_packet_id, Packet::Publish(p.clone()));
-                            } else if let Some(packet_id) = p.packet_id {
-                                pending_recv_ack.insert(packet_id, Packet::Publish(p.clone()));
-                            } else {
-                                // TODO handle error
-                                eprintln!("no packet id");
-                            }
-                            if qos_1_remaining > 0 {
-                                qos_1_remaining -= 1;
-                                packet = Packet::Publish(p);
-                            } else {
-                                // TODO cannot send the packet - need to inform client
-                                if pending_publish.len() < MAX_QUEUE_LEN {
-                                    // && pending_publish_size < MAX_QUEUE_SIZE {
-                                    pending_publish.push(Packet::Publish(p));
-                                    continue;
-                                }
-                            }
-                        }
-                    } else if let Packet::Disconnect(_d) = packet.clone() {
-                        if let Err(e) = MqttClient::send(&mut stream, packet) {
-                            eprintln!("ERROR sending packet to remote: {}", e.message());
-                        }
-                        stream.shutdown().unwrap();
-                        pending_qos1.lock().unwrap().append(&mut pending_publish);
-                        return Ok(());
-                    }
-                    if let Err(e) = MqttClient::send(&mut stream, packet) {
-                        eprintln!("ERROR sending packet to remote: {}", e.message());
-                    }
-                    // send any pending QOS-1 publish packets that we are able to send
-                    while !pending_publish.is_empty() && qos_1_remaining > 0 {
-                        while !pending_publish.is_empty() && qos_1_remaining > 0 {
-                            let packet = pending_publish.remove(0);
-                            // pending_publish_size -= packet.encoded_size();
-                            if let Err(e) = MqttClient::send(&mut stream, packet.clone()) {
-                                pending_publish.insert(0, packet);
-                                // TODO notify calling client of error
-                                eprintln!("ERROR sending packet to remote: {}", e.message());
-                            } else {
-                                qos_1_remaining += 1;
-                            }
-                        }
-                    }
-                }
-            }
+            session
+                .pending_publish
+                .append(&mut session.pending_qos1.lock().unwrap());
+            let result = session.run_with_reconnect(connection, clean_start, reconnect);
+            session
+                .pending_qos1
+                .lock()
+                .unwrap()
+                .append(&mut session.pending_publish);
+            *session.connected.lock().unwrap() = false;
+            let _ = session.state_send.send(ConnectionState::Disconnected);
+            result
         })
     }
 
@@ -547,17 +489,21 @@ impl MqttClient {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn send_connect(
         stream: &mut MqttStream,
         credentials: Option<(String, String)>,
         client_id: Arc<Mutex<Option<String>>>,
         session_expiry: u32,
+        topic_alias_max: u16,
+        protocol_version: ProtocolVersion,
         clean_start: bool,
         connected: Arc<Mutex<bool>>,
-        buffer: &mut Vec<u8>,
-        offset: &mut usize,
+        read_buf: &mut [u8],
+        buffer: &mut BytesMut,
     ) -> crate::Result<ConnAck> {
         let mut connect = Connect::default();
+        connect.protocol_version = protocol_version;
         connect.clean_start = clean_start;
         // scoped mutex guard to set the connect packet client id
         {
@@ -569,6 +515,11 @@ impl MqttClient {
         connect
             .properties_mut()
             .set_property(Property::SessionExpiryInterval(session_expiry));
+        if topic_alias_max > 0 {
+            connect
+                .properties_mut()
+                .set_property(Property::TopicAliasMax(topic_alias_max));
+        }
         if let Some((username, password)) = credentials {
             connect.username = Some(username);
             connect.password = Some(password.into_bytes());
@@ -576,13 +527,16 @@ impl MqttClient {
         let connect_packet = Packet::Connect(Box::new(connect));
         // let mut buffer = [0u8; 128];
         let mut dest = BytesMut::default();
-        let result = encode(connect_packet, &mut dest);
+        let result = encode_with_version(connect_packet, &mut dest, protocol_version);
         if let Err(e) = result {
             panic!("Failed to encode packet: {:?}", e);
         }
         match stream.write_all(&dest) {
             Ok(_) => {
-                match MqttClient::read_next(stream, DEFAULT_MAX_PACKET_SIZE, buffer, offset) {
+                let decoder = StreamDecoder::new()
+                    .with_max_packet_size(DEFAULT_MAX_PACKET_SIZE)
+                    .with_version(protocol_version);
+                match MqttClient::read_next(stream, &decoder, read_buf, buffer) {
                     Ok(Some(packet)) => match packet {
                         Packet::ConnAck(connack) => {
                             Self::handle_connack(connack, connected, client_id)
@@ -685,52 +639,38 @@ impl MqttClient {
         Ok(connack)
     }
 
+    /// Reads the next packet from the connection. Bytes read from the
+    /// connection are appended to the receive buffer until the decoder has a
+    /// complete packet, so the decoded payload refers to the received bytes
+    /// without copying them again. Bytes following the packet remain in the
+    /// buffer for the next call.
     fn read_next(
         connection: &mut dyn std::io::Read,
-        max_packet_size: usize,
-        buffer: &mut Vec<u8>,
-        offset: &mut usize,
+        decoder: &StreamDecoder,
+        read_buf: &mut [u8],
+        buffer: &mut BytesMut,
     ) -> crate::Result<Option<Packet>> {
-        let mut bytes_read = *offset;
         loop {
-            if bytes_read > 0 {
-                let bytes_mut = &mut BytesMut::from(&buffer[0..bytes_read]);
-                match decode(bytes_mut) {
-                    Ok(data_read) => {
-                        if let Some((packet, decode_len)) = data_read {
-                            if decode_len < bytes_read as u32 {
-                                buffer.copy_within(decode_len as usize..bytes_read, 0);
-                                // adjust offset to end of decoded bytes
-                                *offset = bytes_read - decode_len as usize;
-                            } else {
-                                *offset = 0;
-                            }
-                            return Ok(Some(packet));
-                        } else {
-                            return Ok(None);
-                        }
-                    }
-                    Err(e) => match e.kind {
-                        vaux_mqtt::codec::ErrorKind::InsufficientData(_expected, _actual) => {
-                            // fall through the the socket read
-                        }
-                        _ => {
-                            return Err(MqttError::new(
-                                &e.to_string(),
-                                crate::ErrorKind::Protocol(Reason::ProtocolErr),
-                            ));
-                        }
-                    },
+            match decoder.decode(buffer) {
+                Ok(Some(packet)) => return Ok(Some(packet)),
+                Ok(None) => {
+                    // fall through to the socket read
+                }
+                Err(e) => {
+                    return Err(MqttError::new(
+                        &e.to_string(),
+                        crate::ErrorKind::Protocol(e.reason()),
+                    ));
                 }
             }
-            match connection.read(&mut buffer[*offset..max_packet_size]) {
-                Ok(len) => {
-                    if len == 0 && bytes_read == 0 {
-                        return Ok(None);
-                    }
-                    bytes_read += len;
-                    *offset = bytes_read;
+            match connection.read(read_buf) {
+                Ok(0) => {
+                    return Err(MqttError::new(
+                        "connection closed by broker",
+                        ErrorKind::Connection,
+                    ))
                 }
+                Ok(len) => buffer.extend_from_slice(&read_buf[..len]),
                 Err(e) => match e.kind() {
                     std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
                         return Err(MqttError::new(&e.to_string(), ErrorKind::Timeout));
@@ -741,12 +681,23 @@ impl MqttClient {
         }
     }
 
+    /// Sends the packet to the broker encoded as MQTT v5.
     pub fn send(
         connection: &mut dyn std::io::Write,
         packet: Packet,
+    ) -> crate::Result<Option<Packet>> {
+        MqttClient::send_with_version(connection, packet, ProtocolVersion::V5)
+    }
+
+    /// Sends the packet to the broker encoded for the protocol version of the
+    /// connection.
+    pub fn send_with_version(
+        connection: &mut dyn std::io::Write,
+        packet: Packet,
+        protocol_version: ProtocolVersion,
     ) -> crate::Result<Option<Packet>> {
         let mut dest = BytesMut::default();
-        let result = encode(packet, &mut dest);
+        let result = encode_with_version(packet, &mut dest, protocol_version);
         if let Err(e) = result {
             panic!("Failed to encode packet: {:?}", e);
         }
@@ -761,3 +712,860 @@ impl MqttClient {
         Ok(None)
     }
 }
+
+/// State of the client thread. The state is kept across connections to the
+/// broker so that the session can be resumed when the client reconnects.
+struct Session {
+    auto_ack: bool,
+    auto_packet_id: bool,
+    receive_max: u16,
+    max_packet_size: usize,
+    session_expiry: u32,
+    topic_alias_max: u16,
+    protocol_version: ProtocolVersion,
+    client_id: Arc<Mutex<Option<String>>>,
+    connected: Arc<Mutex<bool>>,
+    last_error: Arc<Mutex<Option<MqttError>>>,
+    credentials: Option<(String, String)>,
+    packet_recv: crossbeam_channel::Sender<Packet>,
+    packet_send: crossbeam_channel::Receiver<Packet>,
+    state_send: crossbeam_channel::Sender<ConnectionState>,
+    pending_qos1: Arc<Mutex<Vec<Packet>>>,
+    last_packet_id: u16,
+    /// QoS 1 and QoS 2 publish packets sent and not yet acknowledged, in the
+    /// order sent
+    unacked: Vec<Publish>,
+    /// packet identifiers of QoS 2 publish packets acknowledged with PUBREC
+    /// that are waiting for PUBCOMP
+    released: Vec<u16>,
+    /// packet identifiers of QoS 2 publish packets received from the broker
+    /// that are waiting for PUBREL
+    inbound_qos2: HashSet<u16>,
+    pending_publish: Vec<Packet>,
+    /// subscriptions sent to the broker, sent again if the broker does not
+    /// have the session when the client reconnects
+    subscriptions: Vec<Subscribe>,
+    qos_1_remaining: u16,
+    /// number of times the broker accepted a connection
+    connections: u32,
+}
+
+impl Session {
+    /// Runs sessions with the broker until the client disconnects. When a
+    /// reconnect policy is set and the connection drops, the client waits for
+    /// the policy delay, reconnects and resumes the session.
+    fn run_with_reconnect(
+        &mut self,
+        mut connection: MqttConnection,
+        mut clean_start: bool,
+        policy: Option<ReconnectPolicy>,
+    ) -> crate::Result<()> {
+        let mut attempts = 0;
+        loop {
+            let connections = self.connections;
+            let error = match self.run(&mut connection, clean_start) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            *self.connected.lock().unwrap() = false;
+            // a client that never connected reports the error, as does one
+            // that can no longer deliver packets or has been replaced
+            let policy = match &policy {
+                Some(policy)
+                    if self.connections > 0
+                        && error.kind() != ErrorKind::Transport
+                        && error.kind() != ErrorKind::Protocol(Reason::SessionTakeOver) =>
+                {
+                    policy
+                }
+                _ => return Err(error),
+            };
+            if self.connections > connections {
+                attempts = 0;
+                let _ = self
+                    .state_send
+                    .send(ConnectionState::ConnectionLost(error.clone()));
+            }
+            loop {
+                if !policy.may_retry(attempts) {
+                    return Err(error);
+                }
+                attempts += 1;
+                let delay = policy.delay(attempts);
+                let _ = self.state_send.send(ConnectionState::Reconnecting {
+                    attempt: attempts,
+                    delay,
+                });
+                thread::sleep(delay);
+                if connection.reconnect().is_ok() {
+                    break;
+                }
+            }
+            clean_start = false;
+        }
+    }
+
+    /// Runs a session on the connection, returning when the client
+    /// disconnects or with the error that ended the session.
+    fn run(&mut self, connection: &mut MqttConnection, clean_start: bool) -> crate::Result<()> {
+        let mut read_buf = vec![0; self.max_packet_size];
+        let mut buffer = BytesMut::with_capacity(self.max_packet_size);
+
+        let mut stream = if connection.tls {
+            MqttStream::new_tls(
+                connection.tls_conn.as_mut().unwrap(),
+                connection.tcp_socket.as_mut().unwrap(),
+            )
+        } else {
+            MqttStream::new_tcp(connection.tcp_socket.take().unwrap())
+        };
+
+        if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(100))) {
+            return Err(MqttError::new(
+                &format!("unable to set read timeout: {}", e),
+                ErrorKind::Transport,
+            ));
+        }
+
+        let protocol_version = self.protocol_version;
+        let connack = match MqttClient::send_connect(
+            &mut stream,
+            self.credentials.clone(),
+            self.client_id.clone(),
+            self.session_expiry,
+            self.topic_alias_max,
+            protocol_version,
+            clean_start,
+            self.connected.clone(),
+            &mut read_buf,
+            &mut buffer,
+        ) {
+            Ok(connack) => connack,
+            Err(e) => {
+                let last_error = self.last_error.lock();
+                if let Ok(mut last_error) = last_error {
+                    *last_error = Some(e.clone());
+                }
+                let _ = stream.shutdown();
+                return Err(e);
+            }
+        };
+        let server_alias_max = match connack
+            .properties()
+            .get_property(&PropertyType::TopicAliasMax)
+        {
+            Some(Property::TopicAliasMax(max)) => *max,
+            _ => 0,
+        };
+        let mut aliases = TopicAliases::new(self.topic_alias_max, server_alias_max);
+        self.connections += 1;
+        let _ = self.state_send.send(ConnectionState::Connected {
+            session_present: connack.session_present,
+        });
+        if self.connections > 1 {
+            self.resume(&mut stream, &mut aliases, connack.session_present)?;
+        }
+
+        let decoder = StreamDecoder::new()
+            .with_max_packet_size(self.max_packet_size)
+            .with_version(protocol_version);
+        loop {
+            match MqttClient::read_next(&mut stream, &decoder, &mut read_buf, &mut buffer) {
+                Ok(result) => {
+                    if let Some(mut p) = result {
+                        let mut deliver = true;
+                        if let Packet::Publish(publish) = &mut p {
+                            if let Err(reason) = aliases.resolve_inbound(publish) {
+                                let disconnect = Packet::Disconnect(Disconnect::new(reason));
+                                if let Err(e) = MqttClient::send_with_version(
+                                    &mut stream,
+                                    disconnect,
+                                    protocol_version,
+                                ) {
+                                    eprintln!("ERROR sending packet to remote: {}", e.message());
+                                }
+                                let _ = stream.shutdown();
+                                return Err(MqttError::new(
+                                    "invalid topic alias received",
+                                    ErrorKind::Protocol(reason),
+                                ));
+                            }
+                        }
+                        match &p {
+                            Packet::Disconnect(d) => {
+                                let _ = stream.shutdown();
+                                return Err(MqttError::new(
+                                    &format!("disconnect received: {:?}", d),
+                                    ErrorKind::Protocol(d.reason),
+                                ));
+                            }
+                            Packet::Publish(publish) => {
+                                let packet_id = match (publish.qos(), publish.packet_id) {
+                                    (QoSLevel::AtMostOnce, _) => 0,
+                                    (_, Some(packet_id)) => packet_id,
+                                    (_, None) => {
+                                        let _ = stream.shutdown();
+                                        return Err(MqttError::new(
+                                            "protocol error, no packet ID with QoS > 0",
+                                            ErrorKind::Protocol(Reason::MalformedPacket),
+                                        ));
+                                    }
+                                };
+                                // a QoS 2 packet received again before PUBREL is a
+                                // retransmission and is only acknowledged, MQTT v5 4.3.3
+                                deliver = publish.qos() != QoSLevel::ExactlyOnce
+                                    || self.inbound_qos2.insert(packet_id);
+                                let resp = match publish.qos() {
+                                    QoSLevel::AtLeastOnce if self.auto_ack => {
+                                        let mut puback = PubResp::new_puback();
+                                        puback.packet_id = packet_id;
+                                        Some(Packet::PubAck(puback))
+                                    }
+                                    QoSLevel::ExactlyOnce if self.auto_ack || !deliver => {
+                                        let mut pubrec = PubResp::new_pubrec();
+                                        pubrec.packet_id = packet_id;
+                                        Some(Packet::PubRec(pubrec))
+                                    }
+                                    _ => None,
+                                };
+                                if let Some(resp) = resp {
+                                    if MqttClient::send_with_version(
+                                        &mut stream,
+                                        resp,
+                                        protocol_version,
+                                    )
+                                    .is_err()
+                                    {
+                                        // TODO handle the response next time through
+                                        // push a message to the last error channel
+                                        eprintln!("unable to send publish response");
+                                    }
+                                }
+                            }
+                            Packet::PubRel(pubrel) => {
+                                let found = self.inbound_qos2.remove(&pubrel.packet_id);
+                                if self.auto_ack {
+                                    let mut pubcomp = PubResp::new_pubcomp();
+                                    pubcomp.packet_id = pubrel.packet_id;
+                                    if !found {
+                                        pubcomp.set_reason(Reason::PacketIdNotFound).map_err(
+                                            |e| MqttError::new(e.message(), ErrorKind::Codec),
+                                        )?;
+                                    }
+                                    if MqttClient::send_with_version(
+                                        &mut stream,
+                                        Packet::PubComp(pubcomp),
+                                        protocol_version,
+                                    )
+                                    .is_err()
+                                    {
+                                        eprintln!("unable to send pubcomp");
+                                    }
+                                }
+                            }
+                            // acknowledgements for packets that were not sent are
+                            // ignored
+                            Packet::PubAck(puback)
+                                if self.acknowledge(puback.packet_id, QoSLevel::AtLeastOnce) =>
+                            {
+                                self.release_quota();
+                            }
+                            Packet::PubRec(pubrec)
+                                if self.acknowledge(pubrec.packet_id, QoSLevel::ExactlyOnce) =>
+                            {
+                                if (pubrec.reason() as u8) < 0x80 {
+                                    self.released.push(pubrec.packet_id);
+                                    if self.auto_ack {
+                                        let mut pubrel = PubResp::new_pubrel();
+                                        pubrel.packet_id = pubrec.packet_id;
+                                        if MqttClient::send_with_version(
+                                            &mut stream,
+                                            Packet::PubRel(pubrel),
+                                            protocol_version,
+                                        )
+                                        .is_err()
+                                        {
+                                            eprintln!("unable to send pubrel");
+                                        }
+                                    }
+                                } else {
+                                    // the publish ends with a PUBREC reporting failure
+                                    self.release_quota();
+                                }
+                            }
+                            Packet::PubComp(pubcomp) => {
+                                if let Some(idx) =
+                                    self.released.iter().position(|id| *id == pubcomp.packet_id)
+                                {
+                                    self.released.remove(idx);
+                                    self.release_quota();
+                                }
+                            }
+                            _ => {}
+                        }
+                        if !deliver {
+                            continue;
+                        }
+                        if let Err(e) = self.packet_recv.send(p.clone()) {
+                            let _ = stream.shutdown();
+                            return Err(MqttError::new(
+                                &format!("unable to send packet to consumer: {}", e),
+                                ErrorKind::Transport,
+                            ));
+                        }
+                    }
+                }
+                Err(e) => {
+                    if let ErrorKind::Protocol(reason) = e.kind() {
+                        // nothing after a packet that cannot be decoded can be read, so
+                        // disconnect with the reason for the decode error
+                        let disconnect = Packet::Disconnect(Disconnect::new(reason));
+                        if let Err(e) =
+                            MqttClient::send_with_version(&mut stream, disconnect, protocol_version)
+                        {
+                            eprintln!("ERROR sending packet to remote: {}", e.message());
+                        }
+                        let _ = stream.shutdown();
+                        return Err(e);
+                    }
+                    if e.kind() == ErrorKind::IO || e.kind() == ErrorKind::Connection {
+                        // the connection was closed or reset
+                        let _ = stream.shutdown();
+                        return Err(e);
+                    }
+                    // there may be nothing to read so a timeout is not an error
+                }
+            };
+            if let Ok(mut packet) = self.packet_send.recv_timeout(Duration::from_millis(10)) {
+                if let Packet::Publish(mut p) = packet.clone() {
+                    if p.qos() != QoSLevel::AtMostOnce {
+                        if self.auto_packet_id {
+                            p.packet_id = Some(self.next_packet_id());
+                        } else if p.packet_id.is_none() {
+                            // TODO handle error
+                            eprintln!("no packet id");
+                        }
+                        if self.qos_1_remaining > 0 {
+                            self.qos_1_remaining -= 1;
+                            self.unacked.push(p.clone());
+                            packet = Packet::Publish(p);
+                        } else {
+                            // TODO cannot send the packet - need to inform client
+                            if self.pending_publish.len() < MAX_QUEUE_LEN {
+                                // && pending_publish_size < MAX_QUEUE_SIZE {
+                                self.pending_publish.push(Packet::Publish(p));
+                                continue;
+                            }
+                        }
+                    }
+                } else if let Packet::Subscribe(subscribe) = &packet {
+                    self.subscriptions.push(subscribe.clone());
+                } else if let Packet::Unsubscribe(unsubscribe) = &packet {
+                    self.remove_subscriptions(unsubscribe.filters());
+                } else if let Packet::Disconnect(_d) = packet.clone() {
+                    if let Err(e) =
+                        MqttClient::send_with_version(&mut stream, packet, protocol_version)
+                    {
+                        eprintln!("ERROR sending packet to remote: {}", e.message());
+                    }
+                    let _ = stream.shutdown();
+                    return Ok(());
+                }
+                if let Packet::Publish(p) = &mut packet {
+                    aliases.apply_outbound(p);
+                }
+                if let Err(e) = MqttClient::send_with_version(&mut stream, packet, protocol_version)
+                {
+                    eprintln!("ERROR sending packet to remote: {}", e.message());
+                }
+                // send any pending QOS-1 publish packets that we are able to send
+                while !self.pending_publish.is_empty() && self.qos_1_remaining > 0 {
+                    let packet = self.pending_publish.remove(0);
+                    // pending_publish_size -= packet.encoded_size();
+                    let mut outbound = packet.clone();
+                    if let Packet::Publish(p) = &mut outbound {
+                        aliases.apply_outbound(p);
+                    }
+                    if let Err(e) =
+                        MqttClient::send_with_version(&mut stream, outbound, protocol_version)
+                    {
+                        self.pending_publish.insert(0, packet);
+                        // TODO notify calling client of error
+                        eprintln!("ERROR sending packet to remote: {}", e.message());
+                        break;
+                    } else if let Packet::Publish(p) = packet {
+                        self.qos_1_remaining -= 1;
+                        self.unacked.push(p);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resumes the session after reconnecting. When the broker no longer has
+    /// the session the subscriptions are sent again. Unacknowledged publish
+    /// packets are retransmitted with the DUP flag set and PUBREL is sent
+    /// again for QoS 2 packets waiting on PUBCOMP, MQTT v5 4.4.
+    ///
+    /// A QoS 2 packet acknowledged with PUBREC was accepted by the broker, so
+    /// it is not sent again when the broker no longer has the session. Its
+    /// flow is ended with a PUBCOMP with reason PacketIdNotFound on the
+    /// consumer, the response of a broker to a PUBREL it has no record of.
+    fn resume(
+        &mut self,
+        stream: &mut MqttStream,
+        aliases: &mut TopicAliases,
+        session_present: bool,
+    ) -> crate::Result<()> {
+        if !session_present {
+            for subscribe in &self.subscriptions {
+                MqttClient::send_with_version(
+                    stream,
+                    Packet::Subscribe(subscribe.clone()),
+                    self.protocol_version,
+                )?;
+            }
+            for packet_id in self.released.drain(..) {
+                let mut pubcomp = PubResp::new_pubcomp();
+                pubcomp.packet_id = packet_id;
+                pubcomp
+                    .set_reason(Reason::PacketIdNotFound)
+                    .map_err(|e| MqttError::new(e.message(), ErrorKind::Codec))?;
+                let _ = self.packet_recv.send(Packet::PubComp(pubcomp));
+            }
+            // the broker does not send PUBREL for packets of the old session
+            self.inbound_qos2.clear();
+        }
+        for packet_id in &self.released {
+            let mut pubrel = PubResp::new_pubrel();
+            pubrel.packet_id = *packet_id;
+            MqttClient::send_with_version(stream, Packet::PubRel(pubrel), self.protocol_version)?;
+        }
+        self.qos_1_remaining = self.receive_max;
+        for publish in &mut self.unacked {
+            publish.header.set_dup(true);
+            let mut outbound = publish.clone();
+            aliases.apply_outbound(&mut outbound);
+            MqttClient::send_with_version(
+                stream,
+                Packet::Publish(outbound),
+                self.protocol_version,
+            )?;
+            self.qos_1_remaining = self.qos_1_remaining.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Removes the subscriptions with the topic filters so that they are not
+    /// sent again when the broker does not have the session.
+    fn remove_subscriptions(&mut self, filters: &[String]) {
+        for subscribe in &mut self.subscriptions {
+            for filter in filters {
+                subscribe.remove_subscription(filter);
+            }
+        }
+        self.subscriptions
+            .retain(|subscribe| !subscribe.subscriptions().is_empty());
+    }
+
+    /// Removes the unacknowledged publish with the packet identifier and QoS
+    /// level, returning true if it was found.
+    fn acknowledge(&mut self, packet_id: u16, qos: QoSLevel) -> bool {
+        match self
+            .unacked
+            .iter()
+            .position(|p| p.packet_id == Some(packet_id) && p.qos() == qos)
+        {
+            Some(idx) => {
+                self.unacked.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn release_quota(&mut self) {
+        if self.qos_1_remaining < self.receive_max {
+            self.qos_1_remaining += 1;
+        }
+    }
+
+    /// Gets the next packet identifier, skipping 0 which is not a valid
+    /// identifier, MQTT v5 2.2.1.
+    fn next_packet_id(&mut self) -> u16 {
+        self.last_packet_id = self.last_packet_id.wrapping_add(1);
+        if self.last_packet_id == 0 {
+            self.last_packet_id = 1;
+        }
+        self.last_packet_id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use vaux_mqtt::{decode, encode};
+
+    use super::*;
+
+    fn encoded_publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut publish = Publish::default();
+        publish.topic_name = Some(topic.to_string());
+        publish.set_payload(payload.to_vec());
+        let mut dest = BytesMut::new();
+        encode(Packet::Publish(publish), &mut dest).unwrap();
+        dest.to_vec()
+    }
+
+    #[test]
+    fn test_read_next_multiple_packets() {
+        let mut src = encoded_publish("a", b"first");
+        let second = encoded_publish("b", b"second");
+        // the first read ends part way through the second packet
+        src.extend_from_slice(&second[..4]);
+        let mut reader = &src[..];
+        let mut read_buf = vec![0; DEFAULT_MAX_PACKET_SIZE];
+        let mut buffer = BytesMut::new();
+        let decoder = StreamDecoder::new();
+        let first = MqttClient::read_next(&mut reader, &decoder, &mut read_buf, &mut buffer);
+        match first {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(Some(&b"first"[..]), publish.payload())
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        assert_eq!(4, buffer.len());
+        let mut reader = &second[4..];
+        match MqttClient::read_next(&mut reader, &decoder, &mut read_buf, &mut buffer) {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(Some(&b"second"[..]), publish.payload())
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_read_next_packet_too_large() {
+        let src = encoded_publish("a", &[0; 64]);
+        let mut reader = &src[..];
+        let mut read_buf = vec![0; 16];
+        let mut buffer = BytesMut::new();
+        let decoder = StreamDecoder::new().with_max_packet_size(16);
+        match MqttClient::read_next(&mut reader, &decoder, &mut read_buf, &mut buffer) {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::PacketTooLarge), e.kind()),
+            p => panic!("expected packet too large, found {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_read_next_closed() {
+        let mut reader: &[u8] = &[];
+        let mut read_buf = vec![0; 16];
+        let mut buffer = BytesMut::new();
+        match MqttClient::read_next(
+            &mut reader,
+            &StreamDecoder::new(),
+            &mut read_buf,
+            &mut buffer,
+        ) {
+            Err(e) => assert_eq!(ErrorKind::Connection, e.kind()),
+            p => panic!("expected connection closed, found {:?}", p),
+        }
+    }
+
+    fn broker_read(stream: &mut TcpStream, buffer: &mut BytesMut) -> Packet {
+        let mut read_buf = [0; 1024];
+        loop {
+            if let Some((packet, _)) = decode(buffer).unwrap() {
+                return packet;
+            }
+            let len = stream.read(&mut read_buf).unwrap();
+            assert!(len > 0, "client closed the connection");
+            buffer.extend_from_slice(&read_buf[..len]);
+        }
+    }
+
+    fn broker_send(stream: &mut TcpStream, packet: Packet) {
+        let mut dest = BytesMut::new();
+        encode(packet, &mut dest).unwrap();
+        stream.write_all(&dest).unwrap();
+    }
+
+    fn broker_connack(stream: &mut TcpStream, buffer: &mut BytesMut, session_present: bool) {
+        match broker_read(stream, buffer) {
+            Packet::Connect(_) => {}
+            p => panic!("expected CONNECT, found {:?}", p),
+        }
+        let mut connack = ConnAck::default();
+        connack.session_present = session_present;
+        broker_send(stream, Packet::ConnAck(connack));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = MqttClient::new("reconnect-test", true, 10, true);
+        client.set_reconnect_policy(Some(
+            ReconnectPolicy::default()
+                .with_initial_delay(Duration::from_millis(10))
+                .with_max_attempts(5),
+        ));
+        let states = client.connection_state();
+        let connection = MqttConnection::new()
+            .with_host("127.0.0.1")
+            .with_port(port)
+            .connect()
+            .unwrap();
+        let handle = client.start(connection, true);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = BytesMut::new();
+        broker_connack(&mut stream, &mut buffer, false);
+        client
+            .subscribe(1, &["sensor/temp"], QoSLevel::AtLeastOnce)
+            .unwrap();
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        client.producer().send(Packet::Publish(publish)).unwrap();
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Subscribe(_)
+        ));
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::Publish(p) => assert!(!p.header.dup()),
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        // drop the connection without acknowledging the publish
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = BytesMut::new();
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::Connect(connect) => assert!(!connect.clean_start),
+            p => panic!("expected CONNECT, found {:?}", p),
+        }
+        broker_send(&mut stream, Packet::ConnAck(ConnAck::default()));
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Subscribe(_)
+        ));
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::Publish(p) => {
+                assert!(p.header.dup());
+                assert_eq!(Some(1), p.packet_id);
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        client.stop();
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Disconnect(_)
+        ));
+        assert!(handle.join().unwrap().is_ok());
+
+        let states: Vec<ConnectionState> = states.try_iter().collect();
+        assert!(matches!(
+            states[..],
+            [
+                ConnectionState::Connected {
+                    session_present: false
+                },
+                ConnectionState::ConnectionLost(_),
+                ConnectionState::Reconnecting { attempt: 1, .. },
+                ConnectionState::Connected {
+                    session_present: false
+                },
+                ConnectionState::Disconnected,
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_invalid_filter() {
+        let mut client = MqttClient::default();
+        match client.subscribe(1, &["sensor/temp", "sensor/#/temp"], QoSLevel::AtMostOnce) {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::InvalidTopicFilter), e.kind()),
+            Ok(_) => panic!("expected invalid topic filter to be rejected"),
+        }
+        assert!(client.subscriptions.is_empty());
+        assert!(client
+            .subscribe(1, &["sensor/temp", "sensor/+"], QoSLevel::AtMostOnce)
+            .is_ok());
+        assert_eq!(2, client.subscriptions.len());
+    }
+
+    fn start_test_client(
+        client_id: &str,
+        policy: Option<ReconnectPolicy>,
+    ) -> (
+        MqttClient,
+        thread::JoinHandle<crate::Result<()>>,
+        TcpListener,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = MqttClient::new(client_id, true, 10, true);
+        client.set_reconnect_policy(policy);
+        let connection = MqttConnection::new()
+            .with_host("127.0.0.1")
+            .with_port(port)
+            .connect()
+            .unwrap();
+        let handle = client.start(connection, true);
+        (client, handle, listener)
+    }
+
+    fn next_pubcomp(consumer: &crossbeam_channel::Receiver<Packet>) -> PubResp {
+        loop {
+            match consumer.recv_timeout(Duration::from_secs(5)) {
+                Ok(Packet::PubComp(pubcomp)) => return pubcomp,
+                Ok(_) => {}
+                Err(e) => panic!("expected PUBCOMP on the consumer, {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inbound_qos2() {
+        let (mut client, handle, listener) = start_test_client("qos2-test", None);
+        let consumer = client.consumer();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = BytesMut::new();
+        broker_connack(&mut stream, &mut buffer, false);
+
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::ExactlyOnce);
+        publish.packet_id = Some(5);
+        broker_send(&mut stream, Packet::Publish(publish.clone()));
+        // the retransmission is acknowledged again but not delivered again
+        publish.header.set_dup(true);
+        broker_send(&mut stream, Packet::Publish(publish));
+        for _ in 0..2 {
+            match broker_read(&mut stream, &mut buffer) {
+                Packet::PubRec(pubrec) => assert_eq!(5, pubrec.packet_id),
+                p => panic!("expected PUBREC, found {:?}", p),
+            }
+        }
+        let mut pubrel = PubResp::new_pubrel();
+        pubrel.packet_id = 5;
+        broker_send(&mut stream, Packet::PubRel(pubrel.clone()));
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::PubComp(pubcomp) => {
+                assert_eq!(5, pubcomp.packet_id);
+                assert_eq!(Reason::Success, pubcomp.reason());
+            }
+            p => panic!("expected PUBCOMP, found {:?}", p),
+        }
+        broker_send(&mut stream, Packet::PubRel(pubrel));
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::PubComp(pubcomp) => {
+                assert_eq!(Reason::PacketIdNotFound, pubcomp.reason())
+            }
+            p => panic!("expected PUBCOMP, found {:?}", p),
+        }
+        client.stop();
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Disconnect(_)
+        ));
+        assert!(handle.join().unwrap().is_ok());
+
+        let publishes = consumer
+            .try_iter()
+            .filter(|p| matches!(p, Packet::Publish(_)))
+            .count();
+        assert_eq!(1, publishes);
+    }
+
+    #[test]
+    fn test_resume_without_session() {
+        let (mut client, handle, listener) = start_test_client(
+            "resume-test",
+            Some(
+                ReconnectPolicy::default()
+                    .with_initial_delay(Duration::from_millis(10))
+                    .with_max_attempts(5),
+            ),
+        );
+        let consumer = client.consumer();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = BytesMut::new();
+        broker_connack(&mut stream, &mut buffer, false);
+        client
+            .subscribe(1, &["sensor/temp", "sensor/humidity"], QoSLevel::AtMostOnce)
+            .unwrap();
+        client.unsubscribe(2, &["sensor/humidity"]).unwrap();
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::ExactlyOnce);
+        client.producer().send(Packet::Publish(publish)).unwrap();
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Subscribe(_)
+        ));
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::Unsubscribe(unsubscribe) => {
+                assert_eq!(&["sensor/humidity".to_string()], unsubscribe.filters())
+            }
+            p => panic!("expected UNSUBSCRIBE, found {:?}", p),
+        }
+        let packet_id = match broker_read(&mut stream, &mut buffer) {
+            Packet::Publish(p) => p.packet_id.unwrap(),
+            p => panic!("expected PUBLISH, found {:?}", p),
+        };
+        let mut pubrec = PubResp::new_pubrec();
+        pubrec.packet_id = packet_id;
+        broker_send(&mut stream, Packet::PubRec(pubrec));
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::PubRel(_)
+        ));
+        // drop the connection without completing the publish
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = BytesMut::new();
+        broker_connack(&mut stream, &mut buffer, false);
+        match broker_read(&mut stream, &mut buffer) {
+            Packet::Subscribe(subscribe) => {
+                let filters: Vec<&str> = subscribe
+                    .subscriptions()
+                    .iter()
+                    .map(|sub| sub.filter.as_str())
+                    .collect();
+                assert_eq!(vec!["sensor/temp"], filters);
+            }
+            p => panic!("expected SUBSCRIBE, found {:?}", p),
+        }
+        let pubcomp = next_pubcomp(&consumer);
+        assert_eq!(packet_id, pubcomp.packet_id);
+        assert_eq!(Reason::PacketIdNotFound, pubcomp.reason());
+        client.stop();
+        // the lost QoS 2 packet is neither published nor released again
+        assert!(matches!(
+            broker_read(&mut stream, &mut buffer),
+            Packet::Disconnect(_)
+        ));
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_unsubscribe_invalid_filter() {
+        let mut client = MqttClient::default();
+        assert!(client
+            .subscribe(1, &["sensor/temp", "sensor/+"], QoSLevel::AtMostOnce)
+            .is_ok());
+        match client.unsubscribe(2, &["sensor/#/temp"]) {
+            Err(e) => assert_eq!(ErrorKind::Protocol(Reason::InvalidTopicFilter), e.kind()),
+            Ok(_) => panic!("expected invalid topic filter to be rejected"),
+        }
+        assert_eq!(2, client.subscriptions.len());
+        assert!(client.unsubscribe(2, &["sensor/+"]).is_ok());
+        assert_eq!(1, client.subscriptions.len());
+    }
+}
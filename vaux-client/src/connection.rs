@@ -11,7 +11,7 @@ use crate::{ErrorKind, MqttError};
 const DEFAULT_HOST: &str = "localhost";
 pub const DEFAULT_PORT: u16 = 1883;
 pub const DEFAULT_SECURE_PORT: u16 = 8883;
-const DEFAULT_CONNECTION_TIMEOUT: u64 = 30_000;
+pub(crate) const DEFAULT_CONNECTION_TIMEOUT: u64 = 30_000;
 
 #[derive(Debug)]
 pub struct MqttConnection {
@@ -92,11 +92,20 @@ impl MqttConnection {
     }
 
     pub fn connect_with_timeout(mut self, timeout: Duration) -> crate::Result<Self> {
-        // if not set via with_tls or with_port, set the port to the default
-        if self.port.is_none() {
-            self.port = Some(DEFAULT_PORT);
-        }
-        let addr = self.host.clone() + ":" + &self.port.unwrap().to_string();
+        self.open(timeout)?;
+        Ok(self)
+    }
+
+    /// Opens a new network connection to the broker, replacing the current
+    /// connection, for use when the client reconnects.
+    pub(crate) fn reconnect(&mut self) -> crate::Result<()> {
+        self.tcp_socket = None;
+        self.tls_conn = None;
+        self.open(Duration::from_millis(DEFAULT_CONNECTION_TIMEOUT))
+    }
+
+    fn open(&mut self, timeout: Duration) -> crate::Result<()> {
+        let addr = self.address();
         let socket_addr = addr.to_socket_addrs();
         if let Err(e) = socket_addr {
             return Err(MqttError::new(
@@ -106,38 +115,12 @@ impl MqttConnection {
         }
         let socket_addr = socket_addr.unwrap().next().unwrap();
 
-        if self.tls {
-            if let Some(ca) = self.trusted_ca.clone() {
-                let mut config = rustls::ClientConfig::builder()
-                    .with_safe_defaults()
-                    .with_root_certificates(ca)
-                    .with_no_client_auth();
-                config.key_log = Arc::new(rustls::KeyLogFile::new());
-                #[cfg(feature = "developer")]
-                {
-                    self.verifier = developer::Verifier;
-                    config
-                        .dangerous()
-                        .set_certificate_verifier(Arc::new(self.verifier.clone()));
-                }
-                if let Ok(server_name) = self.host.as_str().try_into() {
-                    if let Ok(c) = rustls::ClientConnection::new(Arc::new(config), server_name) {
-                        self.tls_conn = Some(c);
-                    } else {
-                        return Err(MqttError::new(
-                            "unable to create TLS connection",
-                            ErrorKind::Connection,
-                        ));
-                    }
-                } else {
-                    return Err(MqttError::new(
-                        "unable to convert host to server name",
-                        ErrorKind::Connection,
-                    ));
-                }
+        if let Some((config, server_name)) = self.tls_config()? {
+            if let Ok(c) = rustls::ClientConnection::new(config, server_name) {
+                self.tls_conn = Some(c);
             } else {
                 return Err(MqttError::new(
-                    "no trusted CA(s) provided for TLS connection",
+                    "unable to create TLS connection",
                     ErrorKind::Connection,
                 ));
             }
@@ -146,7 +129,7 @@ impl MqttConnection {
         match TcpStream::connect_timeout(&socket_addr, timeout) {
             Ok(stream) => {
                 self.tcp_socket = Some(stream);
-                Ok(self)
+                Ok(())
             }
             Err(e) => match e.kind() {
                 std::io::ErrorKind::TimedOut => Err(MqttError {
@@ -160,4 +143,52 @@ impl MqttConnection {
             },
         }
     }
+
+    /// Gets the address of the broker, using the default port for the
+    /// transport if no port was set.
+    pub(crate) fn address(&mut self) -> String {
+        // if not set via with_tls or with_port, set the port to the default
+        if self.port.is_none() {
+            self.port = Some(DEFAULT_PORT);
+        }
+        self.host.clone() + ":" + &self.port.unwrap().to_string()
+    }
+
+    /// Gets the TLS client configuration and server name for the broker, or
+    /// None if the connection does not use TLS.
+    pub(crate) fn tls_config(
+        &mut self,
+    ) -> crate::Result<Option<(Arc<rustls::ClientConfig>, rustls::ServerName)>> {
+        if !self.tls {
+            return Ok(None);
+        }
+        let ca = match self.trusted_ca.clone() {
+            Some(ca) => ca,
+            None => {
+                return Err(MqttError::new(
+                    "no trusted CA(s) provided for TLS connection",
+                    ErrorKind::Connection,
+                ))
+            }
+        };
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(ca)
+            .with_no_client_auth();
+        config.key_log = Arc::new(rustls::KeyLogFile::new());
+        #[cfg(feature = "developer")]
+        {
+            self.verifier = developer::Verifier;
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(self.verifier.clone()));
+        }
+        match self.host.as_str().try_into() {
+            Ok(server_name) => Ok(Some((Arc::new(config), server_name))),
+            Err(_) => Err(MqttError::new(
+                "unable to convert host to server name",
+                ErrorKind::Connection,
+            )),
+        }
+    }
 }
@@ -1,12 +1,18 @@
+#[cfg(feature = "async")]
+mod async_client;
 mod client;
 mod connection;
 #[cfg(feature = "developer")]
 mod developer;
+mod reconnect;
 
 use std::fmt::Display;
 
+#[cfg(feature = "async")]
+pub use async_client::{AsyncMqttClient, Messages};
 pub use client::MqttClient;
 pub use connection::MqttConnection;
+pub use reconnect::{ConnectionState, ReconnectPolicy};
 use vaux_mqtt::Reason;
 
 pub type Result<T> = core::result::Result<T, MqttError>;
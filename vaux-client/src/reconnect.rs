@@ -0,0 +1,187 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::MqttError;
+
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_JITTER: f64 = 0.5;
+
+/// Changes to the state of the connection with the broker, reported on the
+/// channel from [`crate::MqttClient::connection_state`].
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    /// The broker accepted the connection. `session_present` is false when
+    /// the broker started a new session, MQTT v5 3.2.2.1.1.
+    Connected { session_present: bool },
+    /// The connection was lost with the error.
+    ConnectionLost(MqttError),
+    /// The client is waiting for the delay before the reconnect attempt,
+    /// attempts are numbered from 1.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The client stopped, either after a DISCONNECT or after giving up on
+    /// reconnecting.
+    Disconnected,
+}
+
+/// Policy for reconnecting to the broker when the connection drops. The delay
+/// before each attempt grows exponentially from the initial delay up to the
+/// maximum delay and is reduced by a random jitter so that clients
+/// disconnected together do not reconnect together.
+///
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use vaux_client::ReconnectPolicy;
+///
+/// let policy = ReconnectPolicy::default()
+///     .with_initial_delay(Duration::from_secs(1))
+///     .with_max_delay(Duration::from_secs(30))
+///     .with_max_attempts(10);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            multiplier: DEFAULT_MULTIPLIER,
+            jitter: DEFAULT_JITTER,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn with_initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sets the factor the delay is multiplied by after each attempt, 2 by
+    /// default.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Sets the fraction, from 0 to 1, of the delay that may be removed at
+    /// random. A jitter of 0 uses the exact delay, the default of 0.5 waits
+    /// between half and all of the delay.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the number of reconnect attempts made before the client gives
+    /// up. The client retries without limit by default.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn initial_delay(&self) -> Duration {
+        self.initial_delay
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    /// Returns true if another attempt may be made after the attempts made so
+    /// far.
+    pub(crate) fn may_retry(&self, attempts: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempts < max)
+    }
+
+    /// Gets the delay before the attempt, without jitter.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exp);
+        if delay.is_finite() && delay < self.max_delay.as_secs_f64() {
+            Duration::from_secs_f64(delay)
+        } else {
+            self.max_delay
+        }
+    }
+
+    /// Gets the delay before the attempt with jitter applied.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        backoff.mul_f64(1.0 - self.jitter * random())
+    }
+}
+
+/// Returns a random value from 0 to 1. The standard library seeds each
+/// `RandomState` at random, which is sufficient for jitter.
+fn random() -> f64 {
+    let value = RandomState::new().build_hasher().finish();
+    (value >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy::default()
+            .with_initial_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_secs(1));
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(Duration::from_millis(800), policy.backoff(4));
+        assert_eq!(Duration::from_secs(1), policy.backoff(5));
+        assert_eq!(Duration::from_secs(1), policy.backoff(u32::MAX));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = ReconnectPolicy::default().with_initial_delay(Duration::from_secs(1));
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+        }
+        let policy = policy.with_jitter(0.0);
+        assert_eq!(Duration::from_secs(1), policy.delay(1));
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = ReconnectPolicy::default();
+        assert!(policy.may_retry(u32::MAX - 1));
+        let policy = policy.with_max_attempts(2);
+        assert!(policy.may_retry(0));
+        assert!(policy.may_retry(1));
+        assert!(!policy.may_retry(2));
+    }
+}
@@ -0,0 +1,298 @@
+//! Codec benchmarks. Run with `cargo bench -p vaux-mqtt`, or select a group
+//! with a filter such as `cargo bench -p vaux-mqtt -- decode/publish`.
+//!
+//! The `decode` groups measure the owned decoder, `decode`, next to the
+//! borrowed decoder, `PacketRef::decode`, for the same encoded frame.
+
+use bytes::BytesMut;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use vaux_mqtt::{
+    codec::{get_var_u32, put_var_u32},
+    decode, encode, encode_slice,
+    packet_ref::PacketRef,
+    property::{PayloadFormat, Property, PropertyBundle},
+    publish::Publish,
+    ConnAck, Connect, Decode, Disconnect, Encode, FixedHeader, Packet, PacketType, PubResp,
+    QoSLevel, Reason, SubAck, Subscribe, Subscription, WillMessage,
+};
+
+const PAYLOAD_SIZES: [usize; 5] = [0, 64, 1024, 16 * 1024, 256 * 1024];
+const USER_PROPERTIES: [usize; 4] = [0, 1, 8, 32];
+const VAR_INTS: [u32; 4] = [127, 16_383, 2_097_151, 268_435_455];
+
+fn publish(payload_size: usize) -> Packet {
+    Packet::Publish(
+        Publish::builder("vaux/bench/sensor/temp")
+            .with_qos(QoSLevel::AtLeastOnce)
+            .with_packet_id(42)
+            .with_payload(vec![0xa5_u8; payload_size])
+            .with_payload_format(PayloadFormat::Bin)
+            .with_message_expiry(60)
+            .with_content_type("application/octet-stream")
+            .build()
+            .unwrap(),
+    )
+}
+
+fn connect() -> Packet {
+    let will = WillMessage::builder("vaux/bench/status", "offline")
+        .with_qos(QoSLevel::AtLeastOnce)
+        .with_will_delay(10)
+        .build()
+        .unwrap();
+    Packet::Connect(Box::new(
+        Connect::builder("vaux-bench-client")
+            .with_keep_alive(60)
+            .with_will(will)
+            .with_username("vaux")
+            .with_password("secret")
+            .with_session_expiry(3600)
+            .with_recv_max(128)
+            .with_user_property("region", "us-east")
+            .build()
+            .unwrap(),
+    ))
+}
+
+fn connack() -> Packet {
+    let mut connack = ConnAck::default();
+    connack.set_reason(Reason::Success);
+    let props = connack.properties_mut();
+    props.set_property(Property::AssignedClientId("vaux-bench-client".to_string()));
+    props.set_property(Property::TopicAliasMax(16));
+    props.set_property(Property::RetainAvail(true));
+    Packet::ConnAck(connack)
+}
+
+fn puback() -> Packet {
+    let mut puback = PubResp::new_puback();
+    puback.packet_id = 42;
+    Packet::PubAck(puback)
+}
+
+fn subscribe() -> Packet {
+    Packet::Subscribe(
+        Subscribe::builder(7)
+            .with_filter("vaux/bench/+/temp", QoSLevel::AtLeastOnce)
+            .with_filter("vaux/bench/#", QoSLevel::AtMostOnce)
+            .with_subscription(Subscription::new(
+                "vaux/bench/status".to_string(),
+                QoSLevel::ExactlyOnce,
+            ))
+            .with_subscription_id(1)
+            .build()
+            .unwrap(),
+    )
+}
+
+fn suback() -> Packet {
+    let mut suback = SubAck::new(7);
+    suback.add_reason(Reason::GrantedQoS1);
+    suback.add_reason(Reason::GrantedQoS0);
+    suback.add_reason(Reason::GrantedQoS2);
+    Packet::SubAck(suback)
+}
+
+fn disconnect() -> Packet {
+    Packet::Disconnect(
+        Disconnect::builder(Reason::NormalDisconnect)
+            .with_reason_string("bench complete")
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Packets of each type other than PUBLISH, which is measured across
+/// payload sizes.
+fn packets() -> Vec<(&'static str, Packet)> {
+    vec![
+        (
+            "pingreq",
+            Packet::PingRequest(FixedHeader::new(PacketType::PingReq)),
+        ),
+        ("connect", connect()),
+        ("connack", connack()),
+        ("puback", puback()),
+        ("subscribe", subscribe()),
+        ("suback", suback()),
+        ("disconnect", disconnect()),
+    ]
+}
+
+fn to_bytes(packet: &Packet) -> BytesMut {
+    let mut buf = BytesMut::new();
+    encode(packet.clone(), &mut buf).unwrap();
+    buf
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, packet) in packets() {
+        let len = to_bytes(&packet).len();
+        group.throughput(Throughput::Bytes(len as u64));
+        let mut buf = BytesMut::with_capacity(len);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || packet.clone(),
+                |packet| {
+                    buf.clear();
+                    encode(packet, &mut buf).unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("encode/publish");
+    for size in PAYLOAD_SIZES {
+        let packet = publish(size);
+        let len = to_bytes(&packet).len();
+        group.throughput(Throughput::Bytes(len as u64));
+        let mut buf = BytesMut::with_capacity(len);
+        group.bench_with_input(BenchmarkId::new("bytes_mut", size), &packet, |b, packet| {
+            b.iter_batched(
+                || packet.clone(),
+                |packet| {
+                    buf.clear();
+                    encode(packet, &mut buf).unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        let mut slice = vec![0_u8; len];
+        group.bench_with_input(BenchmarkId::new("slice", size), &packet, |b, packet| {
+            b.iter_batched(
+                || packet.clone(),
+                |packet| encode_slice(packet, &mut slice).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        let encoded = to_bytes(&packet);
+        let (packet_ref, _) = PacketRef::decode(&encoded).unwrap().unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("borrowed", size),
+            &packet_ref,
+            |b, packet_ref| b.iter(|| packet_ref.encode_slice(black_box(&mut slice)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+/// Measures the owned and the borrowed decoder on the same frame. The owned
+/// decoder consumes its buffer, so each iteration is given a fresh copy that
+/// is made outside of the measurement.
+fn bench_decode_pair(
+    group: &mut criterion::BenchmarkGroup<'_, criterion::measurement::WallTime>,
+    id: &dyn Fn(&str) -> BenchmarkId,
+    encoded: &BytesMut,
+) {
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function(id("owned"), |b| {
+        b.iter_batched(
+            || BytesMut::from(&encoded[..]),
+            |mut src| decode(&mut src).unwrap().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function(id("borrowed"), |b| {
+        b.iter(|| PacketRef::decode(black_box(encoded)).unwrap().unwrap())
+    });
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, packet) in packets() {
+        let encoded = to_bytes(&packet);
+        bench_decode_pair(&mut group, &|path| BenchmarkId::new(path, name), &encoded);
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode/publish");
+    for size in PAYLOAD_SIZES {
+        let encoded = to_bytes(&publish(size));
+        bench_decode_pair(&mut group, &|path| BenchmarkId::new(path, size), &encoded);
+    }
+    group.finish();
+}
+
+/// Properties of a PUBLISH with the number of user properties.
+fn property_bundle(user_properties: usize) -> PropertyBundle {
+    let mut publish = Publish::default();
+    let props = publish.properties_mut();
+    props.set_property(Property::PayloadFormat(PayloadFormat::Utf8));
+    props.set_property(Property::MessageExpiry(60));
+    props.set_property(Property::ContentType("application/json".to_string()));
+    props.set_property(Property::ResponseTopic("vaux/bench/response".to_string()));
+    for idx in 0..user_properties {
+        props.add_user_property(format!("key-{}", idx), format!("value-{}", idx));
+    }
+    props.clone()
+}
+
+fn bench_properties(c: &mut Criterion) {
+    let mut group = c.benchmark_group("properties");
+    for count in USER_PROPERTIES {
+        let bundle = property_bundle(count);
+        let mut encoded = BytesMut::new();
+        bundle.encode(&mut encoded).unwrap();
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+        let mut buf = BytesMut::with_capacity(encoded.len());
+        group.bench_with_input(BenchmarkId::new("encode", count), &bundle, |b, bundle| {
+            b.iter(|| {
+                buf.clear();
+                black_box(bundle).encode(&mut buf).unwrap();
+            })
+        });
+
+        let empty = Publish::default().properties().clone();
+        group.bench_with_input(BenchmarkId::new("decode", count), &encoded, |b, encoded| {
+            b.iter_batched(
+                || (empty.clone(), BytesMut::from(&encoded[..])),
+                |(mut bundle, mut src)| {
+                    bundle.decode(&mut src).unwrap();
+                    bundle
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_var_int(c: &mut Criterion) {
+    let mut group = c.benchmark_group("var_int");
+    for (idx, value) in VAR_INTS.into_iter().enumerate() {
+        let len = idx + 1;
+        let mut buf = BytesMut::with_capacity(len);
+        group.bench_with_input(BenchmarkId::new("put", len), &value, |b, value| {
+            b.iter(|| {
+                buf.clear();
+                put_var_u32(black_box(*value), &mut buf);
+            })
+        });
+
+        let mut encoded = BytesMut::new();
+        put_var_u32(value, &mut encoded);
+        group.bench_with_input(BenchmarkId::new("get", len), &encoded, |b, encoded| {
+            b.iter_batched(
+                || BytesMut::from(&encoded[..]),
+                |mut src| get_var_u32(&mut src).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_encode,
+    bench_decode,
+    bench_properties,
+    bench_var_int
+);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use vaux_mqtt::{decode_with_version, encode_with_version, packet_ref::PacketRef, ProtocolVersion};
+
+fuzz_target!(|data: &[u8]| {
+    for version in [ProtocolVersion::V5, ProtocolVersion::V3_1_1] {
+        let mut src = BytesMut::from(data);
+        if let Ok(Some((packet, _))) = decode_with_version(&mut src, version) {
+            // a decoded packet must encode without panicking
+            let mut dest = BytesMut::new();
+            let _ = encode_with_version(packet, &mut dest, version);
+        }
+    }
+    let _ = PacketRef::decode(data);
+});
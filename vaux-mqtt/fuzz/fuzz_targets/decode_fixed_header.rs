@@ -0,0 +1,9 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use vaux_mqtt::decode_fixed_header;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_fixed_header(&mut BytesMut::from(data));
+});
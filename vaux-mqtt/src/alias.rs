@@ -0,0 +1,464 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+
+use crate::property::Property;
+use crate::publish::Publish;
+use crate::{PropertyType, Reason};
+
+/// Topic alias mappings for a single network connection, used by both clients
+/// and servers. Topic aliases are scoped to the connection, not the session,
+/// and are discarded when the connection closes as required by MQTT v5
+/// 3.3.2.3.4.
+#[derive(Debug, Default)]
+pub struct TopicAliases {
+    inbound: InboundAliases,
+    outbound: OutboundAliases,
+}
+
+impl TopicAliases {
+    /// Creates the alias mappings for a connection. The inbound maximum is the
+    /// topic alias maximum this side of the connection advertised, in CONNECT
+    /// for a client or CONNACK for a server. The outbound maximum is the topic
+    /// alias maximum advertised by the other side.
+    pub fn new(inbound_max: u16, outbound_max: u16) -> Self {
+        Self {
+            inbound: InboundAliases::new(inbound_max),
+            outbound: OutboundAliases::new(outbound_max),
+        }
+    }
+
+    /// Resolves the topic name for a received publish. See
+    /// [`InboundAliases::resolve`].
+    pub fn resolve_inbound(&mut self, publish: &mut Publish) -> Result<(), Reason> {
+        self.inbound.resolve(publish)
+    }
+
+    /// Resolves the topic name of a received publish from its borrowed
+    /// fields. See [`InboundAliases::resolve_topic`].
+    pub fn resolve_inbound_topic<'a>(
+        &'a mut self,
+        topic: Option<&'a str>,
+        alias: Option<u16>,
+    ) -> Result<&'a str, Reason> {
+        self.inbound.resolve_topic(topic, alias)
+    }
+
+    /// Applies a topic alias to a publish to be sent. See
+    /// [`OutboundAliases::apply`].
+    pub fn apply_outbound(&mut self, publish: &mut Publish) {
+        self.outbound.apply(publish)
+    }
+
+    /// Forgets the alias applied to a publish that was not sent. See
+    /// [`OutboundAliases::discard`].
+    pub fn discard_outbound(&mut self, publish: &Publish) {
+        self.outbound.discard(publish)
+    }
+}
+
+/// Topic aliases set by the other side of the connection on received publish
+/// packets.
+#[derive(Debug, Default)]
+pub struct InboundAliases {
+    max: u16,
+    topics: BTreeMap<u16, String>,
+}
+
+impl InboundAliases {
+    pub fn new(max: u16) -> Self {
+        Self {
+            max,
+            topics: BTreeMap::new(),
+        }
+    }
+
+    /// Resolves the topic name for a received publish. A publish with both a
+    /// topic name and a topic alias sets the mapping for the alias and a
+    /// publish with only a topic alias takes the topic name from the existing
+    /// mapping. The topic alias is removed from the publish so that the
+    /// publish carries the full topic name wherever it is delivered.
+    ///
+    /// Returns the reason code for the DISCONNECT if the alias is 0, exceeds
+    /// the topic alias maximum or has not been mapped, or if the publish has
+    /// neither a topic name nor a topic alias.
+    pub fn resolve(&mut self, publish: &mut Publish) -> Result<(), Reason> {
+        let alias = match publish.properties().get_property(&PropertyType::TopicAlias) {
+            Some(Property::TopicAlias(alias)) => *alias,
+            _ if publish.topic_name.is_none() => return Err(Reason::ProtocolErr),
+            _ => return Ok(()),
+        };
+        if alias == 0 || alias > self.max {
+            return Err(Reason::InvalidTopicAlias);
+        }
+        match &publish.topic_name {
+            Some(topic) => {
+                self.topics.insert(alias, topic.clone());
+            }
+            None => match self.topics.get(&alias) {
+                Some(topic) => publish.topic_name = Some(topic.clone()),
+                None => return Err(Reason::InvalidTopicAlias),
+            },
+        }
+        publish
+            .properties_mut()
+            .clear_property(&PropertyType::TopicAlias);
+        Ok(())
+    }
+
+    /// Resolves the topic name of a received publish from the topic name and
+    /// topic alias read from the packet, without changing the packet. The
+    /// rules and errors are those of [`InboundAliases::resolve`].
+    pub fn resolve_topic<'a>(
+        &'a mut self,
+        topic: Option<&'a str>,
+        alias: Option<u16>,
+    ) -> Result<&'a str, Reason> {
+        let alias = match alias {
+            Some(alias) => alias,
+            None => return topic.ok_or(Reason::ProtocolErr),
+        };
+        if alias == 0 || alias > self.max {
+            return Err(Reason::InvalidTopicAlias);
+        }
+        match topic {
+            Some(topic) => {
+                self.topics.insert(alias, topic.into());
+                Ok(topic)
+            }
+            None => self
+                .topics
+                .get(&alias)
+                .map(String::as_str)
+                .ok_or(Reason::InvalidTopicAlias),
+        }
+    }
+}
+
+/// Topic aliases allocated for publish packets sent on the connection. Once
+/// every alias up to the topic alias maximum of the other side is in use the
+/// least recently used alias is reassigned to the new topic. Aliases set by
+/// the application are recorded and are never allocated or reassigned.
+#[derive(Debug, Default)]
+pub struct OutboundAliases {
+    max: u16,
+    /// alias and last use for each aliased topic
+    aliases: BTreeMap<String, (u16, u64)>,
+    /// aliases set by the application, with the topic when it was sent
+    preset: BTreeMap<u16, Option<String>>,
+    uses: u64,
+}
+
+impl OutboundAliases {
+    pub fn new(max: u16) -> Self {
+        Self {
+            max,
+            aliases: BTreeMap::new(),
+            preset: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Applies a topic alias to a publish to be sent. The first publish to a
+    /// topic carries both the topic name and the newly allocated alias, later
+    /// publishes to the topic carry only the alias. No alias is applied if the
+    /// other side does not accept topic aliases.
+    ///
+    /// A topic alias already set by the application is kept and recorded so
+    /// that it is not allocated to another topic. A preset alias of 0 or
+    /// above the topic alias maximum of the other side is removed, which
+    /// leaves a publish without a topic name that cannot be encoded.
+    pub fn apply(&mut self, publish: &mut Publish) {
+        if let Some(Property::TopicAlias(alias)) =
+            publish.properties().get_property(&PropertyType::TopicAlias)
+        {
+            let alias = *alias;
+            if alias == 0 || alias > self.max {
+                publish
+                    .properties_mut()
+                    .clear_property(&PropertyType::TopicAlias);
+            } else {
+                self.preset_alias(alias, publish.topic_name.as_ref());
+                return;
+            }
+        }
+        if self.max == 0 {
+            return;
+        }
+        let topic = match &publish.topic_name {
+            Some(topic) => topic,
+            None => return,
+        };
+        self.uses += 1;
+        if let Some((alias, last_use)) = self.aliases.get_mut(topic) {
+            *last_use = self.uses;
+            let alias = *alias;
+            publish.topic_name = None;
+            publish
+                .properties_mut()
+                .set_property(Property::TopicAlias(alias));
+            return;
+        }
+        let alias = match self.free_alias().or_else(|| self.evict()) {
+            Some(alias) => alias,
+            // every alias is set by the application
+            None => return,
+        };
+        self.aliases.insert(topic.clone(), (alias, self.uses));
+        publish
+            .properties_mut()
+            .set_property(Property::TopicAlias(alias));
+    }
+
+    /// Forgets the alias allocated for a publish that was not sent, so that
+    /// the next publish to the topic sets the alias again.
+    pub fn discard(&mut self, publish: &Publish) {
+        let alias = match publish.properties().get_property(&PropertyType::TopicAlias) {
+            Some(Property::TopicAlias(alias)) => *alias,
+            _ => return,
+        };
+        if let Some(topic) = &publish.topic_name {
+            if self.aliases.get(topic).is_some_and(|(a, _)| *a == alias) {
+                self.aliases.remove(topic);
+            }
+        }
+    }
+
+    /// Records an alias set by the application, taking it from any topic it
+    /// was allocated to.
+    fn preset_alias(&mut self, alias: u16, topic: Option<&String>) {
+        self.aliases.retain(|_, (allocated, _)| *allocated != alias);
+        match topic {
+            Some(topic) => {
+                self.preset.insert(alias, Some(topic.clone()));
+            }
+            None => {
+                self.preset.entry(alias).or_insert(None);
+            }
+        }
+    }
+
+    /// Gets the lowest alias that is neither allocated nor set by the
+    /// application.
+    fn free_alias(&self) -> Option<u16> {
+        if self.aliases.len() + self.preset.len() >= self.max as usize {
+            return None;
+        }
+        let used: BTreeSet<u16> = self
+            .aliases
+            .values()
+            .map(|(alias, _)| *alias)
+            .chain(self.preset.keys().copied())
+            .collect();
+        (1..=self.max).find(|alias| !used.contains(alias))
+    }
+
+    /// Removes the least recently used topic, returning its alias for reuse.
+    fn evict(&mut self) -> Option<u16> {
+        let topic = self
+            .aliases
+            .iter()
+            .min_by_key(|(_, (_, last_use))| *last_use)
+            .map(|(topic, _)| topic.clone())?;
+        self.aliases.remove(&topic).map(|(alias, _)| alias)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    fn publish(topic: Option<&str>, alias: Option<u16>) -> Publish {
+        let mut publish = Publish::default();
+        publish.topic_name = topic.map(|t| t.to_string());
+        if let Some(alias) = alias {
+            publish
+                .properties_mut()
+                .set_property(Property::TopicAlias(alias));
+        }
+        publish
+    }
+
+    fn alias(publish: &Publish) -> Option<u16> {
+        match publish.properties().get_property(&PropertyType::TopicAlias) {
+            Some(Property::TopicAlias(alias)) => Some(*alias),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_inbound_resolve() {
+        let mut aliases = InboundAliases::new(10);
+        let mut first = publish(Some("sensor/temp"), Some(1));
+        assert_eq!(Ok(()), aliases.resolve(&mut first));
+        assert_eq!(None, alias(&first));
+        let mut second = publish(None, Some(1));
+        assert_eq!(Ok(()), aliases.resolve(&mut second));
+        assert_eq!(Some("sensor/temp"), second.topic_name.as_deref());
+        assert_eq!(None, alias(&second));
+        // an alias may be remapped to a different topic
+        let mut remap = publish(Some("sensor/humidity"), Some(1));
+        assert_eq!(Ok(()), aliases.resolve(&mut remap));
+        let mut third = publish(None, Some(1));
+        assert_eq!(Ok(()), aliases.resolve(&mut third));
+        assert_eq!(Some("sensor/humidity"), third.topic_name.as_deref());
+    }
+
+    #[test]
+    fn test_inbound_resolve_topic() {
+        let mut aliases = InboundAliases::new(10);
+        assert_eq!(
+            Ok("sensor/temp"),
+            aliases.resolve_topic(Some("sensor/temp"), None)
+        );
+        assert_eq!(
+            Ok("sensor/temp"),
+            aliases.resolve_topic(Some("sensor/temp"), Some(1))
+        );
+        assert_eq!(Ok("sensor/temp"), aliases.resolve_topic(None, Some(1)));
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            aliases.resolve_topic(None, Some(2))
+        );
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            aliases.resolve_topic(Some("sensor/temp"), Some(11))
+        );
+        assert_eq!(Err(Reason::ProtocolErr), aliases.resolve_topic(None, None));
+    }
+
+    #[test]
+    fn test_inbound_invalid() {
+        let mut aliases = InboundAliases::new(10);
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            aliases.resolve(&mut publish(Some("sensor/temp"), Some(0)))
+        );
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            aliases.resolve(&mut publish(Some("sensor/temp"), Some(11)))
+        );
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            aliases.resolve(&mut publish(None, Some(2)))
+        );
+        assert_eq!(
+            Err(Reason::ProtocolErr),
+            aliases.resolve(&mut publish(None, None))
+        );
+        let mut disabled = InboundAliases::new(0);
+        assert_eq!(
+            Err(Reason::InvalidTopicAlias),
+            disabled.resolve(&mut publish(Some("sensor/temp"), Some(1)))
+        );
+    }
+
+    #[test]
+    fn test_outbound_apply() {
+        let mut aliases = OutboundAliases::new(2);
+        let mut first = publish(Some("a"), None);
+        aliases.apply(&mut first);
+        assert_eq!(Some("a"), first.topic_name.as_deref());
+        assert_eq!(Some(1), alias(&first));
+        let mut second = publish(Some("a"), None);
+        aliases.apply(&mut second);
+        assert_eq!(None, second.topic_name);
+        assert_eq!(Some(1), alias(&second));
+        let mut other = publish(Some("b"), None);
+        aliases.apply(&mut other);
+        assert_eq!(Some(2), alias(&other));
+    }
+
+    #[test]
+    fn test_outbound_evict_least_recent() {
+        let mut aliases = OutboundAliases::new(2);
+        aliases.apply(&mut publish(Some("a"), None));
+        aliases.apply(&mut publish(Some("b"), None));
+        aliases.apply(&mut publish(Some("a"), None));
+        // "b" is the least recently used topic and gives up its alias
+        let mut evicting = publish(Some("c"), None);
+        aliases.apply(&mut evicting);
+        assert_eq!(Some("c"), evicting.topic_name.as_deref());
+        assert_eq!(Some(2), alias(&evicting));
+        let mut reused = publish(Some("a"), None);
+        aliases.apply(&mut reused);
+        assert_eq!(None, reused.topic_name);
+        assert_eq!(Some(1), alias(&reused));
+        let mut restored = publish(Some("b"), None);
+        aliases.apply(&mut restored);
+        assert_eq!(Some("b"), restored.topic_name.as_deref());
+    }
+
+    #[test]
+    fn test_outbound_preset_alias() {
+        let mut aliases = OutboundAliases::new(2);
+        let mut preset = publish(Some("a"), Some(2));
+        aliases.apply(&mut preset);
+        assert_eq!(Some("a"), preset.topic_name.as_deref());
+        assert_eq!(Some(2), alias(&preset));
+        let mut first = publish(Some("a"), None);
+        aliases.apply(&mut first);
+        assert_eq!(Some(1), alias(&first));
+    }
+
+    #[test]
+    fn test_outbound_preset_not_allocated() {
+        let mut aliases = OutboundAliases::new(2);
+        aliases.apply(&mut publish(Some("a"), Some(1)));
+        // the preset alias is skipped when allocating
+        let mut first = publish(Some("b"), None);
+        aliases.apply(&mut first);
+        assert_eq!(Some(2), alias(&first));
+        // and is not evicted once every alias is in use
+        let mut second = publish(Some("c"), None);
+        aliases.apply(&mut second);
+        assert_eq!(Some(2), alias(&second));
+        let mut preset = publish(None, Some(1));
+        aliases.apply(&mut preset);
+        assert_eq!(Some(1), alias(&preset));
+        // a preset alias takes the alias from an allocated topic
+        aliases.apply(&mut publish(Some("d"), Some(2)));
+        let mut reallocated = publish(Some("c"), None);
+        aliases.apply(&mut reallocated);
+        assert_eq!(Some("c"), reallocated.topic_name.as_deref());
+        assert_eq!(None, alias(&reallocated));
+    }
+
+    #[test]
+    fn test_outbound_preset_above_max() {
+        let mut aliases = OutboundAliases::new(2);
+        let mut preset = publish(Some("a"), Some(3));
+        aliases.apply(&mut preset);
+        assert_eq!(Some("a"), preset.topic_name.as_deref());
+        assert_eq!(Some(1), alias(&preset));
+        let mut alias_only = publish(None, Some(3));
+        aliases.apply(&mut alias_only);
+        assert_eq!(None, alias(&alias_only));
+        let mut disabled = OutboundAliases::new(0);
+        let mut preset = publish(Some("a"), Some(1));
+        disabled.apply(&mut preset);
+        assert_eq!(None, alias(&preset));
+    }
+
+    #[test]
+    fn test_outbound_discard() {
+        let mut aliases = OutboundAliases::new(2);
+        let mut dropped = publish(Some("a"), None);
+        aliases.apply(&mut dropped);
+        aliases.discard(&dropped);
+        let mut next = publish(Some("a"), None);
+        aliases.apply(&mut next);
+        assert_eq!(Some("a"), next.topic_name.as_deref());
+        assert_eq!(Some(1), alias(&next));
+    }
+
+    #[test]
+    fn test_outbound_disabled() {
+        let mut aliases = OutboundAliases::new(0);
+        let mut publish = publish(Some("a"), None);
+        aliases.apply(&mut publish);
+        assert_eq!(Some("a"), publish.topic_name.as_deref());
+        assert_eq!(None, alias(&publish));
+    }
+}
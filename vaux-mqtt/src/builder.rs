@@ -0,0 +1,695 @@
+//! Builders for the packets a client sends. Each builder sets the packet
+//! fields and properties in a single expression and checks the packet at
+//! `build()`, returning the same errors the `pedantic` decoder reports for a
+//! packet received with the same fields.
+//!
+//! ```
+//! use vaux_mqtt::{publish::Publish, QoSLevel};
+//!
+//! let publish = Publish::builder("sensor/temp")
+//!     .with_qos(QoSLevel::AtLeastOnce)
+//!     .with_packet_id(1)
+//!     .with_payload("21.5")
+//!     .with_message_expiry(60)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(Some(60), publish.properties().message_expiry());
+//! ```
+
+use alloc::{format, string::String, vec::Vec};
+use bytes::Bytes;
+
+use crate::{
+    codec::ErrorKind,
+    property::{PacketProperties, PayloadFormat, Property, PropertyBundle},
+    publish::Publish,
+    validate::{
+        validate_publish, validate_subscribe, validate_unsubscribe, validate_utf8, validate_will,
+    },
+    Connect, Disconnect, MqttCodecError, ProtocolVersion, QoSLevel, Reason, Subscribe,
+    Subscription, Unsubscribe, WillMessage,
+};
+
+/// Sets the property unless it is not permitted in the packet, in which case
+/// the first such property is reported by `build()`.
+fn set_property(props: &mut PropertyBundle, error: &mut Option<MqttCodecError>, prop: Property) {
+    let prop_type = (&prop).into();
+    if props.supports_property(&prop_type) {
+        props.set_property(prop);
+    } else if error.is_none() {
+        *error = Some(MqttCodecError::new_with_kind(
+            &format!("MQTTv5 2.2.2.2 property {} not permitted", prop_type),
+            ErrorKind::PropertyNotPermitted(prop_type),
+        ));
+    }
+}
+
+/// Checks the UTF-8 string properties, which are not checked by the packet
+/// specific rules.
+fn validate_properties(props: &PropertyBundle) -> Result<(), MqttCodecError> {
+    for prop in props {
+        match prop {
+            Property::ContentType(value)
+            | Property::ResponseTopic(value)
+            | Property::AssignedClientId(value)
+            | Property::AuthMethod(value)
+            | Property::RespInfo(value)
+            | Property::ServerReference(value)
+            | Property::ReasonString(value) => validate_utf8(value)?,
+            Property::UserProperty(key, value) => {
+                validate_utf8(key)?;
+                validate_utf8(value)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn protocol_err(message: &str) -> MqttCodecError {
+    MqttCodecError::new_with_kind(message, ErrorKind::ProtocolErr)
+}
+
+/// Builder for a CONNECT, created with [`Connect::builder`].
+#[derive(Debug)]
+pub struct ConnectBuilder {
+    connect: Connect,
+    error: Option<MqttCodecError>,
+}
+
+impl ConnectBuilder {
+    pub(crate) fn new(client_id: impl Into<String>) -> Self {
+        let mut connect = Connect::default();
+        connect.client_id = client_id.into();
+        Self {
+            connect,
+            error: None,
+        }
+    }
+
+    pub fn with_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.connect.protocol_version = version;
+        self
+    }
+
+    pub fn with_clean_start(mut self, clean_start: bool) -> Self {
+        self.connect.clean_start = clean_start;
+        self
+    }
+
+    /// Sets the keep alive in seconds, 0 turns off the keep alive.
+    pub fn with_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.connect.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_will(mut self, will: WillMessage) -> Self {
+        self.connect.will_message = Some(will);
+        self
+    }
+
+    pub fn with_username(mut self, username: impl Into<String>) -> Self {
+        self.connect.username = Some(username.into());
+        self
+    }
+
+    pub fn with_password(mut self, password: impl Into<Vec<u8>>) -> Self {
+        self.connect.password = Some(password.into());
+        self
+    }
+
+    pub fn with_session_expiry(self, seconds: u32) -> Self {
+        self.with_property(Property::SessionExpiryInterval(seconds))
+    }
+
+    pub fn with_recv_max(self, recv_max: u16) -> Self {
+        self.with_property(Property::RecvMax(recv_max))
+    }
+
+    pub fn with_max_packet_size(self, max_packet_size: u32) -> Self {
+        self.with_property(Property::MaxPacketSize(max_packet_size))
+    }
+
+    pub fn with_topic_alias_max(self, topic_alias_max: u16) -> Self {
+        self.with_property(Property::TopicAliasMax(topic_alias_max))
+    }
+
+    pub fn with_req_resp_info(self, req_resp_info: bool) -> Self {
+        self.with_property(Property::ReqRespInfo(req_resp_info))
+    }
+
+    pub fn with_req_problem_info(self, req_problem_info: bool) -> Self {
+        self.with_property(Property::ReqProblemInfo(req_problem_info))
+    }
+
+    pub fn with_auth_method(self, method: impl Into<String>) -> Self {
+        self.with_property(Property::AuthMethod(method.into()))
+    }
+
+    pub fn with_auth_data(self, data: impl Into<Bytes>) -> Self {
+        self.with_property(Property::AuthData(data.into()))
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(self.connect.properties_mut(), &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<Connect, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let connect = self.connect;
+        validate_utf8(&connect.client_id)?;
+        if let Some(username) = &connect.username {
+            validate_utf8(username)?;
+        }
+        if connect.protocol_version == ProtocolVersion::V3_1_1
+            && connect.password.is_some()
+            && connect.username.is_none()
+        {
+            return Err(protocol_err(
+                "MQTTv3.1.1 3.1.2.9 password must not be set without a username",
+            ));
+        }
+        let props = connect.properties();
+        if props.recv_max() == Some(0) {
+            return Err(protocol_err(
+                "MQTTv5 3.1.2.11.3 receive maximum must not be 0",
+            ));
+        }
+        if props.max_packet_size() == Some(0) {
+            return Err(protocol_err(
+                "MQTTv5 3.1.2.11.4 maximum packet size must not be 0",
+            ));
+        }
+        if props.auth_data().is_some() && props.auth_method().is_none() {
+            return Err(protocol_err(
+                "MQTTv5 3.1.2.11.10 authentication data requires an authentication method",
+            ));
+        }
+        validate_properties(props)?;
+        if let Some(will) = &connect.will_message {
+            validate_will(will)?;
+        }
+        Ok(connect)
+    }
+}
+
+/// Builder for a will message, created with [`WillMessage::builder`].
+#[derive(Debug)]
+pub struct WillBuilder {
+    will: WillMessage,
+    error: Option<MqttCodecError>,
+}
+
+impl WillBuilder {
+    pub(crate) fn new(topic: impl Into<String>, payload: impl Into<Bytes>) -> Self {
+        let mut will = WillMessage::new(QoSLevel::AtMostOnce, false);
+        will.topic = topic.into();
+        will.payload = payload.into();
+        Self { will, error: None }
+    }
+
+    pub fn with_qos(mut self, qos: QoSLevel) -> Self {
+        self.will.qos = qos;
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.will.retain = retain;
+        self
+    }
+
+    /// Sets the delay in seconds before the server publishes the will message.
+    pub fn with_will_delay(self, seconds: u32) -> Self {
+        self.with_property(Property::WillDelay(seconds))
+    }
+
+    pub fn with_payload_format(self, format: PayloadFormat) -> Self {
+        self.with_property(Property::PayloadFormat(format))
+    }
+
+    pub fn with_message_expiry(self, seconds: u32) -> Self {
+        self.with_property(Property::MessageExpiry(seconds))
+    }
+
+    pub fn with_content_type(self, content_type: impl Into<String>) -> Self {
+        self.with_property(Property::ContentType(content_type.into()))
+    }
+
+    pub fn with_response_topic(self, topic: impl Into<String>) -> Self {
+        self.with_property(Property::ResponseTopic(topic.into()))
+    }
+
+    pub fn with_correlation_data(self, data: impl Into<Bytes>) -> Self {
+        self.with_property(Property::CorrelationData(data.into()))
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(&mut self.will.props, &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<WillMessage, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        validate_will(&self.will)?;
+        validate_properties(&self.will.props)?;
+        Ok(self.will)
+    }
+}
+
+/// Builder for a PUBLISH, created with [`Publish::builder`].
+#[derive(Debug)]
+pub struct PublishBuilder {
+    publish: Publish,
+    error: Option<MqttCodecError>,
+}
+
+impl PublishBuilder {
+    pub(crate) fn new(topic_name: impl Into<String>) -> Self {
+        let mut publish = Publish::default();
+        publish.topic_name = Some(topic_name.into());
+        Self {
+            publish,
+            error: None,
+        }
+    }
+
+    /// Sets the QoS level. A QoS level above [`QoSLevel::AtMostOnce`] requires
+    /// a packet identifier.
+    pub fn with_qos(mut self, qos: QoSLevel) -> Self {
+        self.publish.set_qos(qos);
+        self
+    }
+
+    pub fn with_packet_id(mut self, packet_id: u16) -> Self {
+        self.publish.packet_id = Some(packet_id);
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.publish.header.set_retain(retain);
+        self
+    }
+
+    pub fn with_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.publish.set_payload(payload);
+        self
+    }
+
+    /// Sets the topic alias. Where the topic name given to the builder is
+    /// empty the PUBLISH is sent with the topic alias only, MQTT v5 3.3.2.3.4.
+    pub fn with_topic_alias(self, alias: u16) -> Self {
+        self.with_property(Property::TopicAlias(alias))
+    }
+
+    pub fn with_payload_format(self, format: PayloadFormat) -> Self {
+        self.with_property(Property::PayloadFormat(format))
+    }
+
+    pub fn with_message_expiry(self, seconds: u32) -> Self {
+        self.with_property(Property::MessageExpiry(seconds))
+    }
+
+    pub fn with_content_type(self, content_type: impl Into<String>) -> Self {
+        self.with_property(Property::ContentType(content_type.into()))
+    }
+
+    pub fn with_response_topic(self, topic: impl Into<String>) -> Self {
+        self.with_property(Property::ResponseTopic(topic.into()))
+    }
+
+    pub fn with_correlation_data(self, data: impl Into<Bytes>) -> Self {
+        self.with_property(Property::CorrelationData(data.into()))
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(self.publish.properties_mut(), &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<Publish, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let mut publish = self.publish;
+        if publish.topic_name.as_deref() == Some("") && publish.properties().topic_alias().is_some()
+        {
+            publish.topic_name = None;
+        }
+        if publish.qos() != QoSLevel::AtMostOnce && publish.packet_id.is_none() {
+            return Err(MqttCodecError::new_with_kind(
+                "MQTTv5 2.2.1 QOS 1 and 2 PUBLISH must have a packet identifier",
+                ErrorKind::InvalidPacketId,
+            ));
+        }
+        if publish.properties().topic_alias() == Some(0) {
+            return Err(MqttCodecError::new_with_kind(
+                "MQTTv5 3.3.2.3.4 topic alias must not be 0",
+                ErrorKind::ProtocolErr,
+            ));
+        }
+        validate_publish(&publish)?;
+        validate_properties(publish.properties())?;
+        Ok(publish)
+    }
+}
+
+/// Builder for a SUBSCRIBE, created with [`Subscribe::builder`].
+#[derive(Debug)]
+pub struct SubscribeBuilder {
+    subscribe: Subscribe,
+    error: Option<MqttCodecError>,
+}
+
+impl SubscribeBuilder {
+    pub(crate) fn new(packet_id: u16) -> Self {
+        Self {
+            subscribe: Subscribe::new(packet_id, Vec::new()),
+            error: None,
+        }
+    }
+
+    /// Adds a subscription with the default subscription options.
+    pub fn with_filter(self, filter: impl Into<String>, qos: QoSLevel) -> Self {
+        self.with_subscription(Subscription::new(filter.into(), qos))
+    }
+
+    pub fn with_subscription(mut self, subscription: Subscription) -> Self {
+        self.subscribe.add_subscription(subscription);
+        self
+    }
+
+    pub fn with_subscription_id(self, id: u32) -> Self {
+        self.with_property(Property::SubscriptionIdentifier(id))
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(self.subscribe.properties_mut(), &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<Subscribe, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        validate_subscribe(&self.subscribe)?;
+        validate_properties(self.subscribe.properties())?;
+        Ok(self.subscribe)
+    }
+}
+
+/// Builder for an UNSUBSCRIBE, created with [`Unsubscribe::builder`].
+#[derive(Debug)]
+pub struct UnsubscribeBuilder {
+    unsubscribe: Unsubscribe,
+    error: Option<MqttCodecError>,
+}
+
+impl UnsubscribeBuilder {
+    pub(crate) fn new(packet_id: u16) -> Self {
+        Self {
+            unsubscribe: Unsubscribe::new(packet_id, Vec::new()),
+            error: None,
+        }
+    }
+
+    /// Adds a topic filter, which must match the filter of a subscription
+    /// exactly for the subscription to be removed.
+    pub fn with_filter(mut self, filter: impl Into<String>) -> Self {
+        self.unsubscribe.add_filter(filter.into());
+        self
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(self.unsubscribe.properties_mut(), &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<Unsubscribe, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        validate_unsubscribe(&self.unsubscribe)?;
+        validate_properties(self.unsubscribe.properties())?;
+        Ok(self.unsubscribe)
+    }
+}
+
+/// Builder for a DISCONNECT, created with [`Disconnect::builder`].
+#[derive(Debug)]
+pub struct DisconnectBuilder {
+    disconnect: Disconnect,
+    error: Option<MqttCodecError>,
+}
+
+impl DisconnectBuilder {
+    pub(crate) fn new(reason: Reason) -> Self {
+        Self {
+            disconnect: Disconnect::new(reason),
+            error: None,
+        }
+    }
+
+    pub fn with_session_expiry(self, seconds: u32) -> Self {
+        self.with_property(Property::SessionExpiryInterval(seconds))
+    }
+
+    pub fn with_reason_string(self, reason: impl Into<String>) -> Self {
+        self.with_property(Property::ReasonString(reason.into()))
+    }
+
+    pub fn with_server_reference(self, reference: impl Into<String>) -> Self {
+        self.with_property(Property::ServerReference(reference.into()))
+    }
+
+    pub fn with_user_property(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_property(Property::UserProperty(key.into(), value.into()))
+    }
+
+    pub fn with_property(mut self, prop: Property) -> Self {
+        set_property(self.disconnect.properties_mut(), &mut self.error, prop);
+        self
+    }
+
+    pub fn build(self) -> Result<Disconnect, MqttCodecError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if !self.disconnect.reason.is_disconnect_reason() {
+            return Err(protocol_err(&format!(
+                "MQTTv5 3.14.2.1 {} is not a DISCONNECT reason code",
+                self.disconnect.reason
+            )));
+        }
+        validate_properties(self.disconnect.properties())?;
+        Ok(self.disconnect)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{validate::validate_packet, Packet, PropertyType};
+
+    #[test]
+    fn test_publish() {
+        let publish = Publish::builder("sensor/temp")
+            .with_qos(QoSLevel::ExactlyOnce)
+            .with_packet_id(7)
+            .with_retain(true)
+            .with_payload(&b"21.5"[..])
+            .with_content_type("text/plain")
+            .with_user_property("unit", "C")
+            .build()
+            .unwrap();
+        assert_eq!(Some("sensor/temp"), publish.topic_name.as_deref());
+        assert_eq!(QoSLevel::ExactlyOnce, publish.qos());
+        assert_eq!(Some(7), publish.packet_id);
+        assert!(publish.header.retain());
+        assert_eq!(Some(&b"21.5"[..]), publish.payload());
+        assert_eq!(Some("text/plain"), publish.properties().content_type());
+        validate_packet(&Packet::Publish(publish)).unwrap();
+    }
+
+    #[test]
+    fn test_publish_invalid() {
+        let missing_id = Publish::builder("sensor/temp").with_qos(QoSLevel::AtLeastOnce);
+        let unexpected_id = Publish::builder("sensor/temp").with_packet_id(1);
+        let wildcard = Publish::builder("sensor/#");
+        let alias_zero = Publish::builder("sensor/temp").with_topic_alias(0);
+        for (builder, kind) in [
+            (missing_id, ErrorKind::InvalidPacketId),
+            (unexpected_id, ErrorKind::InvalidPacketId),
+            (wildcard, ErrorKind::InvalidTopicName),
+            (alias_zero, ErrorKind::ProtocolErr),
+        ] {
+            assert_eq!(kind, builder.build().unwrap_err().kind());
+        }
+        let not_permitted = Publish::builder("sensor/temp")
+            .with_property(Property::ReasonString("reason".to_string()));
+        assert_eq!(
+            ErrorKind::PropertyNotPermitted(PropertyType::ReasonString),
+            not_permitted.build().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_publish_topic_alias() {
+        let publish = Publish::builder("").with_topic_alias(3).build().unwrap();
+        assert_eq!(None, publish.topic_name);
+        assert_eq!(Some(3), publish.properties().topic_alias());
+        assert_eq!(
+            ErrorKind::InvalidTopicName,
+            Publish::builder("").build().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_connect() {
+        let will = WillMessage::builder("status/client", "offline")
+            .with_qos(QoSLevel::AtLeastOnce)
+            .with_retain(true)
+            .with_will_delay(10)
+            .build()
+            .unwrap();
+        let connect = Connect::builder("client")
+            .with_clean_start(true)
+            .with_keep_alive(30)
+            .with_username("user")
+            .with_password("secret")
+            .with_session_expiry(60)
+            .with_will(will.clone())
+            .build()
+            .unwrap();
+        assert_eq!("client", connect.client_id);
+        assert!(connect.clean_start);
+        assert_eq!(30, connect.keep_alive);
+        assert_eq!(Some(&b"secret"[..]), connect.password.as_deref());
+        assert_eq!(Some(60), connect.properties().session_expiry_interval());
+        assert_eq!(Some(will), connect.will_message);
+    }
+
+    #[test]
+    fn test_connect_invalid() {
+        let will = WillMessage::builder("status/+", "offline");
+        assert_eq!(
+            ErrorKind::InvalidTopicName,
+            will.build().unwrap_err().kind()
+        );
+        for builder in [
+            Connect::builder("client").with_recv_max(0),
+            Connect::builder("client").with_max_packet_size(0),
+            Connect::builder("client").with_auth_data(&b"data"[..]),
+            Connect::builder("client")
+                .with_protocol_version(ProtocolVersion::V3_1_1)
+                .with_password("secret"),
+        ] {
+            assert_eq!(ErrorKind::ProtocolErr, builder.build().unwrap_err().kind());
+        }
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let subscribe = Subscribe::builder(3)
+            .with_filter("sensor/#", QoSLevel::AtLeastOnce)
+            .with_filter("status/+", QoSLevel::AtMostOnce)
+            .with_subscription_id(5)
+            .build()
+            .unwrap();
+        assert_eq!(3, subscribe.packet_id());
+        assert_eq!(2, subscribe.subscriptions().len());
+        assert_eq!(vec![5], subscribe.properties().subscription_ids());
+        for (builder, kind) in [
+            (Subscribe::builder(3), ErrorKind::ProtocolErr),
+            (
+                Subscribe::builder(0).with_filter("sensor/#", QoSLevel::AtMostOnce),
+                ErrorKind::InvalidPacketId,
+            ),
+            (
+                Subscribe::builder(3).with_filter("sensor/#/temp", QoSLevel::AtMostOnce),
+                ErrorKind::InvalidTopicFilter,
+            ),
+        ] {
+            assert_eq!(kind, builder.build().unwrap_err().kind());
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let unsubscribe = Unsubscribe::builder(4)
+            .with_filter("sensor/#")
+            .with_filter("$share/group/status/+")
+            .with_user_property("key", "value")
+            .build()
+            .unwrap();
+        assert_eq!(4, unsubscribe.packet_id());
+        assert_eq!(
+            &["sensor/#".to_string(), "$share/group/status/+".to_string()],
+            unsubscribe.filters()
+        );
+        validate_packet(&Packet::Unsubscribe(unsubscribe)).unwrap();
+        for (builder, kind) in [
+            (Unsubscribe::builder(4), ErrorKind::ProtocolErr),
+            (
+                Unsubscribe::builder(0).with_filter("sensor/#"),
+                ErrorKind::InvalidPacketId,
+            ),
+            (
+                Unsubscribe::builder(4).with_filter("sensor/#/temp"),
+                ErrorKind::InvalidTopicFilter,
+            ),
+            (
+                Unsubscribe::builder(4)
+                    .with_filter("sensor/#")
+                    .with_property(Property::SubscriptionIdentifier(1)),
+                ErrorKind::PropertyNotPermitted(PropertyType::SubscriptionIdentifier),
+            ),
+        ] {
+            assert_eq!(kind, builder.build().unwrap_err().kind());
+        }
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let disconnect = Disconnect::builder(Reason::ServerMoved)
+            .with_server_reference("other:1883")
+            .build()
+            .unwrap();
+        assert_eq!(Reason::ServerMoved, disconnect.reason);
+        assert_eq!(
+            Some("other:1883"),
+            disconnect.properties().server_reference()
+        );
+        assert_eq!(
+            ErrorKind::ProtocolErr,
+            Disconnect::builder(Reason::GrantedQoS1)
+                .build()
+                .unwrap_err()
+                .kind()
+        );
+    }
+}
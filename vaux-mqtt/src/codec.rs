@@ -1,16 +1,32 @@
 use crate::publish::Publish;
 use crate::subscribe::SubAck;
-use crate::{ConnAck, Connect, Decode, Disconnect, Encode, FixedHeader, PubResp, Size, Subscribe};
-use bytes::{Buf, BufMut, BytesMut};
-use std::fmt::{Display, Formatter};
+use crate::unsubscribe::{UnsubAck, Unsubscribe};
+use crate::validate::{validate_packet, validate_utf8};
+use crate::{
+    ConnAck, Connect, Decode, Disconnect, Encode, FixedHeader, PropertyType, PubResp, Size,
+    Subscribe,
+};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use core::fmt::{Display, Formatter};
 
 pub(crate) const SIZE_UTF8_STRING: u32 = 2;
 pub(crate) const PACKET_RESERVED_NONE: u8 = 0x00;
 pub(crate) const PACKET_RESERVED_BIT1: u8 = 0x02;
+/// Maximum number of bytes in the fixed header remaining length
+const MAX_REMAINING_LEN_BYTES: usize = 4;
+/// MQTT v3.1.1 3.9.3 SUBACK return code for a rejected subscription
+const V3_SUBACK_FAILURE: u8 = 0x80;
 
 /// MQTT Control Packet Type
 /// #[repr(u8)]
 #[derive(Default, Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PacketType {
     #[default]
     Connect = 0x10,
@@ -53,7 +69,7 @@ impl From<u8> for PacketType {
 }
 
 impl Display for PacketType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", format!("{:?}", &self).as_str().to_uppercase())
     }
 }
@@ -63,6 +79,7 @@ impl Display for PacketType {
 /// <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901031>
 #[repr(u8)]
 #[derive(Default, Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Reason {
     #[default]
     Success,
@@ -111,7 +128,7 @@ pub enum Reason {
 }
 
 impl Display for Reason {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
@@ -175,9 +192,112 @@ impl TryFrom<u8> for Reason {
     }
 }
 
+impl Reason {
+    /// Checks that the reason may be sent in a DISCONNECT, MQTT v5 3.14.2.1.
+    pub(crate) fn is_disconnect_reason(self) -> bool {
+        !matches!(
+            self,
+            Reason::GrantedQoS1
+                | Reason::GrantedQoS2
+                | Reason::NoSubscribers
+                | Reason::NoSubscriptionExisted
+                | Reason::ContinueAuth
+                | Reason::Reauthenticate
+                | Reason::UnsupportedProtocolVersion
+                | Reason::InvalidClientId
+                | Reason::AuthenticationErr
+                | Reason::ServerUnavailable
+                | Reason::Banned
+                | Reason::AuthMethodErr
+                | Reason::PacketIdInUse
+                | Reason::PacketIdNotFound
+        )
+    }
+
+    /// Maps the reason to an MQTT 3.1.1 CONNACK return code, MQTT v3.1.1
+    /// 3.2.2.3. Reasons without a 3.1.1 equivalent are reported as server
+    /// unavailable.
+    pub(crate) fn to_v3_connack_code(self) -> u8 {
+        match self {
+            Reason::Success => 0x00,
+            Reason::UnsupportedProtocolVersion => 0x01,
+            Reason::InvalidClientId => 0x02,
+            Reason::AuthenticationErr => 0x04,
+            Reason::NotAuthorized => 0x05,
+            _ => 0x03,
+        }
+    }
+
+    pub(crate) fn from_v3_connack_code(code: u8) -> Result<Self, MqttCodecError> {
+        match code {
+            0x00 => Ok(Reason::Success),
+            0x01 => Ok(Reason::UnsupportedProtocolVersion),
+            0x02 => Ok(Reason::InvalidClientId),
+            0x03 => Ok(Reason::ServerUnavailable),
+            0x04 => Ok(Reason::AuthenticationErr),
+            0x05 => Ok(Reason::NotAuthorized),
+            code => Err(MqttCodecError::new(&format!(
+                "invalid CONNACK return code: {}",
+                code
+            ))),
+        }
+    }
+
+    /// Maps the reason to an MQTT 3.1.1 SUBACK return code, MQTT v3.1.1
+    /// 3.9.3. Any reason other than a granted QoS is reported as failure.
+    pub(crate) fn to_v3_suback_code(self) -> u8 {
+        match self {
+            Reason::Success | Reason::GrantedQoS1 | Reason::GrantedQoS2 => self as u8,
+            _ => V3_SUBACK_FAILURE,
+        }
+    }
+
+    pub(crate) fn from_v3_suback_code(code: u8) -> Result<Self, MqttCodecError> {
+        match code {
+            0x00 => Ok(Reason::GrantedQoS0),
+            0x01 => Ok(Reason::GrantedQoS1),
+            0x02 => Ok(Reason::GrantedQoS2),
+            V3_SUBACK_FAILURE => Ok(Reason::UnspecifiedErr),
+            code => Err(MqttCodecError::new(&format!(
+                "invalid SUBACK return code: {}",
+                code
+            ))),
+        }
+    }
+}
+
+/// MQTT protocol version, carried as the protocol level in CONNECT. The
+/// version is negotiated by the CONNECT packet and applies to every packet
+/// that follows on the connection. MQTT 3.1.1 packets have no properties and
+/// reason codes are mapped to the 3.1.1 return codes where they exist.
+#[repr(u8)]
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProtocolVersion {
+    V3_1_1 = 0x04,
+    #[default]
+    V5 = 0x05,
+}
+
+impl TryFrom<u8> for ProtocolVersion {
+    type Error = MqttCodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x04 => Ok(ProtocolVersion::V3_1_1),
+            0x05 => Ok(ProtocolVersion::V5),
+            value => Err(MqttCodecError::new_with_kind(
+                &format!("unsupported protocol version: {}", value),
+                ErrorKind::UnsupportedProtocolVersion,
+            )),
+        }
+    }
+}
+
 #[allow(clippy::enum_variant_names)]
 #[repr(u8)]
 #[derive(Default, Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QoSLevel {
     #[default]
     AtMostOnce = 0,
@@ -202,6 +322,7 @@ impl TryFrom<u8> for QoSLevel {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Packet {
     PingRequest(FixedHeader),
     PingResponse(FixedHeader),
@@ -215,6 +336,8 @@ pub enum Packet {
     Disconnect(Disconnect),
     Subscribe(Subscribe),
     SubAck(SubAck),
+    Unsubscribe(Unsubscribe),
+    UnsubAck(UnsubAck),
 }
 
 impl Size for Packet {
@@ -231,6 +354,8 @@ impl Size for Packet {
             Packet::Disconnect(disc) => disc.size(),
             Packet::Subscribe(sub) => sub.size(),
             Packet::SubAck(ack) => ack.size(),
+            Packet::Unsubscribe(unsub) => unsub.size(),
+            Packet::UnsubAck(ack) => ack.size(),
         }
     }
 
@@ -247,6 +372,8 @@ impl Size for Packet {
             Packet::Disconnect(disc) => disc.property_size(),
             Packet::Subscribe(sub) => sub.property_size(),
             Packet::SubAck(ack) => ack.property_size(),
+            Packet::Unsubscribe(unsub) => unsub.property_size(),
+            Packet::UnsubAck(ack) => ack.property_size(),
         }
     }
 
@@ -263,6 +390,8 @@ impl Size for Packet {
             Packet::Disconnect(disc) => disc.payload_size(),
             Packet::Subscribe(sub) => sub.payload_size(),
             Packet::SubAck(ack) => ack.payload_size(),
+            Packet::Unsubscribe(unsub) => unsub.payload_size(),
+            Packet::UnsubAck(ack) => ack.payload_size(),
         }
     }
 }
@@ -282,145 +411,470 @@ impl From<&Packet> for PacketType {
             Packet::Disconnect(_) => PacketType::Disconnect,
             Packet::Subscribe(_) => PacketType::Subscribe,
             Packet::SubAck(_) => PacketType::SubAck,
+            Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+            Packet::UnsubAck(_) => PacketType::UnsubAck,
         }
     }
 }
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ErrorKind {
     InsufficientData(usize, usize),
+    /// packet size and the maximum packet size
+    PacketTooLarge(usize, usize),
     #[default]
     MalformedPacket,
     UnsupportedQosLevel,
     UnsupportedResponseType,
     UnsupportedReason,
+    /// protocol name or protocol level in CONNECT is not supported
+    UnsupportedProtocolVersion,
+    /// packet type and the fixed header flags received
+    InvalidFlags(PacketType, u8),
+    /// property that may only be included once was repeated
+    DuplicateProperty(PropertyType),
+    /// property is not permitted for the packet type
+    PropertyNotPermitted(PropertyType),
+    InvalidUtf8,
+    InvalidTopicName,
+    InvalidTopicFilter,
+    /// packet identifier missing, zero, or present for QoS 0
+    InvalidPacketId,
+    ProtocolErr,
+    /// encoded packet size and the size of the destination buffer
+    BufferTooSmall(usize, usize),
+    /// error reading or writing the network connection
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
 }
 
+impl ErrorKind {
+    /// Gets the reason code to send to the peer when closing the connection
+    /// because of the error.
+    pub fn reason(&self) -> Reason {
+        match self {
+            ErrorKind::PacketTooLarge(_, _) => Reason::PacketTooLarge,
+            ErrorKind::UnsupportedProtocolVersion => Reason::UnsupportedProtocolVersion,
+            ErrorKind::InvalidTopicName => Reason::InvalidTopicName,
+            ErrorKind::InvalidTopicFilter => Reason::InvalidTopicFilter,
+            ErrorKind::UnsupportedResponseType
+            | ErrorKind::UnsupportedReason
+            | ErrorKind::DuplicateProperty(_)
+            | ErrorKind::InvalidPacketId
+            | ErrorKind::ProtocolErr => Reason::ProtocolErr,
+            ErrorKind::InsufficientData(_, _)
+            | ErrorKind::MalformedPacket
+            | ErrorKind::UnsupportedQosLevel
+            | ErrorKind::InvalidFlags(_, _)
+            | ErrorKind::PropertyNotPermitted(_)
+            | ErrorKind::InvalidUtf8 => Reason::MalformedPacket,
+            ErrorKind::BufferTooSmall(_, _) => Reason::UnspecifiedErr,
+            #[cfg(feature = "std")]
+            ErrorKind::Io(_) => Reason::UnspecifiedErr,
+        }
+    }
+}
+
+/// Error encoding or decoding a packet. The error carries the [`Reason`] to
+/// send to the peer when closing the connection, and where known the type of
+/// the packet and the byte offset from the start of the packet at which the
+/// error was found.
 #[derive(Default, Debug)]
 pub struct MqttCodecError {
-    pub reason: String,
-    pub kind: ErrorKind,
+    message: String,
+    kind: ErrorKind,
+    packet_type: Option<PacketType>,
+    offset: Option<usize>,
+    #[cfg(feature = "std")]
+    source: Option<std::io::Error>,
 }
 
 impl Display for MqttCodecError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Mqtt codec error: {}", self.reason)
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Mqtt codec error: {}", self.message)?;
+        if let Some(packet_type) = self.packet_type {
+            write!(f, " in {}", packet_type)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
     }
 }
 
+#[cfg(feature = "std")]
 impl From<std::io::Error> for MqttCodecError {
-    fn from(_err: std::io::Error) -> Self {
+    fn from(err: std::io::Error) -> Self {
         MqttCodecError {
-            reason: "IO error".to_string(),
+            message: err.to_string(),
+            kind: ErrorKind::Io(err.kind()),
+            source: Some(err),
             ..Default::default()
         }
     }
 }
 
-impl std::error::Error for MqttCodecError {}
+impl core::error::Error for MqttCodecError {
+    #[cfg(feature = "std")]
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|err| err as &(dyn core::error::Error + 'static))
+    }
+}
 
 impl MqttCodecError {
-    pub fn new(reason: &str) -> Self {
+    /// Creates an error for a malformed packet.
+    pub fn new(message: &str) -> Self {
+        Self::new_with_kind(message, ErrorKind::MalformedPacket)
+    }
+
+    pub fn new_with_kind(message: &str, kind: ErrorKind) -> Self {
         MqttCodecError {
-            reason: reason.to_string(),
+            message: message.to_string(),
+            kind,
             ..Default::default()
         }
     }
+
+    pub fn with_packet_type(mut self, packet_type: PacketType) -> Self {
+        self.packet_type = Some(packet_type);
+        self
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Gets the reason code to send to the peer when closing the connection,
+    /// in DISCONNECT or, for an error decoding CONNECT, in CONNACK.
+    pub fn reason(&self) -> Reason {
+        self.kind.reason()
+    }
+
+    pub fn packet_type(&self) -> Option<PacketType> {
+        self.packet_type
+    }
+
+    /// Gets the offset from the start of the fixed header of the packet at
+    /// which the error was found.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Sets the offset where it has not been set by the code reporting the
+    /// error.
+    pub(crate) fn in_offset(mut self, offset: usize) -> Self {
+        self.offset.get_or_insert(offset);
+        self
+    }
+
+    /// Sets the packet type and offset where they have not been set by the
+    /// code reporting the error.
+    pub(crate) fn in_packet(mut self, packet_type: PacketType, offset: usize) -> Self {
+        self.packet_type.get_or_insert(packet_type);
+        self.offset.get_or_insert(offset);
+        self
+    }
 }
 
+/// Decodes the next MQTT v5 packet from the buffer. See [`decode_with_version`].
 pub fn decode(src: &mut BytesMut) -> Result<Option<(Packet, u32)>, MqttCodecError> {
-    match decode_fixed_header(src) {
-        Ok(packet_header) => match packet_header {
-            Some(packet_header) => {
-                let decode_len =
-                    packet_header.remaining + 1 + variable_byte_int_size(packet_header.remaining);
-                match packet_header.packet_type() {
-                    PacketType::PingReq => {
-                        Ok(Some((Packet::PingRequest(packet_header), decode_len)))
-                    }
-                    PacketType::PingResp => {
-                        Ok(Some((Packet::PingResponse(packet_header), decode_len)))
-                    }
-                    PacketType::Connect => {
-                        let mut connect = Connect::default();
-                        connect.decode(src)?;
-                        Ok(Some((Packet::Connect(Box::new(connect)), decode_len)))
-                    }
-                    PacketType::Publish => {
-                        let mut publish = Publish::new_from_header(packet_header)?;
-                        publish.decode(src)?;
-                        Ok(Some((Packet::Publish(publish), decode_len)))
-                    }
-                    PacketType::PubAck => {
-                        let mut puback = PubResp::new_puback();
-                        puback.decode(src)?;
-                        Ok(Some((Packet::PubAck(puback), decode_len)))
-                    }
-                    PacketType::PubComp => {
-                        let mut pubcomp = PubResp::new_pubcomp();
-                        pubcomp.decode(src)?;
-                        Ok(Some((Packet::PubComp(pubcomp), decode_len)))
-                    }
-                    PacketType::PubRec => {
-                        let mut pubrec = PubResp::new_pubrec();
-                        pubrec.decode(src)?;
-                        Ok(Some((Packet::PubRec(pubrec), decode_len)))
-                    }
-                    PacketType::PubRel => {
-                        let mut pubrel = PubResp::new_pubrel();
-                        pubrel.decode(src)?;
-                        Ok(Some((Packet::PubRel(pubrel), decode_len)))
-                    }
-                    PacketType::Disconnect => {
-                        let mut disconnect = Disconnect::default();
-                        disconnect.decode(src)?;
-                        Ok(Some((Packet::Disconnect(disconnect), decode_len)))
-                    }
-                    PacketType::ConnAck => {
-                        let mut connack = ConnAck::default();
-                        connack.decode(src)?;
-                        Ok(Some((Packet::ConnAck(connack), decode_len)))
-                    }
-                    PacketType::Subscribe => {
-                        let mut subscribe = Subscribe::default();
-                        subscribe.decode(src)?;
-                        Ok(Some((Packet::Subscribe(subscribe), decode_len)))
-                    }
-                    PacketType::SubAck => {
-                        let mut suback = SubAck::default();
-                        suback.decode(src)?;
-                        Ok(Some((Packet::SubAck(suback), decode_len)))
-                    }
-                    _ => Err(MqttCodecError::new("unsupported packet type")),
-                }
+    decode_with_version(src, ProtocolVersion::V5)
+}
+
+/// Decodes the next packet from the buffer using the wire format of the
+/// protocol version negotiated for the connection. CONNECT is always decoded
+/// using the protocol level it carries, so the version for a new connection
+/// is taken from the decoded [`Connect::protocol_version`].
+///
+/// The buffer must start with a complete packet and any bytes following the
+/// packet are discarded. Use [`crate::StreamDecoder`] to decode packets from a
+/// stream of bytes.
+///
+/// With the `pedantic` feature reserved flags, UTF-8 strings, duplicate
+/// properties and the rules in [`crate::validate::validate_packet`] are
+/// checked, returning an error that maps to the [`Reason`] for closing the
+/// connection through [`ErrorKind::reason`].
+pub fn decode_with_version(
+    src: &mut BytesMut,
+    version: ProtocolVersion,
+) -> Result<Option<(Packet, u32)>, MqttCodecError> {
+    let packet_header = match decode_fixed_header(src)? {
+        Some(packet_header) => packet_header,
+        None => return Ok(None),
+    };
+    let decode_len = packet_header.remaining + 1 + variable_byte_int_size(packet_header.remaining);
+    let packet_type = packet_header.packet_type();
+    let packet = decode_body(packet_header, src, version)
+        .and_then(|packet| {
+            if cfg!(feature = "pedantic") {
+                validate_packet(&packet)?;
             }
-            None => Ok(None),
-        },
-        Err(e) => Err(e),
-    }
+            Ok(packet)
+        })
+        .map_err(|e| e.in_packet(packet_type, decode_len as usize - src.remaining()))?;
+    Ok(Some((packet, decode_len)))
 }
 
-pub fn encode(packet: Packet, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+/// Decodes the variable header and payload of a packet.
+fn decode_body(
+    packet_header: FixedHeader,
+    src: &mut BytesMut,
+    version: ProtocolVersion,
+) -> Result<Packet, MqttCodecError> {
+    let packet = match packet_header.packet_type() {
+        PacketType::PingReq => Packet::PingRequest(packet_header),
+        PacketType::PingResp => Packet::PingResponse(packet_header),
+        PacketType::Connect => {
+            let mut connect = Connect::default();
+            connect.decode(src)?;
+            Packet::Connect(Box::new(connect))
+        }
+        _ if version == ProtocolVersion::V3_1_1 => decode_v3(packet_header, src)?,
+        PacketType::Publish => {
+            let mut publish = Publish::new_from_header(packet_header)?;
+            publish.decode(src)?;
+            Packet::Publish(publish)
+        }
+        PacketType::PubAck => {
+            let mut puback = PubResp::new_puback();
+            puback.decode(src)?;
+            Packet::PubAck(puback)
+        }
+        PacketType::PubComp => {
+            let mut pubcomp = PubResp::new_pubcomp();
+            pubcomp.decode(src)?;
+            Packet::PubComp(pubcomp)
+        }
+        PacketType::PubRec => {
+            let mut pubrec = PubResp::new_pubrec();
+            pubrec.decode(src)?;
+            Packet::PubRec(pubrec)
+        }
+        PacketType::PubRel => {
+            let mut pubrel = PubResp::new_pubrel();
+            pubrel.decode(src)?;
+            Packet::PubRel(pubrel)
+        }
+        PacketType::Disconnect => {
+            let mut disconnect = Disconnect::default();
+            disconnect.decode(src)?;
+            Packet::Disconnect(disconnect)
+        }
+        PacketType::ConnAck => {
+            let mut connack = ConnAck::default();
+            connack.decode(src)?;
+            Packet::ConnAck(connack)
+        }
+        PacketType::Subscribe => {
+            let mut subscribe = Subscribe::default();
+            subscribe.decode(src)?;
+            Packet::Subscribe(subscribe)
+        }
+        PacketType::SubAck => {
+            let mut suback = SubAck::default();
+            suback.decode(src)?;
+            Packet::SubAck(suback)
+        }
+        PacketType::Unsubscribe => {
+            let mut unsubscribe = Unsubscribe::default();
+            unsubscribe.decode(src)?;
+            Packet::Unsubscribe(unsubscribe)
+        }
+        PacketType::UnsubAck => {
+            let mut unsuback = UnsubAck::default();
+            unsuback.decode(src)?;
+            Packet::UnsubAck(unsuback)
+        }
+        _ => return Err(MqttCodecError::new("unsupported packet type")),
+    };
+    Ok(packet)
+}
+
+/// Decodes the variable header and payload of an MQTT 3.1.1 packet.
+fn decode_v3(packet_header: FixedHeader, src: &mut BytesMut) -> Result<Packet, MqttCodecError> {
+    let packet = match packet_header.packet_type() {
+        PacketType::Publish => {
+            let mut publish = Publish::new_from_header(packet_header)?;
+            publish.decode_v3(src)?;
+            Packet::Publish(publish)
+        }
+        PacketType::PubAck => {
+            let mut puback = PubResp::new_puback();
+            puback.decode_v3(src)?;
+            Packet::PubAck(puback)
+        }
+        PacketType::PubComp => {
+            let mut pubcomp = PubResp::new_pubcomp();
+            pubcomp.decode_v3(src)?;
+            Packet::PubComp(pubcomp)
+        }
+        PacketType::PubRec => {
+            let mut pubrec = PubResp::new_pubrec();
+            pubrec.decode_v3(src)?;
+            Packet::PubRec(pubrec)
+        }
+        PacketType::PubRel => {
+            let mut pubrel = PubResp::new_pubrel();
+            pubrel.decode_v3(src)?;
+            Packet::PubRel(pubrel)
+        }
+        // MQTT v3.1.1 3.14 DISCONNECT has no variable header or payload
+        PacketType::Disconnect => Packet::Disconnect(Disconnect::default()),
+        PacketType::ConnAck => {
+            let mut connack = ConnAck::default();
+            connack.decode_v3(src)?;
+            Packet::ConnAck(connack)
+        }
+        PacketType::Subscribe => {
+            let mut subscribe = Subscribe::default();
+            subscribe.decode_v3(src)?;
+            Packet::Subscribe(subscribe)
+        }
+        PacketType::SubAck => {
+            let mut suback = SubAck::default();
+            suback.decode_v3(src)?;
+            Packet::SubAck(suback)
+        }
+        PacketType::Unsubscribe => {
+            let mut unsubscribe = Unsubscribe::default();
+            unsubscribe.decode_v3(src)?;
+            Packet::Unsubscribe(unsubscribe)
+        }
+        PacketType::UnsubAck => {
+            let mut unsuback = UnsubAck::default();
+            unsuback.decode_v3(src)?;
+            Packet::UnsubAck(unsuback)
+        }
+        _ => return Err(MqttCodecError::new("unsupported packet type")),
+    };
+    Ok(packet)
+}
+
+/// Encodes an MQTT v5 packet. See [`encode_with_version`].
+pub fn encode(packet: Packet, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+    encode_with_version(packet, dest, ProtocolVersion::V5)
+}
+
+/// Encodes a packet using the wire format of the protocol version negotiated
+/// for the connection. Properties are dropped when encoding MQTT 3.1.1 and
+/// reason codes are mapped to 3.1.1 return codes. CONNECT is always encoded
+/// with its own [`Connect::protocol_version`].
+///
+/// With the `pedantic` feature the packet is checked with
+/// [`crate::validate::validate_packet`] before it is encoded.
+pub fn encode_with_version(
+    packet: Packet,
+    dest: &mut impl BufMut,
+    version: ProtocolVersion,
+) -> Result<(), MqttCodecError> {
+    let packet_type = PacketType::from(&packet);
+    let start = dest.remaining_mut();
+    if cfg!(feature = "pedantic") {
+        validate_packet(&packet).map_err(|e| e.in_packet(packet_type, 0))?;
+    }
+    let v3 = version == ProtocolVersion::V3_1_1;
     match packet {
         Packet::Connect(c) => c.encode(dest),
+        Packet::ConnAck(c) if v3 => c.encode_v3(dest),
         Packet::ConnAck(c) => c.encode(dest),
+        Packet::Disconnect(d) if v3 => d.encode_v3(dest),
         Packet::Disconnect(d) => d.encode(dest),
+        Packet::Publish(p) if v3 => p.encode_v3(dest),
         Packet::Publish(p) => p.encode(dest),
+        Packet::PubAck(p) | Packet::PubComp(p) | Packet::PubRec(p) | Packet::PubRel(p) if v3 => {
+            p.encode_v3(dest)
+        }
         Packet::PubAck(p) | Packet::PubComp(p) | Packet::PubRec(p) | Packet::PubRel(p) => {
             p.encode(dest)
         }
-
         Packet::PingRequest(header) | Packet::PingResponse(header) => {
             dest.put_u8(header.packet_type() as u8 | header.flags());
             dest.put_u8(0x_00);
             Ok(())
         }
+        Packet::Subscribe(s) if v3 => s.encode_v3(dest),
         Packet::Subscribe(s) => s.encode(dest),
+        Packet::SubAck(s) if v3 => s.encode_v3(dest),
         Packet::SubAck(s) => s.encode(dest),
-    }?;
+        Packet::Unsubscribe(u) if v3 => u.encode_v3(dest),
+        Packet::Unsubscribe(u) => u.encode(dest),
+        Packet::UnsubAck(u) if v3 => u.encode_v3(dest),
+        Packet::UnsubAck(u) => u.encode(dest),
+    }
+    .map_err(|e| e.in_packet(packet_type, start - dest.remaining_mut()))
+}
+
+/// Encodes an MQTT v5 packet into a fixed size buffer. See
+/// [`encode_slice_with_version`].
+pub fn encode_slice(packet: Packet, dest: &mut [u8]) -> Result<usize, MqttCodecError> {
+    encode_slice_with_version(packet, dest, ProtocolVersion::V5)
+}
+
+/// Encodes a packet into a caller provided buffer without allocating,
+/// returning the number of bytes written from the start of the buffer. If
+/// the buffer cannot hold the packet nothing is written and an error of kind
+/// [`ErrorKind::BufferTooSmall`] with the number of bytes needed is returned.
+pub fn encode_slice_with_version(
+    packet: Packet,
+    dest: &mut [u8],
+    version: ProtocolVersion,
+) -> Result<usize, MqttCodecError> {
+    let len = encoded_len(&packet, version);
+    check_buffer_len(len, dest.len(), PacketType::from(&packet))?;
+    let mut buf = &mut dest[..len];
+    encode_with_version(packet, &mut buf, version)?;
+    Ok(len)
+}
+
+/// Checks that a buffer of `capacity` bytes can hold an encoded packet of
+/// `len` bytes.
+pub(crate) fn check_buffer_len(
+    len: usize,
+    capacity: usize,
+    packet_type: PacketType,
+) -> Result<(), MqttCodecError> {
+    if len > capacity {
+        return Err(MqttCodecError::new_with_kind(
+            &format!("buffer too small, need {} bytes", len),
+            ErrorKind::BufferTooSmall(len, capacity),
+        )
+        .in_packet(packet_type, 0));
+    }
     Ok(())
 }
 
+/// Gets the number of bytes needed to encode the packet, including the fixed
+/// header, using the wire format of the protocol version.
+pub fn encoded_len(packet: &Packet, version: ProtocolVersion) -> usize {
+    let remaining = match packet {
+        Packet::PingRequest(_) | Packet::PingResponse(_) => 0,
+        Packet::Connect(c) => c.size(),
+        _ if version == ProtocolVersion::V5 => packet.size(),
+        Packet::ConnAck(c) => c.size_v3(),
+        Packet::Disconnect(d) => d.size_v3(),
+        Packet::Publish(p) => p.size_v3(),
+        Packet::PubAck(p) | Packet::PubComp(p) | Packet::PubRec(p) | Packet::PubRel(p) => {
+            p.size_v3()
+        }
+        Packet::Subscribe(s) => s.size_v3(),
+        Packet::SubAck(s) => s.size_v3(),
+        Packet::Unsubscribe(u) => u.size_v3(),
+        Packet::UnsubAck(u) => u.size_v3(),
+    };
+    (1 + variable_byte_int_size(remaining) + remaining) as usize
+}
+
 /// Returns the length of an encoded MQTT variable length unsigned int
 pub(crate) fn variable_byte_int_size(value: u32) -> u32 {
     match value {
@@ -431,14 +885,41 @@ pub(crate) fn variable_byte_int_size(value: u32) -> u32 {
     }
 }
 
+/// Checks that the packet holds at least `len` more bytes, so that a packet
+/// that ends early is reported as malformed instead of reading past its end.
+fn check_remaining(src: &BytesMut, len: usize) -> Result<(), MqttCodecError> {
+    if src.remaining() < len {
+        return Err(MqttCodecError::new_with_kind(
+            "malformed packet: insufficient data",
+            ErrorKind::InsufficientData(len, src.remaining()),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn get_u8(src: &mut BytesMut) -> Result<u8, MqttCodecError> {
+    check_remaining(src, 1)?;
+    Ok(src.get_u8())
+}
+
+pub(crate) fn get_u16(src: &mut BytesMut) -> Result<u16, MqttCodecError> {
+    check_remaining(src, 2)?;
+    Ok(src.get_u16())
+}
+
+pub(crate) fn get_u32(src: &mut BytesMut) -> Result<u32, MqttCodecError> {
+    check_remaining(src, 4)?;
+    Ok(src.get_u32())
+}
+
 #[cfg(not(feature = "pedantic"))]
 pub fn get_bool(src: &mut BytesMut) -> Result<bool, MqttCodecError> {
-    Ok(src.get_u8() != 0)
+    Ok(get_u8(src)? != 0)
 }
 
 #[cfg(feature = "pedantic")]
 pub fn get_bool(src: &mut BytesMut) -> Result<bool, MqttCodecError> {
-    match src.get_u8() {
+    match get_u8(src)? {
         0 => Ok(false),
         1 => Ok(true),
         v => Err(MqttCodecError::new(&format!(
@@ -448,42 +929,56 @@ pub fn get_bool(src: &mut BytesMut) -> Result<bool, MqttCodecError> {
     }
 }
 
-pub(crate) fn put_utf8(src: &str, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+pub(crate) fn put_utf8(src: &str, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
     let len = src.len();
     if len > u16::MAX as usize {
         return Err(MqttCodecError::new("string exceeds max length"));
     }
+    if cfg!(feature = "pedantic") {
+        validate_utf8(src)?;
+    }
     dest.put_u16(src.len() as u16);
     dest.put(src.as_bytes());
     Ok(())
 }
 
 pub(crate) fn get_utf8(src: &mut BytesMut) -> Result<String, MqttCodecError> {
-    let len = src.get_u16();
+    let len = get_u16(src)?;
     if src.remaining() < len as usize {
         return Err(MqttCodecError::new("malformed Mqtt packet: string length"));
     }
     let mut chars: Vec<u8> = Vec::with_capacity(len as usize);
     for _ in 0..len {
-        chars.push(src.get_u8());
+        chars.push(get_u8(src)?);
     }
+    // from_utf8 rejects encoded surrogates, MQTT v5 1.5.4
     match String::from_utf8(chars) {
-        Ok(s) => Ok(s),
-        Err(e) => Err(MqttCodecError::new(&format!("{:?}", e))),
+        Ok(s) => {
+            if cfg!(feature = "pedantic") {
+                validate_utf8(&s)?;
+            }
+            Ok(s)
+        }
+        Err(e) => Err(MqttCodecError::new_with_kind(
+            &format!("MQTTv5 1.5.4 {}", e),
+            ErrorKind::InvalidUtf8,
+        )),
     }
 }
 
-pub(crate) fn get_bin(src: &mut BytesMut) -> Result<Vec<u8>, MqttCodecError> {
-    let mut dest = Vec::new();
-    let len = src.get_u16() as usize;
-    dest.resize(len, 0);
-    for _ in 0..len {
-        dest.push(src.get_u8());
+/// Gets binary data from the buffer. The data is split from the source buffer
+/// without copying.
+pub(crate) fn get_bin(src: &mut BytesMut) -> Result<Bytes, MqttCodecError> {
+    let len = get_u16(src)? as usize;
+    if src.remaining() < len {
+        return Err(MqttCodecError::new(
+            "malformed Mqtt packet: binary data length",
+        ));
     }
-    Ok(dest)
+    Ok(src.split_to(len).freeze())
 }
 
-pub(crate) fn put_bin(src: &[u8], dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+pub(crate) fn put_bin(src: &[u8], dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
     let len = src.len();
     if len > u16::MAX as usize {
         return Err(MqttCodecError::new("binary data exceeds max length"));
@@ -493,7 +988,8 @@ pub(crate) fn put_bin(src: &[u8], dest: &mut BytesMut) -> Result<(), MqttCodecEr
     Ok(())
 }
 
-pub(crate) fn put_var_u32(val: u32, dest: &mut BytesMut) {
+/// Encodes an MQTT variable byte integer, MQTT v5 1.5.5.
+pub fn put_var_u32(val: u32, dest: &mut impl BufMut) {
     let mut encode = true;
     let mut input_val = val;
     while encode {
@@ -508,55 +1004,76 @@ pub(crate) fn put_var_u32(val: u32, dest: &mut BytesMut) {
     }
 }
 
-pub(crate) fn get_var_u32(src: &mut BytesMut) -> Result<u32, MqttCodecError> {
+/// Decodes an MQTT variable byte integer, MQTT v5 1.5.5.
+pub fn get_var_u32(src: &mut BytesMut) -> Result<u32, MqttCodecError> {
     let mut result = 0_u32;
     let mut shift = 0;
-    let mut next_byte = src.get_u8();
-    let mut decode = true;
-    while decode {
+    loop {
+        let next_byte = get_u8(src)?;
         result += ((next_byte & 0x7f) as u32) << shift;
-        shift += 7;
         if next_byte & 0x80 == 0 {
-            decode = false;
-        } else {
-            next_byte = src.get_u8();
+            return Ok(result);
         }
+        shift += 7;
+        // a variable byte integer is at most 4 bytes, MQTT v5 1.5.5
         if shift > 21 {
             return Err(MqttCodecError::new(
                 "malformed packet: variable byte integer",
             ));
         }
     }
-    Ok(result)
+}
+
+/// Returns the total length of the next packet in the buffer, including the
+/// fixed header, or None if the fixed header has not been completely received.
+/// The buffer may hold a partial packet or several packets.
+pub fn frame_len(src: &[u8]) -> Result<Option<usize>, MqttCodecError> {
+    let mut remaining = 0_usize;
+    for idx in 0..MAX_REMAINING_LEN_BYTES {
+        match src.get(idx + 1) {
+            Some(next_byte) => {
+                remaining += ((next_byte & 0x7f) as usize) << (7 * idx);
+                if next_byte & 0x80 == 0 {
+                    return Ok(Some(1 + (idx + 1) + remaining));
+                }
+            }
+            None => return Ok(None),
+        }
+    }
+    Err(MqttCodecError::new(
+        "malformed packet: variable byte integer",
+    ))
 }
 
 pub fn decode_fixed_header(src: &mut BytesMut) -> Result<Option<FixedHeader>, MqttCodecError> {
     if src.remaining() < 2 {
         return Ok(None);
     }
-    for idx in 1..=3 {
-        if src[idx] & 0x80 != 0x00 {
-            // insufficient bytes left to read remaining
-            if src.remaining() < 1 {
-                return Ok(None);
-            }
-        } else {
-            break;
+    // wait for the remaining length to be complete, a remaining length longer
+    // than 4 bytes is reported by get_var_u32
+    for idx in 1..=MAX_REMAINING_LEN_BYTES {
+        match src.get(idx) {
+            Some(byte) if byte & 0x80 == 0 => break,
+            Some(_) => {}
+            None => return Ok(None),
         }
     }
-    let first_byte = src.get_u8();
+    let first_byte = get_u8(src)?;
     let packet_type = PacketType::from(first_byte);
-    let flags = first_byte & 0x0f;
-    let packet_remaining = get_var_u32(src)?;
+    let packet_remaining = get_var_u32(src).map_err(|e| e.in_packet(packet_type, 1))?;
     match src.remaining() {
         val if val < packet_remaining as usize => {
-            return Err(MqttCodecError {
-                reason: format!(
+            return Err(MqttCodecError::new_with_kind(
+                &format!(
                     "malformed packet: remaining length actual: {} expected: {}",
                     val, packet_remaining
                 ),
-                kind: ErrorKind::InsufficientData(packet_remaining as usize, val),
-            })
+                ErrorKind::InsufficientData(packet_remaining as usize, val),
+            )
+            .in_packet(
+                packet_type,
+                1 + variable_byte_int_size(packet_remaining) as usize,
+            ))
         }
         val if val > packet_remaining as usize => {
             let total = src.remaining();
@@ -565,23 +1082,25 @@ pub fn decode_fixed_header(src: &mut BytesMut) -> Result<Option<FixedHeader>, Mq
         }
         _ => {}
     }
+    new_fixed_header(first_byte, packet_remaining).map(Some)
+}
+
+/// Creates the fixed header from the first byte of a packet, checking the
+/// flags for the packet type.
+pub(crate) fn new_fixed_header(
+    first_byte: u8,
+    remaining: u32,
+) -> Result<FixedHeader, MqttCodecError> {
+    let packet_type = PacketType::from(first_byte);
+    let flags = first_byte & 0x0f;
     match packet_type {
+        PacketType::PubRel | PacketType::Subscribe | PacketType::Unsubscribe => {
+            check_reserved_flags(packet_type, flags, PACKET_RESERVED_BIT1)?;
+            Ok(FixedHeader::new_with_remaining(packet_type, remaining))
+        }
         PacketType::Connect
-        | PacketType::PubRel
+        | PacketType::ConnAck
         | PacketType::PubAck
-        | PacketType::Subscribe
-        | PacketType::Unsubscribe => {
-            if flags != PACKET_RESERVED_NONE {
-                MqttCodecError::new(
-                    format!("invalid flags for {}: {}", packet_type, flags).as_str(),
-                );
-            }
-            Ok(Some(FixedHeader::new_with_remaining(
-                packet_type,
-                packet_remaining,
-            )))
-        }
-        PacketType::ConnAck
         | PacketType::PubRec
         | PacketType::PubComp
         | PacketType::SubAck
@@ -590,20 +1109,32 @@ pub fn decode_fixed_header(src: &mut BytesMut) -> Result<Option<FixedHeader>, Mq
         | PacketType::PingResp
         | PacketType::Disconnect
         | PacketType::Auth => {
-            if flags != PACKET_RESERVED_NONE {
-                MqttCodecError::new(
-                    format!("invalid flags for {}: {}", packet_type, flags).as_str(),
-                );
-            }
-            Ok(Some(FixedHeader::new_with_remaining(
-                packet_type,
-                packet_remaining,
-            )))
+            check_reserved_flags(packet_type, flags, PACKET_RESERVED_NONE)?;
+            Ok(FixedHeader::new_with_remaining(packet_type, remaining))
         }
         PacketType::Publish => {
-            let mut header = FixedHeader::new_with_remaining(packet_type, packet_remaining);
-            header.set_flags(flags)?;
-            Ok(Some(header))
+            let mut header = FixedHeader::new_with_remaining(packet_type, remaining);
+            header
+                .set_flags(flags)
+                .map_err(|e| e.in_packet(packet_type, 0))?;
+            Ok(header)
         }
     }
 }
+
+/// Reserved fixed header flags must hold the value given for the packet type,
+/// MQTT v5 2.1.3. The flags are only checked with the `pedantic` feature.
+fn check_reserved_flags(
+    packet_type: PacketType,
+    flags: u8,
+    expected: u8,
+) -> Result<(), MqttCodecError> {
+    if cfg!(feature = "pedantic") && flags != expected {
+        return Err(MqttCodecError::new_with_kind(
+            &format!("MQTTv5 2.1.3 invalid flags for {}: {}", packet_type, flags),
+            ErrorKind::InvalidFlags(packet_type, flags),
+        )
+        .in_packet(packet_type, 0));
+    }
+    Ok(())
+}
@@ -1,11 +1,35 @@
-use crate::codec::Reason;
-use crate::property::PropertyBundle;
-use crate::{Decode, Encode, PropertyType, Size};
+use crate::codec::{get_u8, variable_byte_int_size, Reason};
+use crate::property::{
+    PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize,
+};
+use crate::{Decode, Encode, Size};
 use crate::{FixedHeader, MqttCodecError, PacketType};
 use bytes::{Buf, BufMut, BytesMut};
-use std::collections::HashSet;
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, Eq, PartialEq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(
+    SessionExpiryInterval,
+    RecvMax,
+    MaxQoS,
+    RetainAvail,
+    MaxPacketSize,
+    AssignedClientId,
+    TopicAliasMax,
+    ReasonString,
+    SubIdAvail,
+    UserProperty,
+    WildcardSubAvail,
+    ShardSubAvail,
+    KeepAlive,
+    RespInfo,
+    ServerReference,
+    AuthMethod,
+    AuthData
+)]
 pub struct ConnAck {
     pub session_present: bool,
     reason: Reason,
@@ -13,33 +37,14 @@ pub struct ConnAck {
 }
 
 impl ConnAck {
-    fn allowed_properties() -> HashSet<PropertyType> {
-        let mut allowed = HashSet::new();
-        allowed.insert(PropertyType::SessionExpiryInterval);
-        allowed.insert(PropertyType::RecvMax);
-        allowed.insert(PropertyType::MaxQoS);
-        allowed.insert(PropertyType::RetainAvail);
-        allowed.insert(PropertyType::MaxPacketSize);
-        allowed.insert(PropertyType::AssignedClientId);
-        allowed.insert(PropertyType::TopicAliasMax);
-        allowed.insert(PropertyType::ReasonString);
-        allowed.insert(PropertyType::SubIdAvail);
-        allowed.insert(PropertyType::UserProperty);
-        allowed.insert(PropertyType::WildcardSubAvail);
-        allowed.insert(PropertyType::ShardSubAvail);
-        allowed.insert(PropertyType::KeepAlive);
-        allowed.insert(PropertyType::RespInfo);
-        allowed.insert(PropertyType::ServerReference);
-        allowed.insert(PropertyType::AuthMethod);
-        allowed.insert(PropertyType::AuthData);
-
-        allowed
-    }
-
     pub fn reason(&self) -> Reason {
         self.reason
     }
 
+    pub fn set_reason(&mut self, reason: Reason) {
+        self.reason = reason;
+    }
+
     pub fn properties(&self) -> &PropertyBundle {
         &self.properties
     }
@@ -47,6 +52,35 @@ impl ConnAck {
     pub fn properties_mut(&mut self) -> &mut PropertyBundle {
         &mut self.properties
     }
+
+    /// Decodes an MQTT 3.1.1 CONNACK, mapping the return code to a reason.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < 2 {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.2.2 insufficient data for CONNACK",
+            ));
+        }
+        self.session_present = (0x01 & get_u8(src)?) > 0;
+        self.reason = Reason::from_v3_connack_code(get_u8(src)?)?;
+        Ok(())
+    }
+
+    /// Encodes the CONNACK for MQTT 3.1.1. Properties are dropped and the
+    /// reason is mapped to the closest 3.1.1 return code.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let mut header = FixedHeader::new(PacketType::ConnAck);
+        header.set_remaining(self.size_v3());
+        header.encode(dest)?;
+        dest.put_u8(self.session_present as u8);
+        dest.put_u8(self.reason.to_v3_connack_code());
+        Ok(())
+    }
+
+    /// Gets the remaining length of the CONNACK for MQTT 3.1.1, the session
+    /// present flag and the return code.
+    pub(crate) fn size_v3(&self) -> u32 {
+        2
+    }
 }
 
 impl Default for ConnAck {
@@ -54,19 +88,20 @@ impl Default for ConnAck {
         ConnAck {
             session_present: false,
             reason: Reason::Success,
-            properties: PropertyBundle::new(ConnAck::allowed_properties()),
+            properties: PropertyBundle::new(ConnAck::supported_properties()),
         }
     }
 }
 
 impl crate::Size for ConnAck {
     fn size(&self) -> u32 {
-        // variable header is 3 bytes
-        3 + self.property_size()
+        // acknowledge flags and reason code followed by the properties
+        let prop_size = self.property_size();
+        2 + variable_byte_int_size(prop_size) + prop_size
     }
 
     fn property_size(&self) -> u32 {
-        self.properties.size()
+        self.property_size_internal()
     }
 
     /// Implementation of PacketSize. CONNACK packet does not have a payload.
@@ -77,25 +112,23 @@ impl crate::Size for ConnAck {
 
 impl Decode for ConnAck {
     fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
-        self.session_present = (0x01 & src.get_u8()) > 0;
-        if let Ok(reason) = src.get_u8().try_into() {
+        self.session_present = (0x01 & get_u8(src)?) > 0;
+        if let Ok(reason) = get_u8(src)?.try_into() {
             self.reason = reason;
         }
-        self.properties.decode(src)?;
+        self.property_decode(src)?;
         Ok(())
     }
 }
 
 impl Encode for ConnAck {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         let mut header = FixedHeader::new(PacketType::ConnAck);
         header.set_remaining(self.size());
         header.encode(dest)?;
         dest.put_u8(self.session_present as u8);
         dest.put_u8(self.reason as u8);
-        // reserve capacity to avoid intermediate reallocation
-        dest.reserve(self.property_size() as usize);
-        self.properties.encode(dest)?;
+        self.property_encode(dest)?;
         Ok(())
     }
 }
@@ -120,11 +153,13 @@ mod test {
         const REASON: &str = "Malformed Packet";
         let auth_data = vec![0, 1, 2, 3, 4, 5];
         let expected_len = (REASON.len() + auth_data.len() + 11) as u32;
-        let mut connack = ConnAck::default();
-        connack.reason = Reason::MalformedPacket;
+        let mut connack = ConnAck {
+            reason: Reason::MalformedPacket,
+            ..Default::default()
+        };
         let props = connack.properties_mut();
         props.set_property(Property::ReasonString(REASON.to_owned()));
-        props.set_property(Property::AuthData(auth_data.clone()));
+        props.set_property(Property::AuthData(auth_data.clone().into()));
         props.set_property(Property::ShardSubAvail(false));
         assert_eq!(expected_len, connack.size());
     }
@@ -1,16 +1,25 @@
-use crate::codec::{get_bin, get_utf8, put_bin, MqttCodecError};
-use crate::property::{PropertyBundle, PropertySize};
+use crate::builder::ConnectBuilder;
+use crate::codec::{
+    get_bin, get_u16, get_u32, get_u8, get_utf8, put_bin, ErrorKind, MqttCodecError,
+    ProtocolVersion,
+};
+use crate::property::{
+    PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize,
+};
 use crate::{
-    put_utf8, variable_byte_int_size, Decode, Encode, FixedHeader, PacketType, PropertyType,
-    QoSLevel, Size, WillMessage,
+    put_utf8, variable_byte_int_size, Decode, Encode, FixedHeader, PacketType, QoSLevel, Size,
+    WillMessage,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
 };
 use bytes::{Buf, BufMut, BytesMut};
-use prop_macro::PropertySize;
-use std::collections::HashSet;
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
-const MQTT_PROTOCOL_NAME_LEN: u16 = 0x00_04;
-const MQTT_PROTOCOL_U32: u32 = 0x4d515454;
-const MQTT_PROTOCOL_VERSION: u8 = 0x05;
+pub(crate) const MQTT_PROTOCOL_NAME_LEN: u16 = 0x00_04;
+pub(crate) const MQTT_PROTOCOL_U32: u32 = 0x4d515454;
 
 pub(crate) const CONNECT_FLAG_USERNAME: u8 = 0b_1000_0000;
 pub(crate) const CONNECT_FLAG_PASSWORD: u8 = 0b_0100_0000;
@@ -21,20 +30,44 @@ pub(crate) const CONNECT_FLAG_CLEAN_START: u8 = 0b_0000_0010;
 pub(crate) const CONNECT_FLAG_SHIFT: u8 = 0x03;
 
 /// Default remaining size for connect packet
-const DEFAULT_CONNECT_REMAINING: u32 = 10;
-
-#[derive(PropertySize, Debug, Clone, Eq, PartialEq)]
+pub(crate) const DEFAULT_CONNECT_REMAINING: u32 = 10;
+
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, Eq, PartialEq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(
+    SessionExpiryInterval,
+    RecvMax,
+    MaxPacketSize,
+    TopicAliasMax,
+    ReqRespInfo,
+    ReqProblemInfo,
+    UserProperty,
+    AuthMethod,
+    AuthData
+)]
 pub struct Connect {
+    /// Protocol version requested by the client. An MQTT 3.1.1 CONNECT is
+    /// encoded without properties, including the will properties.
+    pub protocol_version: ProtocolVersion,
     props: PropertyBundle,
     pub clean_start: bool,
     pub keep_alive: u16,
     pub will_message: Option<WillMessage>,
     pub client_id: String,
     pub username: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default, with = "crate::serde_bin::option"))]
     pub password: Option<Vec<u8>>,
 }
 
 impl Connect {
+    /// Creates a builder for a CONNECT from the client, see
+    /// [`crate::builder`].
+    pub fn builder(client_id: impl Into<String>) -> ConnectBuilder {
+        ConnectBuilder::new(client_id)
+    }
+
     pub fn properties(&self) -> &PropertyBundle {
         &self.props
     }
@@ -43,7 +76,7 @@ impl Connect {
         &mut self.props
     }
 
-    fn encode_flags(&self, dest: &mut BytesMut) {
+    fn encode_flags(&self, dest: &mut impl BufMut) {
         let mut flags = 0_u8;
         if self.clean_start {
             flags |= CONNECT_FLAG_CLEAN_START;
@@ -65,39 +98,37 @@ impl Connect {
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
-        let len = src.get_u16();
+        let len = get_u16(src)?;
         if len != 0x04 {
             return Err(MqttCodecError::new(
                 format!("invalid protocol name length: {}", len).as_str(),
             ));
         }
-        let mqtt_str = src.get_u32();
+        let mqtt_str = get_u32(src)?;
         if mqtt_str != MQTT_PROTOCOL_U32 {
-            return Err(MqttCodecError::new("unsupported protocol"));
-        }
-        let protocol_version = src.get_u8();
-        if protocol_version != MQTT_PROTOCOL_VERSION {
-            return Err(MqttCodecError::new(
-                format!("unsupported protocol version: {}", protocol_version).as_str(),
+            return Err(MqttCodecError::new_with_kind(
+                "unsupported protocol",
+                ErrorKind::UnsupportedProtocolVersion,
             ));
         }
+        self.protocol_version = ProtocolVersion::try_from(get_u8(src)?)?;
         // connect flags
-        let connect_flags = src.get_u8();
+        let connect_flags = get_u8(src)?;
         let username = connect_flags & CONNECT_FLAG_USERNAME != 0;
         let password = connect_flags & CONNECT_FLAG_PASSWORD != 0;
         self.clean_start = connect_flags & CONNECT_FLAG_CLEAN_START != 0;
         if connect_flags & CONNECT_FLAG_WILL != 0 {
             let will_retain = connect_flags & CONNECT_FLAG_WILL_RETAIN != 0;
-            let qos = connect_flags & CONNECT_FLAG_WILL_QOS >> CONNECT_FLAG_SHIFT;
+            let qos = (connect_flags & CONNECT_FLAG_WILL_QOS) >> CONNECT_FLAG_SHIFT;
             if let Ok(qos) = QoSLevel::try_from(qos) {
                 self.will_message = Some(WillMessage::new(qos, will_retain));
             } else {
                 return Err(MqttCodecError::new("invalid Will QoS level"));
             }
         }
-        self.keep_alive = src.get_u16();
-        if src.remaining() > 0 {
-            self.props.decode(src)?;
+        self.keep_alive = get_u16(src)?;
+        if self.protocol_version == ProtocolVersion::V5 && src.remaining() > 0 {
+            self.property_decode(src)?;
         }
         self.decode_payload(src, username, password)?;
         Ok(())
@@ -110,15 +141,17 @@ impl Connect {
         password: bool,
     ) -> Result<(), MqttCodecError> {
         self.client_id = get_utf8(src)?;
-        if self.will_message.is_some() {
-            let will_message = self.will_message.as_mut().unwrap();
-            will_message.decode(src)?;
+        if let Some(will_message) = self.will_message.as_mut() {
+            match self.protocol_version {
+                ProtocolVersion::V3_1_1 => will_message.decode_v3(src)?,
+                ProtocolVersion::V5 => will_message.decode(src)?,
+            }
         }
         if username {
             self.username = Some(get_utf8(src)?);
         }
         if password {
-            self.password = Some(get_bin(src)?);
+            self.password = Some(get_bin(src)?.to_vec());
         }
         Ok(())
     }
@@ -126,19 +159,25 @@ impl Connect {
 
 impl crate::Size for Connect {
     fn size(&self) -> u32 {
+        if self.protocol_version == ProtocolVersion::V3_1_1 {
+            return DEFAULT_CONNECT_REMAINING + self.payload_size();
+        }
         let property_remaining = self.property_size();
         let len = variable_byte_int_size(property_remaining);
         DEFAULT_CONNECT_REMAINING + len + property_remaining + self.payload_size()
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
         let mut remaining = 2 + self.client_id.len() as u32;
         if let Some(will_message) = &self.will_message {
-            remaining += will_message.size();
+            remaining += match self.protocol_version {
+                ProtocolVersion::V3_1_1 => will_message.payload_size(),
+                ProtocolVersion::V5 => will_message.size(),
+            };
         }
         if let Some(username) = &self.username {
             remaining += username.len() as u32 + 2;
@@ -151,28 +190,26 @@ impl crate::Size for Connect {
 }
 
 impl Encode for Connect {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         let mut header = FixedHeader::new(PacketType::Connect);
-        let prop_remaining = self.property_size();
-        let payload_remaining = self.payload_size();
-        header.set_remaining(
-            DEFAULT_CONNECT_REMAINING
-                + prop_remaining
-                + payload_remaining
-                + variable_byte_int_size(prop_remaining),
-        );
+        header.set_remaining(self.size());
         header.encode(dest)?;
         dest.put_u16(MQTT_PROTOCOL_NAME_LEN);
         dest.put_u32(MQTT_PROTOCOL_U32);
-        dest.put_u8(MQTT_PROTOCOL_VERSION);
+        dest.put_u8(self.protocol_version as u8);
         self.encode_flags(dest);
         dest.put_u16(self.keep_alive);
-        self.props.encode(dest)?;
+        if self.protocol_version == ProtocolVersion::V5 {
+            self.property_encode(dest)?;
+        }
         // payload
         put_utf8(&self.client_id, dest)?;
         // connect payload
         if let Some(will_message) = &self.will_message {
-            will_message.encode(dest)?;
+            match self.protocol_version {
+                ProtocolVersion::V3_1_1 => will_message.encode_v3(dest)?,
+                ProtocolVersion::V5 => will_message.encode(dest)?,
+            }
         }
         if let Some(username) = &self.username {
             put_utf8(username, dest)?;
@@ -192,19 +229,9 @@ impl Decode for Connect {
 
 impl Default for Connect {
     fn default() -> Self {
-        let mut allowed = HashSet::new();
-        allowed.insert(PropertyType::SessionExpiryInterval);
-        allowed.insert(PropertyType::RecvMax);
-        allowed.insert(PropertyType::MaxPacketSize);
-        allowed.insert(PropertyType::TopicAliasMax);
-        allowed.insert(PropertyType::ReqRespInfo);
-        allowed.insert(PropertyType::ReqProblemInfo);
-        allowed.insert(PropertyType::UserProperty);
-        allowed.insert(PropertyType::AuthMethod);
-        allowed.insert(PropertyType::AuthData);
-
         Connect {
-            props: PropertyBundle::new(allowed),
+            protocol_version: ProtocolVersion::default(),
+            props: PropertyBundle::new(Self::supported_properties()),
             clean_start: false,
             keep_alive: 0,
             will_message: None,
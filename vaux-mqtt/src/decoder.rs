@@ -0,0 +1,185 @@
+use alloc::format;
+use bytes::BytesMut;
+
+use crate::codec::{decode_with_version, frame_len, ErrorKind, ProtocolVersion};
+use crate::{MqttCodecError, Packet, PacketType};
+
+/// Largest packet allowed by MQTT v5 1.5.5, a remaining length of 268,435,455
+/// bytes following a 5 byte fixed header.
+pub const MAX_PACKET_SIZE: usize = 268_435_460;
+
+/// Incremental decoder for a stream of MQTT packets. Bytes may arrive in any
+/// number of reads; a packet is only decoded once every byte of it has been
+/// buffered. Each call decodes exactly one packet, splitting it from the front
+/// of the buffer and leaving any bytes that follow for the next call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamDecoder {
+    max_packet_size: usize,
+    version: ProtocolVersion,
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self {
+            max_packet_size: MAX_PACKET_SIZE,
+            version: ProtocolVersion::default(),
+        }
+    }
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum size of a packet, including the fixed header. A packet
+    /// announcing a larger remaining length is rejected as soon as its fixed
+    /// header is read.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets the protocol version used to decode packets following CONNECT.
+    pub fn with_version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// Decodes the next packet from the buffer. Returns None, leaving the
+    /// buffer untouched, if the packet has not been completely received. An
+    /// error is returned if the fixed header is malformed or the packet
+    /// exceeds the maximum packet size.
+    ///
+    /// No space is reserved from the remaining length announced by the peer,
+    /// the buffer only grows as the bytes of the packet arrive.
+    pub fn decode(&self, src: &mut BytesMut) -> Result<Option<Packet>, MqttCodecError> {
+        let len = match frame_len(src)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len > self.max_packet_size {
+            return Err(MqttCodecError::new_with_kind(
+                &format!(
+                    "packet size {} exceeds maximum packet size {}",
+                    len, self.max_packet_size
+                ),
+                ErrorKind::PacketTooLarge(len, self.max_packet_size),
+            )
+            .in_packet(PacketType::from(src[0]), 0));
+        }
+        if src.len() < len {
+            return Ok(None);
+        }
+        let mut frame = src.split_to(len);
+        Ok(decode_with_version(&mut frame, self.version)?.map(|(packet, _)| packet))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use crate::{encode, publish::Publish, FixedHeader, PacketType};
+
+    use super::*;
+
+    fn encoded_publish(payload: &[u8]) -> BytesMut {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_payload(payload.to_vec());
+        let mut dest = BytesMut::new();
+        encode(Packet::Publish(publish), &mut dest).unwrap();
+        dest
+    }
+
+    #[test]
+    fn test_partial_packet() {
+        let decoder = StreamDecoder::new();
+        let packet = encoded_publish(b"21");
+        let mut src = BytesMut::new();
+        for byte in &packet[..packet.len() - 1] {
+            src.put_u8(*byte);
+            assert_eq!(None, decoder.decode(&mut src).unwrap());
+            assert!(!src.is_empty());
+        }
+        src.put_u8(packet[packet.len() - 1]);
+        match decoder.decode(&mut src) {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(Some(&b"21"[..]), publish.payload())
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let decoder = StreamDecoder::new();
+        let mut src = encoded_publish(b"21");
+        let ping = encode_ping();
+        src.extend_from_slice(&ping);
+        src.extend_from_slice(&encoded_publish(b"22")[..3]);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Ok(Some(Packet::Publish(_)))
+        ));
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Ok(Some(Packet::PingRequest(_)))
+        ));
+        assert_eq!(None, decoder.decode(&mut src).unwrap());
+        assert_eq!(3, src.len());
+    }
+
+    #[test]
+    fn test_max_packet_size() {
+        let decoder = StreamDecoder::new().with_max_packet_size(16);
+        // only the fixed header is needed to reject the packet
+        let mut src = BytesMut::from(&encoded_publish(&[0; 64])[..2]);
+        match decoder.decode(&mut src) {
+            Err(e) => assert_eq!(ErrorKind::PacketTooLarge(80, 16), e.kind()),
+            p => panic!("expected packet too large, found {:?}", p),
+        }
+        assert_eq!(2, src.len());
+    }
+
+    #[test]
+    fn test_announced_length_not_reserved() {
+        let decoder = StreamDecoder::new();
+        // PUBLISH announcing the largest remaining length
+        let mut src = BytesMut::from(&[0x30, 0xff, 0xff, 0xff, 0x7f, 0x00][..]);
+        let capacity = src.capacity();
+        assert_eq!(None, decoder.decode(&mut src).unwrap());
+        assert_eq!(capacity, src.capacity());
+    }
+
+    #[test]
+    fn test_malformed_remaining_length() {
+        let decoder = StreamDecoder::new();
+        let mut src = BytesMut::from(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01][..]);
+        assert!(decoder.decode(&mut src).is_err());
+    }
+
+    fn encode_ping() -> BytesMut {
+        let mut dest = BytesMut::new();
+        encode(
+            Packet::PingRequest(FixedHeader::new(PacketType::PingReq)),
+            &mut dest,
+        )
+        .unwrap();
+        dest
+    }
+}
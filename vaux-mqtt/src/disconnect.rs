@@ -1,27 +1,20 @@
-use std::collections::HashSet;
-
 use bytes::{Buf, BufMut};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
 use crate::{
-    codec::variable_byte_int_size,
-    property::{PacketProperties, PropertyBundle},
-    Decode, Encode, FixedHeader, PacketType, PropertyType, Reason, Size,
+    builder::DisconnectBuilder,
+    codec::{get_u8, variable_byte_int_size},
+    property::{PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize},
+    Decode, Encode, FixedHeader, PacketType, Reason, Size,
 };
 
-lazy_static! {
-    static ref SUPPORTED_DISCONNECT_PROPS: HashSet<PropertyType> = {
-        let mut set = HashSet::new();
-        set.insert(PropertyType::SessionExpiryInterval);
-        set.insert(PropertyType::ReasonString);
-        set.insert(PropertyType::UserProperty);
-        set.insert(PropertyType::ServerReference);
-        set
-    };
-}
-
 const DEFAULT_DISCONNECT_REMAINING: u32 = 1;
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Clone, Debug, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(SessionExpiryInterval, ReasonString, UserProperty, ServerReference)]
 pub struct Disconnect {
     pub reason: Reason,
     props: PropertyBundle,
@@ -31,22 +24,33 @@ impl Disconnect {
     pub fn new(reason: Reason) -> Self {
         Self {
             reason,
-            props: PropertyBundle::new(SUPPORTED_DISCONNECT_PROPS.clone()),
+            props: PropertyBundle::new(Self::supported_properties()),
         }
     }
+
+    /// Creates a builder for a DISCONNECT with the reason, see
+    /// [`crate::builder`].
+    pub fn builder(reason: Reason) -> DisconnectBuilder {
+        DisconnectBuilder::new(reason)
+    }
 }
 
-impl PacketProperties for Disconnect {
-    fn properties(&self) -> &PropertyBundle {
-        &self.props
+impl Disconnect {
+    /// Encodes the DISCONNECT for MQTT 3.1.1, which has no reason code or
+    /// properties.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), crate::MqttCodecError> {
+        FixedHeader::new_with_remaining(PacketType::Disconnect, self.size_v3()).encode(dest)
     }
 
-    fn properties_mut(&mut self) -> &mut PropertyBundle {
-        &mut self.props
+    /// Gets the remaining length of the DISCONNECT for MQTT 3.1.1.
+    pub(crate) fn size_v3(&self) -> u32 {
+        0
     }
+}
 
-    fn set_properties(&mut self, props: PropertyBundle) {
-        self.props = props;
+impl Default for Disconnect {
+    fn default() -> Self {
+        Self::new(Reason::Success)
     }
 }
 
@@ -62,7 +66,7 @@ impl Size for Disconnect {
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     /// The Disconnect packet does not have a payload. None is returned
@@ -72,18 +76,15 @@ impl Size for Disconnect {
 }
 
 impl Encode for Disconnect {
-    fn encode(&self, dest: &mut bytes::BytesMut) -> Result<(), crate::MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), crate::MqttCodecError> {
         let mut header = FixedHeader::new(PacketType::Disconnect);
-        let prop_remaining = self.property_size();
-        header.remaining = DEFAULT_DISCONNECT_REMAINING + variable_byte_int_size(prop_remaining);
-        if self.reason == Reason::Success && prop_remaining == 0 {
-            header.remaining = 0;
-            header.encode(dest)?;
+        header.set_remaining(self.size());
+        header.encode(dest)?;
+        if header.remaining == 0 {
             return Ok(());
         }
-        header.encode(dest)?;
         dest.put_u8(self.reason as u8);
-        self.props.encode(dest)?;
+        self.property_encode(dest)?;
         Ok(())
     }
 }
@@ -95,8 +96,8 @@ impl Decode for Disconnect {
             self.reason = Reason::Success;
             return Ok(());
         }
-        self.reason = Reason::try_from(src.get_u8())?;
-        self.props.decode(src)?;
+        self.reason = Reason::try_from(get_u8(src)?)?;
+        self.property_decode(src)?;
         Ok(())
     }
 }
@@ -113,7 +114,7 @@ mod test {
         let mut dest = BytesMut::new();
         match disconnect.encode(&mut dest) {
             Ok(_) => {
-                assert_eq!(2 as usize, dest.len());
+                assert_eq!(2_usize, dest.len());
                 assert_eq!(0, dest[1]);
             }
             Err(e) => panic!("Unexpected encoding error {:?}", e.to_string()),
@@ -131,7 +132,7 @@ mod test {
         let mut dest = BytesMut::new();
         match disconnect.encode(&mut dest) {
             Ok(_) => {
-                assert_eq!("failed".len() + 7 as usize, dest.len());
+                assert_eq!("failed".len() + 7_usize, dest.len());
             }
             Err(e) => panic!("Unexpected encoding error {:?}", e.to_string()),
         }
@@ -139,7 +140,7 @@ mod test {
 
     #[test]
     fn test_encode_server_ref() {
-        const SERVER_REF: &'static str = "bytetrail.org";
+        const SERVER_REF: &str = "bytetrail.org";
         const PROP_LEN: u8 = 16;
         let mut disconnect = Disconnect::new(Reason::ServerMoved);
         disconnect
@@ -163,8 +164,10 @@ mod test {
         let encoded: [u8; 0] = [];
         let mut src = BytesMut::new();
         src.extend_from_slice(&encoded);
-        let mut disconnect = Disconnect::default();
-        disconnect.reason = Reason::ImplementationErr;
+        let mut disconnect = Disconnect {
+            reason: Reason::ImplementationErr,
+            ..Default::default()
+        };
         let result = disconnect.decode(&mut src);
         assert!(
             result.is_ok(),
@@ -179,8 +182,10 @@ mod test {
         let encoded: [u8; 2] = [Reason::AdminAction as u8, 0x00];
         let mut src = BytesMut::new();
         src.extend_from_slice(&encoded);
-        let mut disconnect = Disconnect::default();
-        disconnect.reason = Reason::ImplementationErr;
+        let mut disconnect = Disconnect {
+            reason: Reason::ImplementationErr,
+            ..Default::default()
+        };
         let result = disconnect.decode(&mut src);
         assert!(
             result.is_ok(),
@@ -1,14 +1,16 @@
-use bytes::{BufMut, BytesMut};
+use bytes::BufMut;
 
 use crate::{
     codec::{put_var_u32, ErrorKind, PACKET_RESERVED_BIT1, PACKET_RESERVED_NONE},
     Encode, MqttCodecError, PacketType, QoSLevel,
 };
 
+const DUP_MASK: u8 = 0b_0000_1000;
 const QOS_MASK: u8 = 0b_0000_0110;
 const RETAIN_MASK: u8 = 0b_0000_0001;
 
 #[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedHeader {
     pub packet_type: PacketType,
     flags: u8,
@@ -46,6 +48,16 @@ impl FixedHeader {
         (self.flags & RETAIN_MASK) != 0
     }
 
+    /// Sets the DUP flag of a PUBLISH, set when the packet is a re-delivery
+    /// of an earlier attempt to send it, MQTT v5 3.3.1.1.
+    pub fn set_dup(&mut self, dup: bool) {
+        self.flags = self.flags & !DUP_MASK | (dup as u8) << 3;
+    }
+
+    pub fn dup(&self) -> bool {
+        (self.flags & DUP_MASK) != 0
+    }
+
     pub fn set_qos(&mut self, qos: QoSLevel) {
         self.flags = self.flags & !QOS_MASK | (qos as u8) << 1;
     }
@@ -60,10 +72,10 @@ impl FixedHeader {
 
     pub fn set_flags(&mut self, flags: u8) -> Result<(), MqttCodecError> {
         if flags & QOS_MASK == QOS_MASK {
-            return Err(MqttCodecError {
-                reason: "unsupported QOS level".to_string(),
-                kind: ErrorKind::UnsupportedQosLevel,
-            });
+            return Err(MqttCodecError::new_with_kind(
+                "unsupported QOS level",
+                ErrorKind::UnsupportedQosLevel,
+            ));
         }
         self.flags = flags;
         Ok(())
@@ -93,7 +105,7 @@ impl crate::Size for FixedHeader {
 }
 
 impl Encode for FixedHeader {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         dest.put_u8(self.packet_type as u8 | self.flags);
         put_var_u32(self.remaining, dest);
         Ok(())
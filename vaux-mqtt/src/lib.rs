@@ -1,32 +1,57 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod alias;
+pub mod builder;
 pub mod codec;
 pub mod connack;
 pub mod connect;
+pub mod decoder;
 pub mod disconnect;
 pub mod fixed;
+pub mod packet_ref;
 pub mod property;
 pub mod publish;
 pub mod pubresp;
+#[cfg(feature = "serde")]
+mod serde_bin;
 pub mod subscribe;
 pub mod test;
+#[cfg(feature = "tokio-codec")]
+pub mod tokio_codec;
+pub mod topic;
+pub mod unsubscribe;
+pub mod validate;
 mod will;
 
 use crate::codec::{put_utf8, variable_byte_int_size};
 
 pub use crate::property::PropertyType;
 
+pub use crate::alias::TopicAliases;
+
+pub use crate::builder::{
+    ConnectBuilder, DisconnectBuilder, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder,
+    WillBuilder,
+};
 pub use crate::codec::{
-    decode, decode_fixed_header, encode, MqttCodecError, Packet, PacketType, QoSLevel, Reason,
+    decode, decode_fixed_header, decode_with_version, encode, encode_slice,
+    encode_slice_with_version, encode_with_version, encoded_len, MqttCodecError, Packet,
+    PacketType, ProtocolVersion, QoSLevel, Reason,
 };
 pub use crate::connack::ConnAck;
 pub use crate::connect::Connect;
+pub use crate::decoder::StreamDecoder;
+#[cfg(feature = "tokio-codec")]
+pub use crate::tokio_codec::MqttCodec;
+pub use crate::topic::{TopicFilter, TopicName};
 pub use crate::will::WillMessage;
 pub use crate::{
-    disconnect::Disconnect, fixed::FixedHeader, pubresp::PubResp, subscribe::Subscribe,
-    subscribe::Subscription,
+    disconnect::Disconnect, fixed::FixedHeader, pubresp::PubResp, subscribe::SubAck,
+    subscribe::Subscribe, subscribe::Subscription, unsubscribe::UnsubAck, unsubscribe::Unsubscribe,
 };
-use bytes::BytesMut;
-#[macro_use]
-extern crate lazy_static;
+use bytes::{BufMut, BytesMut};
 
 pub trait Size {
     fn size(&self) -> u32;
@@ -35,7 +60,7 @@ pub trait Size {
 }
 
 pub trait Encode: Size {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError>;
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError>;
 }
 
 pub trait Decode {
@@ -0,0 +1,1652 @@
+//! Packets built from borrowed strings, binary data and properties. The
+//! borrowed packets are encoded in the MQTT v5 wire format without allocating,
+//! for example into a fixed size buffer with [`PacketRef::encode_slice`], and
+//! are decoded in place with [`PacketRef::decode`] so that reading the topic
+//! and QoS of a PUBLISH does not copy the packet.
+
+use alloc::{format, string::String};
+use bytes::BufMut;
+
+use crate::validate::{
+    validate_packet_id, validate_publish_header, validate_response_topic, validate_subscribe_ids,
+    validate_subscription_ids, validate_subscriptions, validate_topic_name,
+    validate_unsubscribe_filters,
+};
+use crate::{
+    codec::{
+        check_buffer_len, frame_len, new_fixed_header, put_bin, put_utf8, put_var_u32,
+        variable_byte_int_size, ErrorKind, SIZE_UTF8_STRING,
+    },
+    connect::{
+        CONNECT_FLAG_CLEAN_START, CONNECT_FLAG_PASSWORD, CONNECT_FLAG_SHIFT, CONNECT_FLAG_USERNAME,
+        CONNECT_FLAG_WILL, CONNECT_FLAG_WILL_QOS, CONNECT_FLAG_WILL_RETAIN,
+        DEFAULT_CONNECT_REMAINING, MQTT_PROTOCOL_NAME_LEN, MQTT_PROTOCOL_U32,
+    },
+    property::{PayloadFormat, PropertyRef},
+    publish::Publish,
+    pubresp::VARIABLE_HEADER_LEN,
+    subscribe::{RetainHandling, Subscription, VAR_HDR_LEN},
+    ConnAck, Connect, Disconnect, Encode, FixedHeader, MqttCodecError, PacketType, PropertyType,
+    ProtocolVersion, PubResp, QoSLevel, Reason, Size, SubAck, Subscribe, UnsubAck, Unsubscribe,
+    WillMessage,
+};
+
+const PUBLISH_DUP_SHIFT: u8 = 0x03;
+const PUBLISH_QOS_SHIFT: u8 = 0x01;
+const SUBSCRIPTION_QOS_MASK: u8 = 0b_0000_0011;
+const SUBSCRIPTION_NO_LOCAL: u8 = 0b_0000_0100;
+const SUBSCRIPTION_RETAIN_AS: u8 = 0b_0000_1000;
+const SUBSCRIPTION_HANDLING_MASK: u8 = 0b_0011_0000;
+const SUBSCRIPTION_HANDLING_SHIFT: u8 = 0x04;
+
+/// Items of a borrowed packet, such as the properties of a packet or the
+/// subscriptions of a SUBSCRIBE. The items are either given by the caller or,
+/// for a decoded packet, read from the encoded packet as they are iterated.
+#[derive(Debug, Clone, Copy)]
+pub struct ListRef<'a, T> {
+    repr: ListRepr<'a, T>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ListRepr<'a, T> {
+    Items(&'a [T]),
+    /// items checked when the packet was decoded
+    Encoded(&'a [u8]),
+}
+
+/// Properties of a borrowed packet, encoded in the order they are given.
+pub type PropertiesRef<'a> = ListRef<'a, PropertyRef<'a>>;
+
+/// Subscriptions of a borrowed SUBSCRIBE.
+pub type SubscriptionsRef<'a> = ListRef<'a, SubscriptionRef<'a>>;
+
+/// Reason codes of a borrowed SUBACK or UNSUBACK.
+pub type ReasonsRef<'a> = ListRef<'a, Reason>;
+
+/// Topic filters of a borrowed UNSUBSCRIBE.
+pub type FiltersRef<'a> = ListRef<'a, &'a str>;
+
+impl<'a, T> ListRef<'a, T> {
+    pub fn new(items: &'a [T]) -> Self {
+        Self {
+            repr: ListRepr::Items(items),
+        }
+    }
+
+    fn new_encoded(src: &'a [u8]) -> Self {
+        Self {
+            repr: ListRepr::Encoded(src),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self.repr {
+            ListRepr::Items(items) => items.is_empty(),
+            ListRepr::Encoded(src) => src.is_empty(),
+        }
+    }
+}
+
+impl<'a, T: ItemRef<'a>> ListRef<'a, T> {
+    pub fn len(&self) -> usize {
+        match self.repr {
+            ListRepr::Items(items) => items.len(),
+            ListRepr::Encoded(_) => self.iter().count(),
+        }
+    }
+
+    pub fn iter(&self) -> ListIter<'a, T> {
+        match self.repr {
+            ListRepr::Items(items) => ListIter {
+                repr: IterRepr::Items(items.iter()),
+            },
+            ListRepr::Encoded(src) => ListIter {
+                repr: IterRepr::Encoded(Reader::new(src, 0)),
+            },
+        }
+    }
+
+    /// Gets the encoded size of the items.
+    pub fn size(&self) -> u32 {
+        match self.repr {
+            ListRepr::Items(items) => items.iter().map(ItemRef::size).sum(),
+            ListRepr::Encoded(src) => src.len() as u32,
+        }
+    }
+
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        match self.repr {
+            ListRepr::Items(items) => {
+                for item in items {
+                    item.encode(dest)?;
+                }
+            }
+            ListRepr::Encoded(src) => dest.put_slice(src),
+        }
+        Ok(())
+    }
+
+    /// Reads the items from the encoded list, checking each item.
+    fn decode(src: &mut Reader<'a>, len: usize) -> Result<Self, MqttCodecError> {
+        let offset = src.offset();
+        let encoded = src.take(len)?;
+        let mut items = Reader::new(encoded, offset);
+        while items.has_remaining() {
+            T::decode(&mut items)?;
+        }
+        Ok(Self::new_encoded(encoded))
+    }
+}
+
+impl<'a> PropertiesRef<'a> {
+    /// Encodes the property length followed by the properties, checking that
+    /// each property is permitted in the packet.
+    fn encode_properties(
+        &self,
+        supported: &[PropertyType],
+        dest: &mut impl BufMut,
+    ) -> Result<(), MqttCodecError> {
+        check_properties(self.iter(), supported)?;
+        put_var_u32(self.size(), dest);
+        self.encode(dest)
+    }
+
+    /// Gets the encoded size of the property length and the properties.
+    fn encoded_size(&self) -> u32 {
+        let size = self.size();
+        variable_byte_int_size(size) + size
+    }
+
+    /// Reads the property length and properties, checking that each property
+    /// is permitted in the packet.
+    fn decode_properties(
+        src: &mut Reader<'a>,
+        supported: &[PropertyType],
+    ) -> Result<Self, MqttCodecError> {
+        let len = src.var_u32()? as usize;
+        let offset = src.offset();
+        let props = Self::decode(src, len)?;
+        check_properties(props.iter(), supported).map_err(|e| e.in_offset(offset))?;
+        Ok(props)
+    }
+}
+
+/// Checks that each property is permitted in the packet and, with the
+/// `pedantic` feature, that properties other than user properties and
+/// subscription identifiers are not repeated.
+fn check_properties<'a>(
+    props: impl Iterator<Item = PropertyRef<'a>>,
+    supported: &[PropertyType],
+) -> Result<(), MqttCodecError> {
+    // property identifiers are less than 64
+    let mut seen = 0_u64;
+    for prop in props {
+        let prop_type = prop.property_type();
+        if !supported.contains(&prop_type) {
+            return Err(MqttCodecError::new_with_kind(
+                &format!("MQTTv5 2.2.2.2 property {} not permitted", prop_type),
+                ErrorKind::PropertyNotPermitted(prop_type),
+            ));
+        }
+        let bit = 1_u64 << (prop_type as u8);
+        if cfg!(feature = "pedantic")
+            && prop_type != PropertyType::UserProperty
+            && prop_type != PropertyType::SubscriptionIdentifier
+            && seen & bit != 0
+        {
+            return Err(MqttCodecError::new_with_kind(
+                &format!(
+                    "MQTTv5 2.2.2.2 property {} included more than once",
+                    prop_type
+                ),
+                ErrorKind::DuplicateProperty(prop_type),
+            ));
+        }
+        seen |= bit;
+    }
+    Ok(())
+}
+
+impl<T> Default for ListRef<'_, T> {
+    fn default() -> Self {
+        Self {
+            repr: ListRepr::Items(&[]),
+        }
+    }
+}
+
+/// Lists are equal when they hold the same items, whether the items were
+/// given by the caller or decoded.
+impl<'a, T: ItemRef<'a> + PartialEq> PartialEq for ListRef<'a, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<'a, T: ItemRef<'a> + Eq> Eq for ListRef<'a, T> {}
+
+impl<'a, T> From<&'a [T]> for ListRef<'a, T> {
+    fn from(items: &'a [T]) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<'a, T, const N: usize> From<&'a [T; N]> for ListRef<'a, T> {
+    fn from(items: &'a [T; N]) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<'a, T: ItemRef<'a>> IntoIterator for ListRef<'a, T> {
+    type Item = T;
+    type IntoIter = ListIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the items of a [`ListRef`].
+#[derive(Debug, Clone)]
+pub struct ListIter<'a, T> {
+    repr: IterRepr<'a, T>,
+}
+
+#[derive(Debug, Clone)]
+enum IterRepr<'a, T> {
+    Items(core::slice::Iter<'a, T>),
+    Encoded(Reader<'a>),
+}
+
+impl<'a, T: ItemRef<'a>> Iterator for ListIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.repr {
+            IterRepr::Items(items) => items.next().copied(),
+            // the items were checked when the packet was decoded
+            IterRepr::Encoded(src) if src.has_remaining() => T::decode(src).ok(),
+            IterRepr::Encoded(_) => None,
+        }
+    }
+}
+
+mod item {
+    use alloc::format;
+    use bytes::BufMut;
+
+    use crate::{codec::ErrorKind, validate::validate_utf8, MqttCodecError, QoSLevel};
+
+    /// Item of a [`super::ListRef`] that is encoded and decoded in place.
+    pub trait ItemRef<'a>: Copy {
+        fn size(&self) -> u32;
+        fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError>;
+        fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError>;
+    }
+
+    /// Reads the fields of a packet in place. Errors are reported with the
+    /// offset of the field from the start of the packet.
+    #[derive(Debug, Clone)]
+    pub struct Reader<'a> {
+        src: &'a [u8],
+        pos: usize,
+        /// offset of the start of `src` from the start of the packet
+        base: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        pub(super) fn new(src: &'a [u8], base: usize) -> Self {
+            Self { src, pos: 0, base }
+        }
+
+        pub(super) fn offset(&self) -> usize {
+            self.base + self.pos
+        }
+
+        pub(super) fn remaining(&self) -> usize {
+            self.src.len() - self.pos
+        }
+
+        pub(super) fn has_remaining(&self) -> bool {
+            self.remaining() > 0
+        }
+
+        pub(super) fn take(&mut self, len: usize) -> Result<&'a [u8], MqttCodecError> {
+            if self.remaining() < len {
+                return Err(MqttCodecError::new_with_kind(
+                    "malformed packet: insufficient data",
+                    ErrorKind::InsufficientData(len, self.remaining()),
+                )
+                .with_offset(self.offset()));
+            }
+            let bytes = &self.src[self.pos..self.pos + len];
+            self.pos += len;
+            Ok(bytes)
+        }
+
+        pub(super) fn rest(&mut self) -> &'a [u8] {
+            let bytes = &self.src[self.pos..];
+            self.pos = self.src.len();
+            bytes
+        }
+
+        pub(super) fn u8(&mut self) -> Result<u8, MqttCodecError> {
+            Ok(self.take(1)?[0])
+        }
+
+        pub(super) fn u16(&mut self) -> Result<u16, MqttCodecError> {
+            let bytes = self.take(2)?;
+            Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+        }
+
+        pub(super) fn u32(&mut self) -> Result<u32, MqttCodecError> {
+            let bytes = self.take(4)?;
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+
+        pub(super) fn var_u32(&mut self) -> Result<u32, MqttCodecError> {
+            let offset = self.offset();
+            let mut result = 0_u32;
+            // a variable byte integer is at most 4 bytes, MQTT v5 1.5.5
+            for shift in [0, 7, 14, 21] {
+                let next_byte = self.u8()?;
+                result += ((next_byte & 0x7f) as u32) << shift;
+                if next_byte & 0x80 == 0 {
+                    return Ok(result);
+                }
+            }
+            Err(MqttCodecError::new("malformed packet: variable byte integer").with_offset(offset))
+        }
+
+        pub(super) fn bool(&mut self) -> Result<bool, MqttCodecError> {
+            let offset = self.offset();
+            match self.u8()? {
+                0 => Ok(false),
+                1 => Ok(true),
+                v if cfg!(feature = "pedantic") => Err(MqttCodecError::new(&format!(
+                    "invalid value {} for boolean property",
+                    v
+                ))
+                .with_offset(offset)),
+                _ => Ok(true),
+            }
+        }
+
+        pub(super) fn qos(&mut self) -> Result<QoSLevel, MqttCodecError> {
+            let offset = self.offset();
+            QoSLevel::try_from(self.u8()?).map_err(|e| e.in_offset(offset))
+        }
+
+        pub(super) fn utf8(&mut self) -> Result<&'a str, MqttCodecError> {
+            let offset = self.offset();
+            let len = self.u16()? as usize;
+            // from_utf8 rejects encoded surrogates, MQTT v5 1.5.4
+            let value = core::str::from_utf8(self.take(len)?).map_err(|e| {
+                MqttCodecError::new_with_kind(
+                    &format!("MQTTv5 1.5.4 {}", e),
+                    ErrorKind::InvalidUtf8,
+                )
+                .with_offset(offset)
+            })?;
+            if cfg!(feature = "pedantic") {
+                validate_utf8(value).map_err(|e| e.in_offset(offset))?;
+            }
+            Ok(value)
+        }
+
+        pub(super) fn bin(&mut self) -> Result<&'a [u8], MqttCodecError> {
+            let len = self.u16()? as usize;
+            self.take(len)
+        }
+    }
+}
+
+use item::{ItemRef, Reader};
+
+impl<'a> ItemRef<'a> for PropertyRef<'a> {
+    fn size(&self) -> u32 {
+        PropertyRef::size(self)
+    }
+
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        PropertyRef::encode(self, dest)
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let offset = src.offset();
+        let prop_type = PropertyType::try_from(src.u8()?).map_err(|e| e.in_offset(offset))?;
+        let prop = match prop_type {
+            PropertyType::PayloadFormat => PropertyRef::PayloadFormat(
+                PayloadFormat::try_from(src.u8()?).map_err(|e| e.in_offset(offset))?,
+            ),
+            PropertyType::MessageExpiry => PropertyRef::MessageExpiry(src.u32()?),
+            PropertyType::ContentType => PropertyRef::ContentType(src.utf8()?),
+            PropertyType::ResponseTopic => PropertyRef::ResponseTopic(src.utf8()?),
+            PropertyType::CorrelationData => PropertyRef::CorrelationData(src.bin()?),
+            PropertyType::SubscriptionIdentifier => {
+                PropertyRef::SubscriptionIdentifier(src.var_u32()?)
+            }
+            PropertyType::SessionExpiryInterval => PropertyRef::SessionExpiryInterval(src.u32()?),
+            PropertyType::AssignedClientId => PropertyRef::AssignedClientId(src.utf8()?),
+            PropertyType::KeepAlive => PropertyRef::KeepAlive(src.u16()?),
+            PropertyType::AuthMethod => PropertyRef::AuthMethod(src.utf8()?),
+            PropertyType::AuthData => PropertyRef::AuthData(src.bin()?),
+            PropertyType::ReqProblemInfo => PropertyRef::ReqProblemInfo(src.bool()?),
+            PropertyType::WillDelay => PropertyRef::WillDelay(src.u32()?),
+            PropertyType::ReqRespInfo => PropertyRef::ReqRespInfo(src.bool()?),
+            PropertyType::RespInfo => PropertyRef::RespInfo(src.utf8()?),
+            PropertyType::ServerReference => PropertyRef::ServerReference(src.utf8()?),
+            PropertyType::ReasonString => PropertyRef::ReasonString(src.utf8()?),
+            PropertyType::RecvMax => PropertyRef::RecvMax(src.u16()?),
+            PropertyType::TopicAliasMax => PropertyRef::TopicAliasMax(src.u16()?),
+            PropertyType::TopicAlias => PropertyRef::TopicAlias(src.u16()?),
+            PropertyType::MaxQoS => PropertyRef::MaxQoS(src.qos()?),
+            PropertyType::RetainAvail => PropertyRef::RetainAvail(src.bool()?),
+            PropertyType::UserProperty => PropertyRef::UserProperty(src.utf8()?, src.utf8()?),
+            PropertyType::MaxPacketSize => PropertyRef::MaxPacketSize(src.u32()?),
+            PropertyType::WildcardSubAvail => PropertyRef::WildcardSubAvail(src.bool()?),
+            PropertyType::SubIdAvail => PropertyRef::SubIdAvail(src.bool()?),
+            PropertyType::ShardSubAvail => PropertyRef::ShardSubAvail(src.bool()?),
+        };
+        Ok(prop)
+    }
+}
+
+impl<'a> ItemRef<'a> for SubscriptionRef<'a> {
+    fn size(&self) -> u32 {
+        SubscriptionRef::size(self)
+    }
+
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        SubscriptionRef::encode(self, dest)
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let filter = src.utf8()?;
+        let offset = src.offset();
+        let flags = src.u8()?;
+        Ok(SubscriptionRef {
+            filter,
+            qos: QoSLevel::try_from(flags & SUBSCRIPTION_QOS_MASK)
+                .map_err(|e| e.in_offset(offset))?,
+            no_local: flags & SUBSCRIPTION_NO_LOCAL != 0,
+            retain_as: flags & SUBSCRIPTION_RETAIN_AS != 0,
+            handling: RetainHandling::try_from(
+                (flags & SUBSCRIPTION_HANDLING_MASK) >> SUBSCRIPTION_HANDLING_SHIFT,
+            )
+            .map_err(|e| e.in_offset(offset))?,
+        })
+    }
+}
+
+impl<'a> ItemRef<'a> for Reason {
+    fn size(&self) -> u32 {
+        1
+    }
+
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        dest.put_u8(*self as u8);
+        Ok(())
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let offset = src.offset();
+        Reason::try_from(src.u8()?).map_err(|e| e.in_offset(offset))
+    }
+}
+
+impl<'a> ItemRef<'a> for &'a str {
+    fn size(&self) -> u32 {
+        SIZE_UTF8_STRING + self.len() as u32
+    }
+
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        put_utf8(self, dest)
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        src.utf8()
+    }
+}
+
+/// Borrowed CONNECT, MQTT v5 3.1.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRef<'a> {
+    pub clean_start: bool,
+    pub keep_alive: u16,
+    pub client_id: &'a str,
+    pub will_message: Option<WillMessageRef<'a>>,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> ConnectRef<'a> {
+    pub fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0_u8;
+        if self.clean_start {
+            flags |= CONNECT_FLAG_CLEAN_START;
+        }
+        if self.username.is_some() {
+            flags |= CONNECT_FLAG_USERNAME;
+        }
+        if self.password.is_some() {
+            flags |= CONNECT_FLAG_PASSWORD;
+        }
+        if let Some(will) = &self.will_message {
+            flags |= CONNECT_FLAG_WILL;
+            if will.retain {
+                flags |= CONNECT_FLAG_WILL_RETAIN
+            }
+            flags |= (will.qos as u8) << CONNECT_FLAG_SHIFT;
+        }
+        flags
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let offset = src.offset();
+        if src.u16()? != MQTT_PROTOCOL_NAME_LEN || src.u32()? != MQTT_PROTOCOL_U32 {
+            return Err(MqttCodecError::new_with_kind(
+                "unsupported protocol",
+                ErrorKind::UnsupportedProtocolVersion,
+            )
+            .with_offset(offset));
+        }
+        let offset = src.offset();
+        let version = ProtocolVersion::try_from(src.u8()?).map_err(|e| e.in_offset(offset))?;
+        if version != ProtocolVersion::V5 {
+            return Err(MqttCodecError::new_with_kind(
+                "borrowed packets are decoded as MQTT v5",
+                ErrorKind::UnsupportedProtocolVersion,
+            )
+            .with_offset(offset));
+        }
+        let flags_offset = src.offset();
+        let flags = src.u8()?;
+        let keep_alive = src.u16()?;
+        let properties = PropertiesRef::decode_properties(src, Connect::SUPPORTED_PROPERTIES)?;
+        let client_id = src.utf8()?;
+        let will_message = if flags & CONNECT_FLAG_WILL != 0 {
+            let qos = QoSLevel::try_from((flags & CONNECT_FLAG_WILL_QOS) >> CONNECT_FLAG_SHIFT)
+                .map_err(|e| e.in_offset(flags_offset))?;
+            let properties =
+                PropertiesRef::decode_properties(src, WillMessage::SUPPORTED_PROPERTIES)?;
+            Some(WillMessageRef {
+                qos,
+                retain: flags & CONNECT_FLAG_WILL_RETAIN != 0,
+                topic: src.utf8()?,
+                payload: src.bin()?,
+                properties,
+            })
+        } else {
+            None
+        };
+        let username = if flags & CONNECT_FLAG_USERNAME != 0 {
+            Some(src.utf8()?)
+        } else {
+            None
+        };
+        let password = if flags & CONNECT_FLAG_PASSWORD != 0 {
+            Some(src.bin()?)
+        } else {
+            None
+        };
+        Ok(Self {
+            clean_start: flags & CONNECT_FLAG_CLEAN_START != 0,
+            keep_alive,
+            client_id,
+            will_message,
+            username,
+            password,
+            properties,
+        })
+    }
+}
+
+impl Size for ConnectRef<'_> {
+    fn size(&self) -> u32 {
+        DEFAULT_CONNECT_REMAINING + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        let mut remaining = SIZE_UTF8_STRING + self.client_id.len() as u32;
+        if let Some(will_message) = &self.will_message {
+            remaining += will_message.size();
+        }
+        if let Some(username) = self.username {
+            remaining += SIZE_UTF8_STRING + username.len() as u32;
+        }
+        if let Some(password) = self.password {
+            remaining += SIZE_UTF8_STRING + password.len() as u32;
+        }
+        remaining
+    }
+}
+
+impl Encode for ConnectRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        FixedHeader::new_with_remaining(PacketType::Connect, self.size()).encode(dest)?;
+        dest.put_u16(MQTT_PROTOCOL_NAME_LEN);
+        dest.put_u32(MQTT_PROTOCOL_U32);
+        dest.put_u8(ProtocolVersion::V5 as u8);
+        dest.put_u8(self.flags());
+        dest.put_u16(self.keep_alive);
+        self.properties
+            .encode_properties(Connect::SUPPORTED_PROPERTIES, dest)?;
+        put_utf8(self.client_id, dest)?;
+        if let Some(will_message) = &self.will_message {
+            will_message.encode(dest)?;
+        }
+        if let Some(username) = self.username {
+            put_utf8(username, dest)?;
+        }
+        if let Some(password) = self.password {
+            put_bin(password, dest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Borrowed will message of a CONNECT, MQTT v5 3.1.3.2.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WillMessageRef<'a> {
+    pub qos: QoSLevel,
+    pub retain: bool,
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> WillMessageRef<'a> {
+    pub fn new(topic: &'a str, payload: &'a [u8]) -> Self {
+        Self {
+            topic,
+            payload,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+}
+
+impl Size for WillMessageRef<'_> {
+    fn size(&self) -> u32 {
+        self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        2 * SIZE_UTF8_STRING + self.topic.len() as u32 + self.payload.len() as u32
+    }
+}
+
+impl Encode for WillMessageRef<'_> {
+    /// Encodes the will properties, topic and payload. The will QoS and
+    /// retain flag are encoded in the CONNECT flags.
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        self.properties
+            .encode_properties(WillMessage::SUPPORTED_PROPERTIES, dest)?;
+        put_utf8(self.topic, dest)?;
+        put_bin(self.payload, dest)
+    }
+}
+
+/// Borrowed CONNACK, MQTT v5 3.2.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnAckRef<'a> {
+    pub session_present: bool,
+    pub reason: Reason,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> ConnAckRef<'a> {
+    pub fn new(reason: Reason) -> Self {
+        Self {
+            reason,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let session_present = src.u8()? & 0x01 != 0;
+        let reason = Reason::decode(src)?;
+        let properties = if src.has_remaining() {
+            PropertiesRef::decode_properties(src, ConnAck::SUPPORTED_PROPERTIES)?
+        } else {
+            PropertiesRef::default()
+        };
+        Ok(Self {
+            session_present,
+            reason,
+            properties,
+        })
+    }
+}
+
+impl Size for ConnAckRef<'_> {
+    fn size(&self) -> u32 {
+        // acknowledge flags and reason code followed by the properties
+        2 + self.properties.encoded_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        0
+    }
+}
+
+impl Encode for ConnAckRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        FixedHeader::new_with_remaining(PacketType::ConnAck, self.size()).encode(dest)?;
+        dest.put_u8(self.session_present as u8);
+        dest.put_u8(self.reason as u8);
+        self.properties
+            .encode_properties(ConnAck::SUPPORTED_PROPERTIES, dest)
+    }
+}
+
+/// Borrowed PUBLISH, MQTT v5 3.3. An empty topic name is sent with a topic
+/// alias property to use a topic alias in place of the topic name.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PublishRef<'a> {
+    pub dup: bool,
+    pub qos: QoSLevel,
+    pub retain: bool,
+    pub topic_name: &'a str,
+    pub packet_id: Option<u16>,
+    pub properties: PropertiesRef<'a>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> PublishRef<'a> {
+    pub fn new(topic_name: &'a str, payload: &'a [u8]) -> Self {
+        Self {
+            topic_name,
+            payload,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn header(&self) -> Result<FixedHeader, MqttCodecError> {
+        let mut header = FixedHeader::new_with_remaining(PacketType::Publish, self.size());
+        header.set_flags(
+            (self.dup as u8) << PUBLISH_DUP_SHIFT
+                | (self.qos as u8) << PUBLISH_QOS_SHIFT
+                | self.retain as u8,
+        )?;
+        Ok(header)
+    }
+
+    fn decode(header: &FixedHeader, src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let topic_name = src.utf8()?;
+        let packet_id = match header.qos() {
+            QoSLevel::AtMostOnce => None,
+            _ => Some(src.u16()?),
+        };
+        let properties = PropertiesRef::decode_properties(src, Publish::SUPPORTED_PROPERTIES)?;
+        Ok(Self {
+            dup: (header.flags() >> PUBLISH_DUP_SHIFT) & 0x01 != 0,
+            qos: header.qos(),
+            retain: header.retain(),
+            topic_name,
+            packet_id,
+            properties,
+            payload: src.rest(),
+        })
+    }
+}
+
+/// Copies a borrowed PUBLISH into an owned packet. An empty topic name, sent
+/// with a topic alias, becomes a topic name of None.
+impl From<&PublishRef<'_>> for Publish {
+    fn from(publish: &PublishRef<'_>) -> Self {
+        let mut owned = Publish::default();
+        owned.header.set_dup(publish.dup);
+        owned.set_qos(publish.qos);
+        owned.header.set_retain(publish.retain);
+        if !publish.topic_name.is_empty() {
+            owned.topic_name = Some(String::from(publish.topic_name));
+        }
+        owned.packet_id = publish.packet_id;
+        for property in publish.properties.iter() {
+            owned.properties_mut().set_property(property.into());
+        }
+        if !publish.payload.is_empty() {
+            owned.set_payload(publish.payload.to_vec());
+        }
+        owned
+    }
+}
+
+impl Size for PublishRef<'_> {
+    fn size(&self) -> u32 {
+        let mut remaining = SIZE_UTF8_STRING + self.topic_name.len() as u32;
+        // packet identifier
+        if self.qos != QoSLevel::AtMostOnce {
+            remaining += 2;
+        }
+        remaining + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.payload.len() as u32
+    }
+}
+
+impl Encode for PublishRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.topic_name.is_empty()
+            && !self
+                .properties
+                .iter()
+                .any(|p| matches!(p, PropertyRef::TopicAlias(_)))
+        {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.3.2.1 must have topic name or topic alias",
+            ));
+        }
+        let packet_id = match (self.qos, self.packet_id) {
+            (QoSLevel::AtMostOnce, _) => None,
+            (_, Some(packet_id)) => Some(packet_id),
+            (_, None) => {
+                return Err(MqttCodecError::new(
+                    "MQTTv5 3.3.2.2 packet identifier must be included for QOS 1 or 2",
+                ))
+            }
+        };
+        self.header()?.encode(dest)?;
+        put_utf8(self.topic_name, dest)?;
+        if let Some(packet_id) = packet_id {
+            dest.put_u16(packet_id);
+        }
+        self.properties
+            .encode_properties(Publish::SUPPORTED_PROPERTIES, dest)?;
+        dest.put_slice(self.payload);
+        Ok(())
+    }
+}
+
+/// Borrowed PUBACK, PUBREC, PUBREL or PUBCOMP, MQTT v5 3.4 to 3.7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubRespRef<'a> {
+    resp_type: PacketType,
+    pub packet_id: u16,
+    pub reason: Reason,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> PubRespRef<'a> {
+    pub fn new_puback(packet_id: u16) -> Self {
+        Self::new(PacketType::PubAck, packet_id)
+    }
+
+    pub fn new_pubrec(packet_id: u16) -> Self {
+        Self::new(PacketType::PubRec, packet_id)
+    }
+
+    pub fn new_pubrel(packet_id: u16) -> Self {
+        Self::new(PacketType::PubRel, packet_id)
+    }
+
+    pub fn new_pubcomp(packet_id: u16) -> Self {
+        Self::new(PacketType::PubComp, packet_id)
+    }
+
+    fn new(resp_type: PacketType, packet_id: u16) -> Self {
+        Self {
+            resp_type,
+            packet_id,
+            reason: Reason::Success,
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_reason(mut self, reason: Reason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        self.resp_type
+    }
+
+    fn decode(resp_type: PacketType, src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let mut resp = Self::new(resp_type, src.u16()?);
+        if src.has_remaining() {
+            resp.reason = Reason::decode(src)?;
+        }
+        if src.has_remaining() {
+            resp.properties = PropertiesRef::decode_properties(src, PubResp::SUPPORTED_PROPERTIES)?;
+        }
+        Ok(resp)
+    }
+}
+
+impl Size for PubRespRef<'_> {
+    fn size(&self) -> u32 {
+        if self.reason == Reason::Success && self.properties.is_empty() {
+            VARIABLE_HEADER_LEN
+        } else {
+            // reason code followed by the property length and properties
+            VARIABLE_HEADER_LEN + 1 + self.properties.encoded_size()
+        }
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        0
+    }
+}
+
+impl Encode for PubRespRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        FixedHeader::new_with_remaining(self.resp_type, self.size()).encode(dest)?;
+        dest.put_u16(self.packet_id);
+        if self.reason == Reason::Success && self.properties.is_empty() {
+            return Ok(());
+        }
+        dest.put_u8(self.reason as u8);
+        self.properties
+            .encode_properties(PubResp::SUPPORTED_PROPERTIES, dest)
+    }
+}
+
+/// Borrowed subscription of a SUBSCRIBE, MQTT v5 3.8.3.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionRef<'a> {
+    pub filter: &'a str,
+    pub qos: QoSLevel,
+    pub no_local: bool,
+    pub retain_as: bool,
+    pub handling: RetainHandling,
+}
+
+impl<'a> SubscriptionRef<'a> {
+    pub fn new(filter: &'a str, qos: QoSLevel) -> Self {
+        Self {
+            filter,
+            qos,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn size(&self) -> u32 {
+        // topic filter followed by the subscription options
+        SIZE_UTF8_STRING + self.filter.len() as u32 + 1
+    }
+
+    pub(crate) fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        put_utf8(self.filter, dest)?;
+        let flags = self.qos as u8
+            | if self.no_local {
+                SUBSCRIPTION_NO_LOCAL
+            } else {
+                0
+            }
+            | if self.retain_as {
+                SUBSCRIPTION_RETAIN_AS
+            } else {
+                0
+            }
+            | (self.handling as u8) << SUBSCRIPTION_HANDLING_SHIFT;
+        dest.put_u8(flags);
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a Subscription> for SubscriptionRef<'a> {
+    fn from(value: &'a Subscription) -> Self {
+        Self {
+            filter: &value.filter,
+            qos: value.qos,
+            no_local: value.no_local,
+            retain_as: value.retain_as,
+            handling: value.handling,
+        }
+    }
+}
+
+/// Borrowed SUBSCRIBE, MQTT v5 3.8.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubscribeRef<'a> {
+    pub packet_id: u16,
+    pub subscriptions: SubscriptionsRef<'a>,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> SubscribeRef<'a> {
+    pub fn new(packet_id: u16, subscriptions: impl Into<SubscriptionsRef<'a>>) -> Self {
+        Self {
+            packet_id,
+            subscriptions: subscriptions.into(),
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let packet_id = src.u16()?;
+        let properties = PropertiesRef::decode_properties(src, Subscribe::SUPPORTED_PROPERTIES)?;
+        let subscriptions = SubscriptionsRef::decode(src, src.remaining())?;
+        Ok(Self {
+            packet_id,
+            subscriptions,
+            properties,
+        })
+    }
+}
+
+impl Size for SubscribeRef<'_> {
+    fn size(&self) -> u32 {
+        VAR_HDR_LEN + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.subscriptions.size()
+    }
+}
+
+impl Encode for SubscribeRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.packet_id == 0 {
+            return Err(MqttCodecError::new(
+                "MQTTv5 2.2.1 packet identifier must not be 0",
+            ));
+        }
+        if self.subscriptions.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.8.3 subscribe payload must exist",
+            ));
+        }
+        FixedHeader::new_with_remaining(PacketType::Subscribe, self.size()).encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.properties
+            .encode_properties(Subscribe::SUPPORTED_PROPERTIES, dest)?;
+        self.subscriptions.encode(dest)
+    }
+}
+
+/// Borrowed SUBACK, MQTT v5 3.9. The reason codes are in the same order as
+/// the subscriptions of the acknowledged SUBSCRIBE.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubAckRef<'a> {
+    pub packet_id: u16,
+    pub reasons: ReasonsRef<'a>,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> SubAckRef<'a> {
+    pub fn new(packet_id: u16, reasons: impl Into<ReasonsRef<'a>>) -> Self {
+        Self {
+            packet_id,
+            reasons: reasons.into(),
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let packet_id = src.u16()?;
+        let properties = PropertiesRef::decode_properties(src, SubAck::SUPPORTED_PROPERTIES)?;
+        let reasons = ReasonsRef::decode(src, src.remaining())?;
+        Ok(Self {
+            packet_id,
+            reasons,
+            properties,
+        })
+    }
+}
+
+impl Size for SubAckRef<'_> {
+    fn size(&self) -> u32 {
+        VAR_HDR_LEN + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.reasons.size()
+    }
+}
+
+impl Encode for SubAckRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.reasons.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.9.3 SUBACK must contain a reason code for each subscription",
+            ));
+        }
+        FixedHeader::new_with_remaining(PacketType::SubAck, self.size()).encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.properties
+            .encode_properties(SubAck::SUPPORTED_PROPERTIES, dest)?;
+        self.reasons.encode(dest)
+    }
+}
+
+/// Borrowed UNSUBSCRIBE, MQTT v5 3.10.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UnsubscribeRef<'a> {
+    pub packet_id: u16,
+    pub filters: FiltersRef<'a>,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> UnsubscribeRef<'a> {
+    pub fn new(packet_id: u16, filters: impl Into<FiltersRef<'a>>) -> Self {
+        Self {
+            packet_id,
+            filters: filters.into(),
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let packet_id = src.u16()?;
+        let properties = PropertiesRef::decode_properties(src, Unsubscribe::SUPPORTED_PROPERTIES)?;
+        let filters = FiltersRef::decode(src, src.remaining())?;
+        Ok(Self {
+            packet_id,
+            filters,
+            properties,
+        })
+    }
+}
+
+impl Size for UnsubscribeRef<'_> {
+    fn size(&self) -> u32 {
+        VAR_HDR_LEN + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.filters.size()
+    }
+}
+
+impl Encode for UnsubscribeRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.packet_id == 0 {
+            return Err(MqttCodecError::new(
+                "MQTTv5 2.2.1 packet identifier must not be 0",
+            ));
+        }
+        if self.filters.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.10.3 unsubscribe payload must exist",
+            ));
+        }
+        FixedHeader::new_with_remaining(PacketType::Unsubscribe, self.size()).encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.properties
+            .encode_properties(Unsubscribe::SUPPORTED_PROPERTIES, dest)?;
+        self.filters.encode(dest)
+    }
+}
+
+/// Borrowed UNSUBACK, MQTT v5 3.11. The reason codes are in the same order
+/// as the topic filters of the acknowledged UNSUBSCRIBE.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UnsubAckRef<'a> {
+    pub packet_id: u16,
+    pub reasons: ReasonsRef<'a>,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> UnsubAckRef<'a> {
+    pub fn new(packet_id: u16, reasons: impl Into<ReasonsRef<'a>>) -> Self {
+        Self {
+            packet_id,
+            reasons: reasons.into(),
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        let packet_id = src.u16()?;
+        let properties = PropertiesRef::decode_properties(src, UnsubAck::SUPPORTED_PROPERTIES)?;
+        let reasons = ReasonsRef::decode(src, src.remaining())?;
+        Ok(Self {
+            packet_id,
+            reasons,
+            properties,
+        })
+    }
+}
+
+impl Size for UnsubAckRef<'_> {
+    fn size(&self) -> u32 {
+        VAR_HDR_LEN + self.properties.encoded_size() + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.reasons.size()
+    }
+}
+
+impl Encode for UnsubAckRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.reasons.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.11.3 UNSUBACK must contain a reason code for each topic filter",
+            ));
+        }
+        FixedHeader::new_with_remaining(PacketType::UnsubAck, self.size()).encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.properties
+            .encode_properties(UnsubAck::SUPPORTED_PROPERTIES, dest)?;
+        self.reasons.encode(dest)
+    }
+}
+
+/// Borrowed DISCONNECT, MQTT v5 3.14.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DisconnectRef<'a> {
+    pub reason: Reason,
+    pub properties: PropertiesRef<'a>,
+}
+
+impl<'a> DisconnectRef<'a> {
+    pub fn new(reason: Reason) -> Self {
+        Self {
+            reason,
+            properties: PropertiesRef::default(),
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<PropertiesRef<'a>>) -> Self {
+        self.properties = properties.into();
+        self
+    }
+
+    fn decode(src: &mut Reader<'a>) -> Result<Self, MqttCodecError> {
+        // MQTTv5 3.14.2.1 the reason code may be omitted for a normal disconnect
+        let mut disconnect = Self::default();
+        if src.has_remaining() {
+            disconnect.reason = Reason::decode(src)?;
+        }
+        if src.has_remaining() {
+            disconnect.properties =
+                PropertiesRef::decode_properties(src, Disconnect::SUPPORTED_PROPERTIES)?;
+        }
+        Ok(disconnect)
+    }
+}
+
+impl Size for DisconnectRef<'_> {
+    fn size(&self) -> u32 {
+        if self.reason == Reason::Success && self.properties.is_empty() {
+            0
+        } else {
+            // reason code followed by the property length and properties
+            1 + self.properties.encoded_size()
+        }
+    }
+
+    fn property_size(&self) -> u32 {
+        self.properties.size()
+    }
+
+    fn payload_size(&self) -> u32 {
+        0
+    }
+}
+
+impl Encode for DisconnectRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let remaining = self.size();
+        FixedHeader::new_with_remaining(PacketType::Disconnect, remaining).encode(dest)?;
+        if remaining == 0 {
+            return Ok(());
+        }
+        dest.put_u8(self.reason as u8);
+        self.properties
+            .encode_properties(Disconnect::SUPPORTED_PROPERTIES, dest)
+    }
+}
+
+/// Packet built from borrowed data, the borrowed counterpart of
+/// [`crate::Packet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketRef<'a> {
+    PingRequest,
+    PingResponse,
+    Connect(ConnectRef<'a>),
+    ConnAck(ConnAckRef<'a>),
+    Publish(PublishRef<'a>),
+    PubAck(PubRespRef<'a>),
+    PubComp(PubRespRef<'a>),
+    PubRec(PubRespRef<'a>),
+    PubRel(PubRespRef<'a>),
+    Disconnect(DisconnectRef<'a>),
+    Subscribe(SubscribeRef<'a>),
+    SubAck(SubAckRef<'a>),
+    Unsubscribe(UnsubscribeRef<'a>),
+    UnsubAck(UnsubAckRef<'a>),
+}
+
+impl PacketRef<'_> {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            PacketRef::PingRequest => PacketType::PingReq,
+            PacketRef::PingResponse => PacketType::PingResp,
+            PacketRef::Connect(_) => PacketType::Connect,
+            PacketRef::ConnAck(_) => PacketType::ConnAck,
+            PacketRef::Publish(_) => PacketType::Publish,
+            PacketRef::PubAck(_) => PacketType::PubAck,
+            PacketRef::PubComp(_) => PacketType::PubComp,
+            PacketRef::PubRec(_) => PacketType::PubRec,
+            PacketRef::PubRel(_) => PacketType::PubRel,
+            PacketRef::Disconnect(_) => PacketType::Disconnect,
+            PacketRef::Subscribe(_) => PacketType::Subscribe,
+            PacketRef::SubAck(_) => PacketType::SubAck,
+            PacketRef::Unsubscribe(_) => PacketType::Unsubscribe,
+            PacketRef::UnsubAck(_) => PacketType::UnsubAck,
+        }
+    }
+
+    /// Gets the number of bytes needed to encode the packet, including the
+    /// fixed header.
+    pub fn encoded_len(&self) -> usize {
+        let remaining = self.size();
+        (1 + variable_byte_int_size(remaining) + remaining) as usize
+    }
+
+    /// Encodes the packet into a caller provided buffer, returning the number
+    /// of bytes written from the start of the buffer. If the buffer cannot
+    /// hold the packet nothing is written and an error of kind
+    /// [`ErrorKind::BufferTooSmall`] with the number of bytes needed is
+    /// returned.
+    pub fn encode_slice(&self, dest: &mut [u8]) -> Result<usize, MqttCodecError> {
+        let len = self.encoded_len();
+        check_buffer_len(len, dest.len(), self.packet_type())?;
+        let mut buf = &mut dest[..len];
+        self.encode(&mut buf)?;
+        Ok(len)
+    }
+}
+
+impl Size for PacketRef<'_> {
+    fn size(&self) -> u32 {
+        match self {
+            PacketRef::PingRequest | PacketRef::PingResponse => 0,
+            PacketRef::Connect(conn) => conn.size(),
+            PacketRef::ConnAck(ack) => ack.size(),
+            PacketRef::Publish(publ) => publ.size(),
+            PacketRef::PubAck(resp)
+            | PacketRef::PubComp(resp)
+            | PacketRef::PubRec(resp)
+            | PacketRef::PubRel(resp) => resp.size(),
+            PacketRef::Disconnect(disc) => disc.size(),
+            PacketRef::Subscribe(sub) => sub.size(),
+            PacketRef::SubAck(ack) => ack.size(),
+            PacketRef::Unsubscribe(unsub) => unsub.size(),
+            PacketRef::UnsubAck(ack) => ack.size(),
+        }
+    }
+
+    fn property_size(&self) -> u32 {
+        match self {
+            PacketRef::PingRequest | PacketRef::PingResponse => 0,
+            PacketRef::Connect(conn) => conn.property_size(),
+            PacketRef::ConnAck(ack) => ack.property_size(),
+            PacketRef::Publish(publ) => publ.property_size(),
+            PacketRef::PubAck(resp)
+            | PacketRef::PubComp(resp)
+            | PacketRef::PubRec(resp)
+            | PacketRef::PubRel(resp) => resp.property_size(),
+            PacketRef::Disconnect(disc) => disc.property_size(),
+            PacketRef::Subscribe(sub) => sub.property_size(),
+            PacketRef::SubAck(ack) => ack.property_size(),
+            PacketRef::Unsubscribe(unsub) => unsub.property_size(),
+            PacketRef::UnsubAck(ack) => ack.property_size(),
+        }
+    }
+
+    fn payload_size(&self) -> u32 {
+        match self {
+            PacketRef::PingRequest | PacketRef::PingResponse => 0,
+            PacketRef::Connect(conn) => conn.payload_size(),
+            PacketRef::ConnAck(ack) => ack.payload_size(),
+            PacketRef::Publish(publ) => publ.payload_size(),
+            PacketRef::PubAck(resp)
+            | PacketRef::PubComp(resp)
+            | PacketRef::PubRec(resp)
+            | PacketRef::PubRel(resp) => resp.payload_size(),
+            PacketRef::Disconnect(disc) => disc.payload_size(),
+            PacketRef::Subscribe(sub) => sub.payload_size(),
+            PacketRef::SubAck(ack) => ack.payload_size(),
+            PacketRef::Unsubscribe(unsub) => unsub.payload_size(),
+            PacketRef::UnsubAck(ack) => ack.payload_size(),
+        }
+    }
+}
+
+impl Encode for PacketRef<'_> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let packet_type = self.packet_type();
+        let start = dest.remaining_mut();
+        match self {
+            PacketRef::PingRequest | PacketRef::PingResponse => {
+                FixedHeader::new(packet_type).encode(dest)
+            }
+            PacketRef::Connect(conn) => conn.encode(dest),
+            PacketRef::ConnAck(ack) => ack.encode(dest),
+            PacketRef::Publish(publ) => publ.encode(dest),
+            PacketRef::PubAck(resp)
+            | PacketRef::PubComp(resp)
+            | PacketRef::PubRec(resp)
+            | PacketRef::PubRel(resp) => resp.encode(dest),
+            PacketRef::Disconnect(disc) => disc.encode(dest),
+            PacketRef::Subscribe(sub) => sub.encode(dest),
+            PacketRef::SubAck(ack) => ack.encode(dest),
+            PacketRef::Unsubscribe(unsub) => unsub.encode(dest),
+            PacketRef::UnsubAck(ack) => ack.encode(dest),
+        }
+        .map_err(|e| e.in_packet(packet_type, start - dest.remaining_mut()))
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    /// Decodes the MQTT v5 packet at the start of the buffer in place. The
+    /// strings, binary data, properties and subscriptions of the packet borrow
+    /// from the buffer. Returns None if the buffer does not hold a complete
+    /// packet, otherwise the packet and the number of bytes it occupies.
+    ///
+    /// With the `pedantic` feature reserved flags, UTF-8 strings, duplicate
+    /// properties, packet identifiers and topic names and filters are checked
+    /// as they are for [`crate::decode`].
+    pub fn decode(src: &'a [u8]) -> Result<Option<(PacketRef<'a>, usize)>, MqttCodecError> {
+        let len = match frame_len(src)? {
+            Some(len) if len <= src.len() => len,
+            _ => return Ok(None),
+        };
+        let mut reader = Reader::new(&src[..len], 0);
+        let first_byte = reader.u8()?;
+        let remaining = reader.var_u32()?;
+        let header = new_fixed_header(first_byte, remaining)?;
+        let packet_type = header.packet_type();
+        let packet = decode_body(&header, &mut reader)
+            .and_then(|packet| {
+                if reader.has_remaining() {
+                    return Err(MqttCodecError::new(
+                        "malformed packet: unexpected bytes after packet",
+                    ));
+                }
+                if cfg!(feature = "pedantic") {
+                    validate_packet_ref(&packet)?;
+                }
+                Ok(packet)
+            })
+            .map_err(|e| e.in_packet(packet_type, reader.offset()))?;
+        Ok(Some((packet, len)))
+    }
+}
+
+/// Decodes the variable header and payload of a packet.
+fn decode_body<'a>(
+    header: &FixedHeader,
+    src: &mut Reader<'a>,
+) -> Result<PacketRef<'a>, MqttCodecError> {
+    let packet = match header.packet_type() {
+        PacketType::PingReq => PacketRef::PingRequest,
+        PacketType::PingResp => PacketRef::PingResponse,
+        PacketType::Connect => PacketRef::Connect(ConnectRef::decode(src)?),
+        PacketType::ConnAck => PacketRef::ConnAck(ConnAckRef::decode(src)?),
+        PacketType::Publish => PacketRef::Publish(PublishRef::decode(header, src)?),
+        PacketType::PubAck => PacketRef::PubAck(PubRespRef::decode(PacketType::PubAck, src)?),
+        PacketType::PubRec => PacketRef::PubRec(PubRespRef::decode(PacketType::PubRec, src)?),
+        PacketType::PubRel => PacketRef::PubRel(PubRespRef::decode(PacketType::PubRel, src)?),
+        PacketType::PubComp => PacketRef::PubComp(PubRespRef::decode(PacketType::PubComp, src)?),
+        PacketType::Subscribe => PacketRef::Subscribe(SubscribeRef::decode(src)?),
+        PacketType::SubAck => PacketRef::SubAck(SubAckRef::decode(src)?),
+        PacketType::Unsubscribe => PacketRef::Unsubscribe(UnsubscribeRef::decode(src)?),
+        PacketType::UnsubAck => PacketRef::UnsubAck(UnsubAckRef::decode(src)?),
+        PacketType::Disconnect => PacketRef::Disconnect(DisconnectRef::decode(src)?),
+        _ => return Err(MqttCodecError::new("unsupported packet type")),
+    };
+    Ok(packet)
+}
+
+/// Checks the rules that [`crate::validate::validate_packet`] checks for
+/// owned packets, using the same field checks.
+fn validate_packet_ref(packet: &PacketRef) -> Result<(), MqttCodecError> {
+    match packet {
+        PacketRef::Connect(connect) => {
+            if let Some(will) = &connect.will_message {
+                validate_topic_name(will.topic)?;
+                validate_response_topic(response_topic(&will.properties))?;
+            }
+        }
+        PacketRef::Publish(publish) => {
+            validate_publish_header(
+                Some(publish.topic_name).filter(|topic| !topic.is_empty()),
+                publish
+                    .properties
+                    .iter()
+                    .any(|p| matches!(p, PropertyRef::TopicAlias(_))),
+                publish.qos,
+                publish.packet_id,
+            )?;
+            validate_response_topic(response_topic(&publish.properties))?;
+            validate_subscription_ids(subscription_ids(&publish.properties))?;
+        }
+        PacketRef::PubAck(resp)
+        | PacketRef::PubRec(resp)
+        | PacketRef::PubRel(resp)
+        | PacketRef::PubComp(resp) => validate_packet_id(resp.packet_id)?,
+        PacketRef::Subscribe(subscribe) => {
+            validate_packet_id(subscribe.packet_id)?;
+            validate_subscriptions(
+                subscribe
+                    .subscriptions
+                    .iter()
+                    .map(|subscription| (subscription.filter, subscription.no_local)),
+            )?;
+            validate_subscribe_ids(subscription_ids(&subscribe.properties))?;
+        }
+        PacketRef::SubAck(suback) => validate_packet_id(suback.packet_id)?,
+        PacketRef::Unsubscribe(unsubscribe) => {
+            validate_packet_id(unsubscribe.packet_id)?;
+            validate_unsubscribe_filters(unsubscribe.filters.iter())?;
+        }
+        PacketRef::UnsubAck(unsuback) => validate_packet_id(unsuback.packet_id)?,
+        PacketRef::PingRequest
+        | PacketRef::PingResponse
+        | PacketRef::ConnAck(_)
+        | PacketRef::Disconnect(_) => {}
+    }
+    Ok(())
+}
+
+fn response_topic<'a>(props: &PropertiesRef<'a>) -> Option<&'a str> {
+    props.iter().find_map(|p| match p {
+        PropertyRef::ResponseTopic(topic) => Some(topic),
+        _ => None,
+    })
+}
+
+fn subscription_ids<'a>(props: &PropertiesRef<'a>) -> impl Iterator<Item = u32> + 'a {
+    props.iter().filter_map(|p| match p {
+        PropertyRef::SubscriptionIdentifier(id) => Some(id),
+        _ => None,
+    })
+}
@@ -1,15 +1,16 @@
-use std::{
-    collections::{HashMap, HashSet},
+use alloc::{collections::BTreeSet, format, string::String, vec, vec::Vec};
+use core::{
     fmt::{Display, Formatter},
     ops::{Index, IndexMut},
+    slice,
 };
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::{
     codec::{
-        get_bin, get_bool, get_utf8, get_var_u32, put_bin, put_utf8, put_var_u32,
-        variable_byte_int_size,
+        get_bin, get_bool, get_u16, get_u32, get_u8, get_utf8, get_var_u32, put_bin, put_utf8,
+        put_var_u32, variable_byte_int_size, ErrorKind,
     },
     Decode, Encode, MqttCodecError, QoSLevel, Size,
 };
@@ -48,7 +49,8 @@ use crate::{
 /// 0x29 | Subscription Identifier Available | byte
 /// 0x2a | Shared Subscription Available | byte
 #[repr(u8)]
-#[derive(Hash, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Hash, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PropertyType {
     PayloadFormat = 0x01,
     MessageExpiry = 0x02,
@@ -80,19 +82,20 @@ pub enum PropertyType {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum Property {
     PayloadFormat(PayloadFormat) = 0x01,
     MessageExpiry(u32) = 0x02,
     ContentType(String) = 0x03,
     ResponseTopic(String) = 0x08,
-    CorrelationData(Vec<u8>) = 0x09,
+    CorrelationData(#[cfg_attr(feature = "serde", serde(with = "crate::serde_bin"))] Bytes) = 0x09,
     SubscriptionIdentifier(u32) = 0x0b,
     SessionExpiryInterval(u32) = 0x11,
     AssignedClientId(String) = 0x12,
     KeepAlive(u16) = 0x13,
     AuthMethod(String) = 0x15,
-    AuthData(Vec<u8>) = 0x16,
+    AuthData(#[cfg_attr(feature = "serde", serde(with = "crate::serde_bin"))] Bytes) = 0x16,
     ReqProblemInfo(bool) = 0x17,
     WillDelay(u32) = 0x18,
     ReqRespInfo(bool) = 0x19,
@@ -152,7 +155,7 @@ impl From<&Property> for PropertyType {
 }
 
 impl Display for PropertyType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         match self {
             PropertyType::PayloadFormat => write!(f, "\"Payload Format Indicator\""),
             PropertyType::MessageExpiry => write!(f, "\"Message Expiry Interval\""),
@@ -225,37 +228,40 @@ impl TryFrom<u8> for PropertyType {
     }
 }
 
+/// Properties of a packet in the order they were added or decoded, which is
+/// the order they are encoded. User properties and subscription identifiers
+/// may be repeated, MQTT v5 3.1.2.11.8 and 3.3.2.3.8, other properties hold a
+/// single value.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PropertyBundle {
-    supported: HashSet<PropertyType>,
-    properties: HashMap<PropertyType, Property>,
-    user_props: HashMap<String, Vec<String>>,
+    supported: BTreeSet<PropertyType>,
+    properties: Vec<Property>,
 }
 
 impl PropertyBundle {
-    pub(crate) fn new(supported: HashSet<PropertyType>) -> Self {
+    pub(crate) fn new(supported: BTreeSet<PropertyType>) -> Self {
         Self {
             supported,
-            properties: HashMap::new(),
-            user_props: HashMap::new(),
+            properties: Vec::new(),
         }
     }
 
     pub fn len(&self) -> usize {
-        let mut len = self.properties.len();
-        for item in &self.user_props {
-            len += item.1.len();
-        }
-        len
+        self.properties.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.properties.is_empty() && self.user_props.is_empty()
+        self.properties.is_empty()
     }
 
     pub fn clear(&mut self) {
         self.properties.clear();
-        self.user_props.clear();
+    }
+
+    /// Iterates over the properties in wire order.
+    pub fn iter(&self) -> slice::Iter<'_, Property> {
+        self.properties.iter()
     }
 
     pub fn supports_property(&self, prop_type: &PropertyType) -> bool {
@@ -263,128 +269,182 @@ impl PropertyBundle {
     }
 
     pub fn has_property(&self, prop_type: &PropertyType) -> bool {
-        self.properties.contains_key(prop_type)
+        self.get_property(prop_type).is_some()
     }
 
+    /// Gets the property of the property type. Where the property is repeated
+    /// the first value is returned.
     pub fn get_property(&self, prop_type: &PropertyType) -> Option<&Property> {
-        self.properties.get(prop_type)
+        self.properties
+            .iter()
+            .find(|prop| PropertyType::from(*prop) == *prop_type)
     }
 
+    /// Gets every property of the property type in wire order.
+    pub fn get_properties<'a>(
+        &'a self,
+        prop_type: &'a PropertyType,
+    ) -> impl Iterator<Item = &'a Property> + 'a {
+        self.properties
+            .iter()
+            .filter(move |prop| PropertyType::from(*prop) == *prop_type)
+    }
+
+    /// Sets the property, replacing any existing property of the same type in
+    /// place. User properties and subscription identifiers may be repeated so
+    /// they are added after the existing properties instead.
     pub fn set_property(&mut self, prop: Property) {
+        let prop_type = PropertyType::from(&prop);
         if let Property::UserProperty(key, value) = prop {
             self.add_user_property(key, value);
-        } else if self.supports_property(&PropertyType::from(&prop)) {
-            self.properties.insert((&prop).into(), prop);
-        } else {
+        } else if let Property::SubscriptionIdentifier(id) = prop {
+            self.add_subscription_id(id);
+        } else if !self.supports_property(&prop_type) {
             panic!("Unsupported property: {:?}", prop);
+        } else if let Some(existing) = self
+            .properties
+            .iter_mut()
+            .find(|existing| PropertyType::from(&**existing) == prop_type)
+        {
+            *existing = prop;
+        } else {
+            self.properties.push(prop);
         }
     }
 
+    /// Removes every property of the property type.
     pub fn clear_property(&mut self, prop_type: &PropertyType) {
-        self.properties.remove(prop_type);
+        self.properties
+            .retain(|prop| PropertyType::from(prop) != *prop_type);
     }
 
-    pub fn user_properties(&self) -> &HashMap<String, Vec<String>> {
-        &self.user_props
+    /// Gets every subscription identifier in the order they were added.
+    pub fn subscription_ids(&self) -> Vec<u32> {
+        self.get_properties(&PropertyType::SubscriptionIdentifier)
+            .filter_map(|prop| match prop {
+                Property::SubscriptionIdentifier(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
     }
 
-    pub fn user_property(&self, key: &str) -> Option<&Vec<String>> {
-        self.user_props.get(key)
+    pub fn add_subscription_id(&mut self, id: u32) {
+        if self.supports_property(&PropertyType::SubscriptionIdentifier) {
+            self.properties.push(Property::SubscriptionIdentifier(id));
+        } else {
+            panic!(
+                "Unsupported property: {:?}",
+                Property::SubscriptionIdentifier(id)
+            );
+        }
+    }
+
+    /// Gets the key and value of every user property in wire order, the order
+    /// the receiver must see them in, MQTT v5 3.3.2.3.7.
+    pub fn user_properties(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.properties.iter().filter_map(|prop| match prop {
+            Property::UserProperty(key, value) => Some((key.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Gets the values of the user properties with the key in wire order.
+    pub fn user_property<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.user_properties()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, value)| value)
     }
 
     pub fn add_user_property(&mut self, key: String, value: String) {
-        if let std::collections::hash_map::Entry::Vacant(e) = self.user_props.entry(key.clone()) {
-            let value = vec![value];
-            e.insert(value);
-        } else {
-            self.user_props.get_mut(&key).unwrap().push(value)
-        }
+        self.properties.push(Property::UserProperty(key, value));
     }
 }
 
+/// Implements a typed getter for each single valued property.
+macro_rules! property_getters {
+    ($($name:ident: $variant:ident -> $ty:ty => |$value:ident| $conv:expr;)*) => {
+        impl PropertyBundle {
+            $(
+                pub fn $name(&self) -> Option<$ty> {
+                    match self.get_property(&PropertyType::$variant) {
+                        Some(Property::$variant($value)) => Some($conv),
+                        _ => None,
+                    }
+                }
+            )*
+        }
+    };
+}
+
+property_getters! {
+    payload_format: PayloadFormat -> PayloadFormat => |v| *v;
+    message_expiry: MessageExpiry -> u32 => |v| *v;
+    content_type: ContentType -> &str => |v| v.as_str();
+    response_topic: ResponseTopic -> &str => |v| v.as_str();
+    correlation_data: CorrelationData -> &[u8] => |v| v.as_ref();
+    session_expiry_interval: SessionExpiryInterval -> u32 => |v| *v;
+    assigned_client_id: AssignedClientId -> &str => |v| v.as_str();
+    keep_alive: KeepAlive -> u16 => |v| *v;
+    auth_method: AuthMethod -> &str => |v| v.as_str();
+    auth_data: AuthData -> &[u8] => |v| v.as_ref();
+    req_problem_info: ReqProblemInfo -> bool => |v| *v;
+    will_delay: WillDelay -> u32 => |v| *v;
+    req_resp_info: ReqRespInfo -> bool => |v| *v;
+    resp_info: RespInfo -> &str => |v| v.as_str();
+    server_reference: ServerReference -> &str => |v| v.as_str();
+    reason_string: ReasonString -> &str => |v| v.as_str();
+    recv_max: RecvMax -> u16 => |v| *v;
+    topic_alias_max: TopicAliasMax -> u16 => |v| *v;
+    topic_alias: TopicAlias -> u16 => |v| *v;
+    max_qos: MaxQoS -> QoSLevel => |v| *v;
+    retain_avail: RetainAvail -> bool => |v| *v;
+    max_packet_size: MaxPacketSize -> u32 => |v| *v;
+    wildcard_sub_avail: WildcardSubAvail -> bool => |v| *v;
+    sub_id_avail: SubIdAvail -> bool => |v| *v;
+    shard_sub_avail: ShardSubAvail -> bool => |v| *v;
+}
+
 impl Index<PropertyType> for PropertyBundle {
     type Output = Property;
 
     fn index(&self, prop_type: PropertyType) -> &Self::Output {
-        &self.properties[&prop_type]
+        self.get_property(&prop_type).unwrap()
     }
 }
 
 impl IndexMut<PropertyType> for PropertyBundle {
     fn index_mut(&mut self, prop_type: PropertyType) -> &mut Self::Output {
-        self.properties.get_mut(&prop_type).unwrap()
+        self.properties
+            .iter_mut()
+            .find(|prop| PropertyType::from(&**prop) == prop_type)
+            .unwrap()
     }
 }
 
 impl IntoIterator for PropertyBundle {
-    type Item = (PropertyType, Property);
-    type IntoIter = std::collections::hash_map::IntoIter<PropertyType, Property>;
+    type Item = Property;
+    type IntoIter = vec::IntoIter<Property>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.user_props
-            .iter()
-            .flat_map(|(k, v)| {
-                v.iter().map(|v| {
-                    (
-                        PropertyType::UserProperty,
-                        Property::UserProperty(k.to_string(), v.to_string()),
-                    )
-                })
-            })
-            .chain(self.properties)
-            .collect::<HashMap<PropertyType, Property>>()
-            .into_iter()
+        self.properties.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a PropertyBundle {
+    type Item = &'a Property;
+    type IntoIter = slice::Iter<'a, Property>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.properties.iter()
     }
 }
 
 impl Size for PropertyBundle {
     fn size(&self) -> u32 {
-        let mut size = 0_u32;
-        for prop in self.properties.values() {
-            match prop {
-                Property::ContentType(p)
-                | Property::ResponseTopic(p)
-                | Property::AssignedClientId(p)
-                | Property::AuthMethod(p)
-                | Property::RespInfo(p)
-                | Property::ServerReference(p)
-                | Property::ReasonString(p) => size += p.len() as u32 + 3,
-
-                Property::SubscriptionIdentifier(p) => size += variable_byte_int_size(*p) + 1,
-
-                Property::MessageExpiry(_)
-                | Property::SessionExpiryInterval(_)
-                | Property::WillDelay(_)
-                | Property::MaxPacketSize(_) => size += 5,
-
-                Property::KeepAlive(_)
-                | Property::RecvMax(_)
-                | Property::TopicAliasMax(_)
-                | Property::TopicAlias(_) => size += 3,
-
-                Property::PayloadFormat(_) => size += 2,
-
-                Property::ReqProblemInfo(_) | Property::ReqRespInfo(_) => size += 2,
-                Property::MaxQoS(_) => size += 2,
-
-                Property::RetainAvail(_)
-                | Property::WildcardSubAvail(_)
-                | Property::SubIdAvail(_)
-                | Property::ShardSubAvail(_) => size += 2,
-
-                Property::CorrelationData(p) | Property::AuthData(p) => size += p.len() as u32 + 3,
-                // ignore user properties here
-                _ => {}
-            }
-        }
-
-        for (key, values) in &self.user_props {
-            for value in values {
-                size += value.len() as u32 + 2;
-            }
-            size += key.len() as u32 + 3;
-        }
-        size
+        self.properties
+            .iter()
+            .map(|prop| PropertyRef::from(prop).size())
+            .sum()
     }
 
     fn property_size(&self) -> u32 {
@@ -397,16 +457,11 @@ impl Size for PropertyBundle {
 }
 
 impl Encode for PropertyBundle {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         put_var_u32(self.size(), dest);
-        for prop in self.properties.values() {
+        for prop in &self.properties {
             prop.encode(dest)?;
         }
-        for (key, values) in &self.user_props {
-            for value in values {
-                Property::UserProperty(key.clone(), value.clone()).encode(dest)?;
-            }
-        }
         Ok(())
     }
 }
@@ -426,14 +481,39 @@ impl Decode for PropertyBundle {
         }
         let remaining = src.remaining();
         let prop_remaining = remaining - prop_size;
+        let mut decoded = BTreeSet::new();
         while src.remaining() > prop_remaining {
-            self.set_property(Property::decode(src)?);
+            let prop = Property::decode(src)?;
+            let prop_type = PropertyType::from(&prop);
+            if !self.supports_property(&prop_type) {
+                return Err(MqttCodecError::new_with_kind(
+                    &format!("MQTTv5 2.2.2.2 property {} not permitted", prop_type),
+                    ErrorKind::PropertyNotPermitted(prop_type),
+                ));
+            }
+            // user properties and subscription identifiers may be repeated,
+            // a repeated subscription identifier is checked per packet type
+            if cfg!(feature = "pedantic")
+                && prop_type != PropertyType::UserProperty
+                && prop_type != PropertyType::SubscriptionIdentifier
+                && !decoded.insert(prop_type)
+            {
+                return Err(MqttCodecError::new_with_kind(
+                    &format!(
+                        "MQTTv5 2.2.2.2 property {} included more than once",
+                        prop_type
+                    ),
+                    ErrorKind::DuplicateProperty(prop_type),
+                ));
+            }
+            self.set_property(prop);
         }
         Ok(())
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PayloadFormat {
     Bin = 0x00,
@@ -454,12 +534,12 @@ impl TryFrom<u8> for PayloadFormat {
 
 impl Property {
     pub fn decode(src: &mut BytesMut) -> Result<Property, MqttCodecError> {
-        match PropertyType::try_from(src.get_u8()) {
+        match PropertyType::try_from(get_u8(src)?) {
             Ok(prop_type) => match prop_type {
                 PropertyType::PayloadFormat => Ok(Property::PayloadFormat(
-                    PayloadFormat::try_from(src.get_u8())?,
+                    PayloadFormat::try_from(get_u8(src)?)?,
                 )),
-                PropertyType::MessageExpiry => Ok(Property::MessageExpiry(src.get_u32())),
+                PropertyType::MessageExpiry => Ok(Property::MessageExpiry(get_u32(src)?)),
                 PropertyType::ContentType => Ok(Property::ContentType(get_utf8(src)?)),
                 PropertyType::ResponseTopic => Ok(Property::ResponseTopic(get_utf8(src)?)),
                 PropertyType::CorrelationData => Ok(Property::CorrelationData(get_bin(src)?)),
@@ -467,29 +547,29 @@ impl Property {
                     Ok(Property::SubscriptionIdentifier(get_var_u32(src)?))
                 }
                 PropertyType::SessionExpiryInterval => {
-                    Ok(Property::SessionExpiryInterval(src.get_u32()))
+                    Ok(Property::SessionExpiryInterval(get_u32(src)?))
                 }
                 PropertyType::AssignedClientId => Ok(Property::AssignedClientId(get_utf8(src)?)),
-                PropertyType::KeepAlive => Ok(Property::KeepAlive(src.get_u16())),
+                PropertyType::KeepAlive => Ok(Property::KeepAlive(get_u16(src)?)),
                 PropertyType::AuthMethod => Ok(Property::AuthMethod(get_utf8(src)?)),
                 PropertyType::AuthData => Ok(Property::AuthData(get_bin(src)?)),
                 PropertyType::ReqProblemInfo => Ok(Property::ReqProblemInfo(get_bool(src)?)),
-                PropertyType::WillDelay => Ok(Property::WillDelay(src.get_u32())),
+                PropertyType::WillDelay => Ok(Property::WillDelay(get_u32(src)?)),
                 PropertyType::ReqRespInfo => Ok(Property::ReqRespInfo(get_bool(src)?)),
                 PropertyType::RespInfo => Ok(Property::RespInfo(get_utf8(src)?)),
                 PropertyType::ServerReference => Ok(Property::ServerReference(get_utf8(src)?)),
                 PropertyType::ReasonString => Ok(Property::ReasonString(get_utf8(src)?)),
-                PropertyType::RecvMax => Ok(Property::RecvMax(src.get_u16())),
-                PropertyType::TopicAliasMax => Ok(Property::TopicAliasMax(src.get_u16())),
-                PropertyType::TopicAlias => Ok(Property::TopicAlias(src.get_u16())),
-                PropertyType::MaxQoS => Ok(Property::MaxQoS(QoSLevel::try_from(src.get_u8())?)),
+                PropertyType::RecvMax => Ok(Property::RecvMax(get_u16(src)?)),
+                PropertyType::TopicAliasMax => Ok(Property::TopicAliasMax(get_u16(src)?)),
+                PropertyType::TopicAlias => Ok(Property::TopicAlias(get_u16(src)?)),
+                PropertyType::MaxQoS => Ok(Property::MaxQoS(QoSLevel::try_from(get_u8(src)?)?)),
                 PropertyType::RetainAvail => Ok(Property::RetainAvail(get_bool(src)?)),
                 PropertyType::UserProperty => {
                     let k = get_utf8(src)?;
                     let v = get_utf8(src)?;
                     Ok(Property::UserProperty(k, v))
                 }
-                PropertyType::MaxPacketSize => Ok(Property::MaxPacketSize(src.get_u32())),
+                PropertyType::MaxPacketSize => Ok(Property::MaxPacketSize(get_u32(src)?)),
                 PropertyType::WildcardSubAvail => Ok(Property::WildcardSubAvail(get_bool(src)?)),
                 PropertyType::SubIdAvail => Ok(Property::SubIdAvail(get_bool(src)?)),
                 PropertyType::ShardSubAvail => Ok(Property::ShardSubAvail(get_bool(src)?)),
@@ -498,45 +578,233 @@ impl Property {
         }
     }
 
-    pub fn encode(&self, dest: &mut bytes::BytesMut) -> Result<(), MqttCodecError> {
-        match self {
-            Property::ContentType(p)
-            | Property::ResponseTopic(p)
-            | Property::AssignedClientId(p)
-            | Property::AuthMethod(p)
-            | Property::RespInfo(p)
-            | Property::ServerReference(p)
-            | Property::ReasonString(p) => encode_utf8_property(self.into(), p, dest)?,
-
-            Property::SubscriptionIdentifier(p) => encode_var_int_property(self.into(), *p, dest),
-
-            Property::MessageExpiry(p)
-            | Property::SessionExpiryInterval(p)
-            | Property::WillDelay(p)
-            | Property::MaxPacketSize(p) => encode_u32_property(self.into(), *p, dest),
-
-            Property::KeepAlive(p)
-            | Property::RecvMax(p)
-            | Property::TopicAliasMax(p)
-            | Property::TopicAlias(p) => encode_u16_property(self.into(), *p, dest),
-
-            Property::PayloadFormat(p) => encode_u8_property(self.into(), *p as u8, dest),
-
-            Property::MaxQoS(p) => encode_u8_property(self.into(), *p as u8, dest),
-
-            Property::ReqProblemInfo(p)
-            | Property::ReqRespInfo(p)
-            | Property::RetainAvail(p)
-            | Property::WildcardSubAvail(p)
-            | Property::SubIdAvail(p)
-            | Property::ShardSubAvail(p) => encode_bool_property(self.into(), *p, dest),
-
-            Property::CorrelationData(p) | Property::AuthData(p) => {
-                encode_bin_property(self.into(), p, dest)?
+    pub fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        PropertyRef::from(self).encode(dest)
+    }
+}
+
+/// Property holding borrowed strings and binary data, used to build packets
+/// without allocating. See [`crate::packet_ref`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyRef<'a> {
+    PayloadFormat(PayloadFormat),
+    MessageExpiry(u32),
+    ContentType(&'a str),
+    ResponseTopic(&'a str),
+    CorrelationData(&'a [u8]),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientId(&'a str),
+    KeepAlive(u16),
+    AuthMethod(&'a str),
+    AuthData(&'a [u8]),
+    ReqProblemInfo(bool),
+    WillDelay(u32),
+    ReqRespInfo(bool),
+    RespInfo(&'a str),
+    ServerReference(&'a str),
+    ReasonString(&'a str),
+    RecvMax(u16),
+    TopicAliasMax(u16),
+    TopicAlias(u16),
+    MaxQoS(QoSLevel),
+    RetainAvail(bool),
+    UserProperty(&'a str, &'a str),
+    MaxPacketSize(u32),
+    WildcardSubAvail(bool),
+    SubIdAvail(bool),
+    ShardSubAvail(bool),
+}
+
+impl<'a> From<&'a Property> for PropertyRef<'a> {
+    fn from(value: &'a Property) -> Self {
+        match value {
+            Property::PayloadFormat(p) => PropertyRef::PayloadFormat(*p),
+            Property::MessageExpiry(p) => PropertyRef::MessageExpiry(*p),
+            Property::ContentType(p) => PropertyRef::ContentType(p),
+            Property::ResponseTopic(p) => PropertyRef::ResponseTopic(p),
+            Property::CorrelationData(p) => PropertyRef::CorrelationData(p),
+            Property::SubscriptionIdentifier(p) => PropertyRef::SubscriptionIdentifier(*p),
+            Property::SessionExpiryInterval(p) => PropertyRef::SessionExpiryInterval(*p),
+            Property::AssignedClientId(p) => PropertyRef::AssignedClientId(p),
+            Property::KeepAlive(p) => PropertyRef::KeepAlive(*p),
+            Property::AuthMethod(p) => PropertyRef::AuthMethod(p),
+            Property::AuthData(p) => PropertyRef::AuthData(p),
+            Property::ReqProblemInfo(p) => PropertyRef::ReqProblemInfo(*p),
+            Property::WillDelay(p) => PropertyRef::WillDelay(*p),
+            Property::ReqRespInfo(p) => PropertyRef::ReqRespInfo(*p),
+            Property::RespInfo(p) => PropertyRef::RespInfo(p),
+            Property::ServerReference(p) => PropertyRef::ServerReference(p),
+            Property::ReasonString(p) => PropertyRef::ReasonString(p),
+            Property::RecvMax(p) => PropertyRef::RecvMax(*p),
+            Property::TopicAliasMax(p) => PropertyRef::TopicAliasMax(*p),
+            Property::TopicAlias(p) => PropertyRef::TopicAlias(*p),
+            Property::MaxQoS(p) => PropertyRef::MaxQoS(*p),
+            Property::RetainAvail(p) => PropertyRef::RetainAvail(*p),
+            Property::UserProperty(k, v) => PropertyRef::UserProperty(k, v),
+            Property::MaxPacketSize(p) => PropertyRef::MaxPacketSize(*p),
+            Property::WildcardSubAvail(p) => PropertyRef::WildcardSubAvail(*p),
+            Property::SubIdAvail(p) => PropertyRef::SubIdAvail(*p),
+            Property::ShardSubAvail(p) => PropertyRef::ShardSubAvail(*p),
+        }
+    }
+}
+
+/// Copies the strings and binary data of a borrowed property.
+impl From<PropertyRef<'_>> for Property {
+    fn from(value: PropertyRef<'_>) -> Self {
+        match value {
+            PropertyRef::PayloadFormat(p) => Property::PayloadFormat(p),
+            PropertyRef::MessageExpiry(p) => Property::MessageExpiry(p),
+            PropertyRef::ContentType(p) => Property::ContentType(String::from(p)),
+            PropertyRef::ResponseTopic(p) => Property::ResponseTopic(String::from(p)),
+            PropertyRef::CorrelationData(p) => Property::CorrelationData(Bytes::copy_from_slice(p)),
+            PropertyRef::SubscriptionIdentifier(p) => Property::SubscriptionIdentifier(p),
+            PropertyRef::SessionExpiryInterval(p) => Property::SessionExpiryInterval(p),
+            PropertyRef::AssignedClientId(p) => Property::AssignedClientId(String::from(p)),
+            PropertyRef::KeepAlive(p) => Property::KeepAlive(p),
+            PropertyRef::AuthMethod(p) => Property::AuthMethod(String::from(p)),
+            PropertyRef::AuthData(p) => Property::AuthData(Bytes::copy_from_slice(p)),
+            PropertyRef::ReqProblemInfo(p) => Property::ReqProblemInfo(p),
+            PropertyRef::WillDelay(p) => Property::WillDelay(p),
+            PropertyRef::ReqRespInfo(p) => Property::ReqRespInfo(p),
+            PropertyRef::RespInfo(p) => Property::RespInfo(String::from(p)),
+            PropertyRef::ServerReference(p) => Property::ServerReference(String::from(p)),
+            PropertyRef::ReasonString(p) => Property::ReasonString(String::from(p)),
+            PropertyRef::RecvMax(p) => Property::RecvMax(p),
+            PropertyRef::TopicAliasMax(p) => Property::TopicAliasMax(p),
+            PropertyRef::TopicAlias(p) => Property::TopicAlias(p),
+            PropertyRef::MaxQoS(p) => Property::MaxQoS(p),
+            PropertyRef::RetainAvail(p) => Property::RetainAvail(p),
+            PropertyRef::UserProperty(k, v) => {
+                Property::UserProperty(String::from(k), String::from(v))
             }
+            PropertyRef::MaxPacketSize(p) => Property::MaxPacketSize(p),
+            PropertyRef::WildcardSubAvail(p) => Property::WildcardSubAvail(p),
+            PropertyRef::SubIdAvail(p) => Property::SubIdAvail(p),
+            PropertyRef::ShardSubAvail(p) => Property::ShardSubAvail(p),
+        }
+    }
+}
+
+impl From<PropertyRef<'_>> for PropertyType {
+    fn from(value: PropertyRef<'_>) -> Self {
+        match value {
+            PropertyRef::PayloadFormat(_) => PropertyType::PayloadFormat,
+            PropertyRef::MessageExpiry(_) => PropertyType::MessageExpiry,
+            PropertyRef::ContentType(_) => PropertyType::ContentType,
+            PropertyRef::ResponseTopic(_) => PropertyType::ResponseTopic,
+            PropertyRef::CorrelationData(_) => PropertyType::CorrelationData,
+            PropertyRef::SubscriptionIdentifier(_) => PropertyType::SubscriptionIdentifier,
+            PropertyRef::SessionExpiryInterval(_) => PropertyType::SessionExpiryInterval,
+            PropertyRef::AssignedClientId(_) => PropertyType::AssignedClientId,
+            PropertyRef::KeepAlive(_) => PropertyType::KeepAlive,
+            PropertyRef::AuthMethod(_) => PropertyType::AuthMethod,
+            PropertyRef::AuthData(_) => PropertyType::AuthData,
+            PropertyRef::ReqProblemInfo(_) => PropertyType::ReqProblemInfo,
+            PropertyRef::WillDelay(_) => PropertyType::WillDelay,
+            PropertyRef::ReqRespInfo(_) => PropertyType::ReqRespInfo,
+            PropertyRef::RespInfo(_) => PropertyType::RespInfo,
+            PropertyRef::ServerReference(_) => PropertyType::ServerReference,
+            PropertyRef::ReasonString(_) => PropertyType::ReasonString,
+            PropertyRef::RecvMax(_) => PropertyType::RecvMax,
+            PropertyRef::TopicAliasMax(_) => PropertyType::TopicAliasMax,
+            PropertyRef::TopicAlias(_) => PropertyType::TopicAlias,
+            PropertyRef::MaxQoS(_) => PropertyType::MaxQoS,
+            PropertyRef::RetainAvail(_) => PropertyType::RetainAvail,
+            PropertyRef::UserProperty(_, _) => PropertyType::UserProperty,
+            PropertyRef::MaxPacketSize(_) => PropertyType::MaxPacketSize,
+            PropertyRef::WildcardSubAvail(_) => PropertyType::WildcardSubAvail,
+            PropertyRef::SubIdAvail(_) => PropertyType::SubIdAvail,
+            PropertyRef::ShardSubAvail(_) => PropertyType::ShardSubAvail,
+        }
+    }
+}
 
-            Property::UserProperty(k, v) => {
-                dest.put_u8(PropertyType::from(self) as u8);
+impl PropertyRef<'_> {
+    pub fn property_type(&self) -> PropertyType {
+        PropertyType::from(*self)
+    }
+
+    /// Gets the encoded size of the property, including the property
+    /// identifier.
+    pub fn size(&self) -> u32 {
+        1 + match self {
+            PropertyRef::ContentType(p)
+            | PropertyRef::ResponseTopic(p)
+            | PropertyRef::AssignedClientId(p)
+            | PropertyRef::AuthMethod(p)
+            | PropertyRef::RespInfo(p)
+            | PropertyRef::ServerReference(p)
+            | PropertyRef::ReasonString(p) => p.len() as u32 + 2,
+
+            PropertyRef::SubscriptionIdentifier(p) => variable_byte_int_size(*p),
+
+            PropertyRef::MessageExpiry(_)
+            | PropertyRef::SessionExpiryInterval(_)
+            | PropertyRef::WillDelay(_)
+            | PropertyRef::MaxPacketSize(_) => 4,
+
+            PropertyRef::KeepAlive(_)
+            | PropertyRef::RecvMax(_)
+            | PropertyRef::TopicAliasMax(_)
+            | PropertyRef::TopicAlias(_) => 2,
+
+            PropertyRef::PayloadFormat(_)
+            | PropertyRef::MaxQoS(_)
+            | PropertyRef::ReqProblemInfo(_)
+            | PropertyRef::ReqRespInfo(_)
+            | PropertyRef::RetainAvail(_)
+            | PropertyRef::WildcardSubAvail(_)
+            | PropertyRef::SubIdAvail(_)
+            | PropertyRef::ShardSubAvail(_) => 1,
+
+            PropertyRef::CorrelationData(p) | PropertyRef::AuthData(p) => p.len() as u32 + 2,
+
+            PropertyRef::UserProperty(k, v) => k.len() as u32 + v.len() as u32 + 4,
+        }
+    }
+
+    pub fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let prop_type = self.property_type();
+        match *self {
+            PropertyRef::ContentType(p)
+            | PropertyRef::ResponseTopic(p)
+            | PropertyRef::AssignedClientId(p)
+            | PropertyRef::AuthMethod(p)
+            | PropertyRef::RespInfo(p)
+            | PropertyRef::ServerReference(p)
+            | PropertyRef::ReasonString(p) => encode_utf8_property(prop_type, p, dest)?,
+
+            PropertyRef::SubscriptionIdentifier(p) => encode_var_int_property(prop_type, p, dest),
+
+            PropertyRef::MessageExpiry(p)
+            | PropertyRef::SessionExpiryInterval(p)
+            | PropertyRef::WillDelay(p)
+            | PropertyRef::MaxPacketSize(p) => encode_u32_property(prop_type, p, dest),
+
+            PropertyRef::KeepAlive(p)
+            | PropertyRef::RecvMax(p)
+            | PropertyRef::TopicAliasMax(p)
+            | PropertyRef::TopicAlias(p) => encode_u16_property(prop_type, p, dest),
+
+            PropertyRef::PayloadFormat(p) => encode_u8_property(prop_type, p as u8, dest),
+
+            PropertyRef::MaxQoS(p) => encode_u8_property(prop_type, p as u8, dest),
+
+            PropertyRef::ReqProblemInfo(p)
+            | PropertyRef::ReqRespInfo(p)
+            | PropertyRef::RetainAvail(p)
+            | PropertyRef::WildcardSubAvail(p)
+            | PropertyRef::SubIdAvail(p)
+            | PropertyRef::ShardSubAvail(p) => encode_bool_property(prop_type, p, dest),
+
+            PropertyRef::CorrelationData(p) | PropertyRef::AuthData(p) => {
+                encode_bin_property(prop_type, p, dest)?
+            }
+
+            PropertyRef::UserProperty(k, v) => {
+                dest.put_u8(prop_type as u8);
                 put_utf8(k, dest)?;
                 put_utf8(v, dest)?;
             }
@@ -545,36 +813,52 @@ impl Property {
     }
 }
 
+/// Access to the properties of a control packet. Implemented with
+/// `#[derive(PacketProperties)]` and the `#[properties(...)]` attribute listing
+/// the properties permitted in the packet.
 pub trait PacketProperties {
+    /// Gets the properties permitted in the packet, MQTT v5 2.2.2.2.
+    fn supported_properties() -> BTreeSet<PropertyType>
+    where
+        Self: Sized;
     fn properties(&self) -> &PropertyBundle;
     fn properties_mut(&mut self) -> &mut PropertyBundle;
     fn set_properties(&mut self, properties: PropertyBundle);
 }
 
 pub trait PropertyEncode {
-    fn property_encode() -> Result<(), MqttCodecError>;
+    fn property_encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError>;
+}
+
+pub trait PropertyDecode {
+    fn property_decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError>;
 }
 
 pub trait PropertySize {
-    fn property_size_internal() -> u32;
+    /// Gets the encoded size of the properties, excluding the property length.
+    fn property_size_internal(&self) -> u32;
 }
 
-pub(crate) fn encode_u8_property(property_type: PropertyType, value: u8, dest: &mut BytesMut) {
+pub(crate) fn encode_u8_property(property_type: PropertyType, value: u8, dest: &mut impl BufMut) {
     dest.put_u8(property_type as u8);
     dest.put_u8(value);
 }
 
-pub(crate) fn encode_bool_property(property_type: PropertyType, value: bool, dest: &mut BytesMut) {
+pub(crate) fn encode_bool_property(
+    property_type: PropertyType,
+    value: bool,
+    dest: &mut impl BufMut,
+) {
     dest.put_u8(property_type as u8);
     dest.put_u8(value as u8);
 }
 
-pub(crate) fn encode_u16_property(property_type: PropertyType, value: u16, dest: &mut BytesMut) {
+pub(crate) fn encode_u16_property(property_type: PropertyType, value: u16, dest: &mut impl BufMut) {
     dest.put_u8(property_type as u8);
     dest.put_u16(value);
 }
 
-pub(crate) fn encode_u32_property(property_type: PropertyType, value: u32, dest: &mut BytesMut) {
+pub(crate) fn encode_u32_property(property_type: PropertyType, value: u32, dest: &mut impl BufMut) {
     dest.put_u8(property_type as u8);
     dest.put_u32(value);
 }
@@ -582,7 +866,7 @@ pub(crate) fn encode_u32_property(property_type: PropertyType, value: u32, dest:
 pub(crate) fn encode_utf8_property(
     property_type: PropertyType,
     value: &str,
-    dest: &mut BytesMut,
+    dest: &mut impl BufMut,
 ) -> Result<(), MqttCodecError> {
     dest.put_u8(property_type as u8);
     put_utf8(value, dest)
@@ -591,7 +875,7 @@ pub(crate) fn encode_utf8_property(
 pub(crate) fn encode_bin_property(
     property_type: PropertyType,
     value: &[u8],
-    dest: &mut BytesMut,
+    dest: &mut impl BufMut,
 ) -> Result<(), MqttCodecError> {
     dest.put_u8(property_type as u8);
     put_bin(value, dest)
@@ -600,8 +884,72 @@ pub(crate) fn encode_bin_property(
 pub(crate) fn encode_var_int_property(
     property_type: PropertyType,
     value: u32,
-    dest: &mut BytesMut,
+    dest: &mut impl BufMut,
 ) {
     dest.put_u8(property_type as u8);
     put_var_u32(value, dest);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{publish::Publish, FixedHeader, PacketType};
+
+    fn publish_props() -> PropertyBundle {
+        let mut props = PropertyBundle::new(Publish::supported_properties());
+        props.add_user_property("z".to_string(), "1".to_string());
+        props.set_property(Property::MessageExpiry(30));
+        props.add_subscription_id(9);
+        props.add_user_property("a".to_string(), "2".to_string());
+        props.add_user_property("z".to_string(), "3".to_string());
+        props.add_subscription_id(4);
+        props
+    }
+
+    #[test]
+    fn test_wire_order() {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("vaux".to_string());
+        publish.set_properties(publish_props());
+        let mut dest = BytesMut::new();
+        publish.encode(&mut dest).unwrap();
+        let mut src = dest.split_off(2);
+        let mut decoded = Publish::new_from_header(FixedHeader::new(PacketType::Publish)).unwrap();
+        decoded.decode(&mut src).unwrap();
+        assert_eq!(publish.properties(), decoded.properties());
+        assert_eq!(
+            vec![("z", "1"), ("a", "2"), ("z", "3")],
+            decoded.properties().user_properties().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["1", "3"],
+            decoded.properties().user_property("z").collect::<Vec<_>>()
+        );
+        assert_eq!(vec![9, 4], decoded.properties().subscription_ids());
+    }
+
+    #[test]
+    fn test_set_property() {
+        let mut props = publish_props();
+        props.set_property(Property::MessageExpiry(60));
+        assert_eq!(6, props.len());
+        assert_eq!(Some(&Property::MessageExpiry(60)), props.iter().nth(1));
+        props.clear_property(&PropertyType::SubscriptionIdentifier);
+        assert!(!props.has_property(&PropertyType::SubscriptionIdentifier));
+        assert_eq!(4, props.len());
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        let mut props = publish_props();
+        props.set_property(Property::ContentType("text/plain".to_string()));
+        props.set_property(Property::CorrelationData(Bytes::from_static(b"id")));
+        props.set_property(Property::PayloadFormat(PayloadFormat::Utf8));
+        assert_eq!(Some(30), props.message_expiry());
+        assert_eq!(Some("text/plain"), props.content_type());
+        assert_eq!(Some(&b"id"[..]), props.correlation_data());
+        assert_eq!(Some(PayloadFormat::Utf8), props.payload_format());
+        assert_eq!(None, props.response_topic());
+        assert_eq!(None, props.topic_alias());
+    }
+}
@@ -1,33 +1,37 @@
 use crate::{
-    codec::{get_utf8, put_utf8, variable_byte_int_size, SIZE_UTF8_STRING},
-    property::{PacketProperties, PropertyBundle},
+    builder::PublishBuilder,
+    codec::{get_u16, get_utf8, put_utf8, variable_byte_int_size, SIZE_UTF8_STRING},
+    property::{PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize},
     Decode, Encode, FixedHeader, MqttCodecError, PacketType, PropertyType, QoSLevel, Size,
 };
-use bytes::{Buf, BufMut};
-use std::collections::HashSet;
+use alloc::{format, string::String};
+use bytes::{Buf, BufMut, Bytes};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
-lazy_static! {
-    static ref SUPPORTED: HashSet<PropertyType> = {
-        let mut set = HashSet::new();
-        set.insert(PropertyType::PayloadFormat);
-        set.insert(PropertyType::MessageExpiry);
-        set.insert(PropertyType::TopicAlias);
-        set.insert(PropertyType::ResponseTopic);
-        set.insert(PropertyType::CorrelationData);
-        set.insert(PropertyType::SubscriptionIdentifier);
-        set.insert(PropertyType::ContentType);
-        set.insert(PropertyType::UserProperty);
-        set
-    };
-}
-
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, Eq, PartialEq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(
+    PayloadFormat,
+    MessageExpiry,
+    TopicAlias,
+    ResponseTopic,
+    CorrelationData,
+    SubscriptionIdentifier,
+    ContentType,
+    UserProperty
+)]
 pub struct Publish {
     pub header: FixedHeader,
+    /// Topic name, or None when only a topic alias is sent. Unlike the
+    /// payload the topic name is copied out of the receive buffer, see
+    /// [`crate::packet_ref::PublishRef`] to read it without allocating.
     pub topic_name: Option<String>,
     pub packet_id: Option<u16>,
     props: PropertyBundle,
-    payload: Option<Vec<u8>>,
+    #[cfg_attr(feature = "serde", serde(default, with = "crate::serde_bin::option"))]
+    payload: Option<Bytes>,
 }
 
 impl Default for Publish {
@@ -36,26 +40,32 @@ impl Default for Publish {
             header: FixedHeader::new(PacketType::Publish),
             topic_name: None,
             packet_id: None,
-            props: PropertyBundle::new(SUPPORTED.clone()),
+            props: PropertyBundle::new(Self::supported_properties()),
             payload: None,
         }
     }
 }
 
 impl Publish {
+    /// Creates a builder for a PUBLISH to the topic name, see
+    /// [`crate::builder`].
+    pub fn builder(topic_name: impl Into<String>) -> PublishBuilder {
+        PublishBuilder::new(topic_name)
+    }
+
     pub fn new_from_header(header: FixedHeader) -> Result<Self, MqttCodecError> {
         match header.packet_type {
             PacketType::Publish => Ok(Publish {
                 header,
                 topic_name: None,
                 packet_id: None,
-                props: PropertyBundle::new(SUPPORTED.clone()),
+                props: PropertyBundle::new(Self::supported_properties()),
                 payload: None,
             }),
-            p => Err(MqttCodecError {
-                reason: format!("unable to construct from {}", p),
-                kind: crate::codec::ErrorKind::MalformedPacket,
-            }),
+            p => Err(MqttCodecError::new_with_kind(
+                &format!("unable to construct from {}", p),
+                crate::codec::ErrorKind::MalformedPacket,
+            )),
         }
     }
 
@@ -67,11 +77,17 @@ impl Publish {
         self.header.set_qos(qos);
     }
 
-    pub fn set_payload(&mut self, data: Vec<u8>) {
-        self.payload = Some(data);
+    /// Sets the application message. The payload is reference counted so
+    /// that a publish delivered to many subscribers shares a single copy.
+    pub fn set_payload(&mut self, data: impl Into<Bytes>) {
+        self.payload = Some(data.into());
+    }
+
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload.as_deref()
     }
 
-    pub fn take_payload(&mut self) -> Option<Vec<u8>> {
+    pub fn take_payload(&mut self) -> Option<Bytes> {
         self.payload.take()
     }
 
@@ -92,17 +108,67 @@ impl Publish {
     pub fn properties_mut(&mut self) -> &mut PropertyBundle {
         &mut self.props
     }
-}
 
-impl PacketProperties for Publish {
-    fn properties(&self) -> &PropertyBundle {
-        &self.props
+    /// Decodes an MQTT 3.1.1 PUBLISH, which has no properties.
+    pub(crate) fn decode_v3(&mut self, src: &mut bytes::BytesMut) -> Result<(), MqttCodecError> {
+        let topic_name = get_utf8(src)?;
+        if topic_name.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.3.2.1 topic name must be present",
+            ));
+        }
+        self.topic_name = Some(topic_name);
+        if self.header.qos() != QoSLevel::AtMostOnce {
+            self.packet_id = Some(get_u16(src)?);
+        }
+        if src.has_remaining() {
+            self.payload = Some(src.split_to(src.remaining()).freeze());
+        }
+        Ok(())
     }
-    fn properties_mut(&mut self) -> &mut PropertyBundle {
-        &mut self.props
+
+    /// Encodes the PUBLISH for MQTT 3.1.1. Properties are dropped so the
+    /// topic name is required as topic aliases do not exist in 3.1.1.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let topic_name = match &self.topic_name {
+            Some(topic_name) => topic_name,
+            None => {
+                return Err(MqttCodecError::new(
+                    "MQTTv3.1.1 3.3.2.1 topic name must be present",
+                ))
+            }
+        };
+        let mut header = self.header.clone();
+        header.set_remaining(self.size_v3());
+        header.encode(dest)?;
+        put_utf8(topic_name, dest)?;
+        if self.header.qos() != QoSLevel::AtMostOnce {
+            match self.packet_id {
+                Some(packet_id) => dest.put_u16(packet_id),
+                None => {
+                    return Err(MqttCodecError::new(
+                        "MQTTv3.1.1 3.3.2.2 packet identifier must be included for QOS 1 or 2",
+                    ))
+                }
+            }
+        }
+        if let Some(p) = self.payload.as_ref() {
+            dest.put_slice(p)
+        }
+        Ok(())
     }
-    fn set_properties(&mut self, properties: PropertyBundle) {
-        self.props = properties;
+
+    /// Gets the remaining length of the PUBLISH for MQTT 3.1.1, which has no
+    /// properties.
+    pub(crate) fn size_v3(&self) -> u32 {
+        let mut remaining = SIZE_UTF8_STRING + self.payload_size();
+        if let Some(topic_name) = self.topic_name.as_ref() {
+            remaining += topic_name.len() as u32;
+        }
+        if self.header.qos() != QoSLevel::AtMostOnce {
+            remaining += 2;
+        }
+        remaining
     }
 }
 
@@ -125,7 +191,7 @@ impl Size for Publish {
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
@@ -138,7 +204,7 @@ impl Size for Publish {
 }
 
 impl Encode for Publish {
-    fn encode(&self, dest: &mut bytes::BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         let mut header = self.header.clone();
         let size = self.size();
         header.set_remaining(size);
@@ -162,7 +228,7 @@ impl Encode for Publish {
                 ));
             }
         }
-        self.props.encode(dest)?;
+        self.property_encode(dest)?;
         if let Some(p) = self.payload.as_ref() {
             dest.put_slice(p)
         }
@@ -179,13 +245,12 @@ impl Decode for Publish {
             self.topic_name = Some(topic_name);
         }
         if self.header.qos() != QoSLevel::AtMostOnce {
-            self.packet_id = Some(src.get_u16());
+            self.packet_id = Some(get_u16(src)?);
         }
-        self.props.decode(src)?;
+        self.property_decode(src)?;
         if src.remaining() > 0 {
-            let idx = src.len() - src.remaining();
-            let end = src.remaining();
-            self.payload = Some(src.copy_to_bytes(end - idx).to_vec());
+            // the payload is the remainder of the packet, split without copying
+            self.payload = Some(src.split_to(src.remaining()).freeze());
         }
         Ok(())
     }
@@ -241,7 +306,7 @@ mod test {
                 match publish.encode(&mut dest) {
                     Ok(_) => panic!("expected error on encode"),
                     Err(e) => {
-                        assert_eq!("MQTTv5 3.3.2.1", &e.reason[0..14]);
+                        assert_eq!("MQTTv5 3.3.2.1", &e.message()[0..14]);
                     }
                 }
             }
@@ -258,7 +323,7 @@ mod test {
                 match publish.encode(&mut dest) {
                     Ok(_) => panic!("expected error on encode"),
                     Err(e) => {
-                        assert_eq!("MQTTv5 3.3.2.1", &e.reason[0..14]);
+                        assert_eq!("MQTTv5 3.3.2.1", &e.message()[0..14]);
                     }
                 }
             }
@@ -277,7 +342,7 @@ mod test {
         match Publish::new_from_header(hdr) {
             Ok(mut publish) => {
                 let mut dest = BytesMut::new();
-                publish.payload = Some([10_u8; 20].into());
+                publish.payload = Some(Bytes::from_static(&[10_u8; 20]));
                 publish.topic_name = Some(String::from("topic"));
                 match publish.encode(&mut dest) {
                     Ok(_) => {
@@ -315,7 +380,8 @@ mod test {
                 assert_eq!("vaux", publish_packet.topic_name.unwrap());
                 assert_eq!(
                     "hello",
-                    String::from_utf8(publish_packet.payload.unwrap()).expect("unable to decode")
+                    String::from_utf8(publish_packet.payload.unwrap().to_vec())
+                        .expect("unable to decode")
                 );
             }
             Err(e) => panic!("unexpected error decoding publish: {}", e),
@@ -348,8 +414,76 @@ mod test {
                 panic!("expected malformed packet");
             }
             Err(e) => {
-                assert_eq!("MQTTv5 2.2.2.1 invalid property length", e.reason);
+                assert_eq!("MQTTv5 2.2.2.1 invalid property length", e.message());
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_subscription_ids() {
+        let mut publish = Publish {
+            topic_name: Some("vaux".to_string()),
+            ..Default::default()
+        };
+        publish
+            .properties_mut()
+            .set_property(crate::property::Property::SubscriptionIdentifier(1));
+        publish.properties_mut().add_subscription_id(268_435_455);
+        let mut dest = BytesMut::new();
+        publish.encode(&mut dest).expect("unable to encode publish");
+        let mut src = dest.split_off(2);
+        let mut decoded = Publish::new_from_header(FixedHeader::new(PacketType::Publish)).unwrap();
+        decoded.decode(&mut src).expect("unable to decode publish");
+        assert_eq!(
+            vec![1, 268_435_455],
+            decoded.properties().subscription_ids()
+        );
+    }
+
+    #[test]
+    fn test_decode_payload_shared() {
+        let mut publish = Publish {
+            topic_name: Some("topic".to_string()),
+            ..Default::default()
+        };
+        publish.set_payload(&b"shared payload"[..]);
+        let mut src = BytesMut::new();
+        publish.encode(&mut src).unwrap();
+        let start = src.as_ptr() as usize;
+        let end = start + src.len();
+        let (decoded, _) = crate::decode(&mut src).unwrap().unwrap();
+        match decoded {
+            crate::Packet::Publish(mut decoded) => {
+                let payload = decoded.take_payload().unwrap();
+                assert_eq!(&b"shared payload"[..], &payload[..]);
+                // the payload references the receive buffer rather than a copy
+                let payload_start = payload.as_ptr() as usize;
+                assert!(payload_start > start && payload_start < end);
+            }
+            p => panic!("expected PUBLISH, found {:?}", p),
+        }
+    }
+    #[test]
+    fn test_dup() {
+        let mut publish = Publish {
+            topic_name: Some("topic".to_string()),
+            packet_id: Some(1),
+            ..Default::default()
+        };
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.header.set_dup(true);
+        assert_eq!(0b_0000_1010, publish.header.flags());
+        let mut src = BytesMut::new();
+        publish.encode(&mut src).unwrap();
+        match crate::decode(&mut src).unwrap().unwrap().0 {
+            crate::Packet::Publish(decoded) => {
+                assert!(decoded.header.dup());
+                assert_eq!(QoSLevel::AtLeastOnce, decoded.qos());
             }
+            p => panic!("expected PUBLISH, found {:?}", p),
         }
+        publish.header.set_dup(false);
+        assert!(!publish.header.dup());
+        assert_eq!(QoSLevel::AtLeastOnce, publish.qos());
     }
 }
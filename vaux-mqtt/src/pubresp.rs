@@ -1,15 +1,19 @@
-use std::collections::HashSet;
-
 use bytes::{Buf, BufMut};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
 use crate::{
-    codec::variable_byte_int_size, property::PropertyBundle, Decode, Encode, FixedHeader,
-    MqttCodecError, PacketType, PropertyType, Reason, Size,
+    codec::{get_u16, get_u8, variable_byte_int_size},
+    property::{PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize},
+    Decode, Encode, FixedHeader, MqttCodecError, PacketType, Reason, Size,
 };
 
-const VARIABLE_HEADER_LEN: u32 = 2;
+pub(crate) const VARIABLE_HEADER_LEN: u32 = 2;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(ReasonString, UserProperty)]
 pub struct PubResp {
     resp_type: PacketType,
     reason: Reason,
@@ -33,23 +37,19 @@ impl PubResp {
     }
 
     fn new(resp_type: PacketType) -> Result<Self, MqttCodecError> {
-        let mut supported = HashSet::new();
-        supported.insert(PropertyType::ReasonString);
-        supported.insert(PropertyType::UserProperty);
-
         match resp_type {
             PacketType::PubAck | PacketType::PubComp | PacketType::PubRec | PacketType::PubRel => {
                 Ok(Self {
                     resp_type,
                     reason: Reason::Success,
                     packet_id: 0,
-                    props: PropertyBundle::new(supported),
+                    props: PropertyBundle::new(Self::supported_properties()),
                 })
             }
-            _ => Err(MqttCodecError {
-                reason: "unsupported response type".to_string(),
-                kind: crate::codec::ErrorKind::UnsupportedResponseType,
-            }),
+            _ => Err(MqttCodecError::new_with_kind(
+                "unsupported response type",
+                crate::codec::ErrorKind::UnsupportedResponseType,
+            )),
         }
     }
 
@@ -62,10 +62,10 @@ impl PubResp {
             self.reason = reason;
             Ok(())
         } else {
-            Err(MqttCodecError {
-                reason: "unsupported reason".to_string(),
-                kind: crate::codec::ErrorKind::UnsupportedReason,
-            })
+            Err(MqttCodecError::new_with_kind(
+                "unsupported reason",
+                crate::codec::ErrorKind::UnsupportedReason,
+            ))
         }
     }
 
@@ -77,6 +77,34 @@ impl PubResp {
         &mut self.props
     }
 
+    /// Decodes an MQTT 3.1.1 response, which carries only the packet identifier.
+    pub(crate) fn decode_v3(&mut self, src: &mut bytes::BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < VARIABLE_HEADER_LEN as usize {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 insufficient data for publish response",
+            ));
+        }
+        self.reason = Reason::Success;
+        self.packet_id = get_u16(src)?;
+        Ok(())
+    }
+
+    /// Encodes the response for MQTT 3.1.1. The reason code and properties
+    /// are dropped as 3.1.1 responses carry only the packet identifier.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let mut header = FixedHeader::new(self.resp_type);
+        header.set_remaining(self.size_v3());
+        header.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        Ok(())
+    }
+
+    /// Gets the remaining length of the response for MQTT 3.1.1, the packet
+    /// identifier.
+    pub(crate) fn size_v3(&self) -> u32 {
+        VARIABLE_HEADER_LEN
+    }
+
     fn supported_reason(resp_type: &PacketType, reason: &Reason) -> bool {
         match resp_type {
             PacketType::PubAck | PacketType::PubRec => matches!(
@@ -92,7 +120,7 @@ impl PubResp {
                     | Reason::PayloadFormatErr
             ),
             PacketType::PubComp | PacketType::PubRel => {
-                matches!(reason, Reason::Success | Reason::PacketIdInUse)
+                matches!(reason, Reason::Success | Reason::PacketIdNotFound)
             }
             _ => false,
         }
@@ -104,16 +132,16 @@ impl Size for PubResp {
         let prop_size = self.property_size();
         let prop_size_len = variable_byte_int_size(prop_size);
 
-        if prop_size == 0 {
+        if self.reason == Reason::Success && prop_size == 0 {
             VARIABLE_HEADER_LEN
         } else {
-            let remaining = 3 + prop_size + prop_size_len;
-            VARIABLE_HEADER_LEN + remaining
+            // reason code followed by the property length and properties
+            VARIABLE_HEADER_LEN + 1 + prop_size_len + prop_size
         }
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
@@ -122,17 +150,17 @@ impl Size for PubResp {
 }
 
 impl Encode for PubResp {
-    fn encode(&self, dest: &mut bytes::BytesMut) -> Result<(), crate::MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), crate::MqttCodecError> {
         let mut header = match self.resp_type {
             PacketType::PubAck => FixedHeader::new(PacketType::PubAck),
             PacketType::PubRec => FixedHeader::new(PacketType::PubRec),
             PacketType::PubComp => FixedHeader::new(PacketType::PubComp),
             PacketType::PubRel => FixedHeader::new(PacketType::PubRel),
             _ => {
-                return Err(MqttCodecError {
-                    reason: "usupported response type".to_string(),
-                    kind: crate::codec::ErrorKind::UnsupportedResponseType,
-                })
+                return Err(MqttCodecError::new_with_kind(
+                    "usupported response type",
+                    crate::codec::ErrorKind::UnsupportedResponseType,
+                ))
             }
         };
         header.set_remaining(self.size());
@@ -142,7 +170,7 @@ impl Encode for PubResp {
             Ok(())
         } else {
             dest.put_u8(self.reason as u8);
-            self.props.encode(dest)?;
+            self.property_encode(dest)?;
             Ok(())
         }
     }
@@ -152,13 +180,13 @@ impl Decode for PubResp {
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<(), crate::MqttCodecError> {
         if src.remaining() == 2 {
             self.reason = Reason::Success;
-            self.packet_id = src.get_u16();
+            self.packet_id = get_u16(src)?;
             return Ok(());
         }
-        self.packet_id = src.get_u16();
-        self.reason = Reason::try_from(src.get_u8())?;
+        self.packet_id = get_u16(src)?;
+        self.reason = Reason::try_from(get_u8(src)?)?;
         if src.remaining() > 0 {
-            self.props.decode(src)?;
+            self.property_decode(src)?;
         }
         Ok(())
     }
@@ -199,4 +227,16 @@ mod test {
         assert!(result.is_ok());
         assert_eq!(EXPECTED_LEN, dest.len());
     }
+
+    #[test]
+    fn encode_reason_no_props() {
+        const EXPECTED_REMAINING: u8 = 4;
+        let mut puback = PubResp::new_puback();
+        puback.packet_id = 12345;
+        assert!(puback.set_reason(Reason::NoSubscribers).is_ok());
+        let mut dest = BytesMut::new();
+        assert!(puback.encode(&mut dest).is_ok());
+        assert_eq!(EXPECTED_REMAINING, dest[1]);
+        assert_eq!(EXPECTED_REMAINING as usize + 2, dest.len());
+    }
 }
@@ -0,0 +1,109 @@
+//! Serialization of binary data, such as payloads, correlation data and
+//! passwords. Binary data is written as a base64 string in human readable
+//! formats, JSON for example, and as bytes in compact binary formats.
+
+use alloc::{format, vec::Vec};
+use core::fmt::Formatter;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{
+    de::{Error, SeqAccess, Visitor},
+    Deserializer, Serializer,
+};
+
+pub(crate) fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&STANDARD.encode(value.as_ref()))
+    } else {
+        serializer.serialize_bytes(value.as_ref())
+    }
+}
+
+pub(crate) fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: From<Vec<u8>>,
+    D: Deserializer<'de>,
+{
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(BinVisitor).map(T::from)
+    } else {
+        deserializer.deserialize_byte_buf(BinVisitor).map(T::from)
+    }
+}
+
+struct BinVisitor;
+
+impl<'de> Visitor<'de> for BinVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "base64 string or bytes")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+        STANDARD
+            .decode(v)
+            .map_err(|e| E::custom(format!("invalid base64 data: {}", e)))
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Optional binary data, for example the payload of a PUBLISH.
+pub(crate) mod option {
+    use alloc::vec::Vec;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct Bin<'a>(&'a [u8]);
+
+    impl Serialize for Bin<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(&self.0, serializer)
+        }
+    }
+
+    struct BinBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for BinBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(BinBuf)
+        }
+    }
+
+    pub(crate) fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        value
+            .as_ref()
+            .map(|v| Bin(v.as_ref()))
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: From<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<BinBuf>::deserialize(deserializer)?.map(|b| T::from(b.0)))
+    }
+}
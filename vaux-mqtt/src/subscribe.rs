@@ -1,29 +1,16 @@
-use std::collections::HashSet;
-
+use alloc::{format, string::String, vec::Vec};
 use bytes::{Buf, BufMut, BytesMut};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
 use crate::{
-    codec::{get_utf8, put_utf8, variable_byte_int_size},
-    property::{PacketProperties, PropertyBundle},
-    Decode, Encode, FixedHeader, MqttCodecError, PropertyType, QoSLevel, Reason, Size,
+    builder::SubscribeBuilder,
+    codec::{get_u16, get_u8, get_utf8, put_utf8, variable_byte_int_size},
+    packet_ref::SubscriptionRef,
+    property::{PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize},
+    Decode, Encode, FixedHeader, MqttCodecError, QoSLevel, Reason, Size,
 };
 
-lazy_static! {
-    static ref SUBACK_SUPPORTED: HashSet<PropertyType> = {
-        let mut supported = HashSet::new();
-        supported.insert(PropertyType::ReasonString);
-        supported.insert(PropertyType::UserProperty);
-        supported
-    };
-    static ref SUBSCRIPTION_SUPPORTED: HashSet<PropertyType> = {
-        let mut supported = HashSet::new();
-        supported.insert(PropertyType::SubscriptionIdentifier);
-        supported.insert(PropertyType::UserProperty);
-        supported
-    };
-}
-
-const VAR_HDR_LEN: u32 = 2;
+pub(crate) const VAR_HDR_LEN: u32 = 2;
 
 /// MQTT v5 3.8.3.1 Subscription Options
 /// bits 4 and 5 of the subscription options hold the retain handling flag.
@@ -32,6 +19,7 @@ const VAR_HDR_LEN: u32 = 2;
 /// is received.
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RetainHandling {
     #[default]
     Send,
@@ -54,13 +42,87 @@ impl TryFrom<u8> for RetainHandling {
     }
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(ReasonString, UserProperty)]
 pub struct SubAck {
     packet_id: u16,
     props: PropertyBundle,
     sub_reason: Vec<Reason>,
 }
 
+impl Default for SubAck {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl SubAck {
+    pub fn new(packet_id: u16) -> Self {
+        Self {
+            packet_id,
+            props: PropertyBundle::new(Self::supported_properties()),
+            sub_reason: Vec::new(),
+        }
+    }
+
+    pub fn packet_id(&self) -> u16 {
+        self.packet_id
+    }
+
+    /// Adds the reason code for the next subscription in the acknowledged
+    /// SUBSCRIBE. Reason codes must be added in the same order as the
+    /// subscriptions in the SUBSCRIBE payload.
+    pub fn add_reason(&mut self, reason: Reason) {
+        self.sub_reason.push(reason);
+    }
+
+    pub fn reasons(&self) -> &[Reason] {
+        &self.sub_reason
+    }
+
+    /// Decodes an MQTT 3.1.1 SUBACK, mapping the return codes to reasons.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < 3 {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.9.3 insufficient data for SUBACK",
+            ));
+        }
+        self.packet_id = get_u16(src)?;
+        while src.has_remaining() {
+            self.sub_reason
+                .push(Reason::from_v3_suback_code(get_u8(src)?)?);
+        }
+        Ok(())
+    }
+
+    /// Encodes the SUBACK for MQTT 3.1.1. Properties are dropped and any
+    /// reason other than a granted QoS is sent as the failure return code.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.sub_reason.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.9.3 SUBACK must contain a return code for each subscription",
+            ));
+        }
+        let mut hdr = FixedHeader::new(crate::PacketType::SubAck);
+        hdr.set_remaining(self.size_v3());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        for reason in &self.sub_reason {
+            dest.put_u8(reason.to_v3_suback_code());
+        }
+        Ok(())
+    }
+
+    /// Gets the remaining length of the SUBACK for MQTT 3.1.1, which has no
+    /// properties.
+    pub(crate) fn size_v3(&self) -> u32 {
+        VAR_HDR_LEN + self.payload_size()
+    }
+}
+
 impl Size for SubAck {
     fn size(&self) -> u32 {
         let prop_size = self.property_size();
@@ -68,7 +130,7 @@ impl Size for SubAck {
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
@@ -83,11 +145,11 @@ impl Decode for SubAck {
                 "MQTTv5 3.9.3 insufficient data for SUBACK",
             ));
         }
-        self.packet_id = src.get_u16();
-        self.props.decode(src)?;
+        self.packet_id = get_u16(src)?;
+        self.property_decode(src)?;
         // decode the reason codes
         while src.has_remaining() {
-            let reason = Reason::try_from(src.get_u8())?;
+            let reason = Reason::try_from(get_u8(src)?)?;
             self.sub_reason.push(reason);
         }
         Ok(())
@@ -95,14 +157,28 @@ impl Decode for SubAck {
 }
 
 impl Encode for SubAck {
-    fn encode(&self, _dest: &mut BytesMut) -> Result<(), MqttCodecError> {
-        todo!()
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.sub_reason.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.9.3 SUBACK must contain a reason code for each subscription",
+            ));
+        }
+        let mut hdr = FixedHeader::new(crate::PacketType::SubAck);
+        hdr.set_remaining(self.size());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.property_encode(dest)?;
+        for reason in &self.sub_reason {
+            dest.put_u8(*reason as u8);
+        }
+        Ok(())
     }
 }
 
 /// Subscription represents an MQTT v5 3.8.3 SUBSCRIBE Payload. The Mqtt v5
 /// 3.8.3.1 options are represented as individual fields in the struct.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Subscription {
     pub filter: String,
     pub qos: QoSLevel,
@@ -122,29 +198,27 @@ impl Subscription {
         }
     }
 
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
-        put_utf8(&self.filter, dest)?;
-        let flags = self.qos as u8
-            | (self.no_local as u8) << 2
-            | (self.retain_as as u8) << 3
-            | (self.handling as u8) << 4;
-        dest.put_u8(flags);
-        Ok(())
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        SubscriptionRef::from(self).encode(dest)
     }
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
         self.filter = get_utf8(src)?;
-        let flags = src.get_u8();
+        let flags = get_u8(src)?;
         self.qos = QoSLevel::try_from(flags & 0b_0000_0011)?;
         self.no_local = flags & 0b_0000_0100 == 0b_0000_0100;
         self.retain_as = flags & 0b_0000_1000 == 0b_0000_1000;
-        self.handling = RetainHandling::try_from(flags & 0b_0011_0000 >> 4)?;
+        self.handling = RetainHandling::try_from((flags & 0b_0011_0000) >> 4)?;
 
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(SubscriptionIdentifier, UserProperty)]
 pub struct Subscribe {
     packet_id: u16,
     props: PropertyBundle,
@@ -155,17 +229,23 @@ impl Default for Subscribe {
     fn default() -> Self {
         Self {
             packet_id: 0,
-            props: PropertyBundle::new(SUBSCRIPTION_SUPPORTED.clone()),
+            props: PropertyBundle::new(Self::supported_properties()),
             payload: Vec::new(),
         }
     }
 }
 
 impl Subscribe {
+    /// Creates a builder for a SUBSCRIBE with the packet identifier, see
+    /// [`crate::builder`].
+    pub fn builder(packet_id: u16) -> SubscribeBuilder {
+        SubscribeBuilder::new(packet_id)
+    }
+
     pub fn new(packet_id: u16, payload: Vec<Subscription>) -> Self {
         Self {
             packet_id,
-            props: PropertyBundle::new(SUBSCRIPTION_SUPPORTED.clone()),
+            props: PropertyBundle::new(Self::supported_properties()),
             payload,
         }
     }
@@ -184,30 +264,80 @@ impl Subscribe {
         self.payload.push(subscription);
     }
 
-    fn encode_payload(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
-        if self.payload.is_empty() {
+    pub fn subscriptions(&self) -> &[Subscription] {
+        &self.payload
+    }
+
+    /// Removes the subscription with the topic filter, returning true if the
+    /// SUBSCRIBE held it.
+    pub fn remove_subscription(&mut self, filter: &str) -> bool {
+        let len = self.payload.len();
+        self.payload.retain(|sub| sub.filter != filter);
+        self.payload.len() != len
+    }
+
+    /// Decodes an MQTT 3.1.1 SUBSCRIBE. The subscription options byte only
+    /// carries the requested QoS, MQTT v3.1.1 3.8.3.1.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.len() < 3 {
             return Err(MqttCodecError::new(
-                "MQTTv5 3.8.3 subscribe payload must exist",
+                "MQTTv3.1.1 3.8.2 insufficient data for SUBSCRIBE",
             ));
         }
-        for sub in &self.payload {
-            sub.encode(dest)?;
+        self.packet_id = get_u16(src)?;
+        while src.remaining() != 0 {
+            let filter = get_utf8(src)?;
+            let qos = get_u8(src)?;
+            if qos & !0b_0000_0011 != 0 {
+                return Err(MqttCodecError::new(
+                    "MQTTv3.1.1 3.8.3.1 reserved subscription bits must be 0",
+                ));
+            }
+            self.add_subscription(Subscription::new(filter, QoSLevel::try_from(qos)?));
         }
         Ok(())
     }
-}
 
-impl PacketProperties for Subscribe {
-    fn properties(&self) -> &PropertyBundle {
-        &self.props
+    /// Encodes the SUBSCRIBE for MQTT 3.1.1. Properties and the subscription
+    /// options introduced in MQTT v5 are dropped, leaving the requested QoS.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.packet_id == 0 {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 2.3.1 packet identifier must not be 0",
+            ));
+        }
+        if self.payload.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.8.3 subscribe payload must exist",
+            ));
+        }
+        let mut hdr = FixedHeader::new(crate::PacketType::Subscribe);
+        hdr.set_remaining(self.size_v3());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        for sub in &self.payload {
+            put_utf8(&sub.filter, dest)?;
+            dest.put_u8(sub.qos as u8);
+        }
+        Ok(())
     }
 
-    fn properties_mut(&mut self) -> &mut PropertyBundle {
-        &mut self.props
+    /// Gets the remaining length of the SUBSCRIBE for MQTT 3.1.1, which has
+    /// no properties.
+    pub(crate) fn size_v3(&self) -> u32 {
+        VAR_HDR_LEN + self.payload_size()
     }
 
-    fn set_properties(&mut self, props: PropertyBundle) {
-        self.props = props;
+    fn encode_payload(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.payload.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.8.3 subscribe payload must exist",
+            ));
+        }
+        for sub in &self.payload {
+            sub.encode(dest)?;
+        }
+        Ok(())
     }
 }
 
@@ -218,20 +348,20 @@ impl Size for Subscribe {
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
         let mut remaining = 0;
         for s in &self.payload {
-            remaining += s.filter.len() + 3;
+            remaining += SubscriptionRef::from(s).size();
         }
-        remaining as u32
+        remaining
     }
 }
 
 impl Encode for Subscribe {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
         if self.packet_id == 0 {
             return Err(MqttCodecError::new(
                 "MQTTv5 2.2.1 packet identifier must not be 0",
@@ -241,7 +371,7 @@ impl Encode for Subscribe {
         hdr.set_remaining(self.size());
         hdr.encode(dest)?;
         dest.put_u16(self.packet_id);
-        self.props.encode(dest)?;
+        self.property_encode(dest)?;
         self.encode_payload(dest)?;
         Ok(())
     }
@@ -254,8 +384,8 @@ impl Decode for Subscribe {
                 "MQTTv5 3.8.2 insufficient data for SUBSCRIBE",
             ));
         }
-        self.packet_id = src.get_u16();
-        self.props.decode(src)?;
+        self.packet_id = get_u16(src)?;
+        self.property_decode(src)?;
         while src.remaining() != 0 {
             let mut s = Subscription::default();
             s.decode(src)?;
@@ -267,25 +397,26 @@ impl Decode for Subscribe {
 
 #[cfg(test)]
 mod test {
-    use bytes::BytesMut;
+    use bytes::{Buf, BytesMut};
 
     use crate::{
         property::{PacketProperties, Property},
-        Decode, Encode, QoSLevel, Size, Subscribe,
+        Decode, Encode, QoSLevel, Reason, Size, Subscribe,
     };
 
-    use super::{RetainHandling, Subscription};
+    use super::{RetainHandling, SubAck, Subscription};
 
     #[test]
     fn test_encode_flags() {
         const EXPECTED_FLAG: u8 = 0b_0010_1110;
         const EXPECTED_LEN: usize = 7;
-        let mut sub = Subscription::default();
-        sub.filter = "test".to_string();
-        sub.qos = QoSLevel::ExactlyOnce;
-        sub.no_local = true;
-        sub.retain_as = true;
-        sub.handling = RetainHandling::None;
+        let mut sub = Subscription {
+            filter: "test".to_string(),
+            qos: QoSLevel::ExactlyOnce,
+            no_local: true,
+            retain_as: true,
+            handling: RetainHandling::None,
+        };
 
         let mut dest = BytesMut::new();
         assert!(sub.encode(&mut dest).is_ok());
@@ -309,12 +440,30 @@ mod test {
         assert_eq!(QOS_EXPECTED_FLAG, dest[6]);
     }
 
+    #[test]
+    fn test_decode_flags() {
+        let sub = Subscription {
+            filter: "test".to_string(),
+            qos: QoSLevel::ExactlyOnce,
+            no_local: false,
+            retain_as: true,
+            handling: RetainHandling::SendNew,
+        };
+        let mut dest = BytesMut::new();
+        assert!(sub.encode(&mut dest).is_ok());
+        let mut decoded = Subscription::default();
+        assert!(decoded.decode(&mut dest).is_ok());
+        assert_eq!(sub, decoded);
+    }
+
     #[test]
     fn test_payload_size() {
         const EXPECTED_PAYLOAD_SIZE: u32 = 7;
 
-        let mut subscribe = Subscribe::default();
-        subscribe.packet_id = 42;
+        let mut subscribe = Subscribe {
+            packet_id: 42,
+            ..Default::default()
+        };
         let subscription = Subscription {
             filter: "test".to_string(),
             qos: QoSLevel::AtLeastOnce,
@@ -330,8 +479,10 @@ mod test {
 
     #[test]
     fn test_bad_packet_id() {
-        let mut subscribe = Subscribe::default();
-        subscribe.packet_id = 0;
+        let mut subscribe = Subscribe {
+            packet_id: 0,
+            ..Default::default()
+        };
         let subscription = Subscription {
             filter: "test".to_string(),
             qos: QoSLevel::AtLeastOnce,
@@ -344,7 +495,7 @@ mod test {
         match subscribe.encode(&mut dest) {
             Ok(_) => panic!("expected MQTT encoding error"),
             Err(e) => {
-                assert!(e.reason.starts_with("MQTTv5 2.2.1"));
+                assert!(e.message().starts_with("MQTTv5 2.2.1"));
             }
         }
     }
@@ -358,8 +509,10 @@ mod test {
         const EXPECTED_PROP_SIZE: u32 = USER_PROP_SIZE as u32 + 3;
         const EXPECTED_PAYLOAD_SIZE: u32 = 7;
         const EXPECTED_SIZE: u32 = 5 + EXPECTED_PAYLOAD_SIZE + EXPECTED_PROP_SIZE;
-        let mut subscribe = Subscribe::default();
-        subscribe.packet_id = 42;
+        let mut subscribe = Subscribe {
+            packet_id: 42,
+            ..Default::default()
+        };
         let props = subscribe.properties_mut();
         props.add_user_property(USER_PROP_KEY.to_string(), USER_PROP_VALUE.to_string());
         props.set_property(Property::SubscriptionIdentifier(4096));
@@ -378,10 +531,24 @@ mod test {
             Ok(()) => {
                 assert_eq!(EXPECTED_SIZE, dest.len() as u32);
             }
-            Err(e) => panic!("Unexpected encoding error: {}", e.reason),
+            Err(e) => panic!("Unexpected encoding error: {}", e.message()),
         }
     }
 
+    #[test]
+    fn test_suback_round_trip() {
+        let mut suback = SubAck::new(0x1234);
+        suback.add_reason(Reason::GrantedQoS1);
+        suback.add_reason(Reason::NotAuthorized);
+        let mut dest = BytesMut::new();
+        assert!(suback.encode(&mut dest).is_ok());
+        assert_eq!(suback.size() as usize + 2, dest.len());
+        dest.advance(2);
+        let mut decoded = SubAck::default();
+        assert!(decoded.decode(&mut dest).is_ok());
+        assert_eq!(suback, decoded);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn test_basic_decode() {
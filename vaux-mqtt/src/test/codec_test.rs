@@ -142,3 +142,347 @@ fn test_decode_var_int() {
     let val = get_var_u32(&mut encoded).unwrap();
     assert_eq!(777, val);
 }
+
+#[cfg(test)]
+fn round_trip_v3(packet: Packet) -> (Packet, BytesMut) {
+    let mut dest = BytesMut::new();
+    encode_with_version(packet, &mut dest, ProtocolVersion::V3_1_1).expect("encode failed");
+    let encoded = dest.clone();
+    let (decoded, len) = decode_with_version(&mut dest, ProtocolVersion::V3_1_1)
+        .expect("decode failed")
+        .expect("expected packet");
+    assert_eq!(encoded.len() as u32, len);
+    (decoded, encoded)
+}
+
+#[test]
+fn test_v3_connect() {
+    use crate::{property::Property, Connect, WillMessage};
+
+    let mut connect = Connect::default();
+    connect.protocol_version = ProtocolVersion::V3_1_1;
+    connect.client_id = "client-1".to_string();
+    connect.will_message = Some(WillMessage::new(QoSLevel::AtLeastOnce, false));
+    connect
+        .properties_mut()
+        .set_property(Property::SessionExpiryInterval(60));
+    // will message properties are not sent for MQTT 3.1.1
+    connect.will_message.as_mut().unwrap().topic = "client/status".to_string();
+    let (decoded, encoded) = round_trip_v3(Packet::Connect(Box::new(connect)));
+    assert_eq!(ProtocolVersion::V3_1_1 as u8, encoded[8]);
+    // header, 10 byte variable header, client id and will topic and payload
+    assert_eq!(2 + 10 + 10 + 15 + 2, encoded.len());
+    match decoded {
+        Packet::Connect(connect) => {
+            assert_eq!(ProtocolVersion::V3_1_1, connect.protocol_version);
+            assert!(connect.properties().is_empty());
+            assert_eq!("client-1", connect.client_id);
+            assert_eq!(
+                "client/status",
+                connect.will_message.as_ref().unwrap().topic
+            );
+        }
+        p => panic!("expected CONNECT, found {:?}", p),
+    }
+}
+
+#[test]
+fn test_v3_connack() {
+    use crate::{property::Property, ConnAck};
+
+    let mut connack = ConnAck::default();
+    connack
+        .properties_mut()
+        .set_property(Property::TopicAliasMax(10));
+    let (decoded, encoded) = round_trip_v3(Packet::ConnAck(connack));
+    assert_eq!(&[0x20, 0x02, 0x00, 0x00], &encoded[..]);
+    assert_eq!(Packet::ConnAck(ConnAck::default()), decoded);
+    let mut src = BytesMut::from(&[0x20, 0x02, 0x00, 0x05][..]);
+    match decode_with_version(&mut src, ProtocolVersion::V3_1_1) {
+        Ok(Some((Packet::ConnAck(connack), _))) => {
+            assert_eq!(Reason::NotAuthorized, connack.reason())
+        }
+        p => panic!("expected CONNACK, found {:?}", p),
+    }
+    assert_eq!(0x03, Reason::ServerBusy.to_v3_connack_code());
+}
+
+#[test]
+fn test_v3_publish() {
+    use crate::{property::Property, publish::Publish};
+
+    let mut publish = Publish::default();
+    publish.set_qos(QoSLevel::AtLeastOnce);
+    publish.set_packet_id(42).unwrap();
+    publish.topic_name = Some("sensor/temp".to_string());
+    publish.set_payload(b"21".to_vec());
+    publish
+        .properties_mut()
+        .set_property(Property::MessageExpiry(30));
+    let (decoded, encoded) = round_trip_v3(Packet::Publish(publish));
+    // header, topic, packet id and payload without a property length
+    assert_eq!(2 + 13 + 2 + 2, encoded.len());
+    match decoded {
+        Packet::Publish(publish) => {
+            assert_eq!(Some(42), publish.packet_id);
+            assert_eq!(Some("sensor/temp"), publish.topic_name.as_deref());
+            assert_eq!(Some(&b"21"[..]), publish.payload());
+            assert!(publish.properties().is_empty());
+        }
+        p => panic!("expected PUBLISH, found {:?}", p),
+    }
+    let mut aliased = Publish::default();
+    aliased
+        .properties_mut()
+        .set_property(Property::TopicAlias(1));
+    assert!(encode_with_version(
+        Packet::Publish(aliased),
+        &mut BytesMut::new(),
+        ProtocolVersion::V3_1_1
+    )
+    .is_err());
+}
+
+#[test]
+fn test_v3_pubresp_disconnect() {
+    use crate::{Disconnect, PubResp};
+
+    let mut puback = PubResp::new_puback();
+    puback.packet_id = 7;
+    puback.set_reason(Reason::NoSubscribers).unwrap();
+    let (decoded, encoded) = round_trip_v3(Packet::PubAck(puback));
+    assert_eq!(&[0x40, 0x02, 0x00, 0x07], &encoded[..]);
+    match decoded {
+        Packet::PubAck(puback) => {
+            assert_eq!(7, puback.packet_id);
+            assert_eq!(Reason::Success, puback.reason());
+        }
+        p => panic!("expected PUBACK, found {:?}", p),
+    }
+    let (_, encoded) = round_trip_v3(Packet::Disconnect(Disconnect::new(Reason::ProtocolErr)));
+    assert_eq!(&[0xe0, 0x00], &encoded[..]);
+}
+
+#[test]
+fn test_v3_subscribe_suback() {
+    use crate::{SubAck, Subscribe, Subscription};
+
+    let mut subscription = Subscription::new("sensor/#".to_string(), QoSLevel::ExactlyOnce);
+    subscription.no_local = true;
+    let subscribe = Subscribe::new(3, vec![subscription]);
+    let (decoded, encoded) = round_trip_v3(Packet::Subscribe(subscribe));
+    // no_local is not part of MQTT 3.1.1 and is not encoded
+    assert_eq!(0x02, encoded[encoded.len() - 1]);
+    match decoded {
+        Packet::Subscribe(subscribe) => {
+            assert_eq!(3, subscribe.packet_id());
+            assert_eq!(
+                &[Subscription::new(
+                    "sensor/#".to_string(),
+                    QoSLevel::ExactlyOnce
+                )],
+                subscribe.subscriptions()
+            );
+        }
+        p => panic!("expected SUBSCRIBE, found {:?}", p),
+    }
+    let mut suback = SubAck::new(3);
+    suback.add_reason(Reason::GrantedQoS1);
+    suback.add_reason(Reason::NotAuthorized);
+    let (decoded, encoded) = round_trip_v3(Packet::SubAck(suback));
+    assert_eq!(&[0x90, 0x04, 0x00, 0x03, 0x01, 0x80], &encoded[..]);
+    match decoded {
+        Packet::SubAck(suback) => {
+            assert_eq!(
+                &[Reason::GrantedQoS1, Reason::UnspecifiedErr],
+                suback.reasons()
+            )
+        }
+        p => panic!("expected SUBACK, found {:?}", p),
+    }
+}
+
+#[test]
+fn test_unsupported_protocol_version() {
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x03, 0x02]);
+    src.put_slice(&[0x00, 0x00, 0x00, 0x00]);
+    assert!(decode(&mut src).is_err());
+}
+
+#[test]
+fn test_property_not_permitted() {
+    // PUBACK with a topic alias
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x40, 0x07, 0x00, 0x01, 0x00, 0x03, 0x23, 0x00, 0x01]);
+    match decode(&mut src) {
+        Err(e) => {
+            assert_eq!(
+                ErrorKind::PropertyNotPermitted(crate::PropertyType::TopicAlias),
+                e.kind()
+            );
+            assert_eq!(Reason::MalformedPacket, e.reason());
+        }
+        Ok(p) => panic!("expected property to be rejected, decoded {:?}", p),
+    }
+}
+
+#[test]
+fn test_supported_properties() {
+    use crate::property::{PacketProperties, Property, PropertySize};
+    use crate::{publish::Publish, PropertyType, PubResp, Subscribe, WillMessage};
+
+    let supported = Publish::supported_properties();
+    assert_eq!(8, supported.len());
+    assert!(supported.contains(&PropertyType::TopicAlias));
+    assert!(!PubResp::supported_properties().contains(&PropertyType::TopicAlias));
+    assert!(WillMessage::supported_properties().contains(&PropertyType::WillDelay));
+    assert!(Subscribe::supported_properties().contains(&PropertyType::SubscriptionIdentifier));
+
+    let mut publish = Publish::default();
+    assert!(publish
+        .properties()
+        .supports_property(&PropertyType::TopicAlias));
+    publish
+        .properties_mut()
+        .set_property(Property::TopicAlias(12));
+    // property identifier and 2 byte integer
+    assert_eq!(3, publish.property_size_internal());
+}
+
+#[test]
+fn test_utf8_surrogate() {
+    // PUBLISH with an encoded surrogate in the topic name
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x30, 0x06, 0x00, 0x03, 0xed, 0xa0, 0x80, 0x00]);
+    match decode(&mut src) {
+        Err(e) => {
+            assert_eq!(ErrorKind::InvalidUtf8, e.kind());
+            assert_eq!(Reason::MalformedPacket, e.reason());
+            assert_eq!(Some(PacketType::Publish), e.packet_type());
+            // the topic name ends at offset 7
+            assert_eq!(Some(7), e.offset());
+            assert!(e.to_string().ends_with("in PUBLISH at offset 7"));
+        }
+        Ok(p) => panic!("expected surrogate to be rejected, decoded {:?}", p),
+    }
+}
+
+#[test]
+fn test_unsupported_protocol_version_reason() {
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x06, 0x02]);
+    src.put_slice(&[0x00, 0x00, 0x00, 0x00]);
+    match decode(&mut src) {
+        Err(e) => {
+            assert_eq!(Reason::UnsupportedProtocolVersion, e.reason());
+            assert_eq!(Some(PacketType::Connect), e.packet_type());
+        }
+        Ok(p) => panic!("expected protocol version to be rejected, decoded {:?}", p),
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_io_error_source() {
+    use std::error::Error;
+
+    let e = MqttCodecError::from(std::io::Error::new(
+        std::io::ErrorKind::ConnectionReset,
+        "reset",
+    ));
+    assert_eq!(ErrorKind::Io(std::io::ErrorKind::ConnectionReset), e.kind());
+    assert_eq!(Reason::UnspecifiedErr, e.reason());
+    assert_eq!("reset", e.source().unwrap().to_string());
+}
+
+#[test]
+fn test_encode_error_packet_type() {
+    let mut dest = BytesMut::new();
+    match encode(Packet::Subscribe(crate::Subscribe::default()), &mut dest) {
+        Err(e) => {
+            assert_eq!(Some(PacketType::Subscribe), e.packet_type());
+            assert_eq!(Some(0), e.offset());
+        }
+        Ok(_) => panic!("expected SUBSCRIBE without packet identifier to be rejected"),
+    }
+}
+
+#[cfg(feature = "pedantic")]
+#[test]
+fn test_pedantic_reserved_flags() {
+    let subscribe = [0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x00];
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x82, 0x07]);
+    src.put_slice(&subscribe);
+    assert!(decode(&mut src).is_ok());
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x80, 0x07]);
+    src.put_slice(&subscribe);
+    match decode(&mut src) {
+        Err(e) => assert_eq!(ErrorKind::InvalidFlags(PacketType::Subscribe, 0), e.kind()),
+        Ok(p) => panic!("expected flags to be rejected, decoded {:?}", p),
+    }
+}
+
+#[cfg(feature = "pedantic")]
+#[test]
+fn test_pedantic_duplicate_property() {
+    // PUBACK with the reason string repeated
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x40, 0x0c, 0x00, 0x01, 0x00, 0x08]);
+    src.put_slice(&[0x1f, 0x00, 0x01, b'a', 0x1f, 0x00, 0x01, b'b']);
+    match decode(&mut src) {
+        Err(e) => {
+            assert_eq!(
+                ErrorKind::DuplicateProperty(crate::PropertyType::ReasonString),
+                e.kind()
+            );
+            assert_eq!(Reason::ProtocolErr, e.reason());
+        }
+        Ok(p) => panic!(
+            "expected duplicate property to be rejected, decoded {:?}",
+            p
+        ),
+    }
+}
+
+#[cfg(feature = "pedantic")]
+#[test]
+fn test_pedantic_strings() {
+    let mut src = BytesMut::new();
+    src.put_slice(&[0x30, 0x05, 0x00, 0x02, b'a', 0x00, 0x00]);
+    match decode(&mut src) {
+        Err(e) => assert_eq!(ErrorKind::InvalidUtf8, e.kind()),
+        Ok(p) => panic!("expected null character to be rejected, decoded {:?}", p),
+    }
+    let mut publish = crate::publish::Publish::default();
+    publish.topic_name = Some("sensor/+".to_string());
+    match encode(Packet::Publish(publish), &mut BytesMut::new()) {
+        Err(e) => assert_eq!(Reason::InvalidTopicName, e.reason()),
+        Ok(_) => panic!("expected wildcard topic name to be rejected"),
+    }
+}
+
+#[test]
+fn test_v3_unsubscribe_unsuback() {
+    use crate::{property::PacketProperties, UnsubAck, Unsubscribe};
+
+    let mut unsubscribe = Unsubscribe::new(5, vec!["sensor/#".to_string()]);
+    unsubscribe
+        .properties_mut()
+        .add_user_property("key".to_string(), "value".to_string());
+    let (decoded, encoded) = round_trip_v3(Packet::Unsubscribe(unsubscribe));
+    // header, packet id and topic filter without a property length
+    assert_eq!(2 + 2 + 10, encoded.len());
+    assert_eq!(
+        Packet::Unsubscribe(Unsubscribe::new(5, vec!["sensor/#".to_string()])),
+        decoded
+    );
+    let mut unsuback = UnsubAck::new(5);
+    unsuback.add_reason(Reason::NoSubscriptionExisted);
+    let (decoded, encoded) = round_trip_v3(Packet::UnsubAck(unsuback));
+    // MQTT 3.1.1 UNSUBACK has no reason codes
+    assert_eq!(&[0xb0, 0x02, 0x00, 0x05], &encoded[..]);
+    assert_eq!(Packet::UnsubAck(UnsubAck::new(5)), decoded);
+}
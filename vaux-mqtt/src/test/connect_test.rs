@@ -43,6 +43,26 @@ fn test_encode_will() {
     assert_eq!(QoSLevel::AtLeastOnce, qos);
 }
 
+#[test]
+fn test_decode_will_qos() {
+    let mut connect = Connect::default();
+    connect.client_id = "test".to_string();
+    let mut will = WillMessage::new(QoSLevel::ExactlyOnce, false);
+    will.topic = "status".to_string();
+    connect.will_message = Some(will);
+    let mut dest = BytesMut::new();
+    assert!(connect.encode(&mut dest).is_ok());
+    match crate::decode(&mut dest) {
+        Ok(Some((crate::Packet::Connect(decoded), _))) => {
+            assert_eq!(
+                QoSLevel::ExactlyOnce,
+                decoded.will_message.as_ref().unwrap().qos
+            );
+        }
+        p => panic!("expected CONNECT, found {:?}", p),
+    }
+}
+
 #[test]
 fn test_encode_keep_alive() {
     let mut connect = Connect::default();
@@ -128,7 +148,7 @@ fn test_encode_auth_data() {
     let mut connect = Connect::default();
     connect
         .properties_mut()
-        .set_property(Property::AuthData(vec![1, 2, 3, 4, 5]));
+        .set_property(Property::AuthData(vec![1, 2, 3, 4, 5].into()));
     let mut dest = BytesMut::new();
     test_property(connect, &mut dest, 8, PropertyType::AuthData);
 }
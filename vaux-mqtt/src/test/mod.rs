@@ -1,3 +1,9 @@
 #[cfg(test)]
 mod codec_test;
 mod connect_test;
+#[cfg(test)]
+mod packet_ref_test;
+#[cfg(test)]
+mod proptest_test;
+#[cfg(all(test, feature = "serde"))]
+mod serde_test;
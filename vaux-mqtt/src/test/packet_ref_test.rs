@@ -0,0 +1,276 @@
+use bytes::BytesMut;
+
+use crate::codec::ErrorKind;
+use crate::packet_ref::*;
+use crate::property::{PacketProperties, Property, PropertyRef};
+use crate::publish::Publish;
+use crate::{
+    encode, encode_slice, encode_slice_with_version, encoded_len, ConnAck, Connect, Disconnect,
+    Encode, Packet, ProtocolVersion, PubResp, QoSLevel, Reason, Subscribe, Subscription, UnsubAck,
+    Unsubscribe, WillMessage,
+};
+
+fn encode_owned(packet: Packet) -> BytesMut {
+    let mut dest = BytesMut::new();
+    encode(packet, &mut dest).unwrap();
+    dest
+}
+
+fn encode_ref(packet: PacketRef) -> Vec<u8> {
+    let mut dest = [0_u8; 512];
+    let len = packet.encode_slice(&mut dest).unwrap();
+    assert_eq!(packet.encoded_len(), len);
+    dest[..len].to_vec()
+}
+
+fn owned_packets() -> Vec<Packet> {
+    let mut connack = ConnAck::default();
+    // properties longer than 127 bytes need a 2 byte property length
+    connack
+        .properties_mut()
+        .set_property(Property::ReasonString("r".repeat(200)));
+    let mut publish = Publish::default();
+    publish.topic_name = Some("sensor/temp".to_string());
+    publish.set_qos(QoSLevel::AtLeastOnce);
+    publish.packet_id = Some(7);
+    publish.set_payload(vec![1, 2, 3]);
+    publish
+        .properties_mut()
+        .add_user_property("unit".to_string(), "C".to_string());
+    publish
+        .properties_mut()
+        .add_user_property("unit".to_string(), "F".to_string());
+    let mut disconnect = Disconnect::new(Reason::ServerShutdown);
+    disconnect
+        .properties_mut()
+        .set_property(Property::ReasonString("shutdown".to_string()));
+    let mut connect = Connect::default();
+    connect.client_id = "client".to_string();
+    let mut will = WillMessage::new(QoSLevel::AtLeastOnce, true);
+    will.topic = "status".to_string();
+    connect.will_message = Some(will);
+    let mut puback = PubResp::new_puback();
+    puback.packet_id = 1;
+    let mut unsuback = UnsubAck::new(2);
+    unsuback.add_reason(Reason::NoSubscriptionExisted);
+    vec![
+        Packet::Connect(Box::new(connect)),
+        Packet::ConnAck(connack),
+        Packet::Publish(publish),
+        Packet::PubAck(puback),
+        Packet::Disconnect(disconnect),
+        Packet::Subscribe(Subscribe::new(
+            1,
+            vec![Subscription::new(
+                "sensor/#".to_string(),
+                QoSLevel::AtMostOnce,
+            )],
+        )),
+        Packet::Unsubscribe(Unsubscribe::new(2, vec!["sensor/#".to_string()])),
+        Packet::UnsubAck(unsuback),
+    ]
+}
+
+#[test]
+fn test_encode_slice() {
+    for packet in owned_packets() {
+        let expected = encode_owned(packet.clone());
+        for version in [ProtocolVersion::V5, ProtocolVersion::V3_1_1] {
+            let mut dest = BytesMut::new();
+            crate::encode_with_version(packet.clone(), &mut dest, version).unwrap();
+            assert_eq!(dest.len(), encoded_len(&packet, version), "{:?}", packet);
+        }
+        let mut dest = [0_u8; 512];
+        let len = encode_slice(packet, &mut dest).unwrap();
+        assert_eq!(&expected[..], &dest[..len]);
+    }
+}
+
+#[test]
+fn test_encode_slice_too_small() {
+    let mut puback = PubResp::new_puback();
+    puback.packet_id = 1;
+    let packet = Packet::PubAck(puback);
+    let mut dest = [0xff_u8; 3];
+    match encode_slice_with_version(packet, &mut dest, ProtocolVersion::V5) {
+        Err(e) => {
+            assert_eq!(ErrorKind::BufferTooSmall(4, 3), e.kind());
+            assert_eq!("buffer too small, need 4 bytes", e.message());
+        }
+        Ok(len) => panic!("expected buffer too small, wrote {} bytes", len),
+    }
+    assert_eq!([0xff_u8; 3], dest);
+    let mut dest = [0_u8; 4];
+    let publish = PacketRef::Publish(PublishRef::new("sensor/temp", b"21.5"));
+    match publish.encode_slice(&mut dest) {
+        Err(e) => assert_eq!(
+            ErrorKind::BufferTooSmall(publish.encoded_len(), 4),
+            e.kind()
+        ),
+        Ok(len) => panic!("expected buffer too small, wrote {} bytes", len),
+    }
+}
+
+#[test]
+fn test_packet_ref_matches_owned() {
+    for (owned, borrowed) in owned_packets().into_iter().zip([
+        PacketRef::Connect(ConnectRef {
+            will_message: Some(WillMessageRef {
+                qos: QoSLevel::AtLeastOnce,
+                retain: true,
+                ..WillMessageRef::new("status", &[])
+            }),
+            ..ConnectRef::new("client")
+        }),
+        PacketRef::ConnAck(
+            ConnAckRef::new(Reason::Success)
+                .with_properties(&[PropertyRef::ReasonString(&"r".repeat(200))]),
+        ),
+        PacketRef::Publish(
+            PublishRef {
+                qos: QoSLevel::AtLeastOnce,
+                packet_id: Some(7),
+                ..PublishRef::new("sensor/temp", &[1, 2, 3])
+            }
+            .with_properties(&[
+                PropertyRef::UserProperty("unit", "C"),
+                PropertyRef::UserProperty("unit", "F"),
+            ]),
+        ),
+        PacketRef::PubAck(PubRespRef::new_puback(1)),
+        PacketRef::Disconnect(
+            DisconnectRef::new(Reason::ServerShutdown)
+                .with_properties(&[PropertyRef::ReasonString("shutdown")]),
+        ),
+        PacketRef::Subscribe(SubscribeRef::new(
+            1,
+            &[SubscriptionRef::new("sensor/#", QoSLevel::AtMostOnce)],
+        )),
+        PacketRef::Unsubscribe(UnsubscribeRef::new(2, &["sensor/#"])),
+        PacketRef::UnsubAck(UnsubAckRef::new(2, &[Reason::NoSubscriptionExisted])),
+    ]) {
+        assert_eq!(&encode_owned(owned)[..], &encode_ref(borrowed)[..]);
+    }
+}
+
+#[test]
+fn test_packet_ref_property_not_permitted() {
+    let puback = PubRespRef::new_puback(1).with_properties(&[PropertyRef::TopicAlias(1)]);
+    let mut dest = BytesMut::new();
+    match PacketRef::PubAck(puback).encode(&mut dest) {
+        Err(e) => assert_eq!(
+            ErrorKind::PropertyNotPermitted(crate::PropertyType::TopicAlias),
+            e.kind()
+        ),
+        Ok(_) => panic!("expected topic alias to be rejected in PUBACK"),
+    }
+}
+
+#[test]
+fn test_packet_ref_decode() {
+    for packet in owned_packets() {
+        let encoded = encode_owned(packet);
+        let (decoded, len) = PacketRef::decode(&encoded).unwrap().unwrap();
+        assert_eq!(encoded.len(), len);
+        assert_eq!(&encoded[..], &encode_ref(decoded)[..]);
+    }
+}
+
+#[test]
+fn test_packet_ref_decode_publish() {
+    let mut encoded = encode_owned(owned_packets().remove(2));
+    // a second packet following the first is not consumed
+    encoded.extend_from_slice(&[0xc0, 0x00]);
+    let (decoded, len) = PacketRef::decode(&encoded).unwrap().unwrap();
+    assert_eq!(encoded.len() - 2, len);
+    match decoded {
+        PacketRef::Publish(publish) => {
+            assert_eq!("sensor/temp", publish.topic_name);
+            assert_eq!(QoSLevel::AtLeastOnce, publish.qos);
+            assert_eq!(Some(7), publish.packet_id);
+            assert_eq!(&[1, 2, 3], publish.payload);
+            assert_eq!(
+                vec![
+                    PropertyRef::UserProperty("unit", "C"),
+                    PropertyRef::UserProperty("unit", "F"),
+                ],
+                publish.properties.iter().collect::<Vec<_>>()
+            );
+        }
+        other => panic!("expected publish, decoded {:?}", other),
+    }
+    let (decoded, _) = PacketRef::decode(&encoded[len..]).unwrap().unwrap();
+    assert_eq!(PacketRef::PingRequest, decoded);
+}
+
+#[test]
+fn test_publish_from_ref() {
+    let mut publish = match owned_packets().remove(2) {
+        Packet::Publish(publish) => publish,
+        other => panic!("expected publish, found {:?}", other),
+    };
+    publish.header.set_dup(true);
+    publish.header.set_retain(true);
+    publish
+        .properties_mut()
+        .set_property(Property::CorrelationData(vec![4, 5].into()));
+    let encoded = encode_owned(Packet::Publish(publish.clone()));
+    match PacketRef::decode(&encoded).unwrap().unwrap().0 {
+        PacketRef::Publish(borrowed) => assert_eq!(publish, Publish::from(&borrowed)),
+        other => panic!("expected publish, decoded {:?}", other),
+    }
+    // a topic alias without a topic name converts to no topic name
+    let alias = [PropertyRef::TopicAlias(3)];
+    let borrowed = PublishRef::new("", b"").with_properties(&alias);
+    let publish = Publish::from(&borrowed);
+    assert_eq!(None, publish.topic_name);
+    assert_eq!(
+        Some(&Property::TopicAlias(3)),
+        publish
+            .properties()
+            .get_property(&crate::PropertyType::TopicAlias)
+    );
+}
+
+#[test]
+fn test_packet_ref_decode_incomplete() {
+    let encoded = encode_owned(owned_packets().remove(0));
+    for len in 0..encoded.len() {
+        assert!(PacketRef::decode(&encoded[..len]).unwrap().is_none());
+    }
+}
+
+#[test]
+fn test_packet_ref_decode_malformed() {
+    // SUBACK with a property length past the end of the packet
+    let encoded = [0x90, 0x04, 0x00, 0x01, 0x05, 0x00];
+    match PacketRef::decode(&encoded) {
+        Err(e) => {
+            assert_eq!(ErrorKind::InsufficientData(5, 1), e.kind());
+            assert_eq!(Some(crate::PacketType::SubAck), e.packet_type());
+            assert_eq!(Some(5), e.offset());
+        }
+        Ok(packet) => panic!("expected malformed packet, decoded {:?}", packet),
+    }
+}
+
+#[cfg(feature = "pedantic")]
+#[test]
+fn test_packet_ref_decode_validates_as_owned() {
+    let response = [PropertyRef::ResponseTopic("reply/+")];
+    let sub_id = [PropertyRef::SubscriptionIdentifier(0)];
+    let packets = [
+        PacketRef::Publish(PublishRef::new("sensor/temp", b"").with_properties(&response)),
+        PacketRef::Publish(PublishRef::new("sensor/temp", b"").with_properties(&sub_id)),
+    ];
+    for packet in packets {
+        let mut encoded = [0_u8; 64];
+        let len = packet.encode_slice(&mut encoded).unwrap();
+        let owned = crate::decode(&mut BytesMut::from(&encoded[..len]));
+        let borrowed = PacketRef::decode(&encoded[..len]);
+        match (owned, borrowed) {
+            (Err(owned), Err(borrowed)) => assert_eq!(owned.kind(), borrowed.kind()),
+            other => panic!("expected both decoders to reject {:?}: {:?}", packet, other),
+        }
+    }
+}
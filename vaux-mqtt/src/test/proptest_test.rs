@@ -0,0 +1,457 @@
+use bytes::{Bytes, BytesMut};
+use proptest::{collection::vec, option, prelude::*, sample::select};
+
+use crate::packet_ref::PacketRef;
+use crate::property::{PacketProperties, PayloadFormat, Property, PropertyBundle};
+use crate::publish::Publish;
+use crate::subscribe::RetainHandling;
+use crate::{
+    codec::variable_byte_int_size, decode, decode_fixed_header, decode_with_version, encode,
+    encoded_len, ConnAck, Connect, Disconnect, FixedHeader, Packet, PacketType, PropertyType,
+    ProtocolVersion, PubResp, QoSLevel, Reason, Size, SubAck, Subscribe, Subscription, UnsubAck,
+    Unsubscribe, WillMessage,
+};
+
+fn utf8() -> impl Strategy<Value = String> {
+    "[^\u{0}]{0,12}"
+}
+
+fn topic_name() -> impl Strategy<Value = String> {
+    "[a-z0-9$ ]{1,6}(/[a-z0-9 ]{0,6}){0,3}"
+}
+
+fn topic_filter() -> impl Strategy<Value = String> {
+    prop_oneof![
+        topic_name(),
+        topic_name().prop_map(|name| format!("{}/#", name)),
+        topic_name().prop_map(|name| format!("+/{}/+", name)),
+        Just("#".to_string()),
+    ]
+}
+
+fn bin() -> impl Strategy<Value = Bytes> {
+    vec(any::<u8>(), 0..16).prop_map(Bytes::from)
+}
+
+fn qos() -> impl Strategy<Value = QoSLevel> {
+    select(vec![
+        QoSLevel::AtMostOnce,
+        QoSLevel::AtLeastOnce,
+        QoSLevel::ExactlyOnce,
+    ])
+}
+
+fn reason(reasons: &[Reason]) -> impl Strategy<Value = Reason> {
+    select(reasons.to_vec())
+}
+
+/// Generates a property of the type with a value that is valid in any packet
+/// permitting the property.
+fn property(prop_type: PropertyType) -> BoxedStrategy<Property> {
+    match prop_type {
+        PropertyType::PayloadFormat => select(vec![PayloadFormat::Bin, PayloadFormat::Utf8])
+            .prop_map(Property::PayloadFormat)
+            .boxed(),
+        PropertyType::MessageExpiry => any::<u32>().prop_map(Property::MessageExpiry).boxed(),
+        PropertyType::ContentType => utf8().prop_map(Property::ContentType).boxed(),
+        PropertyType::ResponseTopic => topic_name().prop_map(Property::ResponseTopic).boxed(),
+        PropertyType::CorrelationData => bin().prop_map(Property::CorrelationData).boxed(),
+        PropertyType::SubscriptionIdentifier => (1_u32..=268_435_455)
+            .prop_map(Property::SubscriptionIdentifier)
+            .boxed(),
+        PropertyType::SessionExpiryInterval => any::<u32>()
+            .prop_map(Property::SessionExpiryInterval)
+            .boxed(),
+        PropertyType::AssignedClientId => utf8().prop_map(Property::AssignedClientId).boxed(),
+        PropertyType::KeepAlive => any::<u16>().prop_map(Property::KeepAlive).boxed(),
+        PropertyType::AuthMethod => utf8().prop_map(Property::AuthMethod).boxed(),
+        PropertyType::AuthData => bin().prop_map(Property::AuthData).boxed(),
+        PropertyType::ReqProblemInfo => any::<bool>().prop_map(Property::ReqProblemInfo).boxed(),
+        PropertyType::WillDelay => any::<u32>().prop_map(Property::WillDelay).boxed(),
+        PropertyType::ReqRespInfo => any::<bool>().prop_map(Property::ReqRespInfo).boxed(),
+        PropertyType::RespInfo => utf8().prop_map(Property::RespInfo).boxed(),
+        PropertyType::ServerReference => utf8().prop_map(Property::ServerReference).boxed(),
+        PropertyType::ReasonString => utf8().prop_map(Property::ReasonString).boxed(),
+        PropertyType::RecvMax => (1_u16..).prop_map(Property::RecvMax).boxed(),
+        PropertyType::TopicAliasMax => any::<u16>().prop_map(Property::TopicAliasMax).boxed(),
+        PropertyType::TopicAlias => (1_u16..).prop_map(Property::TopicAlias).boxed(),
+        PropertyType::MaxQoS => select(vec![QoSLevel::AtMostOnce, QoSLevel::AtLeastOnce])
+            .prop_map(Property::MaxQoS)
+            .boxed(),
+        PropertyType::RetainAvail => any::<bool>().prop_map(Property::RetainAvail).boxed(),
+        PropertyType::UserProperty => (utf8(), utf8())
+            .prop_map(|(key, value)| Property::UserProperty(key, value))
+            .boxed(),
+        PropertyType::MaxPacketSize => (1_u32..).prop_map(Property::MaxPacketSize).boxed(),
+        PropertyType::WildcardSubAvail => {
+            any::<bool>().prop_map(Property::WildcardSubAvail).boxed()
+        }
+        PropertyType::SubIdAvail => any::<bool>().prop_map(Property::SubIdAvail).boxed(),
+        PropertyType::ShardSubAvail => any::<bool>().prop_map(Property::ShardSubAvail).boxed(),
+    }
+}
+
+/// Generates the properties of a packet in a random order. Each permitted
+/// property is included at most once, other than user properties which may be
+/// repeated.
+fn properties<T: PacketProperties>() -> impl Strategy<Value = Vec<Property>> {
+    let single = T::supported_properties()
+        .into_iter()
+        .filter(|prop_type| *prop_type != PropertyType::UserProperty)
+        .map(|prop_type| option::of(property(prop_type)))
+        .collect::<Vec<_>>();
+    (single, vec(property(PropertyType::UserProperty), 0..3))
+        .prop_map(|(single, user)| single.into_iter().flatten().chain(user).collect::<Vec<_>>())
+        .prop_shuffle()
+}
+
+fn set_properties(bundle: &mut PropertyBundle, props: Vec<Property>) {
+    for prop in props {
+        bundle.set_property(prop);
+    }
+}
+
+fn will() -> impl Strategy<Value = WillMessage> {
+    (
+        qos(),
+        any::<bool>(),
+        topic_name(),
+        bin(),
+        properties::<WillMessage>(),
+    )
+        .prop_map(|(qos, retain, topic, payload, props)| {
+            let mut will = WillMessage::new(qos, retain);
+            will.topic = topic;
+            will.payload = payload;
+            set_properties(&mut will.props, props);
+            will
+        })
+}
+
+fn connect() -> impl Strategy<Value = Packet> {
+    (
+        any::<bool>(),
+        any::<u16>(),
+        utf8(),
+        option::of(will()),
+        option::of(utf8()),
+        option::of(vec(any::<u8>(), 0..8)),
+        properties::<Connect>(),
+    )
+        .prop_map(
+            |(clean_start, keep_alive, client_id, will_message, username, password, props)| {
+                let mut connect = Connect::default();
+                connect.clean_start = clean_start;
+                connect.keep_alive = keep_alive;
+                connect.client_id = client_id;
+                connect.will_message = will_message;
+                connect.username = username;
+                connect.password = password;
+                set_properties(connect.properties_mut(), props);
+                Packet::Connect(Box::new(connect))
+            },
+        )
+}
+
+fn connack() -> impl Strategy<Value = Packet> {
+    (
+        any::<bool>(),
+        reason(&[
+            Reason::Success,
+            Reason::UnspecifiedErr,
+            Reason::InvalidClientId,
+            Reason::NotAuthorized,
+            Reason::ServerMoved,
+        ]),
+        properties::<ConnAck>(),
+    )
+        .prop_map(|(session_present, reason, props)| {
+            let mut connack = ConnAck::default();
+            connack.session_present = session_present;
+            connack.set_reason(reason);
+            set_properties(connack.properties_mut(), props);
+            Packet::ConnAck(connack)
+        })
+}
+
+fn publish() -> impl Strategy<Value = Packet> {
+    (
+        qos(),
+        any::<bool>(),
+        topic_name(),
+        1_u16..,
+        option::of(vec(any::<u8>(), 1..32)),
+        properties::<Publish>(),
+        vec(1_u32..=268_435_455, 0..3),
+    )
+        .prop_map(
+            |(qos, retain, topic_name, packet_id, payload, props, sub_ids)| {
+                let mut publish = Publish::default();
+                publish.set_qos(qos);
+                publish.header.set_retain(retain);
+                publish.topic_name = Some(topic_name);
+                if qos != QoSLevel::AtMostOnce {
+                    publish.packet_id = Some(packet_id);
+                }
+                if let Some(payload) = payload {
+                    publish.set_payload(payload);
+                }
+                set_properties(publish.properties_mut(), props);
+                // subscription identifiers may be repeated on PUBLISH
+                for id in sub_ids {
+                    publish.properties_mut().add_subscription_id(id);
+                }
+                Packet::Publish(publish)
+            },
+        )
+}
+
+fn pubresp() -> impl Strategy<Value = Packet> {
+    (0..4, 1_u16.., any::<bool>(), properties::<PubResp>()).prop_map(
+        |(resp_type, packet_id, success, props)| {
+            let (mut resp, reason) = match resp_type {
+                0 => (PubResp::new_puback(), Reason::NoSubscribers),
+                1 => (PubResp::new_pubrec(), Reason::QuotaExceeded),
+                2 => (PubResp::new_pubrel(), Reason::PacketIdNotFound),
+                _ => (PubResp::new_pubcomp(), Reason::PacketIdNotFound),
+            };
+            resp.packet_id = packet_id;
+            if !success {
+                resp.set_reason(reason).unwrap();
+            }
+            set_properties(resp.properties_mut(), props);
+            match resp_type {
+                0 => Packet::PubAck(resp),
+                1 => Packet::PubRec(resp),
+                2 => Packet::PubRel(resp),
+                _ => Packet::PubComp(resp),
+            }
+        },
+    )
+}
+
+fn subscription() -> impl Strategy<Value = Subscription> {
+    (
+        topic_filter(),
+        qos(),
+        any::<bool>(),
+        any::<bool>(),
+        select(vec![
+            RetainHandling::Send,
+            RetainHandling::SendNew,
+            RetainHandling::None,
+        ]),
+    )
+        .prop_map(
+            |(filter, qos, no_local, retain_as, handling)| Subscription {
+                filter,
+                qos,
+                no_local,
+                retain_as,
+                handling,
+            },
+        )
+}
+
+fn subscribe() -> impl Strategy<Value = Packet> {
+    (
+        1_u16..,
+        vec(subscription(), 1..4),
+        properties::<Subscribe>(),
+    )
+        .prop_map(|(packet_id, subscriptions, props)| {
+            let mut subscribe = Subscribe::new(packet_id, subscriptions);
+            set_properties(subscribe.properties_mut(), props);
+            Packet::Subscribe(subscribe)
+        })
+}
+
+fn suback() -> impl Strategy<Value = Packet> {
+    (
+        1_u16..,
+        vec(
+            reason(&[
+                Reason::GrantedQoS0,
+                Reason::GrantedQoS1,
+                Reason::GrantedQoS2,
+                Reason::UnspecifiedErr,
+                Reason::NotAuthorized,
+            ]),
+            1..4,
+        ),
+        properties::<SubAck>(),
+    )
+        .prop_map(|(packet_id, reasons, props)| {
+            let mut suback = SubAck::new(packet_id);
+            for reason in reasons {
+                suback.add_reason(reason);
+            }
+            set_properties(suback.properties_mut(), props);
+            Packet::SubAck(suback)
+        })
+}
+
+fn unsubscribe() -> impl Strategy<Value = Packet> {
+    (
+        1_u16..,
+        vec(topic_filter(), 1..4),
+        properties::<Unsubscribe>(),
+    )
+        .prop_map(|(packet_id, filters, props)| {
+            let mut unsubscribe = Unsubscribe::new(packet_id, filters);
+            set_properties(unsubscribe.properties_mut(), props);
+            Packet::Unsubscribe(unsubscribe)
+        })
+}
+
+fn unsuback() -> impl Strategy<Value = Packet> {
+    (
+        1_u16..,
+        vec(
+            reason(&[
+                Reason::Success,
+                Reason::NoSubscriptionExisted,
+                Reason::UnspecifiedErr,
+                Reason::NotAuthorized,
+                Reason::InvalidTopicFilter,
+            ]),
+            1..4,
+        ),
+        properties::<UnsubAck>(),
+    )
+        .prop_map(|(packet_id, reasons, props)| {
+            let mut unsuback = UnsubAck::new(packet_id);
+            for reason in reasons {
+                unsuback.add_reason(reason);
+            }
+            set_properties(unsuback.properties_mut(), props);
+            Packet::UnsubAck(unsuback)
+        })
+}
+
+fn disconnect() -> impl Strategy<Value = Packet> {
+    (
+        reason(&[
+            Reason::NormalDisconnect,
+            Reason::DisconnectWillMsg,
+            Reason::ServerShutdown,
+            Reason::KeepAliveTimeout,
+        ]),
+        properties::<Disconnect>(),
+    )
+        .prop_map(|(reason, props)| {
+            let mut disconnect = Disconnect::new(reason);
+            set_properties(disconnect.properties_mut(), props);
+            Packet::Disconnect(disconnect)
+        })
+}
+
+/// Generates any valid MQTT v5 packet.
+fn packet() -> impl Strategy<Value = Packet> {
+    prop_oneof![
+        Just(Packet::PingRequest(FixedHeader::new(PacketType::PingReq))),
+        Just(Packet::PingResponse(FixedHeader::new(PacketType::PingResp))),
+        connect(),
+        connack(),
+        publish(),
+        pubresp(),
+        subscribe(),
+        suback(),
+        unsubscribe(),
+        unsuback(),
+        disconnect(),
+    ]
+}
+
+/// Gets the remaining length of the packet from `Size::size()`.
+fn remaining(packet: &Packet) -> u32 {
+    match packet {
+        Packet::PingRequest(_) | Packet::PingResponse(_) => 0,
+        Packet::Connect(connect) => connect.size(),
+        Packet::ConnAck(connack) => connack.size(),
+        Packet::Publish(publish) => publish.size(),
+        Packet::PubAck(resp)
+        | Packet::PubRec(resp)
+        | Packet::PubRel(resp)
+        | Packet::PubComp(resp) => resp.size(),
+        Packet::Subscribe(subscribe) => subscribe.size(),
+        Packet::SubAck(suback) => suback.size(),
+        Packet::Unsubscribe(unsubscribe) => unsubscribe.size(),
+        Packet::UnsubAck(unsuback) => unsuback.size(),
+        Packet::Disconnect(disconnect) => disconnect.size(),
+    }
+}
+
+fn encode_packet(packet: &Packet) -> BytesMut {
+    let mut dest = BytesMut::new();
+    encode(packet.clone(), &mut dest).unwrap();
+    dest
+}
+
+proptest! {
+    #[test]
+    fn test_round_trip(packet in packet()) {
+        let encoded = encode_packet(&packet);
+        let mut src = encoded.clone();
+        let (mut decoded, len) = decode(&mut src).unwrap().unwrap();
+        prop_assert_eq!(encoded.len(), len as usize);
+        if let Packet::Publish(publish) = &mut decoded {
+            // the decoded header records the remaining length
+            publish.header.remaining = 0;
+        }
+        prop_assert_eq!(&packet, &decoded);
+        prop_assert_eq!(&encoded[..], &encode_packet(&decoded)[..]);
+    }
+
+    #[test]
+    fn test_size(packet in packet()) {
+        let encoded = encode_packet(&packet);
+        let remaining = remaining(&packet);
+        prop_assert_eq!(
+            encoded.len(),
+            1 + variable_byte_int_size(remaining) as usize + remaining as usize
+        );
+        prop_assert_eq!(encoded.len(), encoded_len(&packet, ProtocolVersion::V5));
+    }
+
+    #[test]
+    fn test_packet_ref_round_trip(packet in packet()) {
+        let encoded = encode_packet(&packet);
+        let (decoded, len) = PacketRef::decode(&encoded).unwrap().unwrap();
+        prop_assert_eq!(encoded.len(), len);
+        let mut dest = vec![0_u8; decoded.encoded_len()];
+        decoded.encode_slice(&mut dest).unwrap();
+        prop_assert_eq!(&encoded[..], &dest[..]);
+    }
+
+    #[test]
+    fn test_decode_arbitrary(src in vec(any::<u8>(), 0..128)) {
+        for version in [ProtocolVersion::V5, ProtocolVersion::V3_1_1] {
+            let _ = decode_with_version(&mut BytesMut::from(&src[..]), version);
+        }
+        let _ = decode_fixed_header(&mut BytesMut::from(&src[..]));
+        let _ = PacketRef::decode(&src);
+    }
+
+    /// Mutating or truncating a valid packet reaches further into the decoders
+    /// than arbitrary bytes.
+    #[test]
+    fn test_decode_mutated(
+        packet in packet(),
+        index in any::<prop::sample::Index>(),
+        byte in any::<u8>(),
+        truncate in any::<bool>(),
+    ) {
+        let mut src = encode_packet(&packet).to_vec();
+        let index = index.index(src.len());
+        if truncate {
+            src.truncate(index);
+        } else {
+            src[index] = byte;
+        }
+        for version in [ProtocolVersion::V5, ProtocolVersion::V3_1_1] {
+            let _ = decode_with_version(&mut BytesMut::from(&src[..]), version);
+        }
+        let _ = PacketRef::decode(&src);
+    }
+}
@@ -0,0 +1,85 @@
+use bytes::Bytes;
+
+use crate::{
+    property::Property, publish::Publish, ConnAck, Connect, Disconnect, Packet, PubResp, QoSLevel,
+    Reason, SubAck, Subscribe, Subscription, Unsubscribe, WillMessage,
+};
+
+fn packets() -> Vec<Packet> {
+    let mut publish = Publish::default();
+    publish.topic_name = Some("sensor/temp".to_string());
+    publish.set_qos(QoSLevel::AtLeastOnce);
+    publish.packet_id = Some(42);
+    publish.set_payload(vec![0x00, 0xff, 0x10]);
+    let props = publish.properties_mut();
+    props.set_property(Property::CorrelationData(Bytes::from_static(b"corr")));
+    props.add_user_property("unit".to_string(), "celsius".to_string());
+    props.add_subscription_id(7);
+
+    let mut will = WillMessage::new(QoSLevel::AtMostOnce, true);
+    will.topic = "status/offline".to_string();
+    will.payload = Bytes::from_static(b"offline");
+    let mut connect = Connect::default();
+    connect.client_id = "serde-client".to_string();
+    connect.username = Some("user".to_string());
+    connect.password = Some(b"secret".to_vec());
+    connect.will_message = Some(will);
+
+    let mut subscription = Subscription::new("sensor/#".to_string(), QoSLevel::ExactlyOnce);
+    subscription.no_local = true;
+    let mut suback = SubAck::new(3);
+    suback.add_reason(Reason::GrantedQoS2);
+    let mut connack = ConnAck::default();
+    connack.set_reason(Reason::NotAuthorized);
+
+    vec![
+        Packet::Connect(Box::new(connect)),
+        Packet::ConnAck(connack),
+        Packet::Publish(publish),
+        Packet::PubAck(PubResp::new_puback()),
+        Packet::Subscribe(Subscribe::new(3, vec![subscription])),
+        Packet::SubAck(suback),
+        Packet::Unsubscribe(Unsubscribe::new(4, vec!["sensor/#".to_string()])),
+        Packet::Disconnect(Disconnect::new(Reason::ServerShutdown)),
+    ]
+}
+
+#[test]
+fn test_json_round_trip() {
+    for packet in packets() {
+        let json = serde_json::to_string(&packet).unwrap();
+        let decoded: Packet = serde_json::from_str(&json).unwrap();
+        assert_eq!(packet, decoded, "{}", json);
+    }
+}
+
+#[test]
+fn test_json_base64() {
+    let packets = packets();
+    let json = serde_json::to_value(&packets[2]).unwrap();
+    let publish = &json["Publish"];
+    assert_eq!("AP8Q", publish["payload"]);
+    // properties are serialized as a list in wire order
+    let correlation = &publish["props"]["properties"][0];
+    assert_eq!("Y29ycg==", correlation["CorrelationData"]);
+    let json = serde_json::to_value(&packets[0]).unwrap();
+    assert_eq!("c2VjcmV0", json["Connect"]["password"]);
+    assert_eq!("b2ZmbGluZQ==", json["Connect"]["will_message"]["payload"]);
+}
+
+#[test]
+fn test_binary_round_trip() {
+    let packets = packets();
+    let encoded = bincode::serialize(&packets).unwrap();
+    let decoded: Vec<Packet> = bincode::deserialize(&encoded).unwrap();
+    assert_eq!(packets, decoded);
+}
+
+#[test]
+fn test_invalid_base64() {
+    let json = r#"{"qos":"AtMostOnce","retain":false,"topic":"t","payload":"not base64!","props":{"supported":[],"properties":[]}}"#;
+    match serde_json::from_str::<WillMessage>(json) {
+        Err(e) => assert!(e.to_string().contains("invalid base64"), "{}", e),
+        Ok(will) => panic!("expected invalid base64 to be rejected, found {:?}", will),
+    }
+}
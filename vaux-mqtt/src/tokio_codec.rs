@@ -0,0 +1,214 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::codec::{encode_with_version, encoded_len, ErrorKind, ProtocolVersion};
+use crate::decoder::MAX_PACKET_SIZE;
+use crate::PacketType;
+use crate::{MqttCodecError, Packet, StreamDecoder};
+
+/// Tokio codec for MQTT packets, for use with `tokio_util::codec::Framed`.
+/// Every [`Packet`] variant is decoded and encoded using the protocol version
+/// of the connection, which defaults to MQTT v5 and is typically set from the
+/// CONNECT packet. Received packets larger than the maximum packet size and
+/// sent packets larger than the maximum send size are rejected. The two limits
+/// are separate because each side of a connection announces the size it is
+/// willing to accept, MQTT v5 3.1.2.11.4.
+#[derive(Debug, Clone)]
+pub struct MqttCodec {
+    decoder: StreamDecoder,
+    max_send_size: usize,
+}
+
+impl Default for MqttCodec {
+    fn default() -> Self {
+        Self {
+            decoder: StreamDecoder::default(),
+            max_send_size: MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl MqttCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum size of a received packet, including the fixed
+    /// header.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.decoder = self.decoder.with_max_packet_size(max_packet_size);
+        self
+    }
+
+    /// Sets the maximum size of a sent packet, including the fixed header,
+    /// typically the maximum packet size announced by the peer.
+    pub fn with_max_send_size(mut self, max_send_size: usize) -> Self {
+        self.max_send_size = max_send_size;
+        self
+    }
+
+    pub fn with_version(mut self, version: ProtocolVersion) -> Self {
+        self.decoder = self.decoder.with_version(version);
+        self
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.decoder.max_packet_size()
+    }
+
+    pub fn max_send_size(&self) -> usize {
+        self.max_send_size
+    }
+
+    /// Sets the maximum size of a sent packet once the peer has announced
+    /// it, e.g. from the maximum packet size property of CONNECT.
+    pub fn set_max_send_size(&mut self, max_send_size: usize) {
+        self.max_send_size = max_send_size;
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.decoder.version()
+    }
+
+    /// Sets the protocol version for the packets following CONNECT.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.decoder.set_version(version);
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = MqttCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decoder.decode(src)
+    }
+}
+
+impl Encoder<Packet> for MqttCodec {
+    type Error = MqttCodecError;
+
+    /// Encodes the packet. A packet whose encoded size exceeds the maximum
+    /// send size is rejected before anything is written to the destination.
+    fn encode(&mut self, packet: Packet, dest: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = encoded_len(&packet, self.version());
+        if len > self.max_send_size {
+            return Err(MqttCodecError::new_with_kind(
+                &format!(
+                    "packet size {} exceeds maximum send size {}",
+                    len, self.max_send_size
+                ),
+                ErrorKind::PacketTooLarge(len, self.max_send_size),
+            )
+            .in_packet(PacketType::from(&packet), 0));
+        }
+        dest.reserve(len);
+        encode_with_version(packet, dest, self.version())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::publish::Publish;
+    use crate::subscribe::{SubAck, Subscribe, Subscription};
+    use crate::{ConnAck, Connect, Disconnect, FixedHeader, PacketType, PubResp, QoSLevel, Reason};
+
+    use super::*;
+
+    fn packets() -> Vec<Packet> {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        publish.set_packet_id(1).unwrap();
+        publish.set_payload(b"21".to_vec());
+        let mut connect = Connect::default();
+        connect.client_id = "client-1".to_string();
+        let mut suback = SubAck::new(2);
+        suback.add_reason(Reason::GrantedQoS1);
+        let pubresp = |mut resp: PubResp| {
+            resp.packet_id = 1;
+            resp
+        };
+        vec![
+            Packet::Connect(Box::new(connect)),
+            Packet::ConnAck(ConnAck::default()),
+            Packet::Publish(publish),
+            Packet::PubAck(pubresp(PubResp::new_puback())),
+            Packet::PubRec(pubresp(PubResp::new_pubrec())),
+            Packet::PubRel(pubresp(PubResp::new_pubrel())),
+            Packet::PubComp(pubresp(PubResp::new_pubcomp())),
+            Packet::Subscribe(Subscribe::new(
+                2,
+                vec![Subscription::new(
+                    "sensor/#".to_string(),
+                    QoSLevel::AtLeastOnce,
+                )],
+            )),
+            Packet::SubAck(suback),
+            Packet::PingRequest(FixedHeader::new(PacketType::PingReq)),
+            Packet::PingResponse(FixedHeader::new(PacketType::PingResp)),
+            Packet::Disconnect(Disconnect::new(Reason::NormalDisconnect)),
+        ]
+    }
+
+    #[test]
+    fn test_every_packet() {
+        for version in [ProtocolVersion::V5, ProtocolVersion::V3_1_1] {
+            let mut codec = MqttCodec::new().with_version(version);
+            let mut buf = BytesMut::new();
+            for packet in packets() {
+                codec.encode(packet, &mut buf).unwrap();
+            }
+            // decode the stream of packets one byte at a time
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for byte in buf {
+                src.extend_from_slice(&[byte]);
+                if let Some(mut packet) = codec.decode(&mut src).unwrap() {
+                    if let Packet::Publish(publish) = &mut packet {
+                        // the decoded header records the remaining length
+                        publish.header.remaining = 0;
+                    }
+                    decoded.push(packet);
+                }
+            }
+            assert!(src.is_empty());
+            assert_eq!(packets(), decoded, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn test_encode_max_send_size() {
+        // the receive limit does not apply to sent packets
+        let mut codec = MqttCodec::new().with_max_packet_size(8);
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        assert!(codec
+            .encode(Packet::Publish(publish), &mut BytesMut::new())
+            .is_ok());
+
+        let mut codec = MqttCodec::new().with_max_send_size(8);
+        let mut dest = BytesMut::new();
+        codec
+            .encode(
+                Packet::PingRequest(FixedHeader::new(PacketType::PingReq)),
+                &mut dest,
+            )
+            .unwrap();
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        match codec.encode(Packet::Publish(publish), &mut dest) {
+            Err(e) => assert_eq!(ErrorKind::PacketTooLarge(16, 8), e.kind()),
+            r => panic!("expected packet too large, found {:?}", r),
+        }
+        assert_eq!(2, dest.len());
+        // the size is checked before the packet is written
+        let capacity = dest.capacity();
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        publish.set_payload(vec![0; 1 << 20]);
+        assert!(codec.encode(Packet::Publish(publish), &mut dest).is_err());
+        assert_eq!(2, dest.len());
+        assert_eq!(capacity, dest.capacity());
+    }
+}
@@ -0,0 +1,254 @@
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+use crate::validate::{validate_topic_filter, validate_topic_name};
+use crate::MqttCodecError;
+
+const LEVEL_SEPARATOR: char = '/';
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+const WILDCARDS: [char; 2] = ['+', '#'];
+const SYSTEM_TOPIC_PREFIX: char = '$';
+pub(crate) const SHARE_PREFIX: &str = "$share/";
+
+/// Topic name of an application message, MQTT v5 4.7. A topic name is at
+/// least one character long and does not include wildcard characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicName(String);
+
+impl TopicName {
+    pub fn new(name: impl Into<String>) -> Result<Self, MqttCodecError> {
+        let name = name.into();
+        validate_topic_name(&name)?;
+        Ok(Self(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Determines if the topic begins with '$'. Topics beginning with '$' are
+    /// reserved for server specific use and are not matched by topic filters
+    /// beginning with a wildcard, MQTT v5 4.7.2.
+    pub fn is_system(&self) -> bool {
+        self.0.starts_with(SYSTEM_TOPIC_PREFIX)
+    }
+
+    pub fn levels(&self) -> impl Iterator<Item = &str> {
+        self.0.split(LEVEL_SEPARATOR)
+    }
+}
+
+impl Display for TopicName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl AsRef<str> for TopicName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for TopicName {
+    type Err = MqttCodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl From<TopicName> for String {
+    fn from(name: TopicName) -> Self {
+        name.0
+    }
+}
+
+/// Topic filter of a subscription, MQTT v5 4.7. The single level wildcard
+/// '+' occupies an entire level and the multi-level wildcard '#' occupies the
+/// last level. A shared subscription filter, MQTT v5 4.8.2, takes the form
+/// "$share/{ShareName}/{filter}".
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicFilter {
+    filter: String,
+    /// start of the filter following the share name of a shared subscription
+    start: usize,
+    wildcard: bool,
+}
+
+impl TopicFilter {
+    pub fn new(filter: impl Into<String>) -> Result<Self, MqttCodecError> {
+        let filter = filter.into();
+        validate_topic_filter(&filter)?;
+        let start = match filter.strip_prefix(SHARE_PREFIX) {
+            Some(shared) => {
+                // the share name is followed by a separator once validated
+                SHARE_PREFIX.len() + shared.find(LEVEL_SEPARATOR).unwrap_or(0) + 1
+            }
+            None => 0,
+        };
+        let wildcard = filter[start..].contains(WILDCARDS);
+        Ok(Self {
+            filter,
+            start,
+            wildcard,
+        })
+    }
+
+    /// Gets the filter as received, including the share name of a shared
+    /// subscription.
+    pub fn as_str(&self) -> &str {
+        &self.filter
+    }
+
+    /// Gets the filter used to match topic names, without the share name of a
+    /// shared subscription.
+    pub fn filter(&self) -> &str {
+        &self.filter[self.start..]
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.start > 0
+    }
+
+    pub fn share_name(&self) -> Option<&str> {
+        if self.is_shared() {
+            Some(&self.filter[SHARE_PREFIX.len()..self.start - 1])
+        } else {
+            None
+        }
+    }
+
+    pub fn has_wildcard(&self) -> bool {
+        self.wildcard
+    }
+
+    /// Determines whether the topic name matches the filter using the
+    /// wildcard rules in MQTT v5 4.7. Topics beginning with '$' are not matched
+    /// by filters beginning with a wildcard. A filter without wildcards is
+    /// compared to the topic name directly.
+    pub fn matches(&self, topic: &TopicName) -> bool {
+        self.matches_name(topic.as_str())
+    }
+
+    /// Determines whether the topic name matches the filter, see
+    /// [`TopicFilter::matches`]. The topic name is borrowed, e.g. from a
+    /// received packet, and is expected to be a valid topic name.
+    pub fn matches_name(&self, topic: &str) -> bool {
+        let filter = self.filter();
+        if !self.wildcard {
+            return filter == topic;
+        }
+        if topic.starts_with(SYSTEM_TOPIC_PREFIX) && filter.starts_with(WILDCARDS) {
+            return false;
+        }
+        let mut filter_levels = filter.split(LEVEL_SEPARATOR);
+        let mut topic_levels = topic.split(LEVEL_SEPARATOR);
+        loop {
+            match (filter_levels.next(), topic_levels.next()) {
+                (Some(MULTI_LEVEL_WILDCARD), _) => return true,
+                (Some(SINGLE_LEVEL_WILDCARD), Some(_)) => {}
+                (Some(f), Some(t)) if f == t => {}
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Display for TopicFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.filter)
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        &self.filter
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = MqttCodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl From<TopicFilter> for String {
+    fn from(filter: TopicFilter) -> Self {
+        filter.filter
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Reason;
+
+    use super::*;
+
+    fn matches(filter: &str, topic: &str) -> bool {
+        TopicFilter::new(filter)
+            .unwrap()
+            .matches(&TopicName::new(topic).unwrap())
+    }
+
+    #[test]
+    fn test_topic_name() {
+        let name = TopicName::new("sensor/temp").unwrap();
+        assert_eq!(vec!["sensor", "temp"], name.levels().collect::<Vec<_>>());
+        assert!(!name.is_system());
+        assert!(TopicName::new("$SYS/uptime").unwrap().is_system());
+        match TopicName::new("sensor/#") {
+            Err(e) => assert_eq!(Reason::InvalidTopicName, e.reason()),
+            Ok(name) => panic!("expected wildcard to be rejected, found {}", name),
+        }
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        let filter = TopicFilter::new("sensor/+").unwrap();
+        assert!(filter.has_wildcard());
+        assert!(!filter.is_shared());
+        assert_eq!(None, filter.share_name());
+        let shared: TopicFilter = "$share/group/sensor/temp".parse().unwrap();
+        assert!(shared.is_shared());
+        assert!(!shared.has_wildcard());
+        assert_eq!(Some("group"), shared.share_name());
+        assert_eq!("sensor/temp", shared.filter());
+        assert_eq!("$share/group/sensor/temp", shared.as_str());
+        match TopicFilter::new("sensor/#/temp") {
+            Err(e) => assert_eq!(Reason::InvalidTopicFilter, e.reason()),
+            Ok(filter) => panic!(
+                "expected misplaced wildcard to be rejected, found {}",
+                filter
+            ),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("sport/tennis/player1", "sport/tennis/player1"));
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("/+", "/finance"));
+        assert!(!matches("+", "/finance"));
+        assert!(!matches("sport/+", "sport/tennis/player1"));
+        assert!(!matches("sport/tennis", "sport/tennis/player1"));
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(!matches("+/uptime", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(matches("$share/group/sport/#", "sport/tennis"));
+        assert!(!matches("$share/group/#", "$SYS/uptime"));
+    }
+}
@@ -0,0 +1,324 @@
+use alloc::{string::String, vec::Vec};
+use bytes::{Buf, BufMut, BytesMut};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
+
+use crate::{
+    builder::UnsubscribeBuilder,
+    codec::{get_u16, get_u8, get_utf8, put_utf8, variable_byte_int_size, SIZE_UTF8_STRING},
+    property::{PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize},
+    subscribe::VAR_HDR_LEN,
+    Decode, Encode, FixedHeader, MqttCodecError, PacketType, Reason, Size,
+};
+
+/// UNSUBSCRIBE, MQTT v5 3.10. The payload holds the topic filters to remove,
+/// which must match the filters of earlier subscriptions exactly.
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(UserProperty)]
+pub struct Unsubscribe {
+    packet_id: u16,
+    props: PropertyBundle,
+    payload: Vec<String>,
+}
+
+impl Default for Unsubscribe {
+    fn default() -> Self {
+        Self::new(0, Vec::new())
+    }
+}
+
+impl Unsubscribe {
+    /// Creates a builder for an UNSUBSCRIBE with the packet identifier, see
+    /// [`crate::builder`].
+    pub fn builder(packet_id: u16) -> UnsubscribeBuilder {
+        UnsubscribeBuilder::new(packet_id)
+    }
+
+    pub fn new(packet_id: u16, filters: Vec<String>) -> Self {
+        Self {
+            packet_id,
+            props: PropertyBundle::new(Self::supported_properties()),
+            payload: filters,
+        }
+    }
+
+    pub fn packet_id(&self) -> u16 {
+        self.packet_id
+    }
+
+    pub fn add_filter(&mut self, filter: String) {
+        self.payload.push(filter);
+    }
+
+    pub fn filters(&self) -> &[String] {
+        &self.payload
+    }
+
+    /// Decodes an MQTT 3.1.1 UNSUBSCRIBE, which has no properties.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < 2 {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.10.2 insufficient data for UNSUBSCRIBE",
+            ));
+        }
+        self.packet_id = get_u16(src)?;
+        while src.has_remaining() {
+            self.payload.push(get_utf8(src)?);
+        }
+        Ok(())
+    }
+
+    /// Encodes the UNSUBSCRIBE for MQTT 3.1.1. Properties are dropped.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        self.check_encode()?;
+        let mut hdr = FixedHeader::new(PacketType::Unsubscribe);
+        hdr.set_remaining(self.size_v3());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.encode_payload(dest)
+    }
+
+    /// Gets the remaining length of the UNSUBSCRIBE for MQTT 3.1.1, which
+    /// has no properties.
+    pub(crate) fn size_v3(&self) -> u32 {
+        VAR_HDR_LEN + self.payload_size()
+    }
+
+    fn check_encode(&self) -> Result<(), MqttCodecError> {
+        if self.packet_id == 0 {
+            return Err(MqttCodecError::new(
+                "MQTTv5 2.2.1 packet identifier must not be 0",
+            ));
+        }
+        if self.payload.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.10.3 unsubscribe payload must exist",
+            ));
+        }
+        Ok(())
+    }
+
+    fn encode_payload(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        for filter in &self.payload {
+            put_utf8(filter, dest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Size for Unsubscribe {
+    fn size(&self) -> u32 {
+        let prop_size = self.property_size();
+        VAR_HDR_LEN + variable_byte_int_size(prop_size) + prop_size + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.property_size_internal()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.payload
+            .iter()
+            .map(|filter| SIZE_UTF8_STRING + filter.len() as u32)
+            .sum()
+    }
+}
+
+impl Encode for Unsubscribe {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        self.check_encode()?;
+        let mut hdr = FixedHeader::new(PacketType::Unsubscribe);
+        hdr.set_remaining(self.size());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.property_encode(dest)?;
+        self.encode_payload(dest)
+    }
+}
+
+impl Decode for Unsubscribe {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < 3 {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.10.2 insufficient data for UNSUBSCRIBE",
+            ));
+        }
+        self.packet_id = get_u16(src)?;
+        self.property_decode(src)?;
+        while src.has_remaining() {
+            self.payload.push(get_utf8(src)?);
+        }
+        Ok(())
+    }
+}
+
+/// UNSUBACK, MQTT v5 3.11. The reason codes are in the same order as the
+/// topic filters of the acknowledged UNSUBSCRIBE. An MQTT 3.1.1 UNSUBACK has
+/// no reason codes, so none are decoded for it.
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, PartialEq, Eq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(ReasonString, UserProperty)]
+pub struct UnsubAck {
+    packet_id: u16,
+    props: PropertyBundle,
+    reasons: Vec<Reason>,
+}
+
+impl Default for UnsubAck {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl UnsubAck {
+    pub fn new(packet_id: u16) -> Self {
+        Self {
+            packet_id,
+            props: PropertyBundle::new(Self::supported_properties()),
+            reasons: Vec::new(),
+        }
+    }
+
+    pub fn packet_id(&self) -> u16 {
+        self.packet_id
+    }
+
+    /// Adds the reason code for the next topic filter in the acknowledged
+    /// UNSUBSCRIBE.
+    pub fn add_reason(&mut self, reason: Reason) {
+        self.reasons.push(reason);
+    }
+
+    pub fn reasons(&self) -> &[Reason] {
+        &self.reasons
+    }
+
+    /// Decodes an MQTT 3.1.1 UNSUBACK, which holds only the packet
+    /// identifier.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() != VAR_HDR_LEN as usize {
+            return Err(MqttCodecError::new(
+                "MQTTv3.1.1 3.11.1 UNSUBACK remaining length must be 2",
+            ));
+        }
+        self.packet_id = get_u16(src)?;
+        Ok(())
+    }
+
+    /// Encodes the UNSUBACK for MQTT 3.1.1. Properties and reason codes are
+    /// dropped.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        let mut hdr = FixedHeader::new(PacketType::UnsubAck);
+        hdr.set_remaining(self.size_v3());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        Ok(())
+    }
+
+    pub(crate) fn size_v3(&self) -> u32 {
+        VAR_HDR_LEN
+    }
+}
+
+impl Size for UnsubAck {
+    fn size(&self) -> u32 {
+        let prop_size = self.property_size();
+        VAR_HDR_LEN + variable_byte_int_size(prop_size) + prop_size + self.payload_size()
+    }
+
+    fn property_size(&self) -> u32 {
+        self.property_size_internal()
+    }
+
+    fn payload_size(&self) -> u32 {
+        self.reasons.len() as u32
+    }
+}
+
+impl Encode for UnsubAck {
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        if self.reasons.is_empty() {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.11.3 UNSUBACK must contain a reason code for each topic filter",
+            ));
+        }
+        let mut hdr = FixedHeader::new(PacketType::UnsubAck);
+        hdr.set_remaining(self.size());
+        hdr.encode(dest)?;
+        dest.put_u16(self.packet_id);
+        self.property_encode(dest)?;
+        for reason in &self.reasons {
+            dest.put_u8(*reason as u8);
+        }
+        Ok(())
+    }
+}
+
+impl Decode for UnsubAck {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        if src.remaining() < 4 {
+            return Err(MqttCodecError::new(
+                "MQTTv5 3.11.3 insufficient data for UNSUBACK",
+            ));
+        }
+        self.packet_id = get_u16(src)?;
+        self.property_decode(src)?;
+        while src.has_remaining() {
+            self.reasons.push(Reason::try_from(get_u8(src)?)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Buf, BytesMut};
+
+    use crate::{property::PacketProperties, Decode, Encode, Reason, Size};
+
+    use super::{UnsubAck, Unsubscribe};
+
+    #[test]
+    fn test_unsubscribe_round_trip() {
+        let mut unsubscribe = Unsubscribe::new(7, vec!["sensor/#".to_string()]);
+        unsubscribe.add_filter("$share/group/status/+".to_string());
+        unsubscribe
+            .properties_mut()
+            .add_user_property("key".to_string(), "value".to_string());
+        let mut dest = BytesMut::new();
+        unsubscribe.encode(&mut dest).unwrap();
+        assert_eq!(unsubscribe.size() as usize + 2, dest.len());
+        assert_eq!(0xa2, dest[0]);
+        dest.advance(2);
+        let mut decoded = Unsubscribe::default();
+        decoded.decode(&mut dest).unwrap();
+        assert_eq!(unsubscribe, decoded);
+    }
+
+    #[test]
+    fn test_unsubscribe_empty() {
+        let unsubscribe = Unsubscribe::new(7, Vec::new());
+        match unsubscribe.encode(&mut BytesMut::new()) {
+            Err(e) => assert!(e.message().starts_with("MQTTv5 3.10.3")),
+            Ok(_) => panic!("expected UNSUBSCRIBE without filters to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_unsuback_round_trip() {
+        let mut unsuback = UnsubAck::new(0x1234);
+        unsuback.add_reason(Reason::Success);
+        unsuback.add_reason(Reason::NoSubscriptionExisted);
+        let mut dest = BytesMut::new();
+        unsuback.encode(&mut dest).unwrap();
+        assert_eq!(&[0xb0, 0x05, 0x12, 0x34, 0x00, 0x00, 0x11], &dest[..]);
+        dest.advance(2);
+        let mut decoded = UnsubAck::default();
+        decoded.decode(&mut dest).unwrap();
+        assert_eq!(unsuback, decoded);
+    }
+}
@@ -0,0 +1,382 @@
+//! Checks for the MQTT v5 "MUST" rules that are not enforced by the packet
+//! decoders and encoders. The checks are applied when decoding and encoding
+//! with the `pedantic` feature and may be called directly without it.
+
+use crate::{
+    codec::ErrorKind, property::PacketProperties, publish::Publish, topic::SHARE_PREFIX,
+    MqttCodecError, Packet, PropertyType, QoSLevel, Subscribe, Unsubscribe, WillMessage,
+};
+use alloc::{format, string::String};
+
+const LEVEL_SEPARATOR: char = '/';
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+const WILDCARDS: [char; 2] = ['+', '#'];
+
+/// A UTF-8 encoded string must not include the null character U+0000, MQTT
+/// v5 1.5.4. Surrogates cannot be held in a `str` and are rejected when the
+/// string is decoded.
+pub fn validate_utf8(value: &str) -> Result<(), MqttCodecError> {
+    if value.contains('\u{0000}') {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 1.5.4 string must not include the null character",
+            ErrorKind::InvalidUtf8,
+        ));
+    }
+    Ok(())
+}
+
+/// A topic name must be at least one character long and must not include the
+/// wildcard characters, MQTT v5 4.7.
+pub fn validate_topic_name(name: &str) -> Result<(), MqttCodecError> {
+    validate_utf8(name)?;
+    if name.is_empty() {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 4.7.3 topic name must not be empty",
+            ErrorKind::InvalidTopicName,
+        ));
+    }
+    if name.contains(WILDCARDS) {
+        return Err(MqttCodecError::new_with_kind(
+            &format!(
+                "MQTTv5 4.7.1 topic name must not include wildcards: {}",
+                name
+            ),
+            ErrorKind::InvalidTopicName,
+        ));
+    }
+    Ok(())
+}
+
+/// A topic filter must be at least one character long. The single level
+/// wildcard must occupy an entire level and the multi-level wildcard must
+/// occupy the last level, MQTT v5 4.7.1. A shared subscription filter must
+/// have a share name without wildcards followed by a topic filter, MQTT v5
+/// 4.8.2.
+pub fn validate_topic_filter(filter: &str) -> Result<(), MqttCodecError> {
+    validate_utf8(filter)?;
+    let filter = match filter.strip_prefix(SHARE_PREFIX) {
+        Some(shared) => match shared.split_once(LEVEL_SEPARATOR) {
+            Some((share_name, filter))
+                if !share_name.is_empty() && !share_name.contains(WILDCARDS) =>
+            {
+                filter
+            }
+            _ => {
+                return Err(MqttCodecError::new_with_kind(
+                    &format!("MQTTv5 4.8.2 invalid share name: {}", filter),
+                    ErrorKind::InvalidTopicFilter,
+                ))
+            }
+        },
+        None => filter,
+    };
+    if filter.is_empty() {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 4.7.3 topic filter must not be empty",
+            ErrorKind::InvalidTopicFilter,
+        ));
+    }
+    let mut levels = filter.split(LEVEL_SEPARATOR).peekable();
+    while let Some(level) = levels.next() {
+        let valid = match level {
+            SINGLE_LEVEL_WILDCARD => true,
+            MULTI_LEVEL_WILDCARD => levels.peek().is_none(),
+            _ => !level.contains(WILDCARDS),
+        };
+        if !valid {
+            return Err(MqttCodecError::new_with_kind(
+                &format!(
+                    "MQTTv5 4.7.1 misplaced wildcard in topic filter: {}",
+                    filter
+                ),
+                ErrorKind::InvalidTopicFilter,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the rules for a packet that depend on more than one field, such as
+/// the packet identifier required by the QoS level of a PUBLISH and the topic
+/// name and topic filter syntax.
+pub fn validate_packet(packet: &Packet) -> Result<(), MqttCodecError> {
+    match packet {
+        Packet::Connect(connect) => {
+            if let Some(will) = &connect.will_message {
+                validate_will(will)?;
+            }
+        }
+        Packet::Publish(publish) => validate_publish(publish)?,
+        Packet::PubAck(resp)
+        | Packet::PubRec(resp)
+        | Packet::PubRel(resp)
+        | Packet::PubComp(resp) => validate_packet_id(resp.packet_id)?,
+        Packet::Subscribe(subscribe) => validate_subscribe(subscribe)?,
+        Packet::SubAck(suback) => validate_packet_id(suback.packet_id())?,
+        Packet::Unsubscribe(unsubscribe) => validate_unsubscribe(unsubscribe)?,
+        Packet::UnsubAck(unsuback) => validate_packet_id(unsuback.packet_id())?,
+        Packet::PingRequest(_)
+        | Packet::PingResponse(_)
+        | Packet::ConnAck(_)
+        | Packet::Disconnect(_) => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_will(will: &WillMessage) -> Result<(), MqttCodecError> {
+    validate_topic_name(&will.topic)?;
+    validate_response_topic(will.props.response_topic())
+}
+
+pub(crate) fn validate_publish(publish: &Publish) -> Result<(), MqttCodecError> {
+    validate_publish_header(
+        publish.topic_name.as_deref(),
+        publish.properties().has_property(&PropertyType::TopicAlias),
+        publish.qos(),
+        publish.packet_id,
+    )?;
+    validate_response_topic(publish.properties().response_topic())?;
+    validate_subscription_ids(publish.properties().subscription_ids())
+}
+
+pub(crate) fn validate_subscribe(subscribe: &Subscribe) -> Result<(), MqttCodecError> {
+    validate_packet_id(subscribe.packet_id())?;
+    validate_subscriptions(
+        subscribe
+            .subscriptions()
+            .iter()
+            .map(|subscription| (subscription.filter.as_str(), subscription.no_local)),
+    )?;
+    validate_subscribe_ids(subscribe.properties().subscription_ids())
+}
+
+pub(crate) fn validate_unsubscribe(unsubscribe: &Unsubscribe) -> Result<(), MqttCodecError> {
+    validate_packet_id(unsubscribe.packet_id())?;
+    validate_unsubscribe_filters(unsubscribe.filters().iter().map(String::as_str))
+}
+
+/// A PUBLISH must have a topic name or a topic alias, MQTT v5 3.3.2.1, and
+/// must have a packet identifier if and only if the QoS level is above 0,
+/// MQTT v5 2.2.1.
+pub(crate) fn validate_publish_header(
+    topic_name: Option<&str>,
+    has_alias: bool,
+    qos: QoSLevel,
+    packet_id: Option<u16>,
+) -> Result<(), MqttCodecError> {
+    match topic_name {
+        Some(topic_name) => validate_topic_name(topic_name)?,
+        None if has_alias => {}
+        None => {
+            return Err(MqttCodecError::new_with_kind(
+                "MQTTv5 3.3.2.1 must have topic name or topic alias",
+                ErrorKind::ProtocolErr,
+            ))
+        }
+    }
+    match (qos, packet_id) {
+        (QoSLevel::AtMostOnce, None) => Ok(()),
+        (QoSLevel::AtMostOnce, Some(_)) => Err(MqttCodecError::new_with_kind(
+            "MQTTv5 2.2.1 QOS 0 PUBLISH must not have a packet identifier",
+            ErrorKind::InvalidPacketId,
+        )),
+        (_, packet_id) => validate_packet_id(packet_id.unwrap_or(0)),
+    }
+}
+
+/// A SUBSCRIBE must contain at least one subscription, MQTT v5 3.8.3, each
+/// with a valid topic filter. No local must not be set on a shared
+/// subscription, MQTT v5 3.8.3.1. The subscriptions are given as pairs of
+/// topic filter and no local flag.
+pub(crate) fn validate_subscriptions<'a>(
+    subscriptions: impl IntoIterator<Item = (&'a str, bool)>,
+) -> Result<(), MqttCodecError> {
+    let mut empty = true;
+    for (filter, no_local) in subscriptions {
+        empty = false;
+        validate_topic_filter(filter)?;
+        if no_local && filter.starts_with(SHARE_PREFIX) {
+            return Err(MqttCodecError::new_with_kind(
+                "MQTTv5 3.8.3.1 no local must not be set on a shared subscription",
+                ErrorKind::ProtocolErr,
+            ));
+        }
+    }
+    if empty {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 3.8.3 SUBSCRIBE must contain at least one subscription",
+            ErrorKind::ProtocolErr,
+        ));
+    }
+    Ok(())
+}
+
+/// An UNSUBSCRIBE must contain at least one topic filter, MQTT v5 3.10.3.
+pub(crate) fn validate_unsubscribe_filters<'a>(
+    filters: impl IntoIterator<Item = &'a str>,
+) -> Result<(), MqttCodecError> {
+    let mut empty = true;
+    for filter in filters {
+        empty = false;
+        validate_topic_filter(filter)?;
+    }
+    if empty {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 3.10.3 UNSUBSCRIBE must contain at least one topic filter",
+            ErrorKind::ProtocolErr,
+        ));
+    }
+    Ok(())
+}
+
+/// Packets that require a packet identifier must have a non-zero packet
+/// identifier, MQTT v5 2.2.1.
+pub(crate) fn validate_packet_id(packet_id: u16) -> Result<(), MqttCodecError> {
+    if packet_id == 0 {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 2.2.1 packet identifier must not be 0",
+            ErrorKind::InvalidPacketId,
+        ));
+    }
+    Ok(())
+}
+
+/// The response topic must be a topic name, MQTT v5 3.3.2.3.5.
+pub(crate) fn validate_response_topic(topic: Option<&str>) -> Result<(), MqttCodecError> {
+    match topic {
+        Some(topic) => validate_topic_name(topic),
+        None => Ok(()),
+    }
+}
+
+/// A SUBSCRIBE must have at most one subscription identifier, MQTT v5
+/// 3.8.2.1.2.
+pub(crate) fn validate_subscribe_ids(
+    ids: impl IntoIterator<Item = u32>,
+) -> Result<(), MqttCodecError> {
+    let mut ids = ids.into_iter();
+    let first = ids.next();
+    if ids.next().is_some() {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 3.8.2.1.2 SUBSCRIBE must have at most one subscription identifier",
+            ErrorKind::DuplicateProperty(PropertyType::SubscriptionIdentifier),
+        ));
+    }
+    validate_subscription_ids(first)
+}
+
+/// A subscription identifier of 0 is a protocol error, MQTT v5 3.3.2.3.8.
+pub(crate) fn validate_subscription_ids(
+    ids: impl IntoIterator<Item = u32>,
+) -> Result<(), MqttCodecError> {
+    if ids.into_iter().any(|id| id == 0) {
+        return Err(MqttCodecError::new_with_kind(
+            "MQTTv5 3.3.2.3.8 subscription identifier must not be 0",
+            ErrorKind::ProtocolErr,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{publish::Publish, PubResp, Reason, Subscribe, Subscription};
+
+    use super::*;
+
+    #[test]
+    fn test_utf8() {
+        assert!(validate_utf8("sensor/temp").is_ok());
+        match validate_utf8("sensor\u{0000}temp") {
+            Err(e) => assert_eq!(ErrorKind::InvalidUtf8, e.kind()),
+            Ok(_) => panic!("expected null character to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_topic_name() {
+        assert!(validate_topic_name("sensor/temp").is_ok());
+        assert!(validate_topic_name("/").is_ok());
+        assert!(validate_topic_name("$SYS/uptime").is_ok());
+        for name in ["", "sensor/+", "sensor/#", "sensor+"] {
+            match validate_topic_name(name) {
+                Err(e) => assert_eq!(Reason::InvalidTopicName, e.reason()),
+                Ok(_) => panic!("expected {:?} to be rejected", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        for filter in [
+            "sensor/temp",
+            "#",
+            "+",
+            "sensor/+/temp",
+            "sensor/#",
+            "+/+",
+            "/",
+            "$share/group/sensor/#",
+        ] {
+            assert!(validate_topic_filter(filter).is_ok(), "{}", filter);
+        }
+        for filter in [
+            "",
+            "sensor#",
+            "sensor/#/temp",
+            "sensor+/temp",
+            "sensor/te+mp",
+            "$share/group",
+            "$share//sensor",
+            "$share/gr+oup/sensor",
+            "$share/group/",
+        ] {
+            match validate_topic_filter(filter) {
+                Err(e) => assert_eq!(Reason::InvalidTopicFilter, e.reason()),
+                Ok(_) => panic!("expected {:?} to be rejected", filter),
+            }
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_id() {
+        let mut publish = Publish::default();
+        publish.topic_name = Some("sensor/temp".to_string());
+        assert!(validate_packet(&Packet::Publish(publish.clone())).is_ok());
+        publish.packet_id = Some(1);
+        match validate_packet(&Packet::Publish(publish.clone())) {
+            Err(e) => assert_eq!(Reason::ProtocolErr, e.reason()),
+            Ok(_) => panic!("expected packet identifier to be rejected for QoS 0"),
+        }
+        publish.set_qos(QoSLevel::AtLeastOnce);
+        assert!(validate_packet(&Packet::Publish(publish.clone())).is_ok());
+        publish.packet_id = None;
+        assert!(validate_packet(&Packet::Publish(publish)).is_err());
+        assert!(validate_packet(&Packet::PubAck(PubResp::new_puback())).is_err());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let subscription = Subscription::new("sensor/#".to_string(), QoSLevel::AtMostOnce);
+        assert!(validate_packet(&Packet::Subscribe(Subscribe::new(
+            1,
+            vec![subscription.clone()]
+        )))
+        .is_ok());
+        assert!(validate_packet(&Packet::Subscribe(Subscribe::new(1, Vec::new()))).is_err());
+        let mut subscribe = Subscribe::new(1, vec![subscription]);
+        subscribe.properties_mut().add_subscription_id(1);
+        subscribe.properties_mut().add_subscription_id(2);
+        match validate_packet(&Packet::Subscribe(subscribe)) {
+            Err(e) => assert_eq!(
+                ErrorKind::DuplicateProperty(PropertyType::SubscriptionIdentifier),
+                e.kind()
+            ),
+            Ok(_) => panic!("expected repeated subscription identifier to be rejected"),
+        }
+        let mut shared = Subscription::new("$share/group/sensor".to_string(), QoSLevel::AtMostOnce);
+        shared.no_local = true;
+        assert!(validate_packet(&Packet::Subscribe(Subscribe::new(1, vec![shared]))).is_err());
+    }
+}
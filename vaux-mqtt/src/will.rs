@@ -1,24 +1,26 @@
+use crate::builder::WillBuilder;
 use crate::codec::{get_bin, get_utf8, put_bin, variable_byte_int_size};
-use crate::property::PropertyBundle;
-use crate::{put_utf8, Decode, Encode, MqttCodecError, PropertyType, QoSLevel, Size};
-use bytes::BytesMut;
-use std::collections::HashSet;
+use crate::property::{
+    PacketProperties, PropertyBundle, PropertyDecode, PropertyEncode, PropertySize,
+};
+use crate::{put_utf8, Decode, Encode, MqttCodecError, QoSLevel, Size};
+use alloc::string::{String, ToString};
+use bytes::{BufMut, Bytes, BytesMut};
+use prop_macro::{PacketProperties, PropertyDecode, PropertyEncode, PropertySize};
 
-lazy_static! {
-    static ref SUPPORTED_WILL_PROPS: HashSet<PropertyType> = {
-        let mut set = HashSet::new();
-        set.insert(PropertyType::WillDelay);
-        set.insert(PropertyType::PayloadFormat);
-        set.insert(PropertyType::MessageExpiry);
-        set.insert(PropertyType::ContentType);
-        set.insert(PropertyType::ResponseTopic);
-        set.insert(PropertyType::CorrelationData);
-        set.insert(PropertyType::UserProperty);
-        set
-    };
-}
-
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(
+    PacketProperties, PropertySize, PropertyEncode, PropertyDecode, Debug, Clone, Eq, PartialEq,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[properties(
+    WillDelay,
+    PayloadFormat,
+    MessageExpiry,
+    ContentType,
+    ResponseTopic,
+    CorrelationData,
+    UserProperty
+)]
 /// MQTT Will message. The Will message name comes from last will and
 /// testament. The will message is typically sent under the following
 /// conditions when a client disconnects:
@@ -26,13 +28,15 @@ lazy_static! {
 /// * Client loses contact during defined timeout
 /// * Client loses connectivity to the server prior to disconnect
 /// * Server closes connection prior to disconnect
+///
 /// For more information please see
 /// <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc479576982>
 pub struct WillMessage {
     pub qos: QoSLevel,
     pub retain: bool,
     pub topic: String,
-    pub payload: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_bin"))]
+    pub payload: Bytes,
     pub props: PropertyBundle,
 }
 
@@ -42,10 +46,31 @@ impl WillMessage {
             qos,
             retain,
             topic: "".to_string(),
-            payload: Vec::new(),
-            props: PropertyBundle::new(SUPPORTED_WILL_PROPS.clone()),
+            payload: Bytes::new(),
+            props: PropertyBundle::new(Self::supported_properties()),
         }
     }
+
+    /// Creates a builder for a will message, see [`crate::builder`].
+    pub fn builder(topic: impl Into<String>, payload: impl Into<Bytes>) -> WillBuilder {
+        WillBuilder::new(topic, payload)
+    }
+}
+
+impl WillMessage {
+    /// Decodes an MQTT 3.1.1 will message, which has no will properties.
+    pub(crate) fn decode_v3(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
+        self.topic = get_utf8(src)?;
+        self.payload = get_bin(src)?;
+        Ok(())
+    }
+
+    /// Encodes the will message for MQTT 3.1.1, dropping the will properties.
+    pub(crate) fn encode_v3(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        put_utf8(&self.topic, dest)?;
+        put_bin(&self.payload, dest)?;
+        Ok(())
+    }
 }
 
 impl Decode for WillMessage {
@@ -53,7 +78,7 @@ impl Decode for WillMessage {
     /// not attempt to decode the flags QOS and Retain as these are present in the
     /// CONNECT flags variable length header prior to the will message properties
     fn decode(&mut self, src: &mut BytesMut) -> Result<(), MqttCodecError> {
-        self.props.decode(src)?;
+        self.property_decode(src)?;
         self.topic = get_utf8(src)?;
         self.payload = get_bin(src)?;
         Ok(())
@@ -61,8 +86,8 @@ impl Decode for WillMessage {
 }
 
 impl Encode for WillMessage {
-    fn encode(&self, dest: &mut BytesMut) -> Result<(), MqttCodecError> {
-        self.props.encode(dest)?;
+    fn encode(&self, dest: &mut impl BufMut) -> Result<(), MqttCodecError> {
+        self.property_encode(dest)?;
         put_utf8(&self.topic, dest)?;
         put_bin(&self.payload, dest)?;
         Ok(())
@@ -77,7 +102,7 @@ impl Size for WillMessage {
     }
 
     fn property_size(&self) -> u32 {
-        self.props.size()
+        self.property_size_internal()
     }
 
     fn payload_size(&self) -> u32 {
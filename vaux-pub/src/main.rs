@@ -1,7 +1,7 @@
 use std::{io::Read, sync::Arc, time::Duration};
 
 use clap::{error::ErrorKind, Parser};
-use vaux_mqtt::{property::Property, publish::Publish, QoSLevel};
+use vaux_mqtt::{property::PayloadFormat, publish::Publish, QoSLevel};
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -90,24 +90,23 @@ fn publish(
         };
     let producer = client.producer();
 
-    let mut publish = Publish::default();
-    publish
-        .properties_mut()
-        .set_property(Property::PayloadFormat(
-            vaux_mqtt::property::PayloadFormat::Utf8,
-        ));
-    publish
-        .properties_mut()
-        .set_property(Property::MessageExpiry(1000));
-
-    publish.topic_name = Some(args.topic);
-    publish.set_payload(Vec::from(args.message.as_bytes()));
-    publish.set_qos(args.qos);
-    publish.packet_id = Some(101);
-    if producer
-        .send(vaux_mqtt::Packet::Publish(publish.clone()))
-        .is_err()
-    {
+    let mut builder = Publish::builder(args.topic)
+        .with_payload(args.message.into_bytes())
+        .with_qos(args.qos)
+        .with_payload_format(PayloadFormat::Utf8)
+        .with_message_expiry(1000);
+    if args.qos != QoSLevel::AtMostOnce {
+        builder = builder.with_packet_id(101);
+    }
+    let publish = match builder.build() {
+        Ok(publish) => publish,
+        Err(e) => {
+            eprintln!("invalid publish: {}", e);
+            client.stop();
+            return;
+        }
+    };
+    if producer.send(vaux_mqtt::Packet::Publish(publish)).is_err() {
         eprintln!("unable to send packet to broker");
     } else {
         println!("sent message");
@@ -2,10 +2,7 @@ use std::{io::Read, sync::Arc};
 
 use clap::Parser;
 use vaux_client::MqttClient;
-use vaux_mqtt::{
-    property::{PayloadFormat, Property},
-    Packet, PropertyType, PubResp, QoSLevel, Subscribe, Subscription,
-};
+use vaux_mqtt::{property::PayloadFormat, Packet, PubResp, QoSLevel, Subscribe, Subscription};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -101,20 +98,12 @@ fn subscribe(connection: vaux_client::MqttConnection, mut client: MqttClient, ar
                 let iter = consumer.try_iter();
                 for packet in iter {
                     if let Packet::Publish(mut p) = packet {
-                        if p.properties().has_property(&PropertyType::PayloadFormat) {
-                            if let Property::PayloadFormat(indicator) = p
-                                .properties()
-                                .get_property(&PropertyType::PayloadFormat)
-                                .unwrap()
-                            {
-                                if *indicator == PayloadFormat::Utf8 {
-                                    print!("Payload: ");
-                                    println!(
-                                        "{}",
-                                        String::from_utf8(p.take_payload().unwrap()).unwrap()
-                                    );
-                                }
-                            }
+                        if p.properties().payload_format() == Some(PayloadFormat::Utf8) {
+                            print!("Payload: ");
+                            println!(
+                                "{}",
+                                String::from_utf8(p.take_payload().unwrap().to_vec()).unwrap()
+                            );
                         }
                         if args.auto_ack {
                             // check for QOS 1 or 2
@@ -3,13 +3,16 @@ use std::io::{Read, Write};
 use std::net::TcpStream;
 use uuid::Uuid;
 use vaux_mqtt::{
-    decode, encode, ConnAck, Connect, Disconnect, FixedHeader, Packet, PacketType, Reason,
+    decode, encode, property::Property, ConnAck, Connect, Disconnect, FixedHeader, Packet,
+    PacketType, Reason,
 };
 
 const DEFAULT_PORT: u16 = 1883;
-const DEFAULT_HOST: &'static str = "127.0.0.1";
+const DEFAULT_HOST: &str = "127.0.0.1";
 const PING_RESP_LEN: usize = 2;
-const CONNACK_RESP_LEN: usize = 5;
+const CONNACK_RESP_LEN: usize = 13;
+const TOPIC_ALIAS_MAX: u16 = 64;
+const MAX_PACKET_SIZE: u32 = 1024 * 1024;
 
 #[test]
 fn test_basic_ping() {
@@ -26,7 +29,11 @@ fn test_basic_ping() {
 fn test_basic_connect() {
     let mut request = Connect::default();
     request.client_id = Uuid::new_v4().to_string();
-    let ack = ConnAck::default();
+    let mut ack = ConnAck::default();
+    ack.properties_mut()
+        .set_property(Property::TopicAliasMax(TOPIC_ALIAS_MAX));
+    ack.properties_mut()
+        .set_property(Property::MaxPacketSize(MAX_PACKET_SIZE));
     test_basic(
         Packet::Connect(Box::new(request)),
         CONNACK_RESP_LEN,
@@ -37,7 +44,7 @@ fn test_basic_connect() {
 
 #[test]
 fn test_broker_assigned_id() {
-    const EXPECTED_CONNACK_LEN: usize = 44;
+    const EXPECTED_CONNACK_LEN: usize = 52;
     let request = Connect::default();
     let ack = ConnAck::default();
     let packet = Packet::ConnAck(ack);
@@ -64,7 +71,7 @@ fn test_existing_session() {
     assert!(!ack
         .properties()
         .has_property(&vaux_mqtt::PropertyType::AssignedClientId));
-    assert_eq!(false, ack.session_present, "expected no existing session");
+    assert!(!ack.session_present, "expected no existing session");
     client.disconnect();
     let ack = client.connect(Some(&client_id)).unwrap();
     assert!(ack.session_present, "expected session present");
@@ -82,9 +89,9 @@ fn test_basic(
             let mut dest = BytesMut::new();
             let result = encode(request.clone(), &mut dest);
             if let Err(e) = result {
-                assert!(false, "Failed to encode packet: {:?}", e);
+                panic!("Failed to encode packet: {:?}", e);
             }
-            match stream.write_all(&dest.to_vec()) {
+            match stream.write_all(&dest) {
                 Ok(_) => match stream.read(&mut buffer) {
                     Ok(len) => {
                         assert_eq!(
@@ -104,34 +111,28 @@ fn test_basic(
                                     }
                                     Some(data_read.0.clone())
                                 } else {
-                                    assert!(
-                                        false,
+                                    panic!(
                                         "expected {:?} packet type, found None",
                                         expected_response
                                     );
-                                    None
                                 }
                             }
                             Err(e) => {
-                                assert!(false, "Unexpected error decoding ping response: {:?}", e);
-                                None
+                                panic!("Unexpected error decoding ping response: {:?}", e);
                             }
                         }
                     }
                     Err(e) => {
-                        assert!(false, "unable to read message from broker: {:?}", e);
-                        None
+                        panic!("unable to read message from broker: {:?}", e);
                     }
                 },
                 Err(e) => {
-                    assert!(false, "unable to write message to broker: {}", e);
-                    None
+                    panic!("unable to write message to broker: {}", e);
                 }
             }
         }
         Err(e) => {
-            assert!(false, "Unable to connect to test broker: {}", e.to_string());
-            None
+            panic!("Unable to connect to test broker: {}", e);
         }
     }
 }
@@ -158,39 +159,33 @@ impl MQTTClient {
                 let mut dest = BytesMut::default();
                 let result = encode(connect_packet, &mut dest);
                 if let Err(e) = result {
-                    assert!(false, "Failed to encode packet: {:?}", e);
+                    panic!("Failed to encode packet: {:?}", e);
                 }
-                match self.connection.as_ref().unwrap().write_all(&dest.to_vec()) {
+                match self.connection.as_ref().unwrap().write_all(&dest) {
                     Ok(_) => match self.connection.as_ref().unwrap().read(&mut buffer) {
                         Ok(len) => match decode(&mut BytesMut::from(&buffer[0..len])) {
                             Ok(p) => {
-                                if let Some(packet) = p {
-                                    // match packet {
-                                    //     Packet::ConnAck(connack) => {
-                                    //         return Some(connack);
-                                    //     }
-                                    //     Packet::Disconnect(_disconnect) => {
-                                    //         panic!("disconnect");
-                                    //     }
-                                    //     _ => panic!("unexpected packet returned from remote"),
-                                    // }
+                                if let Some((packet, _)) = p {
+                                    match packet {
+                                        Packet::ConnAck(connack) => Some(connack),
+                                        Packet::Disconnect(_disconnect) => {
+                                            panic!("disconnect");
+                                        }
+                                        _ => panic!("unexpected packet returned from remote"),
+                                    }
                                 } else {
                                     panic!("no packet returned");
                                 }
                             }
-                            Err(e) => panic!("unable to decode connect response {}", e.to_string()),
+                            Err(e) => panic!("unable to decode connect response {}", e),
                         },
-                        Err(e) => panic!("unable to read stream: {}", e.to_string()),
+                        Err(e) => panic!("unable to read stream: {}", e),
                     },
-                    Err(e) => panic!(
-                        "Unable to write packet(s) to test broker: {}",
-                        e.to_string()
-                    ),
+                    Err(e) => panic!("Unable to write packet(s) to test broker: {}", e),
                 }
             }
-            Err(e) => assert!(false, "Unable to connect to test broker: {}", e.to_string()),
+            Err(e) => panic!("Unable to connect to test broker: {}", e),
         }
-        None
     }
 
     fn disconnect(&mut self) {
@@ -200,9 +195,9 @@ impl MQTTClient {
         let mut dest = BytesMut::default();
         let result = encode(packet, &mut dest);
         if let Err(e) = result {
-            assert!(false, "Failed to encode packet: {:?}", e);
+            panic!("Failed to encode packet: {:?}", e);
         }
-        match self.connection.as_ref().unwrap().write_all(&dest.to_vec()) {
+        match self.connection.as_ref().unwrap().write_all(&dest) {
             Ok(_) => self.connection = None,
             _ => {
                 panic!("unable to disconnect successfully");